@@ -1,10 +1,6 @@
-use chrono::prelude::*;
-use csv::Writer;
 use minifb::{MouseButton, Window, WindowOptions};
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::time::{Duration, Instant};
+use telemetry::{Kind, Metric, Registry};
 
 /*
 * The calculation for the position on a 1D array on the screen is
@@ -16,85 +12,56 @@ const HEIGHT: usize = 200;
 const WHITE: u32 = 0x00FFFFFF;
 const RED: u32 = 0x00FF0000;
 const BLACK: u32 = 0x00080808;
-
-struct Statistics {
-    clicks_on_dots: usize,
-    clicks_on_lines: usize,
-    number_of_clicks: usize,
-    mouse_x: usize,
-    mouse_y: usize,
-    frames_count: usize,
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stat {
+    ClicksOnDots,
+    ClicksOnLines,
+    NumberOfClicks,
+    MouseX,
+    MouseY,
+    FramesCount,
 }
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            clicks_on_dots: 0,
-            clicks_on_lines: 0,
-            number_of_clicks: 0,
-            mouse_x: 0,
-            mouse_y: 0,
-            frames_count: 0,
+impl Metric for Stat {
+    const ALL: &'static [Self] = &[
+        Stat::ClicksOnDots,
+        Stat::ClicksOnLines,
+        Stat::NumberOfClicks,
+        Stat::MouseX,
+        Stat::MouseY,
+        Stat::FramesCount,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stat::ClicksOnDots => "clicks_on_dots",
+            Stat::ClicksOnLines => "clicks_on_lines",
+            Stat::NumberOfClicks => "number_of_clicks",
+            Stat::MouseX => "mouse_x",
+            Stat::MouseY => "mouse_y",
+            Stat::FramesCount => "frames_count",
         }
     }
 
-    fn increment_frames(&mut self) {
-        self.frames_count += 1;
-    }
-
-    fn increment_clicks(&mut self) {
-        self.number_of_clicks += 1;
-    }
-
-    fn increment_click_on_dots(&mut self) {
-        self.clicks_on_dots += 1;
-    }
-
-    fn increment_click_on_lines(&mut self) {
-        self.clicks_on_lines += 1;
-    }
-
-    fn set_mouse_x(&mut self, x: usize) {
-        self.mouse_x = x;
-    }
-
-    fn set_mouse_y(&mut self, y: usize) {
-        self.mouse_y = y;
+    fn kind(self) -> Kind {
+        match self {
+            Stat::ClicksOnDots | Stat::ClicksOnLines | Stat::NumberOfClicks | Stat::FramesCount => {
+                Kind::Counter
+            }
+            Stat::MouseX | Stat::MouseY => Kind::Gauge,
+        }
     }
 }
 
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
-
-    let mut wtr = Writer::from_writer(file);
-
-    if !file_exists {
-        wtr.write_record(&[
-            "clicks_on_dots",
-            "clicks_on_lines",
-            "number_of_clicks",
-            "mouse_x",
-            "mouse_y",
-            "frames_count",
-            "timestamp",
-        ])?;
-    }
-
-    wtr.write_record(&[
-        stats.clicks_on_dots.to_string(),
-        stats.clicks_on_lines.to_string(),
-        stats.number_of_clicks.to_string(),
-        stats.mouse_x.to_string(),
-        stats.mouse_y.to_string(),
-        stats.frames_count.to_string(),
-        Local::now().to_string(),
-    ])?;
-
-    wtr.flush()?;
-    Ok(())
+fn new_statistics() -> Registry<Stat> {
+    Registry::builder()
+        .csv("stats.csv")
+        .interval(LOG_INTERVAL)
+        .delta_counters()
+        .skip_unchanged()
+        .build()
 }
 
 fn draw_square(buffer: &mut Vec<u32>, side: usize, top_left: usize) {
@@ -225,7 +192,7 @@ fn main() {
     let move_interval = Duration::from_millis(25);
     let red_square_size = 20;
 
-    let mut stats = Statistics::new();
+    let mut stats = new_statistics();
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
     let mut last_move = Instant::now();
     let mut window = Window::new("Moving Box", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
@@ -243,7 +210,7 @@ fn main() {
 
     while window.is_open() && !window.is_key_down(minifb::Key::Escape) {
         buffer.fill(WHITE);
-        stats.increment_frames();
+        stats.increment(Stat::FramesCount);
         let is_pressed = window.get_mouse_down(MouseButton::Left);
 
         if last_move.elapsed() >= move_interval {
@@ -264,16 +231,16 @@ fn main() {
         if let Some((mx, my)) = window.get_mouse_pos(minifb::MouseMode::Clamp) {
             let (x, y) = (mx as usize, my as usize);
 
-            stats.set_mouse_x(x);
-            stats.set_mouse_y(y);
+            stats.set(Stat::MouseX, x);
+            stats.set(Stat::MouseY, y);
 
             if is_pressed && !was_pressed {
-                stats.increment_clicks();
+                stats.increment(Stat::NumberOfClicks);
 
                 let idx = y * WIDTH + x;
 
                 if buffer[idx] == RED {
-                    stats.increment_click_on_dots();
+                    stats.increment(Stat::ClicksOnDots);
 
                     for (i, dot) in dots.iter().enumerate() {
                         if is_point_on_dot(x, y, *dot, 5) {
@@ -284,13 +251,15 @@ fn main() {
 
                 if let Some(line_index) = detect_clicked_line(&lines, x, y, 3) {
                     println!("Clicked on line {}", line_index);
-                    stats.increment_click_on_lines();
+                    stats.increment(Stat::ClicksOnLines);
                 }
             }
         }
 
-        save_statistics(&stats).unwrap();
+        stats.tick().unwrap();
         was_pressed = is_pressed;
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
+
+    stats.flush().unwrap();
 }
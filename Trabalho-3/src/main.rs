@@ -1,12 +1,9 @@
-use chrono::prelude::*;
-use csv::Writer;
+use geometry::{Segment, convex_hull, graham_scan, quick_hull, sort_hull_points};
 use minifb::{Key, MouseButton, Window, WindowOptions};
 use rand::Rng;
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::time::{Duration, Instant};
 use sysinfo::{Pid, Process, System};
+use telemetry::{Kind, Metric, Registry, TimeUnit};
 
 /*
 * The calculation for the position on a 1D array on the screen is
@@ -18,53 +15,60 @@ const HEIGHT: usize = 400;
 const WHITE: u32 = 0x00FFFFFF;
 const RED: u32 = 0x00FF0000;
 const BLACK: u32 = 0x00080808;
-
-struct Statistics {
-    points_on_hull: usize,
-    points_inside_hull: usize,
-    exec_time_ms: u128,
-    memory_kb: u64,
+const DOT_RADIUS: usize = 5;
+const GENERATION_COUNTS: [usize; 6] = [10, 50, 100, 500, 1000, 5000];
+const GAUSSIAN_SIGMA: f64 = 25.0;
+const RING_RADIUS: f64 = 80.0;
+const RING_NOISE: f64 = 4.0;
+const LINE_CLICK_TOLERANCE: f32 = 3.0;
+const HIGHLIGHT_DURATION: Duration = Duration::from_millis(500);
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stat {
+    PointsOnHull,
+    PointsInsideHull,
+    ExecTime,
+    MemoryKb,
+    ClicksOnLines,
 }
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            points_on_hull: 0,
-            points_inside_hull: 0,
-            exec_time_ms: 0,
-            memory_kb: 0,
+impl Metric for Stat {
+    const ALL: &'static [Self] = &[
+        Stat::PointsOnHull,
+        Stat::PointsInsideHull,
+        Stat::ExecTime,
+        Stat::MemoryKb,
+        Stat::ClicksOnLines,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stat::PointsOnHull => "points_on_hull",
+            Stat::PointsInsideHull => "points_inside_hull",
+            Stat::ExecTime => "exec_time_ms",
+            Stat::MemoryKb => "memory_kb",
+            Stat::ClicksOnLines => "clicks_on_lines",
         }
     }
-}
-
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
 
-    let mut wtr = Writer::from_writer(file);
-
-    if !file_exists {
-        wtr.write_record(&[
-            "timestamp",
-            "points_on_hull",
-            "points_inside_hull",
-            "exec_time_ms",
-            "memory_kb",
-        ])?;
+    fn kind(self) -> Kind {
+        match self {
+            Stat::PointsOnHull | Stat::PointsInsideHull | Stat::MemoryKb => Kind::Gauge,
+            Stat::ExecTime => Kind::Timer(TimeUnit::Millis),
+            Stat::ClicksOnLines => Kind::Counter,
+        }
     }
+}
 
-    wtr.write_record(&[
-        Local::now().to_string(),
-        stats.points_on_hull.to_string(),
-        stats.points_inside_hull.to_string(),
-        stats.exec_time_ms.to_string(),
-        stats.memory_kb.to_string(),
-    ])?;
-
-    wtr.flush()?;
-    Ok(())
+// The click counter is written as what happened since the last row; hull figures are kept as is.
+fn new_statistics() -> Registry<Stat> {
+    Registry::builder()
+        .csv("stats.csv")
+        .interval(LOG_INTERVAL)
+        .delta_counters()
+        .skip_unchanged()
+        .build()
 }
 
 fn draw_circle(buffer: &mut [u32], cx: usize, cy: usize, radius: usize) {
@@ -83,7 +87,7 @@ fn draw_circle(buffer: &mut [u32], cx: usize, cy: usize, radius: usize) {
     }
 }
 
-fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize) {
+fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize, color: u32) {
     let mut x0 = x0 as isize;
     let mut y0 = y0 as isize;
     let x1 = x1 as isize;
@@ -98,7 +102,7 @@ fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize) {
     loop {
         if x0 >= 0 && y0 >= 0 && (x0 as usize) < WIDTH && (y0 as usize) < HEIGHT {
             let idx = y0 as usize * WIDTH + x0 as usize;
-            buffer[idx] = BLACK;
+            buffer[idx] = color;
         }
 
         if x0 == x1 && y0 == y1 {
@@ -122,126 +126,96 @@ fn is_point_on_dot(mx: usize, my: usize, dot: (usize, usize), radius: usize) ->
     dx * dx + dy * dy <= (radius as isize).pow(2)
 }
 
-fn distance_from_line(
-    line_start: &(usize, usize),
-    line_end: &(usize, usize),
-    point: &(usize, usize),
-) -> f64 {
-    let (x0, y0) = (line_start.0 as isize, line_start.1 as isize);
-    let (x1, y1) = (line_end.0 as isize, line_end.1 as isize);
-    let (px, py) = (point.0 as isize, point.1 as isize);
-
-    let num = ((y1 - y0) * px - (x1 - x0) * py + x1 * y0 - y1 * x0).abs() as f64;
-    let den = (((y1 - y0).pow(2) + (x1 - x0).pow(2)) as f64).sqrt();
-
-    if den == 0.0 { 0.0 } else { num / den }
-}
-
-fn cross_product(
-    line_start: &(usize, usize),
-    line_end: &(usize, usize),
-    point: &(usize, usize),
-) -> isize {
-    let (x1, y1) = (line_start.0 as isize, line_start.1 as isize);
-    let (x2, y2) = (line_end.0 as isize, line_end.1 as isize);
-    let (px, py) = (point.0 as isize, point.1 as isize);
-
-    (x2 - x1) * (py - y1) - (y2 - y1) * (px - x1)
-}
-
-fn quick_hull(dots: &Vec<(usize, usize)>) -> Vec<(usize, usize)> {
-    let mut convex_hull: Vec<(usize, usize)> = Vec::new();
-    let mut sorted_by_x_dots = dots.clone();
-    sorted_by_x_dots.sort_by_key(|&(x, _y)| x);
-
-    let left_most = sorted_by_x_dots.first().unwrap();
-    let right_most = sorted_by_x_dots.last().unwrap();
+fn generate_random_points(dots: &mut Vec<(usize, usize)>, quantity: usize) {
+    println!("Generating {} random points", quantity);
 
-    let mut upper: Vec<(usize, usize)> = Vec::new();
-    let mut lower: Vec<(usize, usize)> = Vec::new();
+    let mut rng = rand::rng();
 
-    for dot in &sorted_by_x_dots {
-        let cross_result = cross_product(&left_most, &right_most, &dot);
+    for _ in 0..quantity {
+        let random_x: usize = rng.random_range(0..WIDTH);
+        let random_y: usize = rng.random_range(0..HEIGHT);
 
-        if cross_result > 0 {
-            upper.push(dot.clone());
-        } else if cross_result < 0 {
-            lower.push(dot.clone());
-        }
+        dots.push((random_x, random_y));
     }
-
-    convex_hull.push(left_most.clone());
-    find_hull(&upper, left_most, right_most, &mut convex_hull);
-    convex_hull.push(right_most.clone());
-    find_hull(&lower, right_most, left_most, &mut convex_hull);
-
-    convex_hull
 }
 
-fn find_hull(
-    half_dots: &Vec<(usize, usize)>,
-    start_node: &(usize, usize),
-    end_node: &(usize, usize),
-    convex_hull: &mut Vec<(usize, usize)>,
-) {
-    if half_dots.is_empty() {
-        return;
-    }
-
-    let mut max_distance = 0.0;
-    let mut furthest_point = half_dots[0];
+#[derive(Clone, Copy)]
+enum Distribution {
+    Uniform,
+    Gaussian,
+    Ring,
+    Grid,
+}
 
-    for &dot in half_dots.iter() {
-        let distance = distance_from_line(start_node, end_node, &dot);
-        if distance > max_distance {
-            max_distance = distance;
-            furthest_point = dot;
+impl Distribution {
+    fn name(&self) -> &str {
+        match self {
+            Distribution::Uniform => "uniform",
+            Distribution::Gaussian => "gaussian",
+            Distribution::Ring => "ring",
+            Distribution::Grid => "grid",
         }
     }
 
-    convex_hull.push(furthest_point.clone());
-
-    let mut left_upper: Vec<(usize, usize)> = Vec::new();
-    let mut right_upper: Vec<(usize, usize)> = Vec::new();
-
-    for &p in half_dots.iter() {
-        if cross_product(start_node, &furthest_point, &p) > 0 {
-            left_upper.push(p);
-        } else if cross_product(&furthest_point, end_node, &p) > 0 {
-            right_upper.push(p);
+    fn next(self) -> Self {
+        match self {
+            Distribution::Uniform => Distribution::Gaussian,
+            Distribution::Gaussian => Distribution::Ring,
+            Distribution::Ring => Distribution::Grid,
+            Distribution::Grid => Distribution::Uniform,
         }
     }
-
-    find_hull(&left_upper, start_node, &furthest_point, convex_hull);
-    find_hull(&right_upper, &furthest_point, end_node, convex_hull);
 }
 
-fn sort_hull_points(hull: &mut Vec<(usize, usize)>) {
-    let (sum_x, sum_y): (f64, f64) = hull
-        .iter()
-        .map(|&(x, y)| (x as f64, y as f64))
-        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
-
-    let len = hull.len() as f64;
-    let center = (sum_x / len, sum_y / len);
-
-    hull.sort_by(|a, b| {
-        let ang_a = (a.1 as f64 - center.1).atan2(a.0 as f64 - center.0);
-        let ang_b = (b.1 as f64 - center.1).atan2(b.0 as f64 - center.0);
-        ang_a.partial_cmp(&ang_b).unwrap()
-    });
+fn clamp_to_window(x: f64, y: f64) -> (usize, usize) {
+    (
+        (x.round().max(0.0) as usize).min(WIDTH - 1),
+        (y.round().max(0.0) as usize).min(HEIGHT - 1),
+    )
 }
 
-fn generate_random_points(dots: &mut Vec<(usize, usize)>, quantity: usize) {
-    println!("Generating {} random points", quantity);
+// Gaussian and ring clouds are centred on the cursor, the others span the window.
+fn generate_distribution(
+    dots: &mut Vec<(usize, usize)>,
+    quantity: usize,
+    distribution: Distribution,
+    center: (usize, usize),
+) {
+    println!("Generating {} {} points", quantity, distribution.name());
 
     let mut rng = rand::rng();
-
-    for _ in 0..quantity {
-        let random_x: usize = rng.random_range(0..WIDTH);
-        let random_y: usize = rng.random_range(0..HEIGHT);
-
-        dots.push((random_x, random_y));
+    let (cx, cy) = (center.0 as f64, center.1 as f64);
+
+    match distribution {
+        Distribution::Uniform => generate_random_points(dots, quantity),
+        Distribution::Gaussian => {
+            for _ in 0..quantity {
+                // Box-Muller transform.
+                let u1: f64 = rng.random_range(f64::EPSILON..1.0);
+                let u2: f64 = rng.random_range(0.0..1.0);
+                let r = (-2.0 * u1.ln()).sqrt() * GAUSSIAN_SIGMA;
+                let angle = u2 * std::f64::consts::TAU;
+                dots.push(clamp_to_window(cx + r * angle.cos(), cy + r * angle.sin()));
+            }
+        }
+        Distribution::Ring => {
+            for _ in 0..quantity {
+                let angle = rng.random_range(0.0..std::f64::consts::TAU);
+                let r = RING_RADIUS + rng.random_range(-RING_NOISE..=RING_NOISE);
+                dots.push(clamp_to_window(cx + r * angle.cos(), cy + r * angle.sin()));
+            }
+        }
+        Distribution::Grid => {
+            let side = (quantity as f64).sqrt().ceil().max(1.0) as usize;
+            let spacing = WIDTH.min(HEIGHT) as f64 / (side + 1) as f64;
+            let jitter = spacing / 4.0;
+            for i in 0..quantity {
+                let (col, row) = (i % side, i / side);
+                let x = (col + 1) as f64 * spacing + rng.random_range(-jitter..=jitter);
+                let y = (row + 1) as f64 * spacing + rng.random_range(-jitter..=jitter);
+                dots.push(clamp_to_window(x, y));
+            }
+        }
     }
 }
 
@@ -260,6 +234,91 @@ fn draw_hull(hull: &Vec<(usize, usize)>, lines: &mut Vec<(usize, usize, usize, u
     ));
 }
 
+#[derive(Clone, Copy)]
+enum HullAlgorithm {
+    QuickHull,
+    GrahamScan,
+    MonotoneChain,
+}
+
+impl HullAlgorithm {
+    fn name(&self) -> &str {
+        match self {
+            HullAlgorithm::QuickHull => "quick_hull",
+            HullAlgorithm::GrahamScan => "graham_scan",
+            HullAlgorithm::MonotoneChain => "monotone_chain",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            HullAlgorithm::QuickHull => HullAlgorithm::GrahamScan,
+            HullAlgorithm::GrahamScan => HullAlgorithm::MonotoneChain,
+            HullAlgorithm::MonotoneChain => HullAlgorithm::QuickHull,
+        }
+    }
+
+    fn compute(&self, dots: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        match self {
+            HullAlgorithm::QuickHull => quick_hull(dots),
+            HullAlgorithm::GrahamScan => graham_scan(dots),
+            HullAlgorithm::MonotoneChain => convex_hull(dots),
+        }
+    }
+}
+
+fn recompute_hull(
+    dots: &[(usize, usize)],
+    hull: &mut Vec<(usize, usize)>,
+    lines: &mut Vec<(usize, usize, usize, usize)>,
+    algorithm: HullAlgorithm,
+) {
+    if dots.is_empty() {
+        hull.clear();
+        lines.clear();
+        return;
+    }
+    let start_time = Instant::now();
+    *hull = algorithm.compute(dots);
+    println!(
+        "{}: {} points, {} on hull, {} us",
+        algorithm.name(),
+        dots.len(),
+        hull.len(),
+        start_time.elapsed().as_micros()
+    );
+    sort_hull_points(hull);
+    draw_hull(hull, lines);
+}
+
+enum PointEdit {
+    Add,
+    Delete { index: usize, point: (usize, usize) },
+    Move { index: usize, from: (usize, usize) },
+}
+
+// Edits are undone last-first, so the stored indices always match the dots they touched.
+fn undo_edit(edit: PointEdit, dots: &mut Vec<(usize, usize)>) {
+    match edit {
+        PointEdit::Add => {
+            dots.pop();
+        }
+        PointEdit::Delete { index, point } => dots.insert(index, point),
+        PointEdit::Move { index, from } => dots[index] = from,
+    }
+}
+
+fn line_at(lines: &[(usize, usize, usize, usize)], x: usize, y: usize) -> Option<usize> {
+    lines.iter().position(|&(x0, y0, x1, y1)| {
+        Segment::new((x0, y0), (x1, y1)).distance_to_point((x, y)) <= LINE_CLICK_TOLERANCE
+    })
+}
+
+fn dot_at(dots: &[(usize, usize)], x: usize, y: usize) -> Option<usize> {
+    dots.iter()
+        .rposition(|&dot| is_point_on_dot(x, y, dot, DOT_RADIUS))
+}
+
 pub enum Shape {
     Square,
     Circle,
@@ -333,7 +392,7 @@ fn end_log(
     mem_before: u64,
     start_time: Instant,
     hull: &Vec<(usize, usize)>,
-    stats: &mut Statistics,
+    stats: &mut Registry<Stat>,
     dots: &Vec<(usize, usize)>,
 ) {
     let duration = start_time.elapsed();
@@ -345,18 +404,24 @@ fn end_log(
     let points_on_hull = hull.len();
     let points_inside_hull = dots.len().saturating_sub(points_on_hull);
 
-    stats.points_on_hull = points_on_hull;
-    stats.points_inside_hull = points_inside_hull;
-    stats.exec_time_ms = duration.as_millis();
-    stats.memory_kb = mem_used;
+    stats.set(Stat::PointsOnHull, points_on_hull);
+    stats.set(Stat::PointsInsideHull, points_inside_hull);
+    stats.record(Stat::ExecTime, duration);
+    stats.set(Stat::MemoryKb, mem_used);
 }
 
 fn main() {
-    let mut stats = Statistics::new();
+    let mut stats = new_statistics();
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
     let mut window = Window::new("Moving Box", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
     let mut was_pressed = false;
-    let mut last_log_time = Instant::now();
+    let mut was_right_pressed = false;
+    let mut dragging: Option<(usize, (usize, usize))> = None;
+    let mut edits: Vec<PointEdit> = Vec::new();
+    let mut algorithm = HullAlgorithm::QuickHull;
+    let mut distribution = Distribution::Uniform;
+    let mut generation_count = GENERATION_COUNTS[0];
+    let mut highlighted: Option<(usize, Instant)> = None;
 
     let mut dots: Vec<(usize, usize)> = Vec::new();
     let mut hull: Vec<(usize, usize)> = Vec::new();
@@ -366,22 +431,30 @@ fn main() {
     while window.is_open() && !window.is_key_down(minifb::Key::Escape) {
         buffer.fill(WHITE);
         let is_pressed = window.get_mouse_down(MouseButton::Left);
+        let is_right_pressed = window.get_mouse_down(MouseButton::Right);
 
         for (x, y) in &dots {
-            draw_circle(&mut buffer, *x, *y, 5);
+            draw_circle(&mut buffer, *x, *y, DOT_RADIUS);
         }
 
-        for (x0, y0, x1, y1) in &lines {
-            draw_line(&mut buffer, *x0, *y0, *x1, *y1);
+        for (i, (x0, y0, x1, y1)) in lines.iter().enumerate() {
+            let color = match highlighted {
+                Some((line, since)) if line == i && since.elapsed() < HIGHLIGHT_DURATION => RED,
+                _ => BLACK,
+            };
+            draw_line(&mut buffer, *x0, *y0, *x1, *y1, color);
         }
 
         if window.is_key_pressed(Key::R, minifb::KeyRepeat::No) {
             let (pid, mem_before, start_time) = begin_log();
 
-            generate_random_points(&mut dots, 10);
-            hull = quick_hull(&dots);
-            sort_hull_points(&mut hull);
-            draw_hull(&hull, &mut lines);
+            let center = window
+                .get_mouse_pos(minifb::MouseMode::Clamp)
+                .map(|(x, y)| (x as usize, y as usize))
+                .unwrap_or((WIDTH / 2, HEIGHT / 2));
+            edits.clear();
+            generate_distribution(&mut dots, generation_count, distribution, center);
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
 
             end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
         }
@@ -389,10 +462,9 @@ fn main() {
         if window.is_key_pressed(Key::T, minifb::KeyRepeat::No) {
             let (pid, mem_before, start_time) = begin_log();
 
+            edits.clear();
             generate_points(50, Shape::Triangle, &mut dots);
-            hull = quick_hull(&dots);
-            sort_hull_points(&mut hull);
-            draw_hull(&hull, &mut lines);
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
 
             end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
         }
@@ -400,10 +472,9 @@ fn main() {
         if window.is_key_pressed(Key::C, minifb::KeyRepeat::No) {
             let (pid, mem_before, start_time) = begin_log();
 
+            edits.clear();
             generate_points(50, Shape::Circle, &mut dots);
-            hull = quick_hull(&dots);
-            sort_hull_points(&mut hull);
-            draw_hull(&hull, &mut lines);
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
 
             end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
         }
@@ -411,17 +482,55 @@ fn main() {
         if window.is_key_pressed(Key::S, minifb::KeyRepeat::No) {
             let (pid, mem_before, start_time) = begin_log();
 
+            edits.clear();
             generate_points(50, Shape::Square, &mut dots);
-            hull = quick_hull(&dots);
-            sort_hull_points(&mut hull);
-            draw_hull(&hull, &mut lines);
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
 
             end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
         }
 
+        if window.is_key_pressed(Key::D, minifb::KeyRepeat::No) {
+            distribution = distribution.next();
+            println!("Distribution: {}", distribution.name());
+        }
+
+        if window.is_key_pressed(Key::Up, minifb::KeyRepeat::No) {
+            let i = GENERATION_COUNTS
+                .iter()
+                .position(|&c| c == generation_count)
+                .unwrap_or(0);
+            generation_count = GENERATION_COUNTS[(i + 1).min(GENERATION_COUNTS.len() - 1)];
+            println!("Points per generation: {}", generation_count);
+        }
+
+        if window.is_key_pressed(Key::Down, minifb::KeyRepeat::No) {
+            let i = GENERATION_COUNTS
+                .iter()
+                .position(|&c| c == generation_count)
+                .unwrap_or(0);
+            generation_count = GENERATION_COUNTS[i.saturating_sub(1)];
+            println!("Points per generation: {}", generation_count);
+        }
+
+        if window.is_key_pressed(Key::H, minifb::KeyRepeat::No) {
+            algorithm = algorithm.next();
+            println!("Hull algorithm: {}", algorithm.name());
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
+        }
+
         if window.is_key_pressed(Key::Space, minifb::KeyRepeat::No) {
             lines.clear();
             dots.clear();
+            edits.clear();
+            dragging = None;
+        }
+
+        if window.is_key_pressed(Key::Z, minifb::KeyRepeat::Yes)
+            && dragging.is_none()
+            && let Some(edit) = edits.pop()
+        {
+            undo_edit(edit, &mut dots);
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
         }
 
         if let Some((mx, my)) = window.get_mouse_pos(minifb::MouseMode::Clamp) {
@@ -430,23 +539,58 @@ fn main() {
             if is_pressed && !was_pressed {
                 let idx = y * WIDTH + x;
 
-                if buffer[idx] == WHITE {
+                if let Some(index) = dot_at(&dots, x, y) {
+                    dragging = Some((index, dots[index]));
+                } else if let Some(line) = line_at(&lines, x, y) {
+                    println!("Clicked on line {}", line);
+                    stats.increment(Stat::ClicksOnLines);
+                    highlighted = Some((line, Instant::now()));
+                } else if buffer[idx] == WHITE {
                     let (pid, mem_before, start_time) = begin_log();
 
                     dots.push((x, y));
-                    hull = quick_hull(&dots);
-                    sort_hull_points(&mut hull);
-                    draw_hull(&hull, &mut lines);
+                    edits.push(PointEdit::Add);
+                    recompute_hull(&dots, &mut hull, &mut lines, algorithm);
 
                     end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
                 }
+            } else if is_pressed
+                && let Some((index, _)) = dragging
+                && dots[index] != (x, y)
+            {
+                dots[index] = (x, y);
+                recompute_hull(&dots, &mut hull, &mut lines, algorithm);
+            }
+
+            if is_right_pressed
+                && !was_right_pressed
+                && dragging.is_none()
+                && let Some(index) = dot_at(&dots, x, y)
+            {
+                let (pid, mem_before, start_time) = begin_log();
+
+                let point = dots.remove(index);
+                edits.push(PointEdit::Delete { index, point });
+                recompute_hull(&dots, &mut hull, &mut lines, algorithm);
+
+                end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
             }
         }
-        if last_log_time.elapsed() >= Duration::from_secs(1) {
-            save_statistics(&stats).unwrap();
-            last_log_time = Instant::now();
+
+        if !is_pressed
+            && let Some((index, from)) = dragging.take()
+            && dots[index] != from
+        {
+            let (pid, mem_before, start_time) = begin_log();
+
+            edits.push(PointEdit::Move { index, from });
+            recompute_hull(&dots, &mut hull, &mut lines, algorithm);
+
+            end_log(pid, mem_before, start_time, &hull, &mut stats, &dots);
         }
+        stats.tick().unwrap();
         was_pressed = is_pressed;
+        was_right_pressed = is_right_pressed;
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
 }
@@ -0,0 +1,518 @@
+// Planar geometry shared by the projects: hulls, Minkowski sums, segment
+// intersection and polygon distances, on pixel or f32 world coordinates.
+
+use std::cmp::Ordering;
+
+pub trait Coord: Copy + PartialEq + PartialOrd {
+    fn to_f64(self) -> f64;
+    // Integer coordinates round to the nearest pixel.
+    fn from_f64(v: f64) -> Self;
+}
+
+macro_rules! integer_coord {
+    ($($t:ty),*) => {
+        $(impl Coord for $t {
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn from_f64(v: f64) -> Self {
+                v.round() as $t
+            }
+        })*
+    };
+}
+
+integer_coord!(usize, isize, i32);
+
+impl Coord for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+}
+
+pub type Point<T = usize> = (T, T);
+pub type Polygon<T = usize> = Vec<Point<T>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<T = usize> {
+    pub start: Point<T>,
+    pub end: Point<T>,
+}
+
+fn to_f32<T: Coord>(p: Point<T>) -> (f32, f32) {
+    (p.0.to_f64() as f32, p.1.to_f64() as f32)
+}
+
+fn to_f64<T: Coord>(p: Point<T>) -> (f64, f64) {
+    (p.0.to_f64(), p.1.to_f64())
+}
+
+// Orders by x, then y; NaN never reaches here, so incomparable pairs just count as equal.
+fn by_x_then_y<T: Coord>(a: &Point<T>, b: &Point<T>) -> Ordering {
+    a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+fn orient(o: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn within_box(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> bool {
+    p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0) && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+impl<T: Coord> Segment<T> {
+    pub fn new(start: Point<T>, end: Point<T>) -> Self {
+        Segment { start, end }
+    }
+
+    pub fn intersects(&self, other: &Segment<T>) -> bool {
+        let (a, b) = (to_f32(self.start), to_f32(self.end));
+        let (c, d) = (to_f32(other.start), to_f32(other.end));
+        let d1 = orient(c, d, a);
+        let d2 = orient(c, d, b);
+        let d3 = orient(a, b, c);
+        let d4 = orient(a, b, d);
+
+        if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+            && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+        {
+            return true;
+        }
+
+        (d1 == 0.0 && within_box(a, c, d))
+            || (d2 == 0.0 && within_box(b, c, d))
+            || (d3 == 0.0 && within_box(c, a, b))
+            || (d4 == 0.0 && within_box(d, a, b))
+    }
+
+    pub fn distance_to_point(&self, point: Point<T>) -> f32 {
+        let (px, py) = to_f32(point);
+        let (x1, y1) = to_f32(self.start);
+        let (x2, y2) = to_f32(self.end);
+        let dx = x2 - x1;
+        let dy = y2 - y1;
+        let len_sq = dx * dx + dy * dy;
+
+        if len_sq == 0.0 {
+            return ((px - x1) * (px - x1) + (py - y1) * (py - y1)).sqrt();
+        }
+
+        let t = ((px - x1) * dx + (py - y1) * dy) / len_sq;
+        let t = t.clamp(0.0, 1.0);
+
+        let closest_x = x1 + t * dx;
+        let closest_y = y1 + t * dy;
+
+        ((px - closest_x) * (px - closest_x) + (py - closest_y) * (py - closest_y)).sqrt()
+    }
+
+    // Two segments that don't cross are closest at one of the four endpoints.
+    pub fn distance(&self, other: &Segment<T>) -> f32 {
+        if self.intersects(other) {
+            return 0.0;
+        }
+        other
+            .distance_to_point(self.start)
+            .min(other.distance_to_point(self.end))
+            .min(self.distance_to_point(other.start))
+            .min(self.distance_to_point(other.end))
+    }
+
+    // Parameter along the segment where it first touches the polygon, 0 when it starts inside.
+    pub fn entry(&self, polygon: &[Point<T>]) -> Option<f32> {
+        if point_in_polygon(self.start, polygon) {
+            return Some(0.0);
+        }
+        let (ax, ay) = to_f32(self.start);
+        let (bx, by) = to_f32(self.end);
+        let (dx, dy) = (bx - ax, by - ay);
+        let mut entry: Option<f32> = None;
+        for edge in edges(polygon) {
+            let (px, py) = to_f32(edge.start);
+            let (qx, qy) = to_f32(edge.end);
+            let (ex, ey) = (qx - px, qy - py);
+            let denom = dx * ey - dy * ex;
+            if denom.abs() < f32::EPSILON {
+                continue;
+            }
+            let t = ((px - ax) * ey - (py - ay) * ex) / denom;
+            let u = ((px - ax) * dy - (py - ay) * dx) / denom;
+            if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+                entry = Some(entry.map_or(t, |e| e.min(t)));
+            }
+        }
+        entry
+    }
+}
+
+pub fn edges<T: Coord>(polygon: &[Point<T>]) -> impl Iterator<Item = Segment<T>> + '_ {
+    (0..polygon.len()).map(move |i| Segment::new(polygon[i], polygon[(i + 1) % polygon.len()]))
+}
+
+pub fn cross_product<T: Coord>(line_start: Point<T>, line_end: Point<T>, point: Point<T>) -> f64 {
+    let (x1, y1) = to_f64(line_start);
+    let (x2, y2) = to_f64(line_end);
+    let (px, py) = to_f64(point);
+
+    (x2 - x1) * (py - y1) - (y2 - y1) * (px - x1)
+}
+
+pub fn distance_from_line<T: Coord>(
+    line_start: Point<T>,
+    line_end: Point<T>,
+    point: Point<T>,
+) -> f64 {
+    let (x0, y0) = to_f64(line_start);
+    let (x1, y1) = to_f64(line_end);
+    let den = (x1 - x0).hypot(y1 - y0);
+
+    if den == 0.0 {
+        0.0
+    } else {
+        cross_product(line_start, line_end, point).abs() / den
+    }
+}
+
+// Hull points come out unordered; pass them through sort_hull_points to draw them.
+pub fn quick_hull<T: Coord>(points: &[Point<T>]) -> Polygon<T> {
+    let mut sorted_by_x = points.to_vec();
+    sorted_by_x.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    let (Some(&left_most), Some(&right_most)) = (sorted_by_x.first(), sorted_by_x.last()) else {
+        return Vec::new();
+    };
+    if left_most == right_most {
+        return vec![left_most];
+    }
+
+    let mut upper: Polygon<T> = Vec::new();
+    let mut lower: Polygon<T> = Vec::new();
+
+    for &point in &sorted_by_x {
+        let cross_result = cross_product(left_most, right_most, point);
+
+        if cross_result > 0.0 {
+            upper.push(point);
+        } else if cross_result < 0.0 {
+            lower.push(point);
+        }
+    }
+
+    let mut convex_hull: Polygon<T> = vec![left_most];
+    find_hull(&upper, left_most, right_most, &mut convex_hull);
+    convex_hull.push(right_most);
+    find_hull(&lower, right_most, left_most, &mut convex_hull);
+
+    convex_hull
+}
+
+fn find_hull<T: Coord>(
+    half: &[Point<T>],
+    start: Point<T>,
+    end: Point<T>,
+    convex_hull: &mut Polygon<T>,
+) {
+    if half.is_empty() {
+        return;
+    }
+
+    let mut max_distance = 0.0;
+    let mut furthest_point = half[0];
+
+    for &point in half {
+        let distance = distance_from_line(start, end, point);
+        if distance > max_distance {
+            max_distance = distance;
+            furthest_point = point;
+        }
+    }
+
+    convex_hull.push(furthest_point);
+
+    let mut left: Polygon<T> = Vec::new();
+    let mut right: Polygon<T> = Vec::new();
+
+    for &p in half {
+        if cross_product(start, furthest_point, p) > 0.0 {
+            left.push(p);
+        } else if cross_product(furthest_point, end, p) > 0.0 {
+            right.push(p);
+        }
+    }
+
+    find_hull(&left, start, furthest_point, convex_hull);
+    find_hull(&right, furthest_point, end, convex_hull);
+}
+
+pub fn sort_hull_points<T: Coord>(hull: &mut [Point<T>]) {
+    let (sum_x, sum_y): (f64, f64) = hull
+        .iter()
+        .map(|&p| to_f64(p))
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+
+    let len = hull.len() as f64;
+    let center = (sum_x / len, sum_y / len);
+
+    hull.sort_by(|&a, &b| {
+        let (a, b) = (to_f64(a), to_f64(b));
+        let ang_a = (a.1 - center.1).atan2(a.0 - center.0);
+        let ang_b = (b.1 - center.1).atan2(b.0 - center.0);
+        ang_a.total_cmp(&ang_b)
+    });
+}
+
+// Sorts around the lowest point by angle, then keeps only left turns; collinear points are dropped.
+pub fn graham_scan<T: Coord>(points: &[Point<T>]) -> Polygon<T> {
+    let Some(&pivot) = points
+        .iter()
+        .min_by(|a, b| by_x_then_y(&(a.1, a.0), &(b.1, b.0)))
+    else {
+        return Vec::new();
+    };
+    let dist_sq = |p: Point<T>| {
+        let (dx, dy) = (
+            p.0.to_f64() - pivot.0.to_f64(),
+            p.1.to_f64() - pivot.1.to_f64(),
+        );
+        dx * dx + dy * dy
+    };
+
+    let mut pts: Polygon<T> = points.iter().copied().filter(|&p| p != pivot).collect();
+    pts.sort_by(|&a, &b| {
+        cross_product(pivot, b, a)
+            .total_cmp(&0.0)
+            .then_with(|| dist_sq(a).total_cmp(&dist_sq(b)))
+    });
+    pts.dedup();
+
+    let mut stack: Polygon<T> = vec![pivot];
+    for p in pts {
+        while stack.len() >= 2
+            && cross_product(stack[stack.len() - 2], stack[stack.len() - 1], p) <= 0.0
+        {
+            stack.pop();
+        }
+        stack.push(p);
+    }
+    stack
+}
+
+// Andrew's monotone chain; collinear and repeated points are dropped.
+pub fn convex_hull<T: Coord>(points: &[Point<T>]) -> Polygon<T> {
+    let mut pts = points.to_vec();
+    pts.sort_by(by_x_then_y);
+    pts.dedup();
+    if pts.len() < 3 {
+        return pts;
+    }
+
+    let mut lower: Polygon<T> = Vec::new();
+    for &p in &pts {
+        while lower.len() >= 2
+            && cross_product(lower[lower.len() - 2], lower[lower.len() - 1], p) <= 0.0
+        {
+            lower.pop();
+        }
+        lower.push(p);
+    }
+
+    let mut upper: Polygon<T> = Vec::new();
+    for &p in pts.iter().rev() {
+        while upper.len() >= 2
+            && cross_product(upper[upper.len() - 2], upper[upper.len() - 1], p) <= 0.0
+        {
+            upper.pop();
+        }
+        upper.push(p);
+    }
+
+    lower.pop();
+    upper.pop();
+    lower.extend(upper);
+    lower
+}
+
+// Obstacle a grown by the robot b reflected about its centroid (configuration-space obstacle).
+pub fn minkowski_sum<T: Coord>(a: &[Point<T>], b: &[Point<T>]) -> Polygon<T> {
+    let n = b.len().max(1) as f64;
+    let center_x = b.iter().map(|p| p.0.to_f64()).sum::<f64>() / n;
+    let center_y = b.iter().map(|p| p.1.to_f64()).sum::<f64>() / n;
+
+    let mut sum: Polygon<T> = Vec::new();
+    for &(ox, oy) in a {
+        for &(rx, ry) in b {
+            sum.push((
+                T::from_f64(ox.to_f64() - (rx.to_f64() - center_x)),
+                T::from_f64(oy.to_f64() - (ry.to_f64() - center_y)),
+            ));
+        }
+    }
+
+    convex_hull(&sum)
+}
+
+pub fn point_in_polygon<T: Coord>(point: Point<T>, polygon: &[Point<T>]) -> bool {
+    let (px, py) = to_f32(point);
+    let mut inside = false;
+    for edge in edges(polygon) {
+        let ((x0, y0), (x1, y1)) = (to_f32(edge.start), to_f32(edge.end));
+        if (y0 > py) != (y1 > py) && px < x0 + (py - y0) / (y1 - y0) * (x1 - x0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// Gap between the two outlines, also when one polygon sits inside the other.
+pub fn boundary_distance<T: Coord>(a: &[Point<T>], b: &[Point<T>]) -> f32 {
+    let mut min_dist = f32::MAX;
+    for edge_a in edges(a) {
+        for edge_b in edges(b) {
+            min_dist = min_dist.min(edge_a.distance(&edge_b));
+        }
+    }
+    min_dist
+}
+
+// Distance between the filled polygons: zero once they overlap or one contains the other.
+pub fn polygon_distance<T: Coord>(a: &[Point<T>], b: &[Point<T>]) -> f32 {
+    if a.iter().any(|&p| point_in_polygon(p, b)) || b.iter().any(|&p| point_in_polygon(p, a)) {
+        return 0.0;
+    }
+    boundary_distance(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: usize, y: usize, size: usize) -> Polygon {
+        vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)]
+    }
+
+    fn seg(a: Point, b: Point) -> Segment {
+        Segment::new(a, b)
+    }
+
+    #[test]
+    fn parallel_and_crossing_segments() {
+        assert_eq!(seg((0, 0), (10, 0)).distance(&seg((0, 5), (10, 5))), 5.0);
+        assert_eq!(seg((0, 0), (10, 10)).distance(&seg((0, 10), (10, 0))), 0.0);
+        assert_eq!(seg((0, 0), (10, 0)).distance(&seg((10, 0), (20, 0))), 0.0);
+        assert_eq!(seg((0, 0), (4, 0)).distance(&seg((7, 4), (7, 9))), 5.0);
+        assert!(!seg((0, 0), (4, 0)).intersects(&seg((5, 0), (9, 0))));
+    }
+
+    #[test]
+    fn edge_to_edge_gap_without_close_vertices() {
+        // A diamond tip pointing into the middle of a long edge: the closest pair is
+        // vertex-to-edge from the diamond side only.
+        let wall = vec![(0, 20), (100, 20), (100, 30), (0, 30)];
+        let diamond = vec![(50, 0), (60, 10), (50, 15), (40, 10)];
+        assert_eq!(boundary_distance(&wall, &diamond), 5.0);
+        assert_eq!(boundary_distance(&diamond, &wall), 5.0);
+        assert_eq!(polygon_distance(&wall, &diamond), 5.0);
+    }
+
+    #[test]
+    fn nested_and_overlapping_polygons() {
+        let outer = square(0, 0, 100);
+        let inner = square(40, 40, 10);
+        assert_eq!(boundary_distance(&outer, &inner), 40.0);
+        assert_eq!(polygon_distance(&outer, &inner), 0.0);
+        assert_eq!(polygon_distance(&square(0, 0, 10), &square(5, 5, 10)), 0.0);
+        assert_eq!(
+            polygon_distance(&square(0, 0, 10), &square(13, 14, 10)),
+            5.0
+        );
+    }
+
+    #[test]
+    fn point_in_polygon_even_odd() {
+        let poly = square(10, 10, 20);
+        assert!(point_in_polygon((15, 15), &poly));
+        assert!(!point_in_polygon((5, 15), &poly));
+        assert!(!point_in_polygon((35, 15), &poly));
+    }
+
+    #[test]
+    fn hull_algorithms_agree() {
+        let points = vec![
+            (10, 10),
+            (50, 5),
+            (90, 20),
+            (60, 40),
+            (95, 80),
+            (40, 90),
+            (5, 60),
+            (45, 50),
+            (30, 30),
+        ];
+        let mut quick = quick_hull(&points);
+        sort_hull_points(&mut quick);
+        let mut chain = convex_hull(&points);
+        sort_hull_points(&mut chain);
+        let mut graham = graham_scan(&points);
+        sort_hull_points(&mut graham);
+        assert_eq!(quick, chain);
+        assert_eq!(graham, chain);
+        assert!(!chain.contains(&(45, 50)));
+        assert_eq!(chain.len(), 6);
+    }
+
+    #[test]
+    fn graham_scan_drops_collinear_and_duplicates() {
+        let points = vec![(0, 0), (5, 0), (10, 0), (10, 10), (0, 10), (5, 5), (10, 10)];
+        let mut hull = graham_scan(&points);
+        hull.sort();
+        assert_eq!(hull, vec![(0, 0), (0, 10), (10, 0), (10, 10)]);
+        assert!(graham_scan::<usize>(&[]).is_empty());
+    }
+
+    #[test]
+    fn minkowski_sum_of_squares() {
+        let obstacle = square(100, 100, 20);
+        let robot = square(0, 0, 10);
+        let mut sum = minkowski_sum(&obstacle, &robot);
+        sum.sort();
+        assert_eq!(sum, vec![(95, 95), (95, 125), (125, 95), (125, 125)]);
+
+        let corner: Polygon<isize> = vec![(0, 0), (20, 0), (20, 20), (0, 20)];
+        let robot: Polygon<isize> = vec![(0, 0), (10, 0), (10, 10), (0, 10)];
+        assert!(minkowski_sum(&corner, &robot).contains(&(-5, -5)));
+    }
+
+    #[test]
+    fn float_hulls_and_empty_input() {
+        let points: Polygon<f32> = vec![(0.0, 0.0), (2.5, 0.0), (2.5, 2.5), (0.0, 2.5), (1.0, 1.0)];
+        let mut hull = convex_hull(&points);
+        sort_hull_points(&mut hull);
+        let mut quick = quick_hull(&points);
+        sort_hull_points(&mut quick);
+        assert_eq!(hull.len(), 4);
+        assert_eq!(quick, hull);
+        assert!(!hull.contains(&(1.0, 1.0)));
+
+        let robot: Polygon<f32> = vec![(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)];
+        let grown = minkowski_sum(&points, &robot);
+        assert!(grown.contains(&(-0.5, -0.5)) && grown.contains(&(3.0, 3.0)));
+
+        let none: Polygon = Vec::new();
+        assert!(quick_hull(&none).is_empty());
+        assert!(convex_hull(&none).is_empty());
+        assert_eq!(quick_hull(&[(4, 4)]), vec![(4, 4)]);
+    }
+
+    #[test]
+    fn segment_entry_into_polygon() {
+        let poly = square(10, 0, 10);
+        assert_eq!(seg((0, 5), (30, 5)).entry(&poly), Some(1.0 / 3.0));
+        assert_eq!(seg((15, 5), (30, 5)).entry(&poly), Some(0.0));
+        assert_eq!(seg((0, 50), (30, 50)).entry(&poly), None);
+    }
+}
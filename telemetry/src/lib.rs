@@ -0,0 +1,560 @@
+// Metric registry shared by the projects: counters, gauges and timers are
+// declared up front as an enum, sampled with a timestamp and handed to pluggable sinks.
+
+use chrono::prelude::*;
+use csv::Writer;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Text(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Value::Float(v as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Text(v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Millis,
+    Micros,
+}
+
+impl TimeUnit {
+    fn convert(self, duration: Duration) -> Value {
+        match self {
+            TimeUnit::Millis => Value::Int(duration.as_millis() as i64),
+            TimeUnit::Micros => Value::Int(duration.as_micros() as i64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Timer(TimeUnit),
+}
+
+// Implemented by each project's metric enum; `ALL` gives the column order.
+pub trait Metric: Copy + Eq + fmt::Debug + 'static {
+    const ALL: &'static [Self];
+
+    fn name(self) -> &'static str;
+    fn kind(self) -> Kind;
+}
+
+// One row: the timestamp always comes first, then the metrics in declaration order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub timestamp: DateTime<Local>,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+impl Sample {
+    pub fn new(timestamp: DateTime<Local>) -> Self {
+        Sample {
+            timestamp,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    pub fn header(&self) -> Vec<String> {
+        std::iter::once("timestamp".to_string())
+            .chain(self.fields.iter().map(|(n, _)| n.to_string()))
+            .collect()
+    }
+
+    pub fn record(&self) -> Vec<String> {
+        std::iter::once(self.timestamp.to_rfc3339())
+            .chain(self.fields.iter().map(|(_, v)| v.to_string()))
+            .collect()
+    }
+}
+
+pub trait Sink {
+    fn write(&mut self, sample: &Sample) -> io::Result<()>;
+}
+
+// Appends to a CSV file, writing the header only when the file is new or empty.
+// A file whose header doesn't match the samples is moved aside instead of appended to.
+pub struct CsvSink {
+    path: PathBuf,
+    writer: Option<Writer<File>>,
+}
+
+impl CsvSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CsvSink {
+            path: path.into(),
+            writer: None,
+        }
+    }
+}
+
+// `stats.csv` becomes `stats.<timestamp>.csv`, with a counter if that is taken too.
+fn rotate(path: &Path) -> io::Result<()> {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("stats");
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("csv");
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    let mut target = path.with_file_name(format!("{}.{}.{}", stem, stamp, extension));
+    let mut n = 1;
+    while target.exists() {
+        target = path.with_file_name(format!("{}.{}-{}.{}", stem, stamp, n, extension));
+        n += 1;
+    }
+    fs::rename(path, target)
+}
+
+fn header_matches(path: &Path, header: &[String]) -> io::Result<bool> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+    Ok(match reader.records().next() {
+        Some(record) => record?.iter().eq(header.iter().map(String::as_str)),
+        None => true,
+    })
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        if self.writer.is_none() {
+            if self.path.exists() && !header_matches(&self.path, &sample.header())? {
+                rotate(&self.path)?;
+            }
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.path)?;
+            let is_empty = file.metadata()?.len() == 0;
+            let mut wtr = Writer::from_writer(file);
+            if is_empty {
+                wtr.write_record(sample.header())?;
+            }
+            self.writer = Some(wtr);
+        }
+
+        let wtr = self.writer.as_mut().unwrap();
+        wtr.write_record(sample.record())?;
+        wtr.flush()
+    }
+}
+
+pub struct RegistryBuilder<M: Metric> {
+    metric: PhantomData<M>,
+    sinks: Vec<Box<dyn Sink>>,
+    interval: Option<Duration>,
+    delta_counters: bool,
+    skip_unchanged: bool,
+}
+
+impl<M: Metric> RegistryBuilder<M> {
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn csv(self, path: impl Into<PathBuf>) -> Self {
+        self.sink(CsvSink::new(path))
+    }
+
+    // Only let `tick` write once per interval instead of on every call.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    // Counters are written as what happened since the previous row.
+    pub fn delta_counters(mut self) -> Self {
+        self.delta_counters = true;
+        self
+    }
+
+    // Don't write a row when nothing changed since the previous one.
+    pub fn skip_unchanged(mut self) -> Self {
+        self.skip_unchanged = true;
+        self
+    }
+
+    pub fn build(self) -> Registry<M> {
+        for (i, metric) in M::ALL.iter().enumerate() {
+            assert!(
+                M::ALL[..i].iter().all(|m| m.name() != metric.name()),
+                "metric {} declared twice",
+                metric.name()
+            );
+        }
+        let baseline = vec![Value::Int(0); M::ALL.len()];
+        Registry {
+            values: baseline.clone(),
+            sinks: self.sinks,
+            interval: self.interval,
+            delta_counters: self.delta_counters,
+            skip_unchanged: self.skip_unchanged,
+            last_flush: Instant::now(),
+            last_written: baseline.clone(),
+            baseline,
+            metric: PhantomData,
+        }
+    }
+}
+
+pub struct Registry<M: Metric> {
+    values: Vec<Value>,
+    sinks: Vec<Box<dyn Sink>>,
+    interval: Option<Duration>,
+    delta_counters: bool,
+    skip_unchanged: bool,
+    last_flush: Instant,
+    last_written: Vec<Value>,
+    baseline: Vec<Value>,
+    metric: PhantomData<M>,
+}
+
+impl<M: Metric> Registry<M> {
+    pub fn builder() -> RegistryBuilder<M> {
+        RegistryBuilder {
+            metric: PhantomData,
+            sinks: Vec::new(),
+            interval: None,
+            delta_counters: false,
+            skip_unchanged: false,
+        }
+    }
+
+    fn index(metric: M) -> usize {
+        M::ALL
+            .iter()
+            .position(|m| *m == metric)
+            .unwrap_or_else(|| panic!("metric {:?} missing from ALL", metric))
+    }
+
+    fn value_mut(&mut self, metric: M, kind: fn(Kind) -> bool) -> &mut Value {
+        assert!(
+            kind(metric.kind()),
+            "metric {} used as the wrong kind",
+            metric.name()
+        );
+        &mut self.values[Self::index(metric)]
+    }
+
+    pub fn increment(&mut self, metric: M) {
+        self.add(metric, 1);
+    }
+
+    pub fn add(&mut self, metric: M, amount: usize) {
+        if let Value::Int(v) = self.value_mut(metric, |k| k == Kind::Counter) {
+            *v += amount as i64;
+        }
+    }
+
+    pub fn set(&mut self, metric: M, value: impl Into<Value>) {
+        *self.value_mut(metric, |k| k == Kind::Gauge) = value.into();
+    }
+
+    pub fn record(&mut self, metric: M, duration: Duration) {
+        let value = self.value_mut(metric, |k| matches!(k, Kind::Timer(_)));
+        if let Kind::Timer(unit) = metric.kind() {
+            *value = unit.convert(duration);
+        }
+    }
+
+    pub fn get(&self, metric: M) -> &Value {
+        &self.values[Self::index(metric)]
+    }
+
+    pub fn changed(&self) -> bool {
+        self.last_written != self.values
+    }
+
+    // Current values stamped with the current time; counters relative to the last row in delta mode.
+    pub fn sample(&self) -> Sample {
+        let mut sample = Sample::new(Local::now());
+        for ((metric, value), base) in M::ALL.iter().zip(&self.values).zip(&self.baseline) {
+            let value = match (value, base) {
+                (Value::Int(v), Value::Int(b))
+                    if metric.kind() == Kind::Counter && self.delta_counters =>
+                {
+                    Value::Int(v - b)
+                }
+                (v, _) => v.clone(),
+            };
+            sample.fields.push((metric.name(), value));
+        }
+        sample
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        if self.skip_unchanged && !self.changed() {
+            return Ok(());
+        }
+
+        let sample = self.sample();
+        for sink in &mut self.sinks {
+            sink.write(&sample)?;
+        }
+        self.last_written = self.values.clone();
+        if self.delta_counters {
+            self.baseline = self.values.clone();
+        }
+        Ok(())
+    }
+
+    // Call once per frame; writes when the interval elapsed (or every time without one).
+    pub fn tick(&mut self) -> io::Result<()> {
+        match self.interval {
+            Some(interval) if self.last_flush.elapsed() < interval => Ok(()),
+            _ => self.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<Sample>>>);
+
+    impl Sink for Shared {
+        fn write(&mut self, sample: &Sample) -> io::Result<()> {
+            self.0.borrow_mut().push(sample.clone());
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Stat {
+        Clicks,
+        MouseX,
+        ExecTime,
+    }
+
+    impl Metric for Stat {
+        const ALL: &'static [Self] = &[Stat::Clicks, Stat::MouseX, Stat::ExecTime];
+
+        fn name(self) -> &'static str {
+            match self {
+                Stat::Clicks => "clicks",
+                Stat::MouseX => "mouse_x",
+                Stat::ExecTime => "exec_time_ms",
+            }
+        }
+
+        fn kind(self) -> Kind {
+            match self {
+                Stat::Clicks => Kind::Counter,
+                Stat::MouseX => Kind::Gauge,
+                Stat::ExecTime => Kind::Timer(TimeUnit::Millis),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Clicks {
+        Clicks,
+    }
+
+    impl Metric for Clicks {
+        const ALL: &'static [Self] = &[Clicks::Clicks];
+
+        fn name(self) -> &'static str {
+            "clicks"
+        }
+
+        fn kind(self) -> Kind {
+            Kind::Counter
+        }
+    }
+
+    fn registry(shared: &Shared) -> RegistryBuilder<Stat> {
+        Registry::builder().sink(shared.clone())
+    }
+
+    #[test]
+    fn columns_follow_declaration_order_after_timestamp() {
+        let shared = Shared::default();
+        let mut stats = registry(&shared).build();
+        stats.increment(Stat::Clicks);
+        stats.set(Stat::MouseX, 12usize);
+        stats.record(Stat::ExecTime, Duration::from_micros(2500));
+        stats.flush().unwrap();
+
+        let samples = shared.0.borrow();
+        assert_eq!(
+            samples[0].header(),
+            vec!["timestamp", "clicks", "mouse_x", "exec_time_ms"]
+        );
+        assert_eq!(&samples[0].record()[1..], ["1", "12", "2"]);
+    }
+
+    #[test]
+    fn delta_counters_reset_between_rows() {
+        let shared = Shared::default();
+        let mut stats = registry(&shared).delta_counters().build();
+        stats.add(Stat::Clicks, 3);
+        stats.flush().unwrap();
+        stats.increment(Stat::Clicks);
+        stats.flush().unwrap();
+
+        let samples = shared.0.borrow();
+        assert_eq!(samples[0].get("clicks"), Some(&Value::Int(3)));
+        assert_eq!(samples[1].get("clicks"), Some(&Value::Int(1)));
+        assert_eq!(stats.get(Stat::Clicks), &Value::Int(4));
+    }
+
+    #[test]
+    fn skip_unchanged_drops_idle_rows() {
+        let shared = Shared::default();
+        let mut stats = registry(&shared).skip_unchanged().build();
+        stats.flush().unwrap();
+        stats.set(Stat::MouseX, 4usize);
+        stats.flush().unwrap();
+        stats.flush().unwrap();
+
+        assert_eq!(shared.0.borrow().len(), 1);
+    }
+
+    #[test]
+    fn tick_waits_for_the_interval() {
+        let shared = Shared::default();
+        let mut stats = registry(&shared).interval(Duration::from_secs(60)).build();
+        stats.increment(Stat::Clicks);
+        stats.tick().unwrap();
+        assert!(shared.0.borrow().is_empty());
+
+        stats.flush().unwrap();
+        assert_eq!(shared.0.borrow().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "used as the wrong kind")]
+    fn wrong_kind_panics() {
+        let mut stats = Registry::<Stat>::builder().build();
+        stats.increment(Stat::MouseX);
+    }
+
+    #[test]
+    fn csv_sink_writes_header_once() {
+        let path = std::env::temp_dir().join(format!("telemetry-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for _ in 0..2 {
+            let mut stats = Registry::builder().csv(&path).build();
+            stats.increment(Clicks::Clicks);
+            stats.flush().unwrap();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "timestamp,clicks");
+        assert!(lines[1].ends_with(",1"));
+    }
+
+    #[test]
+    fn csv_sink_moves_aside_a_file_with_another_header() {
+        let dir = std::env::temp_dir().join(format!("telemetry-rotate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stats.csv");
+        std::fs::write(&path, "timestamp,old_column\n2024-01-01T00:00:00+00:00,7\n").unwrap();
+
+        let mut stats = Registry::builder().csv(&path).build();
+        stats.increment(Clicks::Clicks);
+        stats.flush().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let rotated: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| *p != path)
+            .collect();
+        let old = std::fs::read_to_string(&rotated[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(contents.starts_with("timestamp,clicks\n"));
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(rotated.len(), 1);
+        assert!(old.starts_with("timestamp,old_column\n"));
+    }
+}
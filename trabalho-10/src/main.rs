@@ -1,16 +1,12 @@
-use chrono::prelude::*;
-use csv::Writer;
 use minifb::{Key, MouseButton, Window, WindowOptions};
 use rand::Rng;
 use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::collections::{BinaryHeap, HashMap};
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use telemetry::{Kind, Metric, Registry};
 
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 1000;
@@ -25,56 +21,50 @@ const LIGHT_BLUE: u32 = 0x00ADD8E6;
 const CELL_WIDTH: usize = WIDTH / COLUMNS;
 const CELL_HEIGHT: usize = HEIGHT / ROWS;
 
-struct Statistics {
-    how_many_recalculations: usize,
-    how_many_collisions: usize,
-    how_many_detections: usize,
-    total_agents_path_length: usize,
-    how_many_steps_agents_made: usize,
-}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stat {
+    Recalculations,
+    Collisions,
+    Detections,
+    TotalPathLength,
+    StepsMade,
+}
+
+impl Metric for Stat {
+    const ALL: &'static [Self] = &[
+        Stat::Recalculations,
+        Stat::Collisions,
+        Stat::Detections,
+        Stat::TotalPathLength,
+        Stat::StepsMade,
+    ];
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            how_many_recalculations: 0,
-            how_many_detections: 0,
-            how_many_collisions: 0,
-            total_agents_path_length: 0,
-            how_many_steps_agents_made: 0,
+    fn name(self) -> &'static str {
+        match self {
+            Stat::Recalculations => "how_many_recalculations",
+            Stat::Collisions => "how_many_collisions",
+            Stat::Detections => "how_many_detections",
+            Stat::TotalPathLength => "total_agents_path_length",
+            Stat::StepsMade => "how_many_steps_agents_made",
         }
     }
-}
-
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
 
-    let mut wtr = Writer::from_writer(file);
-
-    if !file_exists {
-        wtr.write_record(&[
-            "timestamp",
-            "how_many_recalculations",
-            "how_many_collisions",
-            "how_many_detections",
-            "total_agents_path_length",
-            "how_many_steps_agents_made",
-        ])?;
+    fn kind(self) -> Kind {
+        match self {
+            Stat::Recalculations
+            | Stat::Collisions
+            | Stat::Detections
+            | Stat::TotalPathLength
+            | Stat::StepsMade => Kind::Counter,
+        }
     }
+}
 
-    wtr.write_record(&[
-        Local::now().to_string(),
-        stats.how_many_recalculations.to_string(),
-        stats.how_many_collisions.to_string(),
-        stats.how_many_detections.to_string(),
-        stats.total_agents_path_length.to_string(),
-        stats.how_many_steps_agents_made.to_string(),
-    ])?;
-
-    wtr.flush()?;
-    Ok(())
+fn new_statistics() -> Registry<Stat> {
+    Registry::builder()
+        .csv("stats.csv")
+        .interval(Duration::from_secs(1))
+        .build()
 }
 
 fn move_dir(a: Node, b: Node) -> Node {
@@ -515,7 +505,7 @@ impl CollisionDetector {
         }
     }
 
-    fn check_agents(&mut self, agents: &[Agent], stats: &mut Statistics) {
+    fn check_agents(&mut self, agents: &[Agent], stats: &mut Registry<Stat>) {
         for i in 0..agents.len() {
             for j in (i + 1)..agents.len() {
                 let pair = AgentPair::new(agents[i].id, agents[j].id);
@@ -537,7 +527,7 @@ impl CollisionDetector {
                     self.notify_observers(&event);
                     self.ignored_pairs.insert(pair);
 
-                    stats.how_many_collisions += 1;
+                    stats.increment(Stat::Collisions);
                 } else if self.check_path_collision(agent1, agent2) {
                     if let Some(collision_point) = self.find_collision_path(agent1, agent2) {
                         let event = CollisionEvent {
@@ -549,7 +539,7 @@ impl CollisionDetector {
                         self.notify_observers(&event);
                         self.ignored_pairs.insert(pair);
 
-                        stats.how_many_detections += 1;
+                        stats.increment(Stat::Detections);
                     }
                 }
             }
@@ -1000,7 +990,7 @@ fn process_reroute_requests(
     requests: &[RerouteRequest],
     walls: &HashSet<Node>,
     movement_strategy: &dyn MovementStrategy,
-    stats: &mut Statistics,
+    stats: &mut Registry<Stat>,
 ) {
     use std::collections::HashMap;
 
@@ -1039,7 +1029,7 @@ fn process_reroute_requests(
                         Some(pref),
                         movement_strategy,
                     ) {
-                        stats.how_many_recalculations += 1;
+                        stats.increment(Stat::Recalculations);
                         agent.final_path = Some(new_path);
                         agent.current_path_index = 0;
                         agent.collision_radius = agent.calculate_radius();
@@ -1147,7 +1137,7 @@ fn process_reroute_requests(
                         },
                         movement_strategy,
                     ) {
-                        stats.how_many_recalculations += 1;
+                        stats.increment(Stat::Recalculations);
                         agent.final_path = Some(new_path);
                         agent.current_path_index = 0;
                         agent.collision_radius = agent.calculate_radius();
@@ -1163,12 +1153,11 @@ fn process_reroute_requests(
 }
 
 fn game_loop(window: &mut Window, buffer: &mut Vec<u32>, state: &mut GameState) {
-    let mut stats = Statistics::new();
+    let mut stats = new_statistics();
     let artist = ArtistFactory::create(ArtistType::Normal);
     let mut movement = PathMovement::new();
     let mut history = CommandHistory::new();
     let mut agents: Vec<Agent> = Vec::new();
-    let mut last_log_time = Instant::now();
 
     let mut collision_detector = CollisionDetector::new();
     let logger = Rc::new(CollisionLogger);
@@ -1207,7 +1196,7 @@ fn game_loop(window: &mut Window, buffer: &mut Vec<u32>, state: &mut GameState)
                     }
                 }
 
-                stats.how_many_steps_agents_made += 1;
+                stats.increment(Stat::StepsMade);
             }
 
             collision_detector.ignored_pairs.clear();
@@ -1292,7 +1281,7 @@ fn game_loop(window: &mut Window, buffer: &mut Vec<u32>, state: &mut GameState)
                     }
                 }
 
-                stats.total_agents_path_length += temp_path_length;
+                stats.add(Stat::TotalPathLength, temp_path_length);
             }
         }
 
@@ -1432,10 +1421,7 @@ fn game_loop(window: &mut Window, buffer: &mut Vec<u32>, state: &mut GameState)
             assistant.clear_requests();
         }
 
-        if last_log_time.elapsed() >= Duration::from_secs(1) {
-            save_statistics(&stats).unwrap();
-            last_log_time = Instant::now();
-        }
+        stats.tick().unwrap();
         state.was_pressed = is_pressed;
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
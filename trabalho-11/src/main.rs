@@ -2,33 +2,102 @@ use chrono::prelude::*;
 use csv::Writer;
 use dodgy_2d::{Agent as DodgyAgent, AvoidanceOptions};
 use glam::Vec2;
-use minifb::{Key, MouseButton, Window, WindowOptions};
-use rand::Rng;
+use log::{debug, error, info, warn};
+use minifb::{Key, MouseButton, ScaleMode, Window, WindowOptions};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::error::Error;
-use std::fs::OpenOptions;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
+use telemetry::{CsvSink, Sample, Sink};
+use uuid::Uuid;
 
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 1000;
+// The tall-grid feature gives rectangular cells, which testdata/tall.map is laid out for.
+#[cfg(not(feature = "tall-grid"))]
 const ROWS: usize = 20;
+#[cfg(feature = "tall-grid")]
+const ROWS: usize = 30;
 const COLUMNS: usize = 20;
 
 const WHITE: u32 = 0x00FFFFFF;
 const RED: u32 = 0x00FF0000;
 const PALE_RED: u32 = 0x00FFF0F0;
+const RADIUS_ALPHA: f32 = 0.2;
+const FOOTPRINT_MARGIN: usize = 8;
 const BLACK: u32 = 0x00080808;
 const ORANGE: u32 = 0x00FF963C;
 const LIGHT_BLUE: u32 = 0x00ADD8E6;
+const GREY: u32 = 0x00808080;
+const YELLOW: u32 = 0x00FFD700;
+const AVOID_TINT: u32 = 0x00502020;
+const PREFER_TINT: u32 = 0x00205020;
+const CYAN: u32 = 0x0000FFFF;
+const GATE_OPEN: u32 = 0x00403018;
+const GATE_CLOSED: u32 = 0x00A0522D;
+const CHARGER_COLOR: u32 = 0x00203A5A;
+const BATTERY_OK: u32 = 0x0000C853;
+const BATTERY_LOW: u32 = 0x00FF1744;
+const PASSAGE_TINT: u32 = 0x00303050;
+const SELECTION_COLOR: u32 = 0x0000E5FF;
+const LIGHT_CELL: u32 = 0x00282828;
+const LIGHT_GREEN: u32 = 0x0000E676;
+const LIGHT_RED: u32 = 0x00FF1744;
+const OBSTACLE_COLOR: u32 = 0x00606060;
+const INFLATED_COLOR: u32 = 0x00A04040;
+const GRAPH_COLOR: u32 = 0x00283848;
+const REACHABLE_TINT: u32 = 0x00183C18;
+const UNREACHABLE_TINT: u32 = 0x00303030;
+const FIELD_NEAR: u32 = 0x00106030;
+const FIELD_FAR: u32 = 0x00602010;
+const REFUSED_FLASH: Duration = Duration::from_millis(300);
+const RESET_CONFIRM_WINDOW: Duration = Duration::from_secs(2);
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+const TOAST_FADE: Duration = Duration::from_secs(1);
+const MAX_TOASTS: usize = 5;
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(100);
+const PANE_TOP: usize = HEIGHT / 8;
+const THROUGHPUT_WINDOW: usize = 100;
+const PRM_SAMPLES: usize = 300;
+const PRM_RADIUS: f32 = 120.0;
+const PRM_NEIGHBORS: usize = 10;
+const PRM_MAX_ATTEMPTS: usize = 20;
+const RRT_ITERATIONS: usize = 3000;
+const RRT_STEP: f32 = 40.0;
+const RRT_GOAL_BIAS: f64 = 0.1;
+const RRT_REWIRE_RADIUS: f32 = 90.0;
+const RRT_GROWTH_PER_FRAME: usize = 25;
+
+const AGENT_PALETTE: [u32; 8] = [
+    0x00FF0000, 0x0000C853, 0x002979FF, 0x00FFAB00, 0x00D500F9, 0x0000E5FF, 0x00FF6D00, 0x00C6FF00,
+];
 
 const CELL_WIDTH: usize = WIDTH / COLUMNS;
 const CELL_HEIGHT: usize = HEIGHT / ROWS;
+// Marker sizes are tuned for 50px cells and shrink or grow with the actual cell.
+const REFERENCE_CELL: usize = 50;
+const PATH_DASH: usize = 6;
+const PATH_GAP: usize = 4;
+const TRAVELLED_ALPHA: f32 = 0.45;
+const PATH_ARROW_INTERVAL: usize = 3;
+const RULER_HEIGHT: usize = GLYPH_HEIGHT + 4;
+const MAX_SCALE: usize = 4;
+const PRESENTATION_STROKE: usize = 3;
 const NEIGHBOR_RADIUS: f32 = 80.0;
+const TARGET_FPS: usize = 60;
+const DEFAULT_TPS: u32 = 10;
+const MAX_TICKS_PER_FRAME: u32 = 5;
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 struct Node {
@@ -57,6 +126,99 @@ impl Node {
             y: (pos.y / CELL_HEIGHT as f32) as i32,
         }
     }
+
+    fn cell(self) -> CellCoord {
+        CellCoord {
+            col: Col(self.ux()),
+            row: Row(self.uy()),
+        }
+    }
+}
+
+// Columns only ever scale by CELL_WIDTH and rows by CELL_HEIGHT, so the two cannot be mixed up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Col(usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Row(usize);
+
+impl Col {
+    fn left(self) -> usize {
+        self.0 * CELL_WIDTH
+    }
+}
+
+impl Row {
+    fn top(self) -> usize {
+        self.0 * CELL_HEIGHT
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct CellCoord {
+    col: Col,
+    row: Row,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct PixelCoord {
+    x: usize,
+    y: usize,
+}
+
+impl CellCoord {
+    fn top_left(self) -> PixelCoord {
+        PixelCoord {
+            x: self.col.left(),
+            y: self.row.top(),
+        }
+    }
+
+    fn center(self) -> PixelCoord {
+        PixelCoord {
+            x: self.col.left() + CELL_WIDTH / 2,
+            y: self.row.top() + CELL_HEIGHT / 2,
+        }
+    }
+
+    fn node(self) -> Node {
+        Node {
+            x: self.col.0 as i32,
+            y: self.row.0 as i32,
+        }
+    }
+}
+
+impl PixelCoord {
+    // Negative positions clamp to the window edge.
+    fn from_vec(v: Vec2) -> Self {
+        PixelCoord {
+            x: v.x.max(0.0) as usize,
+            y: v.y.max(0.0) as usize,
+        }
+    }
+
+    fn to_vec(self) -> Vec2 {
+        Vec2::new(self.x as f32, self.y as f32)
+    }
+
+    fn cell(self) -> CellCoord {
+        CellCoord {
+            col: Col(self.x / CELL_WIDTH),
+            row: Row(self.y / CELL_HEIGHT),
+        }
+    }
+
+    fn offset(self, dx: usize, dy: usize) -> Self {
+        PixelCoord {
+            x: self.x + dx,
+            y: self.y + dy,
+        }
+    }
+}
+
+fn scaled(px: usize) -> usize {
+    (px * CELL_WIDTH.min(CELL_HEIGHT) / REFERENCE_CELL).max(1)
 }
 
 fn move_dir(a: Node, b: Node) -> Node {
@@ -85,1403 +247,13239 @@ fn in_bounds(n: Node) -> bool {
     n.x >= 0 && n.y >= 0 && (n.x as usize) < COLUMNS && (n.y as usize) < ROWS
 }
 
-struct Statistics {
-    recalculations: usize,
-    collisions: usize,
-    detections: usize,
-    total_path_length: usize,
-    total_steps: usize,
-    agents: usize,
-    method_name: String,
-    actual_distance: f32,
-    reached_goal_count: usize,
-}
+const NO_COMPONENT: usize = usize::MAX;
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            recalculations: 0,
-            collisions: 0,
-            detections: 0,
-            total_path_length: 0,
-            total_steps: 0,
-            agents: 0,
-            method_name: String::new(),
-            actual_distance: 0.0,
-            reached_goal_count: 0,
-        }
-    }
+fn cell_index(n: Node) -> usize {
+    n.uy() * COLUMNS + n.ux()
 }
 
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
-    let mut wtr = Writer::from_writer(file);
+struct ComponentMap {
+    labels: Vec<usize>,
+}
 
-    if !file_exists {
-        wtr.write_record(&[
-            "timestamp",
-            "recalculations",
-            "collisions",
-            "detections",
-            "total_path_length",
-            "total_steps",
-            "agents",
-            "method_name",
-            "actual_distance",
-            "reached_goal_count",
-        ])?;
+impl ComponentMap {
+    fn new() -> Self {
+        ComponentMap {
+            labels: vec![0; ROWS * COLUMNS],
+        }
     }
 
-    wtr.write_record(&[
-        Local::now().to_string(),
-        stats.recalculations.to_string(),
-        stats.collisions.to_string(),
-        stats.detections.to_string(),
-        stats.total_path_length.to_string(),
-        stats.total_steps.to_string(),
-        stats.agents.to_string(),
-        stats.method_name.to_string(),
-        stats.actual_distance.to_string(),
-        stats.reached_goal_count.to_string(),
-    ])?;
-
-    wtr.flush()?;
-    Ok(())
-}
-
-struct LineParams {
-    x0: i32,
-    y0: i32,
-    x1: i32,
-    y1: i32,
-    color: u32,
-}
-struct SquareParams {
-    x: usize,
-    y: usize,
-    color: u32,
-}
-struct CircleParams {
-    x: usize,
-    y: usize,
-    radius: usize,
-    color: u32,
-}
+    fn rebuild(&mut self, walls: &HashSet<Node>, movement: &dyn MovementStrategy) {
+        self.labels.fill(NO_COMPONENT);
+        let mut next_label = 0;
+        let mut stack = Vec::new();
 
-enum DrawType {
-    Line(LineParams),
-    Square(SquareParams),
-    Circle(CircleParams),
-}
+        for y in 0..ROWS {
+            for x in 0..COLUMNS {
+                let seed = Node {
+                    x: x as i32,
+                    y: y as i32,
+                };
+                if walls.contains(&seed) || self.labels[cell_index(seed)] != NO_COMPONENT {
+                    continue;
+                }
 
-fn draw(buffer: &mut [u32], item: &DrawType) {
-    match item {
-        DrawType::Line(p) => draw_line(buffer, p),
-        DrawType::Square(p) => draw_square(buffer, p),
-        DrawType::Circle(p) => draw_circle(buffer, p),
+                self.labels[cell_index(seed)] = next_label;
+                stack.push(seed);
+                while let Some(node) = stack.pop() {
+                    for neighbor in movement.get_neighbors(node) {
+                        if walls.contains(&neighbor)
+                            || self.labels[cell_index(neighbor)] != NO_COMPONENT
+                        {
+                            continue;
+                        }
+                        self.labels[cell_index(neighbor)] = next_label;
+                        stack.push(neighbor);
+                    }
+                }
+                next_label += 1;
+            }
+        }
     }
-}
-
-fn draw_line(buffer: &mut [u32], p: &LineParams) {
-    let (mut x0, mut y0) = (p.x0, p.y0);
-    let (x1, y1) = (p.x1, p.y1);
-    let dx = (x1 - x0).abs();
-    let sx = if x0 < x1 { 1 } else { -1 };
-    let dy = -(y1 - y0).abs();
-    let sy = if y0 < y1 { 1 } else { -1 };
-    let mut err = dx + dy;
 
-    loop {
-        if x0 >= 0 && y0 >= 0 && (x0 as usize) < WIDTH && (y0 as usize) < HEIGHT {
-            buffer[y0 as usize * WIDTH + x0 as usize] = p.color;
-        }
-        if x0 == x1 && y0 == y1 {
-            break;
-        }
-        let e2 = 2 * err;
-        if e2 >= dy {
-            err += dy;
-            x0 += sx;
+    fn label(&self, n: Node) -> Option<usize> {
+        if !in_bounds(n) {
+            return None;
         }
-        if e2 <= dx {
-            err += dx;
-            y0 += sy;
+        match self.labels[cell_index(n)] {
+            NO_COMPONENT => None,
+            l => Some(l),
         }
     }
-}
 
-fn draw_circle(buffer: &mut [u32], p: &CircleParams) {
-    let cx = p.x * CELL_HEIGHT + CELL_WIDTH / 2;
-    let cy = p.y * CELL_WIDTH + CELL_HEIGHT / 2;
-    let r2 = (p.radius * p.radius) as isize;
-
-    for y in cy.saturating_sub(p.radius)..=(cy + p.radius).min(HEIGHT - 1) {
-        let dy = y as isize - cy as isize;
-        for x in cx.saturating_sub(p.radius)..=(cx + p.radius).min(WIDTH - 1) {
-            let dx = x as isize - cx as isize;
-            if dx * dx + dy * dy <= r2 {
-                buffer[y * WIDTH + x] = p.color;
-            }
+    // A* may leave a start cell that sits on a wall, so in that case the
+    // start counts as connected to every component touching it.
+    fn reachable(&self, start: Node, goal: Node, movement: &dyn MovementStrategy) -> bool {
+        if start == goal {
+            return true;
+        }
+        let Some(goal_label) = self.label(goal) else {
+            return false;
+        };
+        match self.label(start) {
+            Some(l) => l == goal_label,
+            None => movement
+                .get_neighbors(start)
+                .into_iter()
+                .any(|n| n == goal || self.label(n) == Some(goal_label)),
         }
     }
 }
 
-fn draw_square(buffer: &mut [u32], p: &SquareParams) {
-    let top_left = (p.y * CELL_WIDTH) * WIDTH + p.x * CELL_HEIGHT;
-    for i in 0..CELL_WIDTH {
-        let row_start = top_left + i * WIDTH;
-        buffer[row_start..row_start + CELL_HEIGHT].fill(p.color);
-    }
-}
-
-fn draw_matrix(buffer: &mut [u32]) {
-    for i in 1..ROWS {
-        let px = (WIDTH / ROWS) * i;
-        draw(
-            buffer,
-            &DrawType::Line(LineParams {
-                x0: px as i32,
-                y0: 0,
-                x1: px as i32,
-                y1: HEIGHT as i32,
-                color: WHITE,
-            }),
-        );
-    }
-    for i in 1..COLUMNS {
-        let py = (HEIGHT / COLUMNS) * i;
-        draw(
-            buffer,
-            &DrawType::Line(LineParams {
-                x0: 0,
-                y0: py as i32,
-                x1: WIDTH as i32,
-                y1: py as i32,
-                color: WHITE,
-            }),
-        );
-    }
-}
+const STEP_COST: i32 = 4;
+const AVOID_ZONE_PENALTY: i32 = 8;
+const PREFER_ZONE_BONUS: i32 = 2;
+const MIN_STEP_COST: i32 = 1;
 
-trait MovementStrategy {
-    fn get_neighbors(&self, node: Node) -> Vec<Node>;
-    fn name(&self) -> &str;
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Zone {
+    Avoid,
+    Prefer,
 }
 
-struct OrthogonalMovement;
-struct DiagonalMovement;
+const GATE_SCHEDULES: [(usize, usize); 3] = [(10, 10), (5, 15), (15, 5)];
+const MAX_PASSAGE_CAPACITY: usize = 3;
 
-impl MovementStrategy for OrthogonalMovement {
-    fn get_neighbors(&self, node: Node) -> Vec<Node> {
-        const DELTAS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
-        DELTAS
-            .iter()
-            .map(|&(dx, dy)| Node {
-                x: node.x + dx,
-                y: node.y + dy,
-            })
-            .filter(|n| in_bounds(*n))
-            .collect()
-    }
-    fn name(&self) -> &str {
-        "Orthogonal"
-    }
+struct Passage {
+    cells: HashSet<Node>,
+    capacity: usize,
 }
 
-impl MovementStrategy for DiagonalMovement {
-    fn get_neighbors(&self, node: Node) -> Vec<Node> {
-        const DELTAS: [(i32, i32); 8] = [
-            (1, 0),
-            (-1, 0),
-            (0, 1),
-            (0, -1),
-            (1, 1),
-            (1, -1),
-            (-1, 1),
-            (-1, -1),
-        ];
-        DELTAS
+impl Passage {
+    fn occupants(&self, agents: &[Agent]) -> Vec<usize> {
+        agents
             .iter()
-            .map(|&(dx, dy)| Node {
-                x: node.x + dx,
-                y: node.y + dy,
-            })
-            .filter(|n| in_bounds(*n))
+            .filter(|a| !a.finished && self.cells.contains(&a.current_point))
+            .map(|a| a.id)
             .collect()
     }
-    fn name(&self) -> &str {
-        "Diagonal"
-    }
 }
 
-trait Command {
-    fn execute(&mut self, steps: &mut Vec<Vec<Node>>);
-    fn undo(&mut self, steps: &mut Vec<Vec<Node>>);
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Gate {
+    open_ticks: usize,
+    closed_ticks: usize,
+    offset: usize,
 }
 
-struct WriteCommand {
-    step: Vec<Node>,
+// Where `tick` falls in a repeating schedule; both terms are reduced first so nothing overflows.
+fn schedule_phase(tick: usize, offset: usize, period: usize) -> usize {
+    let (tick, offset) = (tick % period, offset % period);
+    if tick >= period - offset {
+        tick - (period - offset)
+    } else {
+        tick + offset
+    }
 }
 
-impl Command for WriteCommand {
-    fn execute(&mut self, steps: &mut Vec<Vec<Node>>) {
-        steps.push(self.step.clone());
+impl Gate {
+    fn new(open_ticks: usize, closed_ticks: usize, now: usize) -> Self {
+        let period = open_ticks + closed_ticks;
+        Gate {
+            open_ticks,
+            closed_ticks,
+            offset: (period - now % period) % period,
+        }
     }
-    fn undo(&mut self, steps: &mut Vec<Vec<Node>>) {
-        steps.pop();
+
+    fn is_open(&self, tick: usize) -> bool {
+        let period = self.open_ticks.saturating_add(self.closed_ticks);
+        schedule_phase(tick, self.offset, period) < self.open_ticks
     }
 }
 
-struct DeleteCommand {
-    count: usize,
-    deleted: Vec<Vec<Node>>,
+const LIGHT_SCHEDULES: [usize; 3] = [4, 8, 12];
+// Labels a click in note mode cycles through; the map file accepts any text.
+const NOTE_LABELS: [&str; 5] = [
+    "*",
+    "SPAWN ZONE",
+    "BOTTLENECK A",
+    "BOTTLENECK B",
+    "GOAL AREA",
+];
+
+// Alternates green between horizontal and vertical approaches into its cell.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct TrafficLight {
+    green_ticks: usize,
+    offset: usize,
 }
 
-impl DeleteCommand {
-    fn new(count: usize) -> Self {
-        DeleteCommand {
-            count,
-            deleted: Vec::new(),
+impl TrafficLight {
+    fn new(green_ticks: usize, now: usize) -> Self {
+        let period = green_ticks * 2;
+        TrafficLight {
+            green_ticks,
+            offset: (period - now % period) % period,
         }
     }
-}
 
-impl Command for DeleteCommand {
-    fn execute(&mut self, steps: &mut Vec<Vec<Node>>) {
-        let start = steps.len().saturating_sub(self.count);
-        self.deleted = steps[start..].to_vec();
-        steps.truncate(start);
+    fn horizontal_green(&self, tick: usize) -> bool {
+        schedule_phase(tick, self.offset, self.green_ticks.saturating_mul(2)) < self.green_ticks
     }
-    fn undo(&mut self, steps: &mut Vec<Vec<Node>>) {
-        steps.append(&mut self.deleted);
+
+    fn allows(&self, from: Node, to: Node, tick: usize) -> bool {
+        from == to || (to.x != from.x) == self.horizontal_green(tick)
     }
 }
 
-struct CommandHistory {
-    history: Vec<Box<dyn Command>>,
+#[derive(Clone, Debug, PartialEq)]
+struct Grid {
+    walls: HashSet<Node>,
+    zones: HashMap<Node, Zone>,
+    one_way: HashSet<(Node, Node)>,
+    gates: HashMap<Node, Gate>,
+    chargers: HashSet<Node>,
+    capacities: HashMap<Node, usize>,
+    lights: HashMap<Node, TrafficLight>,
+    notes: HashMap<Node, String>,
+    tick: usize,
 }
 
-impl CommandHistory {
+impl Grid {
     fn new() -> Self {
-        CommandHistory {
-            history: Vec::new(),
+        Grid {
+            walls: HashSet::new(),
+            zones: HashMap::new(),
+            one_way: HashSet::new(),
+            gates: HashMap::new(),
+            chargers: HashSet::new(),
+            capacities: HashMap::new(),
+            lights: HashMap::new(),
+            notes: HashMap::new(),
+            tick: 0,
         }
     }
 
-    fn execute(&mut self, mut cmd: Box<dyn Command>, steps: &mut Vec<Vec<Node>>) {
-        cmd.execute(steps);
-        self.history.push(cmd);
+    fn is_blocked_at(&self, n: Node, tick: usize) -> bool {
+        self.walls.contains(&n) || self.gates.get(&n).is_some_and(|g| !g.is_open(tick))
     }
 
-    fn undo(&mut self, steps: &mut Vec<Vec<Node>>) {
-        if let Some(mut cmd) = self.history.pop() {
-            cmd.undo(steps);
-        }
+    fn closed_gates_since(&self, previous_tick: usize) -> HashSet<Node> {
+        self.gates
+            .iter()
+            .filter(|(_, g)| g.is_open(previous_tick) && !g.is_open(self.tick))
+            .map(|(&n, _)| n)
+            .collect()
     }
-}
 
-#[derive(Clone, Debug)]
-struct Agent {
-    id: usize,
-    start_point: Node,
-    end_point: Option<Node>,
-    current_point: Node,
-    path: Option<Vec<Node>>,
-    path_index: usize,
-    collision_radius: Vec<Node>,
-    forward_path: Vec<Node>,
-
-    position: Vec2,
-    velocity: Vec2,
-    max_speed: f32,
-    radius: f32,
-    last_position: Vec2,
-    finished: bool,
-}
-
-impl Agent {
-    fn new(id: usize, start: Node, end: Option<Node>) -> Self {
-        let position = start.to_pixels();
-
-        let mut agent = Agent {
-            id,
-            start_point: start,
-            end_point: end,
-            current_point: start,
-            path: None,
-            path_index: 0,
-            collision_radius: Vec::with_capacity(8),
-            forward_path: Vec::with_capacity(2),
-            position,
-            velocity: Vec2::ZERO,
-            max_speed: 200.0,
-            radius: 20.0,
-            last_position: position,
-            finished: false,
+    fn cycle_gate(&mut self, n: Node) {
+        let next = match self.gates.get(&n) {
+            None => Some(0),
+            Some(g) => GATE_SCHEDULES
+                .iter()
+                .position(|&(o, c)| o == g.open_ticks && c == g.closed_ticks)
+                .map(|i| i + 1)
+                .filter(|&i| i < GATE_SCHEDULES.len()),
         };
-        agent.collision_radius = agent.calc_radius();
-        agent
+        match next {
+            Some(i) => {
+                let (open, closed) = GATE_SCHEDULES[i];
+                self.gates.insert(n, Gate::new(open, closed, self.tick));
+            }
+            None => {
+                self.gates.remove(&n);
+            }
+        }
     }
 
-    fn calc_radius(&self) -> Vec<Node> {
-        const DELTAS: [(i32, i32); 8] = [
-            (1, 0),
-            (-1, 0),
-            (0, 1),
-            (0, -1),
-            (1, 1),
-            (1, -1),
-            (-1, 1),
-            (-1, -1),
-        ];
-        DELTAS
-            .iter()
-            .map(|&(dx, dy)| Node {
-                x: self.current_point.x + dx,
-                y: self.current_point.y + dy,
-            })
-            .filter(|n| in_bounds(*n))
-            .collect()
+    fn cycle_light(&mut self, n: Node) {
+        let next = match self.lights.get(&n) {
+            None => Some(0),
+            Some(l) => LIGHT_SCHEDULES
+                .iter()
+                .position(|&g| g == l.green_ticks)
+                .map(|i| i + 1)
+                .filter(|&i| i < LIGHT_SCHEDULES.len()),
+        };
+        match next {
+            Some(i) => {
+                self.lights
+                    .insert(n, TrafficLight::new(LIGHT_SCHEDULES[i], self.tick));
+            }
+            None => {
+                self.lights.remove(&n);
+            }
+        }
     }
 
-    fn calc_forward(&self) -> Vec<Node> {
-        let Some(path) = &self.path else {
-            return Vec::new();
-        };
-        let goal = match self.end_point {
-            Some(g) => g,
-            None => return Vec::new(),
+    fn cycle_note(&mut self, n: Node) {
+        let next = match self.notes.get(&n) {
+            None => Some(0),
+            Some(text) => NOTE_LABELS
+                .iter()
+                .position(|&l| l == text)
+                .map(|i| i + 1)
+                .filter(|&i| i < NOTE_LABELS.len()),
         };
+        match next {
+            Some(i) => {
+                self.notes.insert(n, NOTE_LABELS[i].to_string());
+            }
+            None => {
+                self.notes.remove(&n);
+            }
+        }
+    }
 
-        path.iter()
-            .skip(self.path_index + 1)
-            .take(2)
-            .take_while(|&&n| n != goal)
-            .copied()
-            .collect()
+    fn light_allows(&self, from: Node, to: Node) -> bool {
+        self.lights
+            .get(&to)
+            .is_none_or(|l| l.allows(from, to, self.tick))
     }
 
-    fn direction(&self) -> Node {
-        if let Some(path) = &self.path {
-            if self.path_index + 1 < path.len() {
-                return move_dir(self.current_point, path[self.path_index + 1]);
+    fn cycle_capacity(&mut self, n: Node) {
+        match self.capacities.get(&n).copied() {
+            None => {
+                self.capacities.insert(n, 1);
+            }
+            Some(c) if c < MAX_PASSAGE_CAPACITY => {
+                self.capacities.insert(n, c + 1);
+            }
+            Some(_) => {
+                self.capacities.remove(&n);
             }
         }
-        if let Some(goal) = self.end_point {
-            return Node {
-                x: (goal.x - self.current_point.x).signum(),
-                y: (goal.y - self.current_point.y).signum(),
-            };
-        }
-        Node { x: 0, y: 0 }
     }
 
-    fn refresh_cache(&mut self) {
-        self.collision_radius = self.calc_radius();
-        self.forward_path = self.calc_forward();
+    fn passages(&self) -> Vec<Passage> {
+        let mut seen = HashSet::new();
+        let mut passages = Vec::new();
+        for (&start, &capacity) in &self.capacities {
+            if !seen.insert(start) {
+                continue;
+            }
+            let mut cells = HashSet::from([start]);
+            let mut queue = VecDeque::from([start]);
+            while let Some(n) = queue.pop_front() {
+                for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    let next = Node {
+                        x: n.x + dx,
+                        y: n.y + dy,
+                    };
+                    if self.capacities.get(&next) == Some(&capacity) && seen.insert(next) {
+                        cells.insert(next);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            passages.push(Passage { cells, capacity });
+        }
+        passages
     }
-}
-
-trait CollisionStrategy {
-    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent>;
-    fn name(&self) -> &str;
-}
-
-struct CollisionDetector {
-    observers: Vec<Rc<dyn CollisionObserver>>,
-    ignored_pairs: HashSet<AgentPair>,
-    strategy: Box<dyn CollisionStrategy>,
-}
 
-impl CollisionDetector {
-    fn new(strategy: Box<dyn CollisionStrategy>) -> Self {
-        Self {
-            observers: Vec::new(),
-            ignored_pairs: HashSet::new(),
-            strategy,
+    fn full_passage_cells(&self, agents: &[Agent], agent_id: usize) -> HashSet<Node> {
+        let mut full = HashSet::new();
+        for passage in self.passages() {
+            let occupants = passage.occupants(agents);
+            if occupants.contains(&agent_id) {
+                continue;
+            }
+            if occupants.len() >= passage.capacity {
+                full.extend(passage.cells);
+            }
         }
+        full
     }
 
-    fn set_strategy(&mut self, strategy: Box<dyn CollisionStrategy>) {
-        self.strategy = strategy;
-        self.ignored_pairs.clear();
+    fn allows_move(&self, from: Node, to: Node) -> bool {
+        !self.one_way.contains(&(to, from))
     }
 
-    fn notify(&self, event: &CollisionEvent) {
-        for obs in &self.observers {
-            obs.on_collision(event);
+    fn toggle_one_way(&mut self, from: Node, to: Node) {
+        if !self.one_way.remove(&(from, to)) {
+            self.one_way.remove(&(to, from));
+            self.one_way.insert((from, to));
         }
     }
 
-    fn register_observer(&mut self, obs: Rc<dyn CollisionObserver>) {
-        self.observers.push(obs);
+    fn is_blocked(&self, n: Node) -> bool {
+        self.is_blocked_at(n, self.tick)
     }
 
-    fn check_agents(&mut self, agents: &[Agent], stats: &mut Statistics) {
-        for i in 0..agents.len() {
-            for j in (i + 1)..agents.len() {
-                let pair = AgentPair::new(agents[i].id, agents[j].id);
-                if self.ignored_pairs.contains(&pair) {
-                    continue;
+    // Grid-space Minkowski sum: a cell becomes a wall when the footprint anchored there would
+    // overlap a wall or leave the map, so single-cell planners work unchanged for large agents.
+    fn inflated(&self, footprint: Footprint) -> Cow<'_, Grid> {
+        if footprint == Footprint::Cell {
+            return Cow::Borrowed(self);
+        }
+        let mut grid = self.clone();
+        for y in 0..ROWS as i32 {
+            for x in 0..COLUMNS as i32 {
+                let anchor = Node { x, y };
+                if footprint
+                    .cells(anchor)
+                    .iter()
+                    .any(|&c| !in_bounds(c) || self.walls.contains(&c))
+                {
+                    grid.walls.insert(anchor);
                 }
+            }
+        }
+        Cow::Owned(grid)
+    }
 
-                if let Some(event) = self.strategy.detect(&agents[i], &agents[j]) {
-                    self.notify(&event);
-                    self.ignored_pairs.insert(pair);
+    fn step_cost(&self, to: Node) -> i32 {
+        match self.zones.get(&to) {
+            Some(Zone::Avoid) => STEP_COST + AVOID_ZONE_PENALTY,
+            Some(Zone::Prefer) => (STEP_COST - PREFER_ZONE_BONUS).max(MIN_STEP_COST),
+            None => STEP_COST,
+        }
+    }
 
-                    match event.collision_type {
-                        CollisionType::Direct => stats.collisions += 1,
-                        CollisionType::Proximity => stats.detections += 1,
-                    }
-                }
-            }
+    // Orthogonal steps count 1 and diagonal ones √2, scaled by the terrain of the cell entered.
+    fn geometric_length(&self, path: &[Node]) -> f32 {
+        path.windows(2)
+            .map(|w| {
+                let step = if w[0].x != w[1].x && w[0].y != w[1].y {
+                    std::f32::consts::SQRT_2
+                } else {
+                    1.0
+                };
+                step * self.step_cost(w[1]) as f32 / STEP_COST as f32
+            })
+            .sum()
+    }
+
+    fn toggle_zone(&mut self, n: Node, zone: Zone) {
+        if self.zones.get(&n) == Some(&zone) {
+            self.zones.remove(&n);
+        } else {
+            self.zones.insert(n, zone);
         }
     }
 }
 
-#[derive(Debug, Clone)]
-enum CollisionType {
-    Direct,
-    Proximity,
+#[derive(Clone)]
+struct Statistics {
+    recalculations: usize,
+    collisions: usize,
+    detections: usize,
+    total_path_length: usize,
+    geometric_path_length: f32,
+    total_steps: usize,
+    agents: usize,
+    method_name: String,
+    actual_distance: f32,
+    reached_goal_count: usize,
+    deadlocks_resolved: usize,
+    capacity_conflicts: usize,
+    suppressed_reroutes: usize,
+    light_waits: usize,
+    light_crossings: usize,
+    comm_radius: i32,
+    knowledge_replans: usize,
+    environment_replans: usize,
+    makespan: usize,
+    sum_of_costs: usize,
+    unresolved_conflicts: usize,
+    goal_deliveries: usize,
+    goal_throughput: usize,
+    moves: usize,
+    waits: usize,
+    yields: usize,
+    separation_waits: usize,
+    min_separation: Option<i32>,
+    score: f32,
+    frame_ms: [f32; PHASE_COUNT],
 }
 
-struct CollisionEvent {
-    agent1_id: usize,
-    agent2_id: usize,
-    collision_type: CollisionType,
-    collision_point: Node,
-}
+impl Statistics {
+    fn new() -> Self {
+        Statistics {
+            recalculations: 0,
+            collisions: 0,
+            detections: 0,
+            total_path_length: 0,
+            geometric_path_length: 0.0,
+            total_steps: 0,
+            agents: 0,
+            method_name: String::new(),
+            actual_distance: 0.0,
+            reached_goal_count: 0,
+            deadlocks_resolved: 0,
+            capacity_conflicts: 0,
+            suppressed_reroutes: 0,
+            light_waits: 0,
+            light_crossings: 0,
+            comm_radius: -1,
+            knowledge_replans: 0,
+            environment_replans: 0,
+            makespan: 0,
+            sum_of_costs: 0,
+            unresolved_conflicts: 0,
+            goal_deliveries: 0,
+            goal_throughput: 0,
+            moves: 0,
+            waits: 0,
+            yields: 0,
+            separation_waits: 0,
+            min_separation: None,
+            score: 0.0,
+            frame_ms: [0.0; PHASE_COUNT],
+        }
+    }
 
-trait CollisionObserver {
-    fn on_collision(&self, event: &CollisionEvent);
+    // Counters start over with each run; the method, radius, agent count and timings carry on.
+    fn reset_run(&mut self) {
+        *self = Statistics {
+            method_name: std::mem::take(&mut self.method_name),
+            comm_radius: self.comm_radius,
+            agents: self.agents,
+            frame_ms: self.frame_ms,
+            ..Statistics::new()
+        };
+    }
 }
 
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
-struct AgentPair(usize, usize);
+// Cost charged per event; a run's score is the weighted sum, so lower is better.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct ScoreWeights {
+    step: f32,
+    reroute: f32,
+    collision: f32,
+    wait: f32,
+}
 
-impl AgentPair {
-    fn new(a: usize, b: usize) -> Self {
-        if a < b {
-            AgentPair(a, b)
-        } else {
-            AgentPair(b, a)
+impl ScoreWeights {
+    fn new() -> Self {
+        ScoreWeights {
+            step: 1.0,
+            reroute: 5.0,
+            collision: 100.0,
+            wait: 2.0,
         }
     }
-}
 
-struct PathCollisionStrategy;
+    fn parse(value: &str) -> Option<Self> {
+        let weights: Vec<f32> = value
+            .split(',')
+            .map(|w| w.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let [step, reroute, collision, wait] = weights.try_into().ok()?;
+        Some(ScoreWeights {
+            step,
+            reroute,
+            collision,
+            wait,
+        })
+    }
 
-impl CollisionStrategy for PathCollisionStrategy {
-    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
-        if a.current_point == b.current_point {
-            return Some(CollisionEvent {
-                agent1_id: a.id,
-                agent2_id: b.id,
-                collision_type: CollisionType::Direct,
-                collision_point: a.current_point,
-            });
-        }
+    fn score(&self, stats: &Statistics) -> f32 {
+        self.step * stats.moves as f32
+            + self.reroute * stats.recalculations as f32
+            + self.collision * stats.collisions as f32
+            + self.wait * stats.waits as f32
+    }
+}
 
-        let b_set: HashSet<Node> = b.forward_path.iter().copied().collect();
+#[derive(Debug, thiserror::Error)]
+enum SimError {
+    #[error("window error: {0}")]
+    Window(#[from] minifb::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[cfg(not(all(feature = "sqlite", feature = "parquet")))]
+    #[error("built without the `{0}` feature")]
+    FeatureDisabled(&'static str),
+    #[error("initialization failed: {0}")]
+    Init(String),
+    #[error("map line {line}: {message}")]
+    MapFormat { line: usize, message: String },
+    #[error("theme line {line}: {message}")]
+    ThemeFormat { line: usize, message: String },
+    #[error("macro line {line}: {message}")]
+    MacroFormat { line: usize, message: String },
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+}
 
-        for &node in &a.forward_path {
-            if b_set.contains(&node) || node == b.current_point {
-                return Some(CollisionEvent {
-                    agent1_id: a.id,
-                    agent2_id: b.id,
-                    collision_type: CollisionType::Proximity,
-                    collision_point: node,
-                });
-            }
-        }
+fn init_logging(log_file: Option<&str>) -> Result<(), SimError> {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    builder.format_timestamp_millis();
+    if let Some(path) = log_file {
+        builder.target(env_logger::Target::Pipe(Box::new(std::fs::File::create(
+            path,
+        )?)));
+    }
+    builder.init();
+    Ok(())
+}
 
-        for &node in &b.forward_path {
-            if node == a.current_point {
-                return Some(CollisionEvent {
-                    agent1_id: a.id,
-                    agent2_id: b.id,
-                    collision_type: CollisionType::Proximity,
-                    collision_point: node,
-                });
-            }
-        }
+struct Span {
+    label: String,
+    start: Instant,
+}
 
-        None
+impl Span {
+    fn enter(label: impl Into<String>) -> Self {
+        Span {
+            label: label.into(),
+            start: Instant::now(),
+        }
     }
+}
 
-    fn name(&self) -> &str {
-        "Path-based"
+impl Drop for Span {
+    fn drop(&mut self) {
+        debug!("{} took {:?}", self.label, self.start.elapsed());
     }
 }
 
-struct GridCollisionStrategy;
+struct ExperimentRun {
+    id: Uuid,
+    label: String,
+    scenario: String,
+    started: DateTime<Local>,
+}
 
-impl CollisionStrategy for GridCollisionStrategy {
-    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
-        if a.current_point == b.current_point {
-            return Some(CollisionEvent {
-                agent1_id: a.id,
-                agent2_id: b.id,
-                collision_type: CollisionType::Direct,
-                collision_point: a.current_point,
-            });
+impl ExperimentRun {
+    fn new(label: &str, scenario: &str) -> Self {
+        ExperimentRun {
+            id: Uuid::new_v4(),
+            label: label.to_string(),
+            scenario: scenario.to_string(),
+            started: Local::now(),
         }
+    }
 
-        let a_radius: HashSet<Node> = a.collision_radius.iter().copied().collect();
-
-        for &node in &b.collision_radius {
-            if a_radius.contains(&node) {
-                return Some(CollisionEvent {
-                    agent1_id: a.id,
-                    agent2_id: b.id,
-                    collision_type: CollisionType::Proximity,
-                    collision_point: node,
-                });
-            }
-        }
-
-        None
+    fn write_summary(&self, stats: &Statistics) -> Result<(), SimError> {
+        std::fs::create_dir_all("runs")?;
+        let mut wtr = Writer::from_path(format!("runs/{}.csv", self.id))?;
+        wtr.write_record([
+            "run_id",
+            "label",
+            "scenario",
+            "started",
+            "finished",
+            "method_name",
+            "agents",
+            "reached_goal_count",
+            "recalculations",
+            "collisions",
+            "detections",
+            "total_path_length",
+            "geometric_path_length",
+            "total_steps",
+            "actual_distance",
+            "deadlocks_resolved",
+            "capacity_conflicts",
+            "environment_replans",
+            "makespan",
+            "sum_of_costs",
+            "unresolved_conflicts",
+            "moves",
+            "waits",
+            "yields",
+            "min_separation",
+            "score",
+        ])?;
+        wtr.write_record(&[
+            self.id.to_string(),
+            self.label.clone(),
+            self.scenario.clone(),
+            self.started.to_string(),
+            Local::now().to_string(),
+            stats.method_name.to_string(),
+            stats.agents.to_string(),
+            stats.reached_goal_count.to_string(),
+            stats.recalculations.to_string(),
+            stats.collisions.to_string(),
+            stats.detections.to_string(),
+            stats.total_path_length.to_string(),
+            stats.geometric_path_length.to_string(),
+            stats.total_steps.to_string(),
+            stats.actual_distance.to_string(),
+            stats.deadlocks_resolved.to_string(),
+            stats.capacity_conflicts.to_string(),
+            stats.environment_replans.to_string(),
+            stats.makespan.to_string(),
+            stats.sum_of_costs.to_string(),
+            stats.unresolved_conflicts.to_string(),
+            stats.moves.to_string(),
+            stats.waits.to_string(),
+            stats.yields.to_string(),
+            stats
+                .min_separation
+                .map_or(String::new(), |gap| gap.to_string()),
+            stats.score.to_string(),
+        ])?;
+        wtr.flush()?;
+        Ok(())
     }
+}
 
-    fn name(&self) -> &str {
-        "Grid-based"
-    }
+struct AgentMetrics {
+    agent_id: usize,
+    path_length: usize,
+    geometric_length: f32,
+    steps_taken: usize,
+    reached_goal: bool,
 }
 
-struct OrcaCollisionStrategy;
+struct FinishedRun {
+    run: ExperimentRun,
+    stats: Statistics,
+    agents: Vec<AgentMetrics>,
+    deliveries: Vec<GoalArrival>,
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    events: Vec<EventRow>,
+}
 
-impl CollisionStrategy for OrcaCollisionStrategy {
-    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
-        let dist = a.position.distance(b.position);
-        let min_dist = a.radius + b.radius;
+#[derive(Clone, Debug, PartialEq)]
+struct GoalArrival {
+    tick: usize,
+    goal: Node,
+    agent_id: usize,
+    order: usize,
+}
 
-        if dist < min_dist {
-            Some(CollisionEvent {
-                agent1_id: a.id,
-                agent2_id: b.id,
-                collision_type: CollisionType::Direct,
-                collision_point: Node::from_pixels(a.position),
-            })
-        } else {
-            None
+// Agents that arrived this tick on a shared goal cell, numbered in arrival order per goal.
+fn record_goal_deliveries(
+    log: &mut Vec<GoalArrival>,
+    goals: &HashSet<Node>,
+    agents: &[Agent],
+    tick: usize,
+) {
+    for agent in agents.iter().filter(|a| a.arrival_tick == Some(tick)) {
+        let Some(goal) = agent.end_point.filter(|g| goals.contains(g)) else {
+            continue;
+        };
+        let order = log.iter().filter(|d| d.goal == goal).count() + 1;
+        log.push(GoalArrival {
+            tick,
+            goal,
+            agent_id: agent.id,
+            order,
+        });
+    }
+}
+
+// Deliveries at goal within the last THROUGHPUT_WINDOW ticks, or at every goal when None.
+fn goal_throughput(log: &[GoalArrival], goal: Option<Node>, tick: usize) -> usize {
+    log.iter()
+        .filter(|d| goal.is_none_or(|g| d.goal == g))
+        .filter(|d| d.tick + THROUGHPUT_WINDOW > tick)
+        .count()
+}
+
+// Agents still on the way cost the current tick, so the makespan is a lower bound until all arrive.
+fn mapf_metrics(stats: &mut Statistics, agents: &[Agent], tick: usize) {
+    let costs = agents.iter().map(|a| a.arrival_tick.unwrap_or(tick));
+    stats.makespan = costs.clone().max().unwrap_or(0);
+    stats.sum_of_costs = costs.sum();
+    stats.unresolved_conflicts = 0;
+    for (i, a) in agents.iter().enumerate() {
+        for b in &agents[i + 1..] {
+            if !(a.finished && b.finished) && first_conflict(a, b).is_some() {
+                stats.unresolved_conflicts += 1;
+            }
         }
     }
+}
 
-    fn name(&self) -> &str {
-        "ORCA"
+fn finish_run(state: &mut GameState, stats: &Statistics, agents: &[Agent]) {
+    let mut stats = stats.clone();
+    mapf_metrics(&mut stats, agents, state.grid.tick);
+    if !agents.is_empty() {
+        info!(
+            "Run {}: makespan {}, sum of costs {}, {} unresolved conflicts",
+            state.run.id, stats.makespan, stats.sum_of_costs, stats.unresolved_conflicts
+        );
     }
+    if let Some(gap) = stats.min_separation {
+        info!("Run {}: minimum separation {} cells", state.run.id, gap);
+    }
+    let run = std::mem::replace(&mut state.run, ExperimentRun::new(&state.label, "custom"));
+    let agents = agents
+        .iter()
+        .map(|a| AgentMetrics {
+            agent_id: a.id,
+            path_length: a.path.as_ref().map_or(0, |p| p.len()),
+            geometric_length: a
+                .path
+                .as_ref()
+                .map_or(0.0, |p| state.grid.geometric_length(p)),
+            steps_taken: a.path_index,
+            reached_goal: a.finished,
+        })
+        .collect();
+    let deliveries = std::mem::take(&mut state.goal_deliveries);
+    // The run takes its events along, so the next one starts with an empty log.
+    let events = state.recorder.events.take();
+    state.finished_runs.push(FinishedRun {
+        run,
+        stats,
+        agents,
+        deliveries,
+        events,
+    });
 }
 
-struct CollisionLogger;
+fn begin_run(state: &mut GameState, stats: &mut Statistics) {
+    stats.reset_run();
+    let scenario = state.scenario.as_ref().map_or("custom", |s| s.name());
+    let run = ExperimentRun::new(&state.label, scenario);
+    info!("Started run {} ({})", run.id, run.scenario);
+    state.run = run;
+}
 
-impl CollisionObserver for CollisionLogger {
-    fn on_collision(&self, event: &CollisionEvent) {
-        match event.collision_type {
-            CollisionType::Direct => {
-                println!(
-                    "DIRECT COLLISION: agents {} and {} at ({}, {})",
-                    event.agent1_id,
-                    event.agent2_id,
-                    event.collision_point.x,
-                    event.collision_point.y,
-                );
-            }
-            CollisionType::Proximity => {}
-        }
+fn write_agent_metrics(finished: &FinishedRun) -> Result<(), SimError> {
+    let mut wtr = Writer::from_path(format!("runs/{}_agents.csv", finished.run.id))?;
+    wtr.write_record([
+        "agent_id",
+        "path_length",
+        "geometric_length",
+        "steps_taken",
+        "reached_goal",
+    ])?;
+    for a in &finished.agents {
+        wtr.write_record(&[
+            a.agent_id.to_string(),
+            a.path_length.to_string(),
+            a.geometric_length.to_string(),
+            a.steps_taken.to_string(),
+            a.reached_goal.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_goal_deliveries(finished: &FinishedRun) -> Result<(), SimError> {
+    if finished.deliveries.is_empty() {
+        return Ok(());
+    }
+    let mut wtr = Writer::from_path(format!("runs/{}_goals.csv", finished.run.id))?;
+    wtr.write_record(["tick", "goal_x", "goal_y", "agent_id", "order"])?;
+    for d in &finished.deliveries {
+        wtr.write_record(&[
+            d.tick.to_string(),
+            d.goal.x.to_string(),
+            d.goal.y.to_string(),
+            d.agent_id.to_string(),
+            d.order.to_string(),
+        ])?;
     }
+    wtr.flush()?;
+    Ok(())
 }
 
-struct CollisionAssistant {
-    requests: RefCell<Vec<RerouteRequest>>,
+trait StatsSink {
+    fn record_sample(&mut self, stats: &Statistics, run: &ExperimentRun);
+    fn finish_run(&mut self, finished: &FinishedRun);
+    fn close(&mut self) {}
 }
 
-#[derive(Debug, Clone)]
-struct RerouteRequest {
-    agent_id: usize,
-    avoid_point: Node,
+const STATS_CHANNEL_CAPACITY: usize = 64;
+
+struct StatsRecord {
+    timestamp: DateTime<Local>,
+    run_id: Uuid,
+    label: String,
+    scenario: String,
+    stats: Statistics,
 }
 
-impl CollisionAssistant {
-    fn new() -> Self {
-        CollisionAssistant {
-            requests: RefCell::new(Vec::new()),
+impl StatsRecord {
+    fn sample(&self) -> Sample {
+        let stats = &self.stats;
+        Sample::new(self.timestamp)
+            .with("run_id", self.run_id.to_string())
+            .with("label", self.label.as_str())
+            .with("scenario", self.scenario.as_str())
+            .with("recalculations", stats.recalculations)
+            .with("collisions", stats.collisions)
+            .with("detections", stats.detections)
+            .with("total_path_length", stats.total_path_length)
+            .with("geometric_path_length", stats.geometric_path_length)
+            .with("total_steps", stats.total_steps)
+            .with("agents", stats.agents)
+            .with("method_name", stats.method_name.to_string())
+            .with("actual_distance", stats.actual_distance)
+            .with("reached_goal_count", stats.reached_goal_count)
+            .with("deadlocks_resolved", stats.deadlocks_resolved)
+            .with("capacity_conflicts", stats.capacity_conflicts)
+            .with("suppressed_reroutes", stats.suppressed_reroutes)
+            .with("light_waits", stats.light_waits)
+            .with("light_crossings", stats.light_crossings)
+            .with("comm_radius", stats.comm_radius)
+            .with("knowledge_replans", stats.knowledge_replans)
+            .with("environment_replans", stats.environment_replans)
+            .with("goal_deliveries", stats.goal_deliveries)
+            .with("goal_throughput", stats.goal_throughput)
+            .with("moves", stats.moves)
+            .with("waits", stats.waits)
+            .with("yields", stats.yields)
+            .with("separation_waits", stats.separation_waits)
+            .with("min_separation", stats.min_separation.unwrap_or(-1))
+            .with("score", stats.score)
+            .with("input_ms", stats.frame_ms[0])
+            .with("simulation_ms", stats.frame_ms[1])
+            .with("render_ms", stats.frame_ms[2])
+            .with("collision_ms", stats.frame_ms[3])
+            .with("planning_ms", stats.frame_ms[4])
+    }
+}
+
+struct StatsWriter {
+    sender: Option<SyncSender<StatsRecord>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsWriter {
+    fn spawn(path: &str) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(STATS_CHANNEL_CAPACITY);
+        let path = path.to_string();
+        let handle = thread::spawn(move || {
+            if let Err(e) = write_statistics(&path, receiver) {
+                error!("Stats writer stopped: {}", e);
+            }
+        });
+        StatsWriter {
+            sender: Some(sender),
+            handle: Some(handle),
         }
     }
 
-    fn take_requests(&self) -> Vec<RerouteRequest> {
-        std::mem::take(&mut *self.requests.borrow_mut())
+    fn record(&self, stats: &Statistics, run: &ExperimentRun) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let record = StatsRecord {
+            timestamp: Local::now(),
+            run_id: run.id,
+            label: run.label.clone(),
+            scenario: run.scenario.clone(),
+            stats: stats.clone(),
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(record) {
+            warn!("Stats writer is behind, dropping a sample");
+        }
     }
 
-    fn has_requests(&self) -> bool {
-        !self.requests.borrow().is_empty()
+    fn shutdown(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
     }
 }
 
-impl CollisionObserver for CollisionAssistant {
-    fn on_collision(&self, event: &CollisionEvent) {
-        if let CollisionType::Proximity = event.collision_type {
-            let mut reqs = self.requests.borrow_mut();
-            reqs.push(RerouteRequest {
-                agent_id: event.agent1_id,
-                avoid_point: event.collision_point,
-            });
-            reqs.push(RerouteRequest {
-                agent_id: event.agent2_id,
-                avoid_point: event.collision_point,
-            });
+impl StatsSink for StatsWriter {
+    fn record_sample(&mut self, stats: &Statistics, run: &ExperimentRun) {
+        self.record(stats, run);
+    }
+
+    fn finish_run(&mut self, finished: &FinishedRun) {
+        if let Err(e) = finished
+            .run
+            .write_summary(&finished.stats)
+            .and_then(|_| write_agent_metrics(finished))
+            .and_then(|_| write_goal_deliveries(finished))
+        {
+            error!("Failed to write run summary: {}", e);
         }
     }
+
+    fn close(&mut self) {
+        self.shutdown();
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-struct State {
-    cost: i32,
-    position: Node,
+#[cfg(feature = "sqlite")]
+struct SqliteSink {
+    conn: rusqlite::Connection,
 }
 
-impl Ord for State {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.cost.cmp(&self.cost)
+#[cfg(feature = "sqlite")]
+impl SqliteSink {
+    fn open(path: &str) -> Result<Self, SimError> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS runs (
+                id TEXT PRIMARY KEY,
+                label TEXT NOT NULL,
+                scenario TEXT NOT NULL,
+                started TEXT NOT NULL,
+                finished TEXT,
+                method_name TEXT,
+                agents INTEGER,
+                reached_goal_count INTEGER,
+                recalculations INTEGER,
+                collisions INTEGER,
+                detections INTEGER,
+                total_path_length INTEGER,
+                total_steps INTEGER,
+                actual_distance REAL,
+                deadlocks_resolved INTEGER,
+                capacity_conflicts INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS samples (
+                run_id TEXT NOT NULL REFERENCES runs(id),
+                timestamp TEXT NOT NULL,
+                recalculations INTEGER NOT NULL,
+                collisions INTEGER NOT NULL,
+                detections INTEGER NOT NULL,
+                total_steps INTEGER NOT NULL,
+                reached_goal_count INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS events (
+                run_id TEXT NOT NULL REFERENCES runs(id),
+                tick INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                agent1_id INTEGER NOT NULL,
+                agent2_id INTEGER NOT NULL,
+                cell_x INTEGER NOT NULL,
+                cell_y INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS agent_metrics (
+                run_id TEXT NOT NULL REFERENCES runs(id),
+                agent_id INTEGER NOT NULL,
+                path_length INTEGER NOT NULL,
+                steps_taken INTEGER NOT NULL,
+                reached_goal INTEGER NOT NULL,
+                PRIMARY KEY (run_id, agent_id)
+            );",
+        )?;
+        Ok(SqliteSink { conn })
     }
-}
-impl PartialOrd for State {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+    fn ensure_run(&self, run: &ExperimentRun) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO runs (id, label, scenario, started) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                run.id.to_string(),
+                run.label,
+                run.scenario,
+                run.started.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn write_run(&mut self, finished: &FinishedRun) -> rusqlite::Result<()> {
+        let run = &finished.run;
+        let stats = &finished.stats;
+        let id = run.id.to_string();
+        self.ensure_run(run)?;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "UPDATE runs SET finished = ?2, method_name = ?3, agents = ?4,
+                reached_goal_count = ?5, recalculations = ?6, collisions = ?7,
+                detections = ?8, total_path_length = ?9, total_steps = ?10,
+                actual_distance = ?11, deadlocks_resolved = ?12, capacity_conflicts = ?13
+             WHERE id = ?1",
+            rusqlite::params![
+                id,
+                Local::now().to_string(),
+                stats.method_name,
+                stats.agents as i64,
+                stats.reached_goal_count as i64,
+                stats.recalculations as i64,
+                stats.collisions as i64,
+                stats.detections as i64,
+                stats.total_path_length as i64,
+                stats.total_steps as i64,
+                stats.actual_distance,
+                stats.deadlocks_resolved as i64,
+                stats.capacity_conflicts as i64,
+            ],
+        )?;
+        for e in &finished.events {
+            tx.execute(
+                "INSERT INTO events VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![
+                    id,
+                    e.tick as i64,
+                    e.kind,
+                    e.agent1_id as i64,
+                    e.agent2_id as i64,
+                    e.cell.x,
+                    e.cell.y
+                ],
+            )?;
+        }
+        for a in &finished.agents {
+            tx.execute(
+                "INSERT OR REPLACE INTO agent_metrics VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![
+                    id,
+                    a.agent_id as i64,
+                    a.path_length as i64,
+                    a.steps_taken as i64,
+                    a.reached_goal
+                ],
+            )?;
+        }
+        tx.commit()
     }
 }
 
-fn heuristic(a: Node, b: Node) -> i32 {
-    (a.x - b.x).abs() + (a.y - b.y).abs()
+#[cfg(feature = "sqlite")]
+impl StatsSink for SqliteSink {
+    fn record_sample(&mut self, stats: &Statistics, run: &ExperimentRun) {
+        let result = self.ensure_run(run).and_then(|_| {
+            self.conn.execute(
+                "INSERT INTO samples VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![
+                    run.id.to_string(),
+                    Local::now().to_string(),
+                    stats.recalculations as i64,
+                    stats.collisions as i64,
+                    stats.detections as i64,
+                    stats.total_steps as i64,
+                    stats.reached_goal_count as i64,
+                ],
+            )
+        });
+        if let Err(e) = result {
+            error!("Failed to store stats sample: {}", e);
+        }
+    }
+
+    fn finish_run(&mut self, finished: &FinishedRun) {
+        if let Err(e) = self.write_run(finished) {
+            error!("Failed to store run {}: {}", finished.run.id, e);
+        }
+    }
 }
 
-fn a_star(
-    start: Node,
-    goal: Node,
-    walls: &HashSet<Node>,
-    movement: &dyn MovementStrategy,
-) -> Option<Vec<Node>> {
-    a_star_inner(start, goal, walls, &HashSet::new(), None, movement)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SinkKind {
+    Csv,
+    Sqlite,
 }
 
-fn a_star_with_avoidance(
-    start: Node,
-    goal: Node,
-    walls: &HashSet<Node>,
-    avoid: &HashSet<Node>,
-    preferred_dir: Option<Node>,
-    movement: &dyn MovementStrategy,
-) -> Option<Vec<Node>> {
-    a_star_inner(start, goal, walls, avoid, preferred_dir, movement)
+fn open_stats_sink(kind: SinkKind, suffix: &str) -> Result<Box<dyn StatsSink>, SimError> {
+    match kind {
+        SinkKind::Csv => Ok(Box::new(StatsWriter::spawn(&format!("stats{suffix}.csv")))),
+        #[cfg(feature = "sqlite")]
+        SinkKind::Sqlite => Ok(Box::new(SqliteSink::open(&format!(
+            "stats{suffix}.sqlite"
+        ))?)),
+        #[cfg(not(feature = "sqlite"))]
+        SinkKind::Sqlite => Err(SimError::FeatureDisabled("sqlite")),
+    }
 }
 
-fn a_star_inner(
-    start: Node,
-    goal: Node,
-    walls: &HashSet<Node>,
-    avoid: &HashSet<Node>,
-    preferred_dir: Option<Node>,
-    movement: &dyn MovementStrategy,
-) -> Option<Vec<Node>> {
-    let mut open = BinaryHeap::new();
-    let mut came_from = HashMap::new();
-    let mut g_score: HashMap<Node, i32> = HashMap::new();
+impl Drop for StatsWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
 
-    g_score.insert(start, 0);
-    open.push(State {
-        cost: heuristic(start, goal),
-        position: start,
-    });
+fn write_statistics(path: &str, receiver: Receiver<StatsRecord>) -> Result<(), SimError> {
+    let mut sink = CsvSink::new(path);
 
-    while let Some(State { position, .. }) = open.pop() {
-        if position == goal {
-            let mut path = vec![position];
-            let mut cur = position;
-            while let Some(&prev) = came_from.get(&cur) {
-                path.push(prev);
-                cur = prev;
-            }
-            path.reverse();
-            return Some(path);
+    while let Ok(first) = receiver.recv() {
+        for record in std::iter::once(first).chain(receiver.try_iter()) {
+            sink.write(&record.sample())?;
         }
+    }
+    Ok(())
+}
 
-        let base_g = *g_score.get(&position).unwrap_or(&i32::MAX);
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+struct TrajectoryRow {
+    tick: usize,
+    agent_id: usize,
+    cell: Node,
+    position: Vec2,
+    finished: bool,
+}
 
-        for neighbor in movement.get_neighbors(position) {
-            if walls.contains(&neighbor) || avoid.contains(&neighbor) {
-                continue;
-            }
+#[cfg_attr(not(any(feature = "parquet", feature = "sqlite")), allow(dead_code))]
+struct EventRow {
+    tick: usize,
+    kind: &'static str,
+    agent1_id: usize,
+    agent2_id: usize,
+    cell: Node,
+}
 
-            let mut tentative_g = base_g.saturating_add(1);
+struct RunRecorder {
+    tick: Cell<usize>,
+    trajectories: RefCell<Vec<TrajectoryRow>>,
+    events: RefCell<Vec<EventRow>>,
+}
 
-            if let Some(pref) = preferred_dir {
-                let mv = move_dir(position, neighbor);
-                if mv == pref {
-                    tentative_g -= 4;
-                } else if mv == negate(pref) {
-                    tentative_g += 8;
-                } else if dot(mv, pref) == 0 {
-                    tentative_g -= 1;
-                }
-            }
+impl RunRecorder {
+    fn new() -> Self {
+        RunRecorder {
+            tick: Cell::new(0),
+            trajectories: RefCell::new(Vec::new()),
+            events: RefCell::new(Vec::new()),
+        }
+    }
 
-            if tentative_g < *g_score.get(&neighbor).unwrap_or(&i32::MAX) {
-                came_from.insert(neighbor, position);
-                g_score.insert(neighbor, tentative_g);
-                open.push(State {
-                    cost: tentative_g + heuristic(neighbor, goal),
-                    position: neighbor,
-                });
-            }
+    fn record_tick(&self, tick: usize, agents: &[Agent]) {
+        if tick <= self.tick.get() {
+            self.trajectories.borrow_mut().retain(|r| r.tick < tick);
+            self.events.borrow_mut().retain(|r| r.tick < tick);
         }
+        self.tick.set(tick);
+        self.trajectories
+            .borrow_mut()
+            .extend(agents.iter().map(|a| TrajectoryRow {
+                tick,
+                agent_id: a.id,
+                cell: a.current_point,
+                position: a.position,
+                finished: a.finished,
+            }));
     }
-    None
 }
 
-fn process_reroute_requests(
-    agents: &mut [Agent],
-    requests: &[RerouteRequest],
-    walls: &HashSet<Node>,
-    movement: &dyn MovementStrategy,
-    stats: &mut Statistics,
-) {
-    let mut by_point: HashMap<Node, Vec<usize>> = HashMap::new();
-    for req in requests {
-        by_point
-            .entry(req.avoid_point)
-            .or_default()
-            .push(req.agent_id);
+impl Subscriber for RunRecorder {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        let SimEvent::Collision(event) = event else {
+            return;
+        };
+        self.events.borrow_mut().push(EventRow {
+            tick: self.tick.get(),
+            kind: match event.collision_type {
+                CollisionType::Direct => "direct",
+                CollisionType::Proximity => "proximity",
+            },
+            agent1_id: event.agent1_id,
+            agent2_id: event.agent2_id,
+            cell: event.collision_point,
+        });
     }
 
-    for (collision_point, agent_ids) in &by_point {
-        let per_agent = compute_avoidance_plan(agents, agent_ids, *collision_point);
-
-        for (agent_id, avoid_set, pref_dir) in per_agent {
-            let agent = &agents[agent_id];
-            let Some(goal) = agent.end_point else {
-                continue;
-            };
-
-            let pref = if is_zero_dir(pref_dir) {
-                None
-            } else {
-                Some(pref_dir)
-            };
-
-            if let Some(new_path) =
-                a_star_with_avoidance(agent.current_point, goal, walls, &avoid_set, pref, movement)
-            {
-                stats.recalculations += 1;
-                let agent = &mut agents[agent_id];
-                agent.path = Some(new_path);
-                agent.path_index = 0;
-                agent.refresh_cache();
-            }
-        }
+    fn id(&self) -> &str {
+        "recorder"
     }
 }
 
-fn compute_avoidance_plan(
-    agents: &[Agent],
-    agent_ids: &[usize],
-    collision_point: Node,
-) -> Vec<(usize, HashSet<Node>, Node)> {
-    let dirs: Vec<(usize, Node)> = agent_ids
-        .iter()
-        .filter_map(|&id| {
-            let agent = &agents[id];
-            let d = agent.direction();
-            let final_dir = if is_zero_dir(d) {
-                agent
-                    .end_point
-                    .map(|g| Node {
-                        x: (g.x - agent.current_point.x).signum(),
-                        y: (g.y - agent.current_point.y).signum(),
-                    })
-                    .unwrap_or(d)
-            } else {
-                d
-            };
-            Some((id, final_dir))
-        })
-        .collect();
+#[cfg(feature = "parquet")]
+fn export_parquet(recorder: &RunRecorder, suffix: &str) -> Result<(), SimError> {
+    use arrow_array::{
+        ArrayRef, BooleanArray, Float32Array, Int32Array, RecordBatch, StringArray, UInt32Array,
+        UInt64Array,
+    };
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+
+    fn write(path: &str, schema: Schema, columns: Vec<ArrayRef>) -> Result<(), SimError> {
+        let schema = Arc::new(schema);
+        let batch = RecordBatch::try_new(schema.clone(), columns)?;
+        let mut writer = ArrowWriter::try_new(std::fs::File::create(path)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
 
-    let mut plan = Vec::with_capacity(dirs.len());
+    let rows = recorder.trajectories.borrow();
+    write(
+        &format!("trajectories{suffix}.parquet"),
+        Schema::new(vec![
+            Field::new("tick", DataType::UInt64, false),
+            Field::new("agent_id", DataType::UInt32, false),
+            Field::new("cell_x", DataType::Int32, false),
+            Field::new("cell_y", DataType::Int32, false),
+            Field::new("pos_x", DataType::Float32, false),
+            Field::new("pos_y", DataType::Float32, false),
+            Field::new("finished", DataType::Boolean, false),
+        ]),
+        vec![
+            Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|r| r.tick as u64),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                rows.iter().map(|r| r.agent_id as u32),
+            )),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.cell.x))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.cell.y))),
+            Arc::new(Float32Array::from_iter_values(
+                rows.iter().map(|r| r.position.x),
+            )),
+            Arc::new(Float32Array::from_iter_values(
+                rows.iter().map(|r| r.position.y),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|r| Some(r.finished)),
+            )),
+        ],
+    )?;
+
+    let rows = recorder.events.borrow();
+    write(
+        &format!("events{suffix}.parquet"),
+        Schema::new(vec![
+            Field::new("tick", DataType::UInt64, false),
+            Field::new("kind", DataType::Utf8, false),
+            Field::new("agent1_id", DataType::UInt32, false),
+            Field::new("agent2_id", DataType::UInt32, false),
+            Field::new("cell_x", DataType::Int32, false),
+            Field::new("cell_y", DataType::Int32, false),
+        ]),
+        vec![
+            Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|r| r.tick as u64),
+            )),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.kind))),
+            Arc::new(UInt32Array::from_iter_values(
+                rows.iter().map(|r| r.agent1_id as u32),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                rows.iter().map(|r| r.agent2_id as u32),
+            )),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.cell.x))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.cell.y))),
+        ],
+    )?;
+    Ok(())
+}
 
-    if dirs.len() >= 2 {
-        let (a_id, a_dir) = dirs[0];
-        let (b_id, _b_dir) = dirs[1];
+#[cfg(not(feature = "parquet"))]
+fn export_parquet(_recorder: &RunRecorder, _suffix: &str) -> Result<(), SimError> {
+    Err(SimError::FeatureDisabled("parquet"))
+}
 
-        let axis = rotate_right(a_dir);
+struct LineParams {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    color: u32,
+    thickness: usize,
+}
+// `phase` carries the dash pattern over from the previous segment of a polyline.
+struct DashedLineParams {
+    line: LineParams,
+    dash: usize,
+    gap: usize,
+    phase: usize,
+}
+struct TriangleParams {
+    points: [Vec2; 3],
+    color: u32,
+}
+struct SquareParams {
+    cell: CellCoord,
+    color: u32,
+}
+struct CircleParams {
+    center: PixelCoord,
+    radius: usize,
+    color: u32,
+}
 
-        let (steer_a, steer_b) = if a_id <= b_id {
-            (axis, negate(axis))
-        } else {
-            (negate(axis), axis)
-        };
+struct RectParams {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    color: u32,
+}
+struct PolygonParams {
+    points: Vec<Vec2>,
+    color: u32,
+    filled: bool,
+}
+struct TextParams {
+    x: usize,
+    y: usize,
+    text: String,
+    scale: usize,
+    color: u32,
+}
 
-        plan.push(make_avoid_entry(a_id, collision_point, steer_a));
-        plan.push(make_avoid_entry(b_id, collision_point, steer_b));
+// Straight RGBA with alpha in the top byte, already scaled to the size it is drawn at.
+struct Sprite {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+struct BlendCircleParams {
+    center: PixelCoord,
+    radius: usize,
+    color: u32,
+    alpha: f32,
+}
+struct SpriteParams {
+    center: PixelCoord,
+    sprite: Rc<Sprite>,
+    tint: Option<u32>,
+}
 
-        for &(id, dir) in &dirs[2..] {
-            plan.push(make_avoid_entry(id, collision_point, rotate_right(dir)));
-        }
-    } else {
-        for &(id, _) in &dirs {
-            let mut avoid = HashSet::new();
-            avoid.insert(collision_point);
-            plan.push((id, avoid, Node { x: 0, y: 0 }));
+enum DrawType {
+    Triangle(TriangleParams),
+    DashedLine(DashedLineParams),
+    Sprite(SpriteParams),
+    BlendCircle(BlendCircleParams),
+    Line(LineParams),
+    Square(SquareParams),
+    Circle(CircleParams),
+    Rect(RectParams),
+    Text(TextParams),
+    Polygon(PolygonParams),
+}
+
+fn draw(buffer: &mut [u32], item: &DrawType) {
+    match item {
+        DrawType::Line(p) => draw_line(buffer, p),
+        DrawType::Square(p) => draw_square(buffer, p),
+        DrawType::Circle(p) => draw_circle(buffer, p),
+        DrawType::Rect(p) => draw_rect(buffer, p),
+        DrawType::Text(p) => draw_text(buffer, p),
+        DrawType::Polygon(p) => draw_polygon(buffer, p),
+        DrawType::Sprite(p) => draw_sprite(buffer, p),
+        DrawType::DashedLine(p) => draw_dashed_line(buffer, p),
+        DrawType::Triangle(p) => draw_triangle(buffer, p),
+        DrawType::BlendCircle(p) => {
+            blend_circle_at_pixels(buffer, p.center, p.radius, p.color, p.alpha)
         }
     }
+}
 
-    plan
+// Channel-wise multiply, so white areas of a sprite take the tint and grey ones darken it.
+fn tint(color: u32, tint: u32) -> u32 {
+    let channel = |shift: u32| ((color >> shift) & 0xFF) * ((tint >> shift) & 0xFF) / 255;
+    (channel(16) << 16) | (channel(8) << 8) | channel(0)
 }
 
-fn make_avoid_entry(
-    id: usize,
-    collision_point: Node,
-    avoid_dir: Node,
-) -> (usize, HashSet<Node>, Node) {
-    let mut avoid = HashSet::new();
-    avoid.insert(collision_point);
-    let nudge = Node {
-        x: collision_point.x + avoid_dir.x,
-        y: collision_point.y + avoid_dir.y,
-    };
-    if in_bounds(nudge) {
-        avoid.insert(nudge);
+fn draw_sprite(buffer: &mut [u32], p: &SpriteParams) {
+    let sprite = &p.sprite;
+    let left = p.center.x as isize - (sprite.width / 2) as isize;
+    let top = p.center.y as isize - (sprite.height / 2) as isize;
+    for sy in 0..sprite.height {
+        let y = top + sy as isize;
+        if y < 0 || y >= HEIGHT as isize {
+            continue;
+        }
+        for sx in 0..sprite.width {
+            let x = left + sx as isize;
+            if x < 0 || x >= WIDTH as isize {
+                continue;
+            }
+            let texel = sprite.pixels[sy * sprite.width + sx];
+            let alpha = texel >> 24;
+            if alpha == 0 {
+                continue;
+            }
+            let color = match p.tint {
+                Some(t) => tint(texel & 0x00FFFFFF, t),
+                None => texel & 0x00FFFFFF,
+            };
+            let pixel = &mut buffer[y as usize * WIDTH + x as usize];
+            *pixel = if alpha == 0xFF {
+                color
+            } else {
+                blend(color, *pixel, alpha as f32 / 255.0)
+            };
+        }
     }
-    (id, avoid, avoid_dir)
 }
 
-#[derive(Eq, PartialEq)]
-enum Step {
-    Obstacles,
-    Start,
-    End,
+fn draw_polygon(buffer: &mut [u32], p: &PolygonParams) {
+    let n = p.points.len();
+    if n < 2 {
+        return;
+    }
+    if p.filled && n >= 3 {
+        let min_y = p.points.iter().map(|v| v.y).fold(f32::MAX, f32::min);
+        let max_y = p.points.iter().map(|v| v.y).fold(f32::MIN, f32::max);
+        let y_start = min_y.max(0.0).ceil() as usize;
+        let y_end = max_y.min(HEIGHT as f32 - 1.0).floor().max(0.0) as usize;
+        for y in y_start..=y_end {
+            let scan = y as f32 + 0.5;
+            let mut xs: Vec<f32> = (0..n)
+                .filter_map(|i| {
+                    let (a, b) = (p.points[i], p.points[(i + 1) % n]);
+                    ((a.y <= scan) != (b.y <= scan))
+                        .then(|| a.x + (scan - a.y) / (b.y - a.y) * (b.x - a.x))
+                })
+                .collect();
+            xs.sort_by(f32::total_cmp);
+            for pair in xs.chunks_exact(2) {
+                let x0 = pair[0].max(0.0).round() as usize;
+                let x1 = (pair[1].round().max(0.0) as usize).min(WIDTH);
+                if x0 < x1 {
+                    buffer[y * WIDTH + x0..y * WIDTH + x1].fill(p.color);
+                }
+            }
+        }
+    }
+    for i in 0..n {
+        let (a, b) = (p.points[i], p.points[(i + 1) % n]);
+        draw_line(
+            buffer,
+            &LineParams {
+                x0: a.x.round() as i32,
+                y0: a.y.round() as i32,
+                x1: b.x.round() as i32,
+                y1: b.y.round() as i32,
+                color: p.color,
+                thickness: 1,
+            },
+        );
+    }
 }
 
-struct GameState {
-    was_pressed: bool,
-    current_step: Step,
-    walls: HashSet<Node>,
-    movement_strategy: Box<dyn MovementStrategy>,
-    step_history: Vec<Vec<Node>>,
+// Fills the pixels whose centres lie inside the triangle (edges included), for either winding.
+fn draw_triangle(buffer: &mut [u32], p: &TriangleParams) {
+    let [a, b, c] = p.points;
+    let edge = |p0: Vec2, p1: Vec2, q: Vec2| (p1 - p0).perp_dot(q - p0);
+    let area = edge(a, b, c);
+    if area == 0.0 {
+        return;
+    }
+    let min = a.min(b).min(c).max(Vec2::ZERO);
+    let max = a
+        .max(b)
+        .max(c)
+        .min(Vec2::new(WIDTH as f32 - 1.0, HEIGHT as f32 - 1.0));
+    if min.x > max.x || min.y > max.y {
+        return;
+    }
+    for y in min.y.floor() as usize..=max.y.ceil() as usize {
+        for x in min.x.floor() as usize..=max.x.ceil() as usize {
+            let q = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+            let inside = [edge(a, b, q), edge(b, c, q), edge(c, a, q)]
+                .iter()
+                .all(|&w| w * area.signum() >= 0.0);
+            if inside && x < WIDTH && y < HEIGHT {
+                buffer[y * WIDTH + x] = p.color;
+            }
+        }
+    }
 }
 
-struct InitContext {
-    window: Option<Window>,
-    buffer: Option<Vec<u32>>,
-    game_state: Option<GameState>,
+// An arrowhead with its point at `tip`, facing `dir` (normalized).
+fn arrowhead(tip: Vec2, dir: Vec2, length: f32, color: u32) -> DrawType {
+    let base = tip - dir * length;
+    let side = Vec2::new(-dir.y, dir.x) * length * 0.6;
+    DrawType::Triangle(TriangleParams {
+        points: [tip, base + side, base - side],
+        color,
+    })
 }
 
-trait InitHandler {
-    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), String>;
+fn draw_rect(buffer: &mut [u32], p: &RectParams) {
+    let x_end = (p.x + p.width).min(WIDTH);
+    if p.x >= x_end {
+        return;
+    }
+    for y in p.y..(p.y + p.height).min(HEIGHT) {
+        buffer[y * WIDTH + p.x..y * WIDTH + x_end].fill(p.color);
+    }
 }
 
-struct WindowInitHandler;
-struct BufferInitHandler;
-struct GameStateInitHandler;
-
-impl InitHandler for WindowInitHandler {
-    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), String> {
-        ctx.window = Some(
-            Window::new(
-                "Navigation grid - Hybrid",
-                WIDTH,
-                HEIGHT,
-                WindowOptions::default(),
-            )
-            .map_err(|e| format!("Window creation failed: {:?}", e))?,
-        );
-        Ok(())
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ' ' => [0x00; GLYPH_HEIGHT],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '[' => [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E],
+        ']' => [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '*' => [0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '\'' => [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '"' => [0x0A, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
     }
 }
 
-impl InitHandler for BufferInitHandler {
-    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), String> {
-        ctx.buffer = Some(vec![0; WIDTH * HEIGHT]);
-        Ok(())
-    }
+fn text_width(text: &str, scale: usize) -> usize {
+    text.chars().count() * (GLYPH_WIDTH + 1) * scale
 }
 
-impl InitHandler for GameStateInitHandler {
-    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), String> {
-        ctx.game_state = Some(GameState {
-            was_pressed: false,
-            current_step: Step::Obstacles,
-            walls: HashSet::new(),
-            movement_strategy: Box::new(OrthogonalMovement),
-            step_history: Vec::new(),
-        });
-        Ok(())
+fn draw_text(buffer: &mut [u32], p: &TextParams) {
+    for (i, c) in p.text.chars().enumerate() {
+        let origin_x = p.x + i * (GLYPH_WIDTH + 1) * p.scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                draw_rect(
+                    buffer,
+                    &RectParams {
+                        x: origin_x + col * p.scale,
+                        y: p.y + row * p.scale,
+                        width: p.scale,
+                        height: p.scale,
+                        color: p.color,
+                    },
+                );
+            }
+        }
     }
 }
 
-fn draw_circle_at_pixels(buffer: &mut [u32], cx: usize, cy: usize, radius: usize, color: u32) {
-    let r2 = (radius * radius) as isize;
+fn draw_line(buffer: &mut [u32], p: &LineParams) {
+    rasterize_line(buffer, p, |_| true);
+}
+
+fn draw_dashed_line(buffer: &mut [u32], p: &DashedLineParams) {
+    let period = (p.dash + p.gap).max(1);
+    rasterize_line(buffer, &p.line, |step| (step + p.phase) % period < p.dash);
+}
+
+// Pixels along a line, for dash patterns.
+fn line_steps(p: &LineParams) -> usize {
+    (p.x1 - p.x0)
+        .unsigned_abs()
+        .max((p.y1 - p.y0).unsigned_abs()) as usize
+}
+
+// Bresenham; `visible` is asked about every step so patterns can skip some of them.
+fn rasterize_line(buffer: &mut [u32], p: &LineParams, visible: impl Fn(usize) -> bool) {
+    let (mut x0, mut y0) = (p.x0, p.y0);
+    let (x1, y1) = (p.x1, p.y1);
+    let dx = (x1 - x0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let dy = -(y1 - y0).abs();
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
 
-    for y in cy.saturating_sub(radius)..=(cy + radius).min(HEIGHT - 1) {
-        let dy = y as isize - cy as isize;
-        for x in cx.saturating_sub(radius)..=(cx + radius).min(WIDTH - 1) {
-            let dx = x as isize - cx as isize;
-            if dx * dx + dy * dy <= r2 {
-                buffer[y * WIDTH + x] = color;
+    // Thick lines stamp a square brush centred on each Bresenham pixel.
+    let half = (p.thickness.max(1) / 2) as i32;
+    let span = p.thickness.max(1) as i32;
+    for step in 0.. {
+        if visible(step) {
+            for by in y0 - half..y0 - half + span {
+                for bx in x0 - half..x0 - half + span {
+                    if bx >= 0 && by >= 0 && (bx as usize) < WIDTH && (by as usize) < HEIGHT {
+                        buffer[by as usize * WIDTH + bx as usize] = p.color;
+                    }
+                }
             }
         }
+        if x0 == x1 && y0 == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x0 += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y0 += sy;
+        }
     }
 }
 
-fn handle_input(
-    window: &Window,
-    state: &mut GameState,
-    agents: &mut Vec<Agent>,
-    history: &mut CommandHistory,
-    collision_detector: &mut CollisionDetector,
-    stats: &mut Statistics,
-) {
-    if window.is_key_pressed(Key::Space, minifb::KeyRepeat::No) {
-        state.current_step = Step::Start;
+fn draw_circle(buffer: &mut [u32], p: &CircleParams) {
+    draw_circle_at_pixels(buffer, p.center, p.radius, p.color);
+}
+
+fn draw_square(buffer: &mut [u32], p: &SquareParams) {
+    let corner = p.cell.top_left();
+    for y in corner.y..(corner.y + CELL_HEIGHT).min(HEIGHT) {
+        let row_start = y * WIDTH;
+        buffer[row_start + corner.x..row_start + (corner.x + CELL_WIDTH).min(WIDTH)].fill(p.color);
     }
-    if window.is_key_pressed(Key::O, minifb::KeyRepeat::No) {
-        state.current_step = Step::Obstacles;
+}
+
+fn draw_matrix(buffer: &mut [u32], color: u32) {
+    for i in 1..COLUMNS {
+        let px = Col(i).left();
+        draw(
+            buffer,
+            &DrawType::Line(LineParams {
+                x0: px as i32,
+                y0: 0,
+                x1: px as i32,
+                y1: HEIGHT as i32,
+                color,
+                thickness: 1,
+            }),
+        );
     }
-    if window.is_key_pressed(Key::M, minifb::KeyRepeat::No) {
-        state.movement_strategy = if state.movement_strategy.name() == "Orthogonal" {
-            Box::new(DiagonalMovement)
-        } else {
-            Box::new(OrthogonalMovement)
-        };
+    for i in 1..ROWS {
+        let py = Row(i).top();
+        draw(
+            buffer,
+            &DrawType::Line(LineParams {
+                x0: 0,
+                y0: py as i32,
+                x1: WIDTH as i32,
+                y1: py as i32,
+                color,
+                thickness: 1,
+            }),
+        );
     }
+}
 
-    if window.is_key_pressed(Key::C, minifb::KeyRepeat::No) {
-        let current = collision_detector.strategy.name();
-        collision_detector.set_strategy(match current {
-            "Path-based" => Box::new(GridCollisionStrategy),
-            "Grid-based" => Box::new(OrcaCollisionStrategy),
-            _ => Box::new(PathCollisionStrategy),
-        });
+trait MovementStrategy {
+    fn get_neighbors(&self, node: Node) -> Vec<Node>;
+    fn name(&self) -> &str;
 
-        stats.collisions = 0;
-        stats.detections = 0;
-        stats.recalculations = 0;
-        stats.total_steps = 0;
-        stats.total_path_length = 0;
-        stats.reached_goal_count = 0;
+    fn passable_neighbors(&self, node: Node, grid: &Grid) -> Vec<Node> {
+        self.get_neighbors(node)
+            .into_iter()
+            .filter(|&n| grid.allows_move(node, n))
+            .collect()
+    }
+}
+
+struct OrthogonalMovement;
+struct DiagonalMovement;
+
+impl MovementStrategy for OrthogonalMovement {
+    fn get_neighbors(&self, node: Node) -> Vec<Node> {
+        const DELTAS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+        DELTAS
+            .iter()
+            .map(|&(dx, dy)| Node {
+                x: node.x + dx,
+                y: node.y + dy,
+            })
+            .filter(|n| in_bounds(*n))
+            .collect()
+    }
+    fn name(&self) -> &str {
+        "Orthogonal"
+    }
+}
+
+impl MovementStrategy for DiagonalMovement {
+    fn get_neighbors(&self, node: Node) -> Vec<Node> {
+        const DELTAS: [(i32, i32); 8] = [
+            (1, 0),
+            (-1, 0),
+            (0, 1),
+            (0, -1),
+            (1, 1),
+            (1, -1),
+            (-1, 1),
+            (-1, -1),
+        ];
+        DELTAS
+            .iter()
+            .map(|&(dx, dy)| Node {
+                x: node.x + dx,
+                y: node.y + dy,
+            })
+            .filter(|n| in_bounds(*n))
+            .collect()
+    }
+    fn name(&self) -> &str {
+        "Diagonal"
+    }
+}
+
+// Map edits go through commands so they can be undone, redone and saved with the map.
+trait Command {
+    fn execute(&mut self, state: &mut GameState, agents: &mut Vec<Agent>);
+    fn undo(&mut self, state: &mut GameState, agents: &mut Vec<Agent>);
+    // The map-file form read back by parse_command; None for edits to state the map does not keep.
+    fn to_line(&self) -> Option<String> {
+        None
+    }
+}
+
+struct WriteCommand {
+    step: Vec<Node>,
+}
+
+impl Command for WriteCommand {
+    fn execute(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        state.step_history.push(self.step.clone());
+    }
+    fn undo(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        state.step_history.pop();
+    }
+}
+
+struct DeleteCommand {
+    count: usize,
+    deleted: Vec<Vec<Node>>,
+}
+
+impl DeleteCommand {
+    fn new(count: usize) -> Self {
+        DeleteCommand {
+            count,
+            deleted: Vec::new(),
+        }
+    }
+}
+
+impl Command for DeleteCommand {
+    fn execute(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        let steps = &mut state.step_history;
+        let start = steps.len().saturating_sub(self.count);
+        self.deleted = steps[start..].to_vec();
+        steps.truncate(start);
+    }
+    fn undo(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        state.step_history.append(&mut self.deleted);
+    }
+}
+
+// Places or erases one wall; the edit is queued so affected agents get replanned.
+struct WallCommand {
+    cell: Node,
+    place: bool,
+}
+
+impl WallCommand {
+    fn set(&self, state: &mut GameState, wall: bool) {
+        if wall {
+            state.grid.walls.insert(self.cell);
+        } else {
+            state.grid.walls.remove(&self.cell);
+        }
+        state.layout_revision += 1;
+        state
+            .components
+            .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+        state.wall_edits.push((self.cell, wall));
+    }
+}
+
+impl Command for WallCommand {
+    fn execute(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        self.set(state, self.place);
+    }
+    fn undo(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        self.set(state, !self.place);
+    }
+    fn to_line(&self) -> Option<String> {
+        let action = if self.place { "place" } else { "erase" };
+        Some(format!("wall {} {} {}", self.cell.x, self.cell.y, action))
+    }
+}
+
+// Swaps the walls for an imported set in one edit; reserved cells stay free and every change is queued for replanning.
+struct ImportWallsCommand {
+    placed: Vec<Node>,
+    erased: Vec<Node>,
+}
+
+impl ImportWallsCommand {
+    fn new(walls: &HashSet<Node>, state: &GameState, agents: &[Agent]) -> Self {
+        let mut placed: Vec<Node> = walls
+            .iter()
+            .copied()
+            .filter(|&c| {
+                !state.grid.walls.contains(&c) && !cell_reserved(c, agents, &state.goal_set)
+            })
+            .collect();
+        let mut erased: Vec<Node> = state.grid.walls.difference(walls).copied().collect();
+        placed.sort_by_key(|n| (n.y, n.x));
+        erased.sort_by_key(|n| (n.y, n.x));
+        ImportWallsCommand { placed, erased }
+    }
+
+    fn apply(state: &mut GameState, place: &[Node], erase: &[Node]) {
+        for &cell in place {
+            state.grid.walls.insert(cell);
+            state.wall_edits.push((cell, true));
+        }
+        for &cell in erase {
+            state.grid.walls.remove(&cell);
+            state.wall_edits.push((cell, false));
+        }
+        state.layout_revision += 1;
+        state
+            .components
+            .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+    }
+}
+
+impl Command for ImportWallsCommand {
+    fn execute(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        Self::apply(state, &self.placed, &self.erased);
+    }
+    fn undo(&mut self, state: &mut GameState, _agents: &mut Vec<Agent>) {
+        Self::apply(state, &self.erased, &self.placed);
+    }
+    fn to_line(&self) -> Option<String> {
+        let cells = |nodes: &[Node]| {
+            nodes
+                .iter()
+                .map(|n| format!(" {} {}", n.x, n.y))
+                .collect::<String>()
+        };
+        Some(format!(
+            "import_walls place{} erase{}",
+            cells(&self.placed),
+            cells(&self.erased)
+        ))
+    }
+}
+
+fn goal_text(goal: Option<Node>) -> String {
+    match goal {
+        Some(g) => format!("{} {}", g.x, g.y),
+        None => "-".to_string(),
+    }
+}
+
+// Appends an agent; undoing removes the last one again.
+struct AddAgentCommand {
+    start: Node,
+    goal: Option<Node>,
+}
+
+impl Command for AddAgentCommand {
+    fn execute(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        agents.push(Agent::new(agents.len(), self.start, self.goal));
+    }
+    fn undo(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        agents.pop();
+    }
+    fn to_line(&self) -> Option<String> {
+        Some(format!(
+            "add_agent {} {} {}",
+            self.start.x,
+            self.start.y,
+            goal_text(self.goal)
+        ))
+    }
+}
+
+struct SetGoalCommand {
+    agent: usize,
+    goal: Node,
+    previous: Option<Node>,
+}
+
+impl SetGoalCommand {
+    fn set(&self, agents: &mut [Agent], goal: Option<Node>) {
+        if let Some(agent) = agents.get_mut(self.agent) {
+            agent.end_point = goal;
+            agent.refresh_cache();
+        }
+    }
+}
+
+impl Command for SetGoalCommand {
+    fn execute(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        self.set(agents, Some(self.goal));
+    }
+    fn undo(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        self.set(agents, self.previous);
+    }
+    fn to_line(&self) -> Option<String> {
+        Some(format!(
+            "set_goal {} {} {} {}",
+            self.agent,
+            self.goal.x,
+            self.goal.y,
+            goal_text(self.previous)
+        ))
+    }
+}
+
+// Removes agents by index and renumbers the rest; undoing puts them back where they were, as they were.
+// A saved history keeps only each agent's index, start and goal.
+struct DeleteAgentsCommand {
+    removed: Vec<Agent>,
+}
+
+impl DeleteAgentsCommand {
+    fn new(agents: &[Agent], ids: &HashSet<usize>) -> Self {
+        DeleteAgentsCommand {
+            removed: agents
+                .iter()
+                .filter(|a| ids.contains(&a.id))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl Command for DeleteAgentsCommand {
+    fn execute(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        delete_agents(agents, &self.removed.iter().map(|a| a.id).collect());
+    }
+    fn undo(&mut self, _state: &mut GameState, agents: &mut Vec<Agent>) {
+        for agent in &self.removed {
+            agents.insert(agent.id.min(agents.len()), agent.clone());
+        }
+        for (index, agent) in agents.iter_mut().enumerate() {
+            agent.id = index;
+        }
+    }
+    fn to_line(&self) -> Option<String> {
+        let mut line = "delete_agents".to_string();
+        for a in &self.removed {
+            line += &format!(
+                " {} {} {} {}",
+                a.id,
+                a.start_point.x,
+                a.start_point.y,
+                goal_text(a.end_point)
+            );
+        }
+        Some(line)
+    }
+}
+
+// Cells are checked against the grid so a hand-edited map cannot index past it on undo/redo.
+fn parse_command(line: &str) -> Result<Box<dyn Command>, String> {
+    let malformed = || "unknown or malformed command".to_string();
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (&keyword, args) = fields.split_first().ok_or_else(malformed)?;
+    let num = |k: usize| {
+        args.get(k)
+            .and_then(|v| v.parse::<i32>().ok())
+            .ok_or_else(malformed)
+    };
+    let index = |k: usize| usize::try_from(num(k)?).map_err(|_| malformed());
+    let node = |k: usize| {
+        let n = Node {
+            x: num(k)?,
+            y: num(k + 1)?,
+        };
+        if !in_bounds(n) {
+            return Err(format!("cell ({}, {}) is outside the grid", n.x, n.y));
+        }
+        Ok(n)
+    };
+    // A goal is either "x y" or "-"; returns it with the number of fields it took.
+    let goal = |k: usize| match args.get(k) {
+        Some(&"-") => Ok((None, 1)),
+        _ => node(k).map(|n| (Some(n), 2)),
+    };
+    match keyword {
+        "wall" => {
+            let place = match args.get(2) {
+                Some(&"place") => true,
+                Some(&"erase") => false,
+                _ => return Err(malformed()),
+            };
+            Ok(Box::new(WallCommand {
+                cell: node(0)?,
+                place,
+            }))
+        }
+        "add_agent" => Ok(Box::new(AddAgentCommand {
+            start: node(0)?,
+            goal: goal(2)?.0,
+        })),
+        "set_goal" => Ok(Box::new(SetGoalCommand {
+            agent: index(0)?,
+            goal: node(1)?,
+            previous: goal(3)?.0,
+        })),
+        "delete_agents" => {
+            let mut removed = Vec::new();
+            let mut k = 0;
+            while k < args.len() {
+                let agent = index(k)?;
+                let (target, used) = goal(k + 3)?;
+                removed.push(Agent::new(agent, node(k + 1)?, target));
+                k += 3 + used;
+            }
+            Ok(Box::new(DeleteAgentsCommand { removed }))
+        }
+        "import_walls" => {
+            let (mut placed, mut erased) = (Vec::new(), Vec::new());
+            let mut place = None;
+            let mut k = 0;
+            while k < args.len() {
+                match args[k] {
+                    "place" => place = Some(true),
+                    "erase" => place = Some(false),
+                    _ => {
+                        let cell = node(k)?;
+                        match place {
+                            Some(true) => placed.push(cell),
+                            Some(false) => erased.push(cell),
+                            None => return Err(malformed()),
+                        }
+                        k += 1;
+                    }
+                }
+                k += 1;
+            }
+            Ok(Box::new(ImportWallsCommand { placed, erased }))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+struct CommandHistory {
+    history: Vec<Box<dyn Command>>,
+    undone: Vec<Box<dyn Command>>,
+}
+
+impl CommandHistory {
+    fn new() -> Self {
+        CommandHistory {
+            history: Vec::new(),
+            undone: Vec::new(),
+        }
+    }
+
+    // Rebuilds both stacks from saved lines, oldest first; one bad line rejects the whole history,
+    // since replaying the rest out of sequence would corrupt the map.
+    fn restore(undo: &[String], redo: &[String]) -> Result<Self, String> {
+        let parse = |lines: &[String]| {
+            lines
+                .iter()
+                .map(|l| parse_command(l).map_err(|e| format!("\"{}\": {}", l, e)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(CommandHistory {
+            history: parse(undo)?,
+            undone: parse(redo)?,
+        })
+    }
+
+    fn execute(
+        &mut self,
+        mut cmd: Box<dyn Command>,
+        state: &mut GameState,
+        agents: &mut Vec<Agent>,
+    ) {
+        cmd.execute(state, agents);
+        self.history.push(cmd);
+        self.undone.clear();
+    }
+
+    fn undo(&mut self, state: &mut GameState, agents: &mut Vec<Agent>) -> bool {
+        let Some(mut cmd) = self.history.pop() else {
+            return false;
+        };
+        cmd.undo(state, agents);
+        self.undone.push(cmd);
+        true
+    }
+
+    fn redo(&mut self, state: &mut GameState, agents: &mut Vec<Agent>) -> bool {
+        let Some(mut cmd) = self.undone.pop() else {
+            return false;
+        };
+        cmd.execute(state, agents);
+        self.history.push(cmd);
+        true
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+        self.undone.clear();
+    }
+
+    fn lines(commands: &[Box<dyn Command>]) -> Vec<String> {
+        commands.iter().filter_map(|c| c.to_line()).collect()
+    }
+}
+
+const TICK_HISTORY_LEN: usize = 256;
+const SNAPSHOT_KEYS: [Key; 5] = [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5];
+const MAX_TRAIL_LEN: usize = 100;
+const TRAIL_LENGTHS: [usize; 4] = [0, 10, 20, 50];
+
+// Colours the renderer takes from the active theme; everything else (HUD, overlays tied to a
+// specific meaning like traffic lights) keeps its fixed constant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Theme {
+    name: &'static str,
+    background: u32,
+    gridline: u32,
+    wall: u32,
+    path: u32,
+    goal: u32,
+    forward: u32,
+    warning: u32,
+    critical: u32,
+    collision: u32,
+    selection: u32,
+    agents: [u32; 8],
+}
+
+const THEME_NAMES: [&str; 3] = ["dark", "light", "colorblind"];
+
+impl Theme {
+    fn dark() -> Self {
+        Theme {
+            name: "dark",
+            background: BLACK,
+            gridline: WHITE,
+            wall: WHITE,
+            path: WHITE,
+            goal: ORANGE,
+            forward: LIGHT_BLUE,
+            warning: YELLOW,
+            critical: RED,
+            collision: PALE_RED,
+            selection: SELECTION_COLOR,
+            agents: AGENT_PALETTE,
+        }
+    }
+
+    fn light() -> Self {
+        Theme {
+            name: "light",
+            background: 0x00F4F4F4,
+            gridline: 0x00C8C8C8,
+            wall: 0x00303030,
+            path: 0x00404040,
+            goal: 0x00E65100,
+            forward: 0x0090CAF9,
+            warning: 0x00FFA000,
+            critical: 0x00D50000,
+            collision: 0x00FF8A80,
+            selection: 0x000091EA,
+            agents: [
+                0x00D50000, 0x0000873E, 0x002962FF, 0x00C77800, 0x00AA00FF, 0x0000838F, 0x00DD2C00,
+                0x00558B2F,
+            ],
+        }
+    }
+
+    // Okabe-Ito palette: distinguishable under the common forms of colour blindness.
+    fn colorblind() -> Self {
+        Theme {
+            name: "colorblind",
+            background: BLACK,
+            gridline: 0x00555555,
+            wall: 0x00BBBBBB,
+            path: WHITE,
+            goal: 0x00F0E442,
+            forward: 0x0056B4E9,
+            warning: 0x00E69F00,
+            critical: 0x00D55E00,
+            collision: 0x00D55E00,
+            selection: WHITE,
+            agents: [
+                0x00E69F00, 0x0056B4E9, 0x00009E73, 0x00F0E442, 0x000072B2, 0x00D55E00, 0x00CC79A7,
+                0x00FFFFFF,
+            ],
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Theme::dark()),
+            "light" => Some(Theme::light()),
+            "colorblind" => Some(Theme::colorblind()),
+            _ => None,
+        }
+    }
+
+    // Cycles through the built-ins; a theme loaded from a file goes back to the first one.
+    fn next(&self) -> Self {
+        let i = THEME_NAMES.iter().position(|&n| n == self.name);
+        let next = i.map_or(0, |i| (i + 1) % THEME_NAMES.len());
+        Theme::builtin(THEME_NAMES[next]).unwrap()
+    }
+
+    fn agent_color(&self, id: usize) -> u32 {
+        self.agents[id % self.agents.len()]
+    }
+
+    // A built-in name, or a file of `key 0xRRGGBB` lines that starts from `base NAME` (dark by default).
+    fn load(source: &str) -> Result<Self, SimError> {
+        match Theme::builtin(source) {
+            Some(theme) => Ok(theme),
+            None => Theme::parse(&std::fs::read_to_string(source)?),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, SimError> {
+        let mut theme = Theme {
+            name: "custom",
+            ..Theme::dark()
+        };
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: String| SimError::ThemeFormat {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or("");
+            let Some((key, value)) = line.trim().split_once(char::is_whitespace) else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Err(err(format!("'{}' needs a value", line.trim())));
+            };
+            let value = value.trim();
+            let color = |v: &str| {
+                u32::from_str_radix(v.trim().trim_start_matches("0x"), 16)
+                    .map_err(|_| err(format!("bad colour '{}'", v.trim())))
+            };
+            match key {
+                "base" => {
+                    let base = Theme::builtin(value)
+                        .ok_or_else(|| err(format!("unknown base theme '{}'", value)))?;
+                    theme = Theme {
+                        name: "custom",
+                        ..base
+                    };
+                }
+                "background" => theme.background = color(value)?,
+                "gridline" => theme.gridline = color(value)?,
+                "wall" => theme.wall = color(value)?,
+                "path" => theme.path = color(value)?,
+                "goal" => theme.goal = color(value)?,
+                "forward" => theme.forward = color(value)?,
+                "warning" => theme.warning = color(value)?,
+                "critical" => theme.critical = color(value)?,
+                "collision" => theme.collision = color(value)?,
+                "selection" => theme.selection = color(value)?,
+                "agents" => {
+                    let colors = value.split(',').map(color).collect::<Result<Vec<_>, _>>()?;
+                    if colors.is_empty() || colors.len() > theme.agents.len() {
+                        return Err(err(format!(
+                            "agents needs 1 to {} colours",
+                            theme.agents.len()
+                        )));
+                    }
+                    for (i, slot) in theme.agents.iter_mut().enumerate() {
+                        *slot = colors[i % colors.len()];
+                    }
+                }
+                other => return Err(err(format!("unknown key '{}'", other))),
+            }
+        }
+        Ok(theme)
+    }
+}
+
+fn blend(color: u32, background: u32, alpha: f32) -> u32 {
+    let channel = |shift: u32| {
+        let c = ((color >> shift) & 0xFF) as f32;
+        let b = ((background >> shift) & 0xFF) as f32;
+        ((b + (c - b) * alpha).round() as u32) << shift
+    };
+    channel(16) | channel(8) | channel(0)
+}
+
+struct AgentSnapshot {
+    current_point: Node,
+    path: Option<Vec<Node>>,
+    path_index: usize,
+    position: Vec2,
+    velocity: Vec2,
+    last_position: Vec2,
+    finished: bool,
+    trail: VecDeque<Node>,
+    battery: u32,
+    last_reroute_tick: Option<usize>,
+}
+
+struct TickSnapshot {
+    tick: usize,
+    agents: Vec<AgentSnapshot>,
+    stats: Statistics,
+}
+
+struct TickHistory {
+    snapshots: VecDeque<TickSnapshot>,
+    tick: usize,
+}
+
+impl TickHistory {
+    fn new() -> Self {
+        TickHistory {
+            snapshots: VecDeque::with_capacity(TICK_HISTORY_LEN),
+            tick: 0,
+        }
+    }
+
+    fn record(&mut self, agents: &[Agent], stats: &Statistics) {
+        if self.snapshots.len() == TICK_HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(TickSnapshot {
+            tick: self.tick,
+            agents: agents
+                .iter()
+                .map(|a| AgentSnapshot {
+                    current_point: a.current_point,
+                    path: a.path.clone(),
+                    path_index: a.path_index,
+                    position: a.position,
+                    velocity: a.velocity,
+                    last_position: a.last_position,
+                    finished: a.finished,
+                    trail: a.trail.clone(),
+                    battery: a.battery,
+                    last_reroute_tick: a.last_reroute_tick,
+                })
+                .collect(),
+            stats: stats.clone(),
+        });
+        self.tick += 1;
+    }
+
+    fn rewind(&mut self, agents: &mut [Agent], stats: &mut Statistics) -> bool {
+        let Some(snapshot) = self.snapshots.pop_back() else {
+            return false;
+        };
+        for (agent, saved) in agents.iter_mut().zip(snapshot.agents) {
+            agent.current_point = saved.current_point;
+            agent.path = saved.path;
+            agent.path_index = saved.path_index;
+            agent.position = saved.position;
+            agent.velocity = saved.velocity;
+            agent.last_position = saved.last_position;
+            agent.finished = saved.finished;
+            agent.trail = saved.trail;
+            agent.battery = saved.battery;
+            agent.last_reroute_tick = saved.last_reroute_tick;
+            agent.refresh_cache();
+        }
+        *stats = snapshot.stats;
+        self.tick = snapshot.tick;
+        true
+    }
+
+    fn clear(&mut self) {
+        self.snapshots.clear();
+        self.tick = 0;
+    }
+}
+
+// A quick-save slot: the map, every agent mid-run and the counters, restorable any number of times.
+struct SimSnapshot {
+    grid: Grid,
+    goal_set: HashSet<Node>,
+    goal_deliveries: Vec<GoalArrival>,
+    agents: Vec<Agent>,
+    stats: Statistics,
+    ignored_pairs: HashMap<AgentPair, usize>,
+    tick: usize,
+}
+
+impl SimSnapshot {
+    fn capture(
+        state: &GameState,
+        agents: &[Agent],
+        collision_detector: &CollisionDetector,
+        stats: &Statistics,
+    ) -> Self {
+        SimSnapshot {
+            grid: state.grid.clone(),
+            goal_set: state.goal_set.clone(),
+            goal_deliveries: state.goal_deliveries.clone(),
+            agents: agents.to_vec(),
+            stats: stats.clone(),
+            ignored_pairs: collision_detector.ignored_pairs.clone(),
+            tick: state.tick_history.tick,
+        }
+    }
+
+    // Avoidance and planner settings stay as they are now, so the same moment can be retried with new ones.
+    fn restore(
+        &self,
+        state: &mut GameState,
+        agents: &mut Vec<Agent>,
+        collision_detector: &mut CollisionDetector,
+        stats: &mut Statistics,
+    ) {
+        state.grid = self.grid.clone();
+        state.layout_revision += 1;
+        state.goal_set = self.goal_set.clone();
+        state.goal_deliveries = self.goal_deliveries.clone();
+        state
+            .components
+            .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+        *agents = self.agents.clone();
+        *stats = self.stats.clone();
+        collision_detector.ignored_pairs = self.ignored_pairs.clone();
+        collision_detector.tick = self.tick;
+        state.tick_history.clear();
+        state.tick_history.tick = self.tick;
+        state.selection.clear();
+        state.drag = None;
+        state.preview = None;
+        state.distance_field = None;
+        if state.current_step == Step::End && agents.last().is_none_or(|a| a.end_point.is_some()) {
+            state.current_step = Step::Start;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Agent {
+    id: usize,
+    start_point: Node,
+    end_point: Option<Node>,
+    current_point: Node,
+    path: Option<Vec<Node>>,
+    path_index: usize,
+    collision_radius: Vec<Node>,
+    forward_path: Vec<Node>,
+
+    position: Vec2,
+    velocity: Vec2,
+    max_speed: f32,
+    radius: f32,
+    last_position: Vec2,
+    finished: bool,
+    trail: VecDeque<Node>,
+    battery: u32,
+    last_reroute_tick: Option<usize>,
+    arrival_tick: Option<usize>,
+    priority: u8,
+    known_walls: HashSet<Node>,
+    heading: Node,
+    waypoints: Vec<Vec2>,
+    waypoint_index: usize,
+    yield_streak: usize,
+    cell_radius: i32,
+    footprint: Footprint,
+}
+
+// Cells a large agent covers, relative to the cell it is planned through.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Footprint {
+    Cell,
+    Square,
+    Bar,
+}
+
+impl Footprint {
+    fn offsets(self) -> &'static [(i32, i32)] {
+        match self {
+            Footprint::Cell => &[(0, 0)],
+            Footprint::Square => &[(0, 0), (1, 0), (0, 1), (1, 1)],
+            Footprint::Bar => &[(-1, 0), (0, 0), (1, 0)],
+        }
+    }
+
+    fn cells(self, anchor: Node) -> Vec<Node> {
+        self.offsets()
+            .iter()
+            .map(|&(dx, dy)| Node {
+                x: anchor.x + dx,
+                y: anchor.y + dy,
+            })
+            .collect()
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Footprint::Cell => Footprint::Square,
+            Footprint::Square => Footprint::Bar,
+            Footprint::Bar => Footprint::Cell,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Footprint::Cell => "1x1",
+            Footprint::Square => "2x2",
+            Footprint::Bar => "1x3",
+        }
+    }
+}
+
+const MAX_PRIORITY: u8 = 9;
+// Footprint in cells around the agent's own; 1 covers the eight neighbours.
+const AGENT_CELL_RADIUS: i32 = 1;
+const MAX_CELL_RADIUS: i32 = 2;
+
+impl Agent {
+    fn new(id: usize, start: Node, end: Option<Node>) -> Self {
+        let position = start.to_pixels();
+
+        let mut agent = Agent {
+            id,
+            start_point: start,
+            end_point: end,
+            current_point: start,
+            path: None,
+            path_index: 0,
+            collision_radius: Vec::with_capacity(8),
+            forward_path: Vec::with_capacity(2),
+            position,
+            velocity: Vec2::ZERO,
+            max_speed: 200.0,
+            radius: 20.0,
+            last_position: position,
+            finished: false,
+            trail: VecDeque::new(),
+            battery: BATTERY_CAPACITY,
+            last_reroute_tick: None,
+            arrival_tick: None,
+            priority: 0,
+            known_walls: HashSet::new(),
+            heading: Node { x: 0, y: 0 },
+            waypoints: Vec::new(),
+            waypoint_index: 0,
+            yield_streak: 0,
+            cell_radius: AGENT_CELL_RADIUS,
+            footprint: Footprint::Cell,
+        };
+        agent.collision_radius = agent.calc_radius();
+        agent
+    }
+
+    fn calc_radius(&self) -> Vec<Node> {
+        let r = self.cell_radius;
+        (-r..=r)
+            .flat_map(|dy| (-r..=r).map(move |dx| (dx, dy)))
+            .filter(|&delta| delta != (0, 0))
+            .map(|(dx, dy)| Node {
+                x: self.current_point.x + dx,
+                y: self.current_point.y + dy,
+            })
+            .filter(|n| in_bounds(*n))
+            .collect()
+    }
+
+    fn calc_forward(&self) -> Vec<Node> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        let goal = match self.end_point {
+            Some(g) => g,
+            None => return Vec::new(),
+        };
+
+        path.iter()
+            .skip(self.path_index + 1)
+            .take(2)
+            .take_while(|&&n| n != goal)
+            .copied()
+            .collect()
+    }
+
+    fn direction(&self) -> Node {
+        if let Some(path) = &self.path {
+            if self.path_index + 1 < path.len() {
+                return move_dir(self.current_point, path[self.path_index + 1]);
+            }
+        }
+        if let Some(goal) = self.end_point {
+            return Node {
+                x: (goal.x - self.current_point.x).signum(),
+                y: (goal.y - self.current_point.y).signum(),
+            };
+        }
+        Node { x: 0, y: 0 }
+    }
+
+    fn record_trail(&mut self, cell: Node) {
+        if !in_bounds(cell) || self.trail.back() == Some(&cell) {
+            return;
+        }
+        if self.trail.len() == MAX_TRAIL_LEN {
+            self.trail.pop_front();
+        }
+        self.trail.push_back(cell);
+    }
+
+    fn next_cell(&self) -> Option<Node> {
+        let path = self.path.as_ref()?;
+        path.get(self.path_index + 1).copied()
+    }
+
+    fn path_blocked(&self, grid: &Grid) -> bool {
+        self.path.as_ref().is_some_and(|path| {
+            path.iter()
+                .skip(self.path_index + 1)
+                .any(|&n| grid.is_blocked(n))
+        })
+    }
+
+    // Whether the footprint covers `cell` anywhere from the current position to the goal.
+    fn path_crosses(&self, cell: Node) -> bool {
+        self.path.as_ref().is_some_and(|path| {
+            path.iter()
+                .skip(self.path_index)
+                .any(|&n| self.footprint.cells(n).contains(&cell))
+        })
+    }
+
+    fn path_near(&self, cell: Node) -> bool {
+        self.path.as_ref().is_some_and(|path| {
+            path.iter()
+                .skip(self.path_index)
+                .any(|n| (n.x - cell.x).abs() <= 1 && (n.y - cell.y).abs() <= 1)
+        })
+    }
+
+    fn remaining_len(&self) -> usize {
+        self.path
+            .as_ref()
+            .map_or(0, |p| p.len().saturating_sub(self.path_index))
+    }
+
+    // Cell the agent is expected to occupy `t` ticks from now; it parks at the end of its path.
+    fn cell_at(&self, t: usize) -> Node {
+        match &self.path {
+            Some(path) if !path.is_empty() => path[(self.path_index + t).min(path.len() - 1)],
+            _ => self.current_point,
+        }
+    }
+
+    fn refresh_cache(&mut self) {
+        self.collision_radius = self.calc_radius();
+        self.forward_path = self.calc_forward();
+    }
+}
+
+trait CollisionStrategy {
+    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent>;
+    fn name(&self) -> &str;
+}
+
+const PAIR_EXPIRY_TICKS: usize = 10;
+const PAIR_EXPIRY_DISTANCE: i32 = 3;
+
+// When a reported pair becomes eligible for detection again.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PairExpiry {
+    EveryTick,
+    Ticks(usize),
+    Distance(i32),
+}
+
+impl PairExpiry {
+    fn next(self) -> Self {
+        match self {
+            PairExpiry::EveryTick => PairExpiry::Ticks(PAIR_EXPIRY_TICKS),
+            PairExpiry::Ticks(_) => PairExpiry::Distance(PAIR_EXPIRY_DISTANCE),
+            PairExpiry::Distance(_) => PairExpiry::EveryTick,
+        }
+    }
+}
+
+struct CollisionDetector {
+    bus: Rc<EventBus>,
+    ignored_pairs: HashMap<AgentPair, usize>,
+    strategy: Box<dyn CollisionStrategy>,
+    expiry: PairExpiry,
+    clear_on_reroute: bool,
+    safety_distance: i32,
+    tick: usize,
+}
+
+impl CollisionDetector {
+    fn new(strategy: Box<dyn CollisionStrategy>) -> Self {
+        Self::with_bus(strategy, Rc::new(EventBus::new()))
+    }
+
+    fn with_bus(strategy: Box<dyn CollisionStrategy>, bus: Rc<EventBus>) -> Self {
+        Self {
+            bus,
+            ignored_pairs: HashMap::new(),
+            strategy,
+            expiry: PairExpiry::EveryTick,
+            clear_on_reroute: false,
+            safety_distance: 0,
+            tick: 0,
+        }
+    }
+
+    fn expire_pairs(&mut self, agents: &[Agent], tick: usize) {
+        self.tick = tick;
+        match self.expiry {
+            PairExpiry::EveryTick => self.ignored_pairs.clear(),
+            PairExpiry::Ticks(ticks) => self
+                .ignored_pairs
+                .retain(|_, since| tick >= *since && tick - *since < ticks),
+            PairExpiry::Distance(cells) => {
+                let cell_of: HashMap<usize, Node> =
+                    agents.iter().map(|a| (a.id, a.current_point)).collect();
+                self.ignored_pairs.retain(|pair, _| {
+                    match (cell_of.get(&pair.0), cell_of.get(&pair.1)) {
+                        (Some(a), Some(b)) => (a.x - b.x).abs().max((a.y - b.y).abs()) <= cells,
+                        _ => false,
+                    }
+                });
+            }
+        }
+    }
+
+    fn forget_agents(&mut self, ids: &[usize]) {
+        self.ignored_pairs
+            .retain(|pair, _| !ids.contains(&pair.0) && !ids.contains(&pair.1));
+    }
+
+    fn set_strategy(&mut self, strategy: Box<dyn CollisionStrategy>) {
+        self.strategy = strategy;
+        self.ignored_pairs.clear();
+    }
+
+    // Agents closer than the safety distance count as a near miss even when the strategy sees none.
+    fn too_close(&self, a: &Agent, b: &Agent, gap: i32) -> Option<CollisionEvent> {
+        if gap == 0 || gap > self.safety_distance || a.finished || b.finished {
+            return None;
+        }
+        let (a_cell, b_cell) = (Node::from_pixels(a.position), Node::from_pixels(b.position));
+        Some(CollisionEvent {
+            agent1_id: a.id,
+            agent2_id: b.id,
+            collision_type: CollisionType::Proximity,
+            collision_point: Node {
+                x: (a_cell.x + b_cell.x) / 2,
+                y: (a_cell.y + b_cell.y) / 2,
+            },
+        })
+    }
+
+    // Large agents collide as soon as their footprints share a cell, whatever the strategy says.
+    fn footprint_overlap(a: &Agent, b: &Agent) -> Option<CollisionEvent> {
+        if a.footprint == Footprint::Cell && b.footprint == Footprint::Cell {
+            return None;
+        }
+        let b_cells = b.footprint.cells(b.current_point);
+        let cell = a
+            .footprint
+            .cells(a.current_point)
+            .into_iter()
+            .find(|c| b_cells.contains(c))?;
+        Some(CollisionEvent {
+            agent1_id: a.id,
+            agent2_id: b.id,
+            collision_type: CollisionType::Direct,
+            collision_point: cell,
+        })
+    }
+
+    fn check_agents(&mut self, agents: &[Agent], stats: &mut Statistics) {
+        for i in 0..agents.len() {
+            for j in (i + 1)..agents.len() {
+                let gap = chebyshev(
+                    Node::from_pixels(agents[i].position),
+                    Node::from_pixels(agents[j].position),
+                );
+                if !agents[i].finished && !agents[j].finished {
+                    stats.min_separation = Some(stats.min_separation.map_or(gap, |m| m.min(gap)));
+                }
+                let pair = AgentPair::new(agents[i].id, agents[j].id);
+                if self.ignored_pairs.contains_key(&pair) {
+                    continue;
+                }
+
+                let event = Self::footprint_overlap(&agents[i], &agents[j])
+                    .or_else(|| self.strategy.detect(&agents[i], &agents[j]))
+                    .or_else(|| self.too_close(&agents[i], &agents[j], gap));
+                if let Some(event) = event {
+                    self.ignored_pairs.insert(pair, self.tick);
+
+                    match event.collision_type {
+                        CollisionType::Direct => stats.collisions += 1,
+                        CollisionType::Proximity => stats.detections += 1,
+                    }
+                    if let CollisionType::Proximity = event.collision_type
+                        && let Some(ticks) = ticks_to_collision(
+                            &agents[i],
+                            &agents[j],
+                            self.strategy.name() == "ORCA",
+                        )
+                    {
+                        debug!(
+                            "Agents {} and {}: {} warning, collision in {} ticks",
+                            event.agent1_id,
+                            event.agent2_id,
+                            WarningLevel::from_ticks(ticks).name(),
+                            ticks
+                        );
+                    }
+                    self.bus.publish(SimEvent::Collision(event));
+                }
+            }
+        }
+    }
+
+    fn check_capacity(&mut self, agents: &[Agent], grid: &Grid, stats: &mut Statistics) {
+        for passage in grid.passages() {
+            let occupants = passage.occupants(agents);
+            if let Some(&resident) = occupants.first() {
+                for &id in occupants.iter().skip(passage.capacity) {
+                    let pair = AgentPair::new(resident, id);
+                    if let Entry::Vacant(entry) = self.ignored_pairs.entry(pair) {
+                        entry.insert(self.tick);
+                        stats.capacity_conflicts += 1;
+                        self.bus.publish(SimEvent::Collision(CollisionEvent {
+                            agent1_id: resident,
+                            agent2_id: id,
+                            collision_type: CollisionType::Direct,
+                            collision_point: agents[id].current_point,
+                        }));
+                    }
+                }
+            }
+            if occupants.len() < passage.capacity {
+                continue;
+            }
+            for agent in agents {
+                if agent.finished || occupants.contains(&agent.id) {
+                    continue;
+                }
+                let Some(next) = agent.next_cell().filter(|n| passage.cells.contains(n)) else {
+                    continue;
+                };
+                let pair = AgentPair::new(occupants[0], agent.id);
+                if let Entry::Vacant(entry) = self.ignored_pairs.entry(pair) {
+                    entry.insert(self.tick);
+                    stats.capacity_conflicts += 1;
+                    self.bus.publish(SimEvent::Collision(CollisionEvent {
+                        agent1_id: agent.id,
+                        agent2_id: occupants[0],
+                        collision_type: CollisionType::Proximity,
+                        collision_point: next,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CollisionType {
+    Direct,
+    Proximity,
+}
+
+#[derive(Debug, Clone)]
+struct CollisionEvent {
+    agent1_id: usize,
+    agent2_id: usize,
+    collision_type: CollisionType,
+    collision_point: Node,
+}
+
+// Everything the simulation announces; each subscriber matches the variants it cares about.
+#[derive(Debug, Clone)]
+enum SimEvent {
+    Collision(CollisionEvent),
+    Arrival(usize),
+    Reroute(RerouteRequest),
+    Notice {
+        level: log::Level,
+        text: String,
+    },
+    RunCompleted {
+        run: String,
+        arrived: usize,
+        agents: usize,
+        collisions: usize,
+        reroutes: usize,
+        makespan: usize,
+        sum_of_costs: usize,
+    },
+}
+
+trait Subscriber {
+    fn on_event(&self, event: &SimEvent, bus: &EventBus);
+    fn id(&self) -> &str;
+}
+
+// Publish/subscribe hub shared by the detector and the session. Events published from inside a
+// subscriber are queued behind the one being delivered, so every subscriber sees the same order.
+struct EventBus {
+    subscribers: RefCell<Vec<Rc<dyn Subscriber>>>,
+    disabled: RefCell<Vec<Rc<dyn Subscriber>>>,
+    queue: RefCell<VecDeque<SimEvent>>,
+    dispatching: Cell<bool>,
+}
+
+impl EventBus {
+    fn new() -> Self {
+        EventBus {
+            subscribers: RefCell::new(Vec::new()),
+            disabled: RefCell::new(Vec::new()),
+            queue: RefCell::new(VecDeque::new()),
+            dispatching: Cell::new(false),
+        }
+    }
+
+    fn subscribe(&self, subscriber: Rc<dyn Subscriber>) {
+        let mut subscribers = self.subscribers.borrow_mut();
+        subscribers.retain(|s| s.id() != subscriber.id());
+        subscribers.push(subscriber);
+    }
+
+    fn unsubscribe(&self, id: &str) -> Option<Rc<dyn Subscriber>> {
+        let mut subscribers = self.subscribers.borrow_mut();
+        let index = subscribers.iter().position(|s| s.id() == id)?;
+        Some(subscribers.remove(index))
+    }
+
+    fn toggle(&self, id: &str) -> Option<bool> {
+        if let Some(subscriber) = self.unsubscribe(id) {
+            self.disabled.borrow_mut().push(subscriber);
+            return Some(false);
+        }
+        let subscriber = {
+            let mut disabled = self.disabled.borrow_mut();
+            let index = disabled.iter().position(|s| s.id() == id)?;
+            disabled.remove(index)
+        };
+        self.subscribe(subscriber);
+        Some(true)
+    }
+
+    fn publish(&self, event: SimEvent) {
+        self.queue.borrow_mut().push_back(event);
+        if self.dispatching.replace(true) {
+            return;
+        }
+        while let Some(event) = self.next_event() {
+            let subscribers = self.subscribers.borrow().clone();
+            for subscriber in subscribers {
+                subscriber.on_event(&event, self);
+            }
+        }
+        self.dispatching.set(false);
+    }
+
+    fn next_event(&self) -> Option<SimEvent> {
+        self.queue.borrow_mut().pop_front()
+    }
+
+    fn notice(&self, level: log::Level, text: String) {
+        self.publish(SimEvent::Notice { level, text });
+    }
+
+    fn info(&self, text: String) {
+        self.notice(log::Level::Info, text);
+    }
+
+    fn warn(&self, text: String) {
+        self.notice(log::Level::Warn, text);
+    }
+
+    fn error(&self, text: String) {
+        self.notice(log::Level::Error, text);
+    }
+}
+
+// Keeps matching events until taken, for work that needs mutable access to the simulation.
+struct Mailbox {
+    id: &'static str,
+    accepts: fn(&SimEvent) -> bool,
+    events: RefCell<Vec<SimEvent>>,
+}
+
+impl Mailbox {
+    fn new(id: &'static str, accepts: fn(&SimEvent) -> bool) -> Self {
+        Mailbox {
+            id,
+            accepts,
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn take(&self) -> Vec<SimEvent> {
+        std::mem::take(&mut *self.events.borrow_mut())
+    }
+}
+
+impl Subscriber for Mailbox {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        if (self.accepts)(event) {
+            self.events.borrow_mut().push(event.clone());
+        }
+    }
+
+    fn id(&self) -> &str {
+        self.id
+    }
+}
+
+#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+struct AgentPair(usize, usize);
+
+impl AgentPair {
+    fn new(a: usize, b: usize) -> Self {
+        if a < b {
+            AgentPair(a, b)
+        } else {
+            AgentPair(b, a)
+        }
+    }
+}
+
+struct PathCollisionStrategy;
+
+impl CollisionStrategy for PathCollisionStrategy {
+    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
+        if a.current_point == b.current_point {
+            return Some(CollisionEvent {
+                agent1_id: a.id,
+                agent2_id: b.id,
+                collision_type: CollisionType::Direct,
+                collision_point: a.current_point,
+            });
+        }
+
+        let b_set: HashSet<Node> = b.forward_path.iter().copied().collect();
+
+        for &node in &a.forward_path {
+            if b_set.contains(&node) || node == b.current_point {
+                return Some(CollisionEvent {
+                    agent1_id: a.id,
+                    agent2_id: b.id,
+                    collision_type: CollisionType::Proximity,
+                    collision_point: node,
+                });
+            }
+        }
+
+        for &node in &b.forward_path {
+            if node == a.current_point {
+                return Some(CollisionEvent {
+                    agent1_id: a.id,
+                    agent2_id: b.id,
+                    collision_type: CollisionType::Proximity,
+                    collision_point: node,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn name(&self) -> &str {
+        "Path-based"
+    }
+}
+
+// Earliest (cell, tick) where the two remaining paths meet or swap.
+fn first_conflict(a: &Agent, b: &Agent) -> Option<(Node, usize)> {
+    let horizon = a.remaining_len().max(b.remaining_len());
+    for t in 0..=horizon {
+        let (a_cell, b_cell) = (a.cell_at(t), b.cell_at(t));
+        if a_cell == b_cell {
+            return Some((a_cell, t));
+        }
+        if t > 0 && a_cell == b.cell_at(t - 1) && b_cell == a.cell_at(t - 1) {
+            let cell = if (a_cell.x, a_cell.y) < (b_cell.x, b_cell.y) {
+                a_cell
+            } else {
+                b_cell
+            };
+            return Some((cell, t));
+        }
+    }
+    None
+}
+
+const WARN_TICKS: usize = 5;
+const CRITICAL_TICKS: usize = 2;
+const ORCA_TICK_SECONDS: f32 = 1.0 / 60.0;
+
+// How soon a predicted collision happens; reroutes can wait until a pair reaches a chosen level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum WarningLevel {
+    Info,
+    Warn,
+    Critical,
+}
+
+impl WarningLevel {
+    fn from_ticks(ticks: usize) -> Self {
+        if ticks <= CRITICAL_TICKS {
+            WarningLevel::Critical
+        } else if ticks <= WARN_TICKS {
+            WarningLevel::Warn
+        } else {
+            WarningLevel::Info
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "info" => Some(WarningLevel::Info),
+            "warn" => Some(WarningLevel::Warn),
+            "critical" => Some(WarningLevel::Critical),
+            _ => None,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            WarningLevel::Info => WarningLevel::Warn,
+            WarningLevel::Warn => WarningLevel::Critical,
+            WarningLevel::Critical => WarningLevel::Info,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WarningLevel::Info => "info",
+            WarningLevel::Warn => "warn",
+            WarningLevel::Critical => "critical",
+        }
+    }
+}
+
+// Ticks until two agents touch: along their remaining paths at one cell per tick, or from the
+// closing speed when they steer freely (ORCA).
+fn ticks_to_collision(a: &Agent, b: &Agent, is_orca: bool) -> Option<usize> {
+    if a.finished && b.finished {
+        return None;
+    }
+    if !is_orca {
+        return first_conflict(a, b).map(|(_, ticks)| ticks);
+    }
+    let offset = b.position - a.position;
+    let closing = b.velocity - a.velocity;
+    let reach = a.radius + b.radius;
+    let gap = offset.length_squared() - reach * reach;
+    if gap <= 0.0 {
+        return Some(0);
+    }
+    let (speed, approach) = (closing.length_squared(), offset.dot(closing));
+    let discriminant = approach * approach - speed * gap;
+    if approach >= 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let seconds = (-approach - discriminant.sqrt()) / speed;
+    Some((seconds / ORCA_TICK_SECONDS).ceil() as usize)
+}
+
+fn warning_level(a: &Agent, b: &Agent, is_orca: bool) -> Option<WarningLevel> {
+    ticks_to_collision(a, b, is_orca).map(WarningLevel::from_ticks)
+}
+
+// Most urgent warning each agent is part of; refreshed once per tick.
+fn warning_levels(agents: &[Agent], is_orca: bool) -> HashMap<usize, WarningLevel> {
+    let mut levels: HashMap<usize, WarningLevel> = HashMap::new();
+    for (i, a) in agents.iter().enumerate() {
+        for b in &agents[i + 1..] {
+            if let Some(level) = warning_level(a, b, is_orca) {
+                for id in [a.id, b.id] {
+                    let slot = levels.entry(id).or_insert(level);
+                    *slot = (*slot).max(level);
+                }
+            }
+        }
+    }
+    levels
+}
+
+struct PredictiveCollisionStrategy;
+
+impl CollisionStrategy for PredictiveCollisionStrategy {
+    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
+        let (cell, tick) = first_conflict(a, b)?;
+        debug!(
+            "Agents {} and {} predicted to meet at ({}, {}) in {} ticks",
+            a.id, b.id, cell.x, cell.y, tick
+        );
+        Some(CollisionEvent {
+            agent1_id: a.id,
+            agent2_id: b.id,
+            collision_type: if tick == 0 {
+                CollisionType::Direct
+            } else {
+                CollisionType::Proximity
+            },
+            collision_point: cell,
+        })
+    }
+
+    fn name(&self) -> &str {
+        "Predictive"
+    }
+}
+
+struct GridCollisionStrategy;
+
+impl CollisionStrategy for GridCollisionStrategy {
+    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
+        if a.current_point == b.current_point {
+            return Some(CollisionEvent {
+                agent1_id: a.id,
+                agent2_id: b.id,
+                collision_type: CollisionType::Direct,
+                collision_point: a.current_point,
+            });
+        }
+
+        let a_radius: HashSet<Node> = a.collision_radius.iter().copied().collect();
+
+        for &node in &b.collision_radius {
+            if a_radius.contains(&node) {
+                return Some(CollisionEvent {
+                    agent1_id: a.id,
+                    agent2_id: b.id,
+                    collision_type: CollisionType::Proximity,
+                    collision_point: node,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn name(&self) -> &str {
+        "Grid-based"
+    }
+}
+
+struct OrcaCollisionStrategy;
+
+impl CollisionStrategy for OrcaCollisionStrategy {
+    fn detect(&self, a: &Agent, b: &Agent) -> Option<CollisionEvent> {
+        let dist = a.position.distance(b.position);
+        let min_dist = a.radius + b.radius;
+
+        if dist < min_dist {
+            Some(CollisionEvent {
+                agent1_id: a.id,
+                agent2_id: b.id,
+                collision_type: CollisionType::Direct,
+                collision_point: Node::from_pixels(a.position),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &str {
+        "ORCA"
+    }
+}
+
+struct CollisionLogger;
+
+impl Subscriber for CollisionLogger {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        match event {
+            SimEvent::Collision(event) => {
+                if let CollisionType::Direct = event.collision_type {
+                    warn!(
+                        "DIRECT COLLISION: agents {} and {} at ({}, {})",
+                        event.agent1_id,
+                        event.agent2_id,
+                        event.collision_point.x,
+                        event.collision_point.y,
+                    );
+                }
+            }
+            SimEvent::Arrival(id) => debug!("Agent {} arrived", id),
+            SimEvent::RunCompleted {
+                run,
+                arrived,
+                agents,
+                collisions,
+                reroutes,
+                makespan,
+                sum_of_costs,
+            } => info!(
+                "All agents arrived ({}): {}/{} agents, {} collisions, {} reroutes, makespan {}, sum of costs {}",
+                run, arrived, agents, collisions, reroutes, makespan, sum_of_costs
+            ),
+            _ => {}
+        }
+    }
+
+    fn id(&self) -> &str {
+        "logger"
+    }
+}
+
+struct Toast {
+    text: String,
+    color: u32,
+    shown: Instant,
+}
+
+// In-window notices, logged as well; the oldest is dropped once MAX_TOASTS are queued.
+// Anything published as SimEvent::Notice ends up here.
+struct ToastBoard {
+    toasts: RefCell<VecDeque<Toast>>,
+}
+
+impl ToastBoard {
+    fn new() -> Self {
+        ToastBoard {
+            toasts: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    fn push(&self, text: String, color: u32) {
+        let mut toasts = self.toasts.borrow_mut();
+        if toasts.len() == MAX_TOASTS {
+            toasts.pop_front();
+        }
+        toasts.push_back(Toast {
+            text: text.replace('—', "-"),
+            color,
+            shown: Instant::now(),
+        });
+    }
+}
+
+impl Subscriber for ToastBoard {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        match event {
+            SimEvent::Collision(event) => {
+                if let CollisionType::Direct = event.collision_type {
+                    self.push(
+                        format!(
+                            "Collision: agents {} and {} at ({}, {})",
+                            event.agent1_id,
+                            event.agent2_id,
+                            event.collision_point.x,
+                            event.collision_point.y
+                        ),
+                        RED,
+                    );
+                }
+            }
+            SimEvent::Notice { level, text } => {
+                log::log!(*level, "{}", text);
+                let color = match level {
+                    log::Level::Error => RED,
+                    log::Level::Warn => ORANGE,
+                    _ => WHITE,
+                };
+                self.push(text.clone(), color);
+            }
+            _ => {}
+        }
+    }
+
+    fn id(&self) -> &str {
+        "toasts"
+    }
+}
+
+#[cfg(feature = "sound")]
+const BEEP_INTERVAL: Duration = Duration::from_millis(150);
+
+#[cfg(feature = "sound")]
+struct SoundObserver {
+    last_beep: Cell<Option<Instant>>,
+}
+
+#[cfg(feature = "sound")]
+impl SoundObserver {
+    fn new() -> Self {
+        SoundObserver {
+            last_beep: Cell::new(None),
+        }
+    }
+
+    fn beep(&self, count: usize) {
+        use std::io::Write;
+        if self
+            .last_beep
+            .get()
+            .is_some_and(|t| t.elapsed() < BEEP_INTERVAL)
+        {
+            return;
+        }
+        self.last_beep.set(Some(Instant::now()));
+        let mut out = std::io::stdout();
+        let _ = out.write_all("\x07".repeat(count).as_bytes());
+        let _ = out.flush();
+    }
+}
+
+#[cfg(feature = "sound")]
+impl Subscriber for SoundObserver {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        match event {
+            SimEvent::Collision(event) => match event.collision_type {
+                CollisionType::Proximity => self.beep(1),
+                CollisionType::Direct => self.beep(2),
+            },
+            SimEvent::Arrival(_) => self.beep(3),
+            _ => {}
+        }
+    }
+
+    fn id(&self) -> &str {
+        "sound"
+    }
+}
+
+#[cfg(feature = "notify")]
+const NOTIFY_COLLISION_THRESHOLD: usize = 25;
+
+#[cfg(feature = "notify")]
+struct DesktopNotifier {
+    direct_collisions: Cell<usize>,
+    alerted: Cell<bool>,
+}
+
+#[cfg(feature = "notify")]
+impl DesktopNotifier {
+    fn new() -> Self {
+        DesktopNotifier {
+            direct_collisions: Cell::new(0),
+            alerted: Cell::new(false),
+        }
+    }
+
+    fn send(summary: &str, body: &str) {
+        if let Err(e) = notify_rust::Notification::new()
+            .appname("trabalho-11")
+            .summary(summary)
+            .body(body)
+            .show()
+        {
+            warn!("Desktop notification failed: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "notify")]
+impl Subscriber for DesktopNotifier {
+    fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+        match event {
+            SimEvent::Collision(event) => {
+                if let CollisionType::Direct = event.collision_type {
+                    let count = self.direct_collisions.get() + 1;
+                    self.direct_collisions.set(count);
+                    if count > NOTIFY_COLLISION_THRESHOLD && !self.alerted.replace(true) {
+                        Self::send(
+                            "Collision threshold exceeded",
+                            &format!("{} direct collisions in the current run", count),
+                        );
+                    }
+                }
+            }
+            SimEvent::RunCompleted {
+                run,
+                arrived,
+                agents,
+                collisions,
+                reroutes,
+                ..
+            } => {
+                Self::send(
+                    "Run finished",
+                    &format!(
+                        "{}: {}/{} agents arrived, {} collisions, {} reroutes",
+                        run, arrived, agents, collisions, reroutes
+                    ),
+                );
+                self.direct_collisions.set(0);
+                self.alerted.set(false);
+            }
+            _ => {}
+        }
+    }
+
+    fn id(&self) -> &str {
+        "notifier"
+    }
+}
+
+// Turns near misses into reroute requests for both agents involved.
+struct CollisionAssistant;
+
+#[derive(Debug, Clone)]
+struct RerouteRequest {
+    agent_id: usize,
+    other_id: usize,
+    avoid_point: Node,
+}
+
+impl Subscriber for CollisionAssistant {
+    fn on_event(&self, event: &SimEvent, bus: &EventBus) {
+        if let SimEvent::Collision(event) = event
+            && let CollisionType::Proximity = event.collision_type
+        {
+            bus.publish(SimEvent::Reroute(RerouteRequest {
+                agent_id: event.agent1_id,
+                other_id: event.agent2_id,
+                avoid_point: event.collision_point,
+            }));
+            bus.publish(SimEvent::Reroute(RerouteRequest {
+                agent_id: event.agent2_id,
+                other_id: event.agent1_id,
+                avoid_point: event.collision_point,
+            }));
+        }
+    }
+
+    fn id(&self) -> &str {
+        "assistant"
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct State {
+    cost: i32,
+    position: Node,
+}
+
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Scaled by the cheapest step (a prefer zone) so it never overestimates.
+fn heuristic(a: Node, b: Node) -> i32 {
+    ((a.x - b.x).abs() + (a.y - b.y).abs()) * (STEP_COST - PREFER_ZONE_BONUS).max(MIN_STEP_COST)
+}
+
+fn heuristic_multi(a: Node, goals: &[Node]) -> i32 {
+    goals.iter().map(|&g| heuristic(a, g)).min().unwrap_or(0)
+}
+
+// Every cell an agent standing on origin could walk to, honouring one-way edges and gates.
+fn reachable_cells(origin: Node, grid: &Grid, movement: &dyn MovementStrategy) -> HashSet<Node> {
+    let mut seen = HashSet::from([origin]);
+    let mut stack = vec![origin];
+    while let Some(node) = stack.pop() {
+        for neighbor in movement.passable_neighbors(node, grid) {
+            if !grid.is_blocked(neighbor) && seen.insert(neighbor) {
+                stack.push(neighbor);
+            }
+        }
+    }
+    seen
+}
+
+// Reverse Dijkstra: the cost a walker at each cell would pay to reach goal, edges walked backwards.
+fn distance_field(goal: Node, grid: &Grid, movement: &dyn MovementStrategy) -> HashMap<Node, i32> {
+    let mut costs = HashMap::from([(goal, 0)]);
+    let mut open = BinaryHeap::from([State {
+        cost: 0,
+        position: goal,
+    }]);
+    while let Some(State { cost, position }) = open.pop() {
+        if cost > costs[&position] {
+            continue;
+        }
+        let entered = cost + grid.step_cost(position);
+        for from in movement.get_neighbors(position) {
+            if grid.is_blocked(from) || !grid.allows_move(from, position) {
+                continue;
+            }
+            if entered < *costs.get(&from).unwrap_or(&i32::MAX) {
+                costs.insert(from, entered);
+                open.push(State {
+                    cost: entered,
+                    position: from,
+                });
+            }
+        }
+    }
+    costs
+}
+
+fn a_star(
+    start: Node,
+    goal: Node,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<Node>> {
+    a_star_inner(start, &[goal], grid, &HashSet::new(), None, movement)
+}
+
+fn a_star_multi(
+    start: Node,
+    goals: &[Node],
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<Node>> {
+    if goals.is_empty() {
+        return None;
+    }
+    a_star_inner(start, goals, grid, &HashSet::new(), None, movement)
+}
+
+fn a_star_with_avoidance(
+    start: Node,
+    goal: Node,
+    grid: &Grid,
+    avoid: &HashSet<Node>,
+    preferred_dir: Option<Node>,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<Node>> {
+    a_star_inner(start, &[goal], grid, avoid, preferred_dir, movement)
+}
+
+fn a_star_inner(
+    start: Node,
+    goals: &[Node],
+    grid: &Grid,
+    avoid: &HashSet<Node>,
+    preferred_dir: Option<Node>,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<Node>> {
+    let mut open = BinaryHeap::new();
+    let mut came_from = HashMap::new();
+    let mut g_score: HashMap<Node, i32> = HashMap::new();
+
+    g_score.insert(start, 0);
+    open.push(State {
+        cost: heuristic_multi(start, goals),
+        position: start,
+    });
+
+    while let Some(State { position, .. }) = open.pop() {
+        if goals.contains(&position) {
+            let mut path = vec![position];
+            let mut cur = position;
+            while let Some(&prev) = came_from.get(&cur) {
+                path.push(prev);
+                cur = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        let base_g = *g_score.get(&position).unwrap_or(&i32::MAX);
+
+        for neighbor in movement.passable_neighbors(position, grid) {
+            if grid.is_blocked(neighbor) || avoid.contains(&neighbor) {
+                continue;
+            }
+
+            let mut tentative_g = base_g.saturating_add(grid.step_cost(neighbor));
+
+            if let Some(pref) = preferred_dir {
+                let mv = move_dir(position, neighbor);
+                if mv == pref {
+                    tentative_g -= 4 * STEP_COST;
+                } else if mv == negate(pref) {
+                    tentative_g += 8 * STEP_COST;
+                } else if dot(mv, pref) == 0 {
+                    tentative_g -= STEP_COST;
+                }
+            }
+
+            if tentative_g < *g_score.get(&neighbor).unwrap_or(&i32::MAX) {
+                came_from.insert(neighbor, position);
+                g_score.insert(neighbor, tentative_g);
+                open.push(State {
+                    cost: tentative_g + heuristic_multi(neighbor, goals),
+                    position: neighbor,
+                });
+            }
+        }
+    }
+    None
+}
+
+// Turn penalties are per 45 degrees of heading change.
+const TURN_PENALTIES: [i32; 3] = [1, 2, 4];
+
+fn turn_steps(from: Node, to: Node) -> i32 {
+    if is_zero_dir(from) || is_zero_dir(to) {
+        return 0;
+    }
+    let octant = |d: Node| {
+        [
+            (1, 0),
+            (1, 1),
+            (0, 1),
+            (-1, 1),
+            (-1, 0),
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+        ]
+        .iter()
+        .position(|&(x, y)| x == d.x.signum() && y == d.y.signum())
+        .unwrap_or(0) as i32
+    };
+    let diff = (octant(from) - octant(to)).rem_euclid(8);
+    diff.min(8 - diff)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct KinematicState {
+    cost: i32,
+    position: Node,
+    heading: Node,
+}
+
+impl Ord for KinematicState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+impl PartialOrd for KinematicState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A* over (cell, heading) so that every change of direction costs extra.
+fn a_star_kinematic(
+    start: Node,
+    heading: Node,
+    goal: Node,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+    turn_penalty: i32,
+) -> Option<Vec<Node>> {
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<(Node, Node), (Node, Node)> = HashMap::new();
+    let mut g_score: HashMap<(Node, Node), i32> = HashMap::new();
+
+    g_score.insert((start, heading), 0);
+    open.push(KinematicState {
+        cost: heuristic(start, goal),
+        position: start,
+        heading,
+    });
+
+    while let Some(KinematicState {
+        position, heading, ..
+    }) = open.pop()
+    {
+        if position == goal {
+            let mut path = vec![position];
+            let mut cur = (position, heading);
+            while let Some(&prev) = came_from.get(&cur) {
+                path.push(prev.0);
+                cur = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        let base_g = *g_score.get(&(position, heading)).unwrap_or(&i32::MAX);
+
+        for neighbor in movement.passable_neighbors(position, grid) {
+            if grid.is_blocked(neighbor) {
+                continue;
+            }
+            let dir = move_dir(position, neighbor);
+            let tentative_g = base_g
+                .saturating_add(grid.step_cost(neighbor))
+                .saturating_add(turn_steps(heading, dir) * turn_penalty);
+            let key = (neighbor, dir);
+            if tentative_g < *g_score.get(&key).unwrap_or(&i32::MAX) {
+                came_from.insert(key, (position, heading));
+                g_score.insert(key, tentative_g);
+                open.push(KinematicState {
+                    cost: tentative_g + heuristic(neighbor, goal),
+                    position: neighbor,
+                    heading: dir,
+                });
+            }
+        }
+    }
+    None
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct TimedState {
+    cost: i32,
+    position: Node,
+    tick: usize,
+}
+
+impl Ord for TimedState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+impl PartialOrd for TimedState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Schedule {
+    timelines: Vec<Vec<Node>>,
+}
+
+impl Schedule {
+    fn from_agents(agents: &[Agent], exclude: usize) -> Self {
+        let timelines = agents
+            .iter()
+            .filter(|a| a.id != exclude)
+            .map(|a| match &a.path {
+                Some(path) if a.path_index < path.len() => path[a.path_index..].to_vec(),
+                _ => vec![a.current_point],
+            })
+            .collect();
+        Schedule { timelines }
+    }
+
+    fn horizon(&self) -> usize {
+        self.timelines.iter().map(|t| t.len()).max().unwrap_or(0)
+    }
+
+    fn at(timeline: &[Node], tick: usize) -> Node {
+        timeline[tick.min(timeline.len() - 1)]
+    }
+
+    fn occupied(&self, node: Node, tick: usize) -> bool {
+        self.timelines.iter().any(|t| Self::at(t, tick) == node)
+    }
+
+    fn blocks_move(&self, from: Node, to: Node, tick: usize) -> bool {
+        self.timelines.iter().any(|t| {
+            Self::at(t, tick + 1) == to
+                || (Self::at(t, tick) == to && Self::at(t, tick + 1) == from)
+        })
+    }
+}
+
+fn a_star_timed(
+    start: Node,
+    goal: Node,
+    grid: &Grid,
+    schedule: &Schedule,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<(Node, usize)>> {
+    let horizon = schedule.horizon();
+    let max_tick = horizon + ROWS * COLUMNS;
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<(Node, usize), (Node, usize)> = HashMap::new();
+    let mut g_score: HashMap<(Node, usize), i32> = HashMap::new();
+    let mut closed: HashSet<(Node, usize)> = HashSet::new();
+
+    g_score.insert((start, 0), 0);
+    open.push(TimedState {
+        cost: heuristic(start, goal),
+        position: start,
+        tick: 0,
+    });
+
+    while let Some(TimedState { position, tick, .. }) = open.pop() {
+        if !closed.insert((position, tick)) {
+            continue;
+        }
+
+        if position == goal && (tick..=horizon).all(|t| !schedule.occupied(goal, t)) {
+            let mut path = vec![(position, tick)];
+            let mut cur = (position, tick);
+            while let Some(&prev) = came_from.get(&cur) {
+                path.push(prev);
+                cur = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        if tick >= max_tick {
+            continue;
+        }
+
+        let base_g = g_score[&(position, tick)];
+        let mut moves = movement.passable_neighbors(position, grid);
+        moves.push(position);
+
+        for next in moves {
+            if grid.is_blocked_at(next, grid.tick + tick + 1)
+                || closed.contains(&(next, tick + 1))
+                || schedule.blocks_move(position, next, tick)
+            {
+                continue;
+            }
+            let tentative_g = base_g + grid.step_cost(next);
+            if tentative_g < *g_score.get(&(next, tick + 1)).unwrap_or(&i32::MAX) {
+                came_from.insert((next, tick + 1), (position, tick));
+                g_score.insert((next, tick + 1), tentative_g);
+                open.push(TimedState {
+                    cost: tentative_g + heuristic(next, goal),
+                    position: next,
+                    tick: tick + 1,
+                });
+            }
+        }
+    }
+    None
+}
+
+trait TriggerPolicy {
+    fn should_trigger(&self, request: &RerouteRequest, agents: &[Agent]) -> bool;
+    fn name(&self) -> &str;
+}
+
+struct AlwaysTrigger;
+
+impl TriggerPolicy for AlwaysTrigger {
+    fn should_trigger(&self, _request: &RerouteRequest, _agents: &[Agent]) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "Always"
+    }
+}
+
+const TRIGGER_STEPS: usize = 3;
+
+struct WithinStepsTrigger {
+    steps: usize,
+}
+
+impl TriggerPolicy for WithinStepsTrigger {
+    fn should_trigger(&self, request: &RerouteRequest, agents: &[Agent]) -> bool {
+        let agent = &agents[request.agent_id];
+        let ahead = agent.path.as_ref().and_then(|path| {
+            path.iter()
+                .skip(agent.path_index)
+                .position(|&n| n == request.avoid_point)
+        });
+        let steps = ahead.unwrap_or_else(|| {
+            ((agent.current_point.x - request.avoid_point.x).abs()
+                + (agent.current_point.y - request.avoid_point.y).abs()) as usize
+        });
+        steps <= self.steps
+    }
+
+    fn name(&self) -> &str {
+        "Within steps"
+    }
+}
+
+const TRIGGER_PROBABILITY: f64 = 0.5;
+
+struct ProbabilityTrigger {
+    probability: f64,
+}
+
+impl TriggerPolicy for ProbabilityTrigger {
+    fn should_trigger(&self, _request: &RerouteRequest, _agents: &[Agent]) -> bool {
+        rand::rng().random_bool(self.probability)
+    }
+
+    fn name(&self) -> &str {
+        "Probability"
+    }
+}
+
+struct PriorityTrigger;
+
+impl TriggerPolicy for PriorityTrigger {
+    fn should_trigger(&self, request: &RerouteRequest, agents: &[Agent]) -> bool {
+        let rank = |id: usize| (agents.get(id).map_or(0, |a| a.priority), usize::MAX - id);
+        rank(request.agent_id) < rank(request.other_id)
+    }
+
+    fn name(&self) -> &str {
+        "Priority"
+    }
+}
+
+const AVOID_RADIUS: i32 = 1;
+const MAX_AVOID_RADIUS: i32 = 4;
+
+struct RerouteSettings {
+    time_aware: bool,
+    trigger: Box<dyn TriggerPolicy>,
+    avoid_radius: i32,
+    turn_penalty: Option<i32>,
+    min_level: WarningLevel,
+}
+
+impl RerouteSettings {
+    fn new() -> Self {
+        RerouteSettings {
+            time_aware: false,
+            trigger: Box::new(AlwaysTrigger),
+            avoid_radius: AVOID_RADIUS,
+            turn_penalty: None,
+            min_level: WarningLevel::Info,
+        }
+    }
+
+    // A pair whose paths no longer meet only rates as info.
+    fn urgent_enough(&self, request: &RerouteRequest, agents: &[Agent], is_orca: bool) -> bool {
+        let level = match (agents.get(request.agent_id), agents.get(request.other_id)) {
+            (Some(a), Some(b)) => warning_level(a, b, is_orca).unwrap_or(WarningLevel::Info),
+            _ => WarningLevel::Info,
+        };
+        level >= self.min_level
+    }
+}
+
+const BATTERY_CAPACITY: u32 = 30;
+
+fn recharge(battery: u32, from: Node, to: Node, grid: &Grid) -> Option<u32> {
+    if from == to {
+        return Some(battery);
+    }
+    if battery == 0 {
+        return None;
+    }
+    if grid.chargers.contains(&to) {
+        Some(BATTERY_CAPACITY)
+    } else {
+        Some(battery - 1)
+    }
+}
+
+fn path_is_feasible(path: &[Node], battery: u32, grid: &Grid) -> bool {
+    let mut charge = battery;
+    for w in path.windows(2) {
+        match recharge(charge, w[0], w[1], grid) {
+            Some(c) => charge = c,
+            None => return false,
+        }
+    }
+    true
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct BatteryState {
+    cost: i32,
+    position: Node,
+    battery: u32,
+}
+
+impl Ord for BatteryState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+impl PartialOrd for BatteryState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn a_star_battery(
+    start: Node,
+    battery: u32,
+    goal: Node,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+) -> Option<Vec<Node>> {
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<(Node, u32), (Node, u32)> = HashMap::new();
+    let mut g_score: HashMap<(Node, u32), i32> = HashMap::new();
+
+    g_score.insert((start, battery), 0);
+    open.push(BatteryState {
+        cost: heuristic(start, goal),
+        position: start,
+        battery,
+    });
+
+    while let Some(BatteryState {
+        cost,
+        position,
+        battery,
+    }) = open.pop()
+    {
+        let base_g = g_score[&(position, battery)];
+        if cost > base_g + heuristic(position, goal) {
+            continue;
+        }
+
+        if position == goal {
+            let mut path = vec![position];
+            let mut cur = (position, battery);
+            while let Some(&prev) = came_from.get(&cur) {
+                path.push(prev.0);
+                cur = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for neighbor in movement.passable_neighbors(position, grid) {
+            if grid.is_blocked(neighbor) {
+                continue;
+            }
+            let Some(next_battery) = recharge(battery, position, neighbor, grid) else {
+                continue;
+            };
+            let tentative_g = base_g + grid.step_cost(neighbor);
+            let key = (neighbor, next_battery);
+            if tentative_g < *g_score.get(&key).unwrap_or(&i32::MAX) {
+                came_from.insert(key, (position, battery));
+                g_score.insert(key, tentative_g);
+                open.push(BatteryState {
+                    cost: tentative_g + heuristic(neighbor, goal),
+                    position: neighbor,
+                    battery: next_battery,
+                });
+            }
+        }
+    }
+    None
+}
+
+// Agents whose goal was out of range, with the cell and goal they failed from. A failure
+// stands until the agent moves, its goal changes or the layout revision moves on.
+#[derive(Default)]
+struct BatteryFailures {
+    revision: Option<usize>,
+    failed: HashMap<usize, (Node, Node)>,
+}
+
+impl BatteryFailures {
+    fn forget_if_edited(&mut self, revision: usize) {
+        if self.revision.is_some_and(|r| r != revision) {
+            self.revision = None;
+            self.failed.clear();
+        }
+    }
+
+    fn known(&self, agent: &Agent, goal: Node) -> bool {
+        self.failed.get(&agent.id) == Some(&(agent.current_point, goal))
+    }
+
+    fn record(&mut self, agent: &Agent, goal: Node, revision: usize) {
+        self.revision.get_or_insert(revision);
+        self.failed.insert(agent.id, (agent.current_point, goal));
+    }
+}
+
+fn enforce_battery_plans(
+    agents: &mut [Agent],
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+    failures: &mut BatteryFailures,
+    revision: usize,
+) {
+    failures.forget_if_edited(revision);
+    for agent in agents.iter_mut() {
+        let (Some(path), Some(goal)) = (&agent.path, agent.end_point) else {
+            continue;
+        };
+        if path_is_feasible(&path[agent.path_index..], agent.battery, grid)
+            || failures.known(agent, goal)
+        {
+            continue;
+        }
+        match a_star_battery(agent.current_point, agent.battery, goal, grid, movement) {
+            Some(new_path) => {
+                failures.failed.remove(&agent.id);
+                agent.path = Some(new_path);
+                agent.path_index = 0;
+                agent.refresh_cache();
+            }
+            None => {
+                warn!(
+                    "Agent {} cannot reach its goal on its remaining charge",
+                    agent.id
+                );
+                failures.record(agent, goal, revision);
+            }
+        }
+    }
+}
+
+const FOOTPRINT_RADIUS: f32 = 15.0;
+const FOOTPRINT_SIDES: usize = 8;
+const OBSTACLE_MAX_RADIUS: f32 = 50.0;
+const CONTINUOUS_STEP: f32 = CELL_WIDTH as f32;
+const GEOM_EPS: f32 = 1e-3;
+
+type Polygon = Vec<Vec2>;
+
+fn regular_polygon(center: Vec2, radius: f32, sides: usize) -> Polygon {
+    (0..sides)
+        .map(|i| {
+            let angle = i as f32 / sides as f32 * std::f32::consts::TAU;
+            center + Vec2::new(angle.cos(), angle.sin()) * radius
+        })
+        .collect()
+}
+
+// Same star-shaped random obstacles as trabalho-4.
+fn random_obstacle(center: Vec2) -> Polygon {
+    let mut rng = rand::rng();
+    let sides = rng.random_range(3..=8);
+    (0..sides)
+        .map(|i| {
+            let angle = i as f32 / sides as f32 * std::f32::consts::TAU;
+            let radius = rng.random_range(10.0..=OBSTACLE_MAX_RADIUS);
+            center + Vec2::new(angle.cos(), angle.sin()) * radius
+        })
+        .collect()
+}
+
+// Obstacle grown by the robot reflected about its centroid (configuration-space obstacle).
+fn minkowski_sum(obstacle: &[Vec2], robot: &[Vec2]) -> Polygon {
+    let points =
+        |poly: &[Vec2]| -> geometry::Polygon<f32> { poly.iter().map(|&v| v.into()).collect() };
+    geometry::minkowski_sum(&points(obstacle), &points(robot))
+        .into_iter()
+        .map(Vec2::from)
+        .collect()
+}
+
+fn polygon_orientation(poly: &[Vec2]) -> f32 {
+    let n = poly.len();
+    (0..n)
+        .map(|i| poly[i].perp_dot(poly[(i + 1) % n]))
+        .sum::<f32>()
+        .signum()
+}
+
+fn strictly_inside_convex(p: Vec2, poly: &[Vec2]) -> bool {
+    let n = poly.len();
+    if n < 3 {
+        return false;
+    }
+    let orient = polygon_orientation(poly);
+    (0..n).all(|i| {
+        let edge = poly[(i + 1) % n] - poly[i];
+        orient * edge.perp_dot(p - poly[i]) > GEOM_EPS * edge.length()
+    })
+}
+
+// Cyrus-Beck clip: true when the segment runs through the polygon interior,
+// grazing an edge or a vertex does not count.
+fn segment_crosses_convex(a: Vec2, b: Vec2, poly: &[Vec2]) -> bool {
+    let n = poly.len();
+    if n < 3 {
+        return false;
+    }
+    let orient = polygon_orientation(poly);
+    let d = b - a;
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+    for i in 0..n {
+        let edge = poly[(i + 1) % n] - poly[i];
+        let num = orient * edge.perp_dot(a - poly[i]) - GEOM_EPS * edge.length();
+        let den = orient * edge.perp_dot(d);
+        if den.abs() < f32::EPSILON {
+            if num <= 0.0 {
+                return false;
+            }
+            continue;
+        }
+        let t = -num / den;
+        if den > 0.0 {
+            t0 = t0.max(t);
+        } else {
+            t1 = t1.min(t);
+        }
+        if t0 >= t1 {
+            return false;
+        }
+    }
+    t1 - t0 > 1e-6
+}
+
+struct ContinuousWorld {
+    obstacles: Vec<Polygon>,
+    inflated: Vec<Polygon>,
+    footprint: Polygon,
+    graph: Roadmap,
+    revision: usize,
+}
+
+impl ContinuousWorld {
+    fn new() -> Self {
+        ContinuousWorld {
+            obstacles: Vec::new(),
+            inflated: Vec::new(),
+            footprint: regular_polygon(Vec2::ZERO, FOOTPRINT_RADIUS, FOOTPRINT_SIDES),
+            graph: Roadmap::new(),
+            revision: 0,
+        }
+    }
+
+    fn add_obstacle(&mut self, polygon: Polygon) {
+        self.inflated.push(minkowski_sum(&polygon, &self.footprint));
+        self.obstacles.push(polygon);
+        self.graph = Roadmap::visibility(self);
+        self.revision += 1;
+        debug!(
+            "Visibility graph: {} vertices, {} edges",
+            self.graph.points.len(),
+            self.graph.edge_count()
+        );
+    }
+
+    fn clear(&mut self) {
+        self.obstacles.clear();
+        self.inflated.clear();
+        self.graph = Roadmap::new();
+        self.revision += 1;
+    }
+
+    fn is_free(&self, p: Vec2) -> bool {
+        !self
+            .inflated
+            .iter()
+            .any(|poly| strictly_inside_convex(p, poly))
+    }
+
+    fn segment_free(&self, a: Vec2, b: Vec2) -> bool {
+        !self
+            .inflated
+            .iter()
+            .any(|poly| segment_crosses_convex(a, b, poly))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct GraphState {
+    cost: f32,
+    node: usize,
+}
+
+impl Eq for GraphState {}
+
+impl Ord for GraphState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+impl PartialOrd for GraphState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Roadmap {
+    points: Vec<Vec2>,
+    edges: Vec<Vec<(usize, f32)>>,
+}
+
+impl Roadmap {
+    fn new() -> Self {
+        Roadmap {
+            points: Vec::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    // Free inflated-obstacle vertices joined wherever the segment between them is clear.
+    fn visibility(world: &ContinuousWorld) -> Self {
+        let points: Vec<Vec2> = world
+            .inflated
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&p| world.is_free(p))
+            .collect();
+        let mut edges = vec![Vec::new(); points.len()];
+        for i in 0..points.len() {
+            for j in (i + 1)..points.len() {
+                if world.segment_free(points[i], points[j]) {
+                    let d = points[i].distance(points[j]);
+                    edges[i].push((j, d));
+                    edges[j].push((i, d));
+                }
+            }
+        }
+        Roadmap { points, edges }
+    }
+
+    // Random free samples, each tied to its k nearest neighbours within the radius.
+    fn prm(world: &ContinuousWorld, samples: usize, radius: f32, rng: &mut impl Rng) -> Self {
+        let mut points = Vec::with_capacity(samples);
+        for _ in 0..samples * PRM_MAX_ATTEMPTS {
+            if points.len() == samples {
+                break;
+            }
+            let p = Vec2::new(
+                rng.random_range(0.0..WIDTH as f32),
+                rng.random_range(0.0..HEIGHT as f32),
+            );
+            if world.is_free(p) {
+                points.push(p);
+            }
+        }
+        let mut edges: Vec<Vec<(usize, f32)>> = vec![Vec::new(); points.len()];
+        for i in 0..points.len() {
+            let mut near: Vec<(usize, f32)> = (0..points.len())
+                .filter(|&j| j != i)
+                .map(|j| (j, points[i].distance(points[j])))
+                .filter(|&(_, d)| d <= radius)
+                .collect();
+            near.sort_by(|a, b| a.1.total_cmp(&b.1));
+            for (j, d) in near.into_iter().take(PRM_NEIGHBORS) {
+                if edges[i].iter().any(|&(k, _)| k == j)
+                    || !world.segment_free(points[i], points[j])
+                {
+                    continue;
+                }
+                edges[i].push((j, d));
+                edges[j].push((i, d));
+            }
+        }
+        Roadmap { points, edges }
+    }
+
+    fn segments(&self) -> Vec<(Vec2, Vec2)> {
+        self.edges
+            .iter()
+            .enumerate()
+            .flat_map(|(i, edges)| {
+                edges
+                    .iter()
+                    .filter(move |&&(j, _)| j > i)
+                    .map(move |&(j, _)| (self.points[i], self.points[j]))
+            })
+            .collect()
+    }
+
+    fn edge_count(&self) -> usize {
+        self.edges.iter().map(Vec::len).sum::<usize>() / 2
+    }
+
+    // Start and goal join the graph as two temporary nodes at the end, linked to
+    // every clear node within link_radius.
+    fn search(
+        &self,
+        world: &ContinuousWorld,
+        start: Vec2,
+        goal: Vec2,
+        use_heuristic: bool,
+        link_radius: f32,
+    ) -> Option<Vec<Vec2>> {
+        if !world.is_free(start) || !world.is_free(goal) {
+            return None;
+        }
+        let n = self.points.len();
+        let (start_id, goal_id) = (n, n + 1);
+        let point = |i: usize| match i {
+            i if i == start_id => start,
+            i if i == goal_id => goal,
+            i => self.points[i],
+        };
+        let links = |from: Vec2| -> Vec<(usize, f32)> {
+            (0..n)
+                .map(|i| (i, from.distance(self.points[i])))
+                .filter(|&(i, d)| d <= link_radius && world.segment_free(from, self.points[i]))
+                .collect()
+        };
+        let start_links = links(start);
+        let goal_links: HashMap<usize, f32> = links(goal).into_iter().collect();
+        let h = |i: usize| {
+            if use_heuristic {
+                point(i).distance(goal)
+            } else {
+                0.0
+            }
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<usize, usize> = HashMap::new();
+        let mut g_score = vec![f32::MAX; n + 2];
+        g_score[start_id] = 0.0;
+        open.push(GraphState {
+            cost: h(start_id),
+            node: start_id,
+        });
+
+        while let Some(GraphState { node, .. }) = open.pop() {
+            if node == goal_id {
+                let mut path = vec![goal];
+                let mut cur = goal_id;
+                while let Some(&prev) = came_from.get(&cur) {
+                    path.push(point(prev));
+                    cur = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let mut neighbors = if node == start_id {
+                start_links.clone()
+            } else {
+                self.edges[node].clone()
+            };
+            if node == start_id && world.segment_free(start, goal) {
+                neighbors.push((goal_id, start.distance(goal)));
+            } else if let Some(&d) = goal_links.get(&node) {
+                neighbors.push((goal_id, d));
+            }
+            for (next, d) in neighbors {
+                let tentative = g_score[node] + d;
+                if tentative < g_score[next] {
+                    g_score[next] = tentative;
+                    came_from.insert(next, node);
+                    open.push(GraphState {
+                        cost: tentative + h(next),
+                        node: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+trait ContinuousPlanner {
+    fn plan(&self, start: Vec2, goal: Vec2, world: &ContinuousWorld) -> Option<Vec<Vec2>>;
+    fn name(&self) -> &str;
+    fn overlay(&self, world: &ContinuousWorld) -> Vec<(Vec2, Vec2)>;
+}
+
+struct VisibilityAStar;
+
+impl ContinuousPlanner for VisibilityAStar {
+    fn plan(&self, start: Vec2, goal: Vec2, world: &ContinuousWorld) -> Option<Vec<Vec2>> {
+        world.graph.search(world, start, goal, true, f32::INFINITY)
+    }
+
+    fn name(&self) -> &str {
+        "Visibility A*"
+    }
+
+    fn overlay(&self, world: &ContinuousWorld) -> Vec<(Vec2, Vec2)> {
+        world.graph.segments()
+    }
+}
+
+struct VisibilityDijkstra;
+
+impl ContinuousPlanner for VisibilityDijkstra {
+    fn plan(&self, start: Vec2, goal: Vec2, world: &ContinuousWorld) -> Option<Vec<Vec2>> {
+        world.graph.search(world, start, goal, false, f32::INFINITY)
+    }
+
+    fn name(&self) -> &str {
+        "Visibility Dijkstra"
+    }
+
+    fn overlay(&self, world: &ContinuousWorld) -> Vec<(Vec2, Vec2)> {
+        world.graph.segments()
+    }
+}
+
+// The roadmap is sampled lazily and rebuilt whenever the world's obstacles change.
+// Samples come from the planner's own generator, so a seeded one gives the same roadmaps.
+struct PrmPlanner {
+    samples: usize,
+    radius: f32,
+    rng: RefCell<StdRng>,
+    roadmap: RefCell<Option<(usize, Roadmap)>>,
+}
+
+impl PrmPlanner {
+    fn new(samples: usize, radius: f32, rng: StdRng) -> Self {
+        PrmPlanner {
+            samples,
+            radius,
+            rng: RefCell::new(rng),
+            roadmap: RefCell::new(None),
+        }
+    }
+
+    fn with_roadmap<T>(&self, world: &ContinuousWorld, f: impl FnOnce(&Roadmap) -> T) -> T {
+        let mut cached = self.roadmap.borrow_mut();
+        if cached
+            .as_ref()
+            .is_none_or(|(rev, _)| *rev != world.revision)
+        {
+            let start_time = Instant::now();
+            let roadmap = Roadmap::prm(
+                world,
+                self.samples,
+                self.radius,
+                &mut *self.rng.borrow_mut(),
+            );
+            info!(
+                "PRM roadmap: {} nodes, {} edges built in {:.2} ms",
+                roadmap.points.len(),
+                roadmap.edge_count(),
+                start_time.elapsed().as_secs_f64() * 1000.0
+            );
+            *cached = Some((world.revision, roadmap));
+        }
+        f(&cached.as_ref().unwrap().1)
+    }
+}
+
+impl ContinuousPlanner for PrmPlanner {
+    fn plan(&self, start: Vec2, goal: Vec2, world: &ContinuousWorld) -> Option<Vec<Vec2>> {
+        self.with_roadmap(world, |roadmap| {
+            roadmap.search(world, start, goal, true, self.radius)
+        })
+    }
+
+    fn name(&self) -> &str {
+        "PRM"
+    }
+
+    fn overlay(&self, world: &ContinuousWorld) -> Vec<(Vec2, Vec2)> {
+        self.with_roadmap(world, Roadmap::segments)
+    }
+}
+
+struct RrtTree {
+    points: Vec<Vec2>,
+    parents: Vec<usize>,
+    costs: Vec<f32>,
+    goal: Option<usize>,
+}
+
+impl RrtTree {
+    fn grow(
+        world: &ContinuousWorld,
+        start: Vec2,
+        goal: Vec2,
+        star: bool,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut tree = RrtTree {
+            points: vec![start],
+            parents: vec![0],
+            costs: vec![0.0],
+            goal: None,
+        };
+        if !world.is_free(start) || !world.is_free(goal) {
+            return tree;
+        }
+        for _ in 0..RRT_ITERATIONS {
+            let sample = if rng.random_bool(RRT_GOAL_BIAS) {
+                goal
+            } else {
+                Vec2::new(
+                    rng.random_range(0.0..WIDTH as f32),
+                    rng.random_range(0.0..HEIGHT as f32),
+                )
+            };
+            let nearest = (0..tree.points.len())
+                .min_by(|&a, &b| {
+                    tree.points[a]
+                        .distance(sample)
+                        .total_cmp(&tree.points[b].distance(sample))
+                })
+                .unwrap();
+            let from = tree.points[nearest];
+            let dist = from.distance(sample);
+            if dist < GEOM_EPS {
+                continue;
+            }
+            let new = from + (sample - from) / dist * dist.min(RRT_STEP);
+            if !world.is_free(new) || !world.segment_free(from, new) {
+                continue;
+            }
+            let near: Vec<usize> = if star {
+                (0..tree.points.len())
+                    .filter(|&i| tree.points[i].distance(new) <= RRT_REWIRE_RADIUS)
+                    .filter(|&i| world.segment_free(tree.points[i], new))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let parent = near
+                .iter()
+                .copied()
+                .min_by(|&a, &b| {
+                    let ca = tree.costs[a] + tree.points[a].distance(new);
+                    let cb = tree.costs[b] + tree.points[b].distance(new);
+                    ca.total_cmp(&cb)
+                })
+                .unwrap_or(nearest);
+            let id = tree.points.len();
+            tree.points.push(new);
+            tree.parents.push(parent);
+            tree.costs
+                .push(tree.costs[parent] + tree.points[parent].distance(new));
+            for &j in &near {
+                let cost = tree.costs[id] + new.distance(tree.points[j]);
+                if cost < tree.costs[j] {
+                    tree.parents[j] = id;
+                    tree.refresh_costs(j, cost);
+                }
+            }
+
+            if tree.goal.is_none()
+                && new.distance(goal) <= RRT_STEP
+                && world.segment_free(new, goal)
+            {
+                tree.goal = Some(tree.points.len());
+                tree.points.push(goal);
+                tree.parents.push(id);
+                tree.costs.push(tree.costs[id] + new.distance(goal));
+                if !star {
+                    break;
+                }
+            }
+        }
+        tree
+    }
+
+    // Rewiring lowers a node's cost, so push the improvement down its subtree.
+    fn refresh_costs(&mut self, root: usize, cost: f32) {
+        let delta = self.costs[root] - cost;
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            self.costs[node] -= delta;
+            stack.extend((1..self.points.len()).filter(|&i| self.parents[i] == node && i != node));
+        }
+    }
+
+    fn path(&self) -> Option<Vec<Vec2>> {
+        let mut node = self.goal?;
+        let mut path = vec![self.points[node]];
+        while node != 0 {
+            node = self.parents[node];
+            path.push(self.points[node]);
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+// Keeps the tree of the latest query so the overlay can replay its growth.
+struct RrtPlanner {
+    star: bool,
+    tree: RefCell<Option<RrtTree>>,
+    shown: Cell<usize>,
+}
+
+impl RrtPlanner {
+    fn new(star: bool) -> Self {
+        RrtPlanner {
+            star,
+            tree: RefCell::new(None),
+            shown: Cell::new(0),
+        }
+    }
+}
+
+impl ContinuousPlanner for RrtPlanner {
+    fn plan(&self, start: Vec2, goal: Vec2, world: &ContinuousWorld) -> Option<Vec<Vec2>> {
+        let tree = RrtTree::grow(world, start, goal, self.star, &mut rand::rng());
+        let path = tree.path();
+        *self.tree.borrow_mut() = Some(tree);
+        self.shown.set(1);
+        path
+    }
+
+    fn name(&self) -> &str {
+        if self.star { "RRT*" } else { "RRT" }
+    }
+
+    fn overlay(&self, _world: &ContinuousWorld) -> Vec<(Vec2, Vec2)> {
+        let tree = self.tree.borrow();
+        let Some(tree) = tree.as_ref() else {
+            return Vec::new();
+        };
+        let shown = (self.shown.get() + RRT_GROWTH_PER_FRAME).min(tree.points.len());
+        self.shown.set(shown);
+        (1..shown)
+            .filter(|&i| tree.parents[i] < shown)
+            .map(|i| (tree.points[tree.parents[i]], tree.points[i]))
+            .collect()
+    }
+}
+
+fn path_length(path: &[Vec2]) -> f32 {
+    path.windows(2).map(|w| w[0].distance(w[1])).sum()
+}
+
+// Runs every agent's query through each backend and logs path length and planning time.
+fn compare_continuous_planners(agents: &[Agent], world: &ContinuousWorld) {
+    let planners: [Box<dyn ContinuousPlanner>; 3] = [
+        Box::new(VisibilityAStar),
+        Box::new(RrtPlanner::new(false)),
+        Box::new(RrtPlanner::new(true)),
+    ];
+    let queries: Vec<(Vec2, Vec2)> = agents
+        .iter()
+        .filter_map(|a| Some((a.start_point.to_pixels(), a.end_point?.to_pixels())))
+        .collect();
+    for planner in &planners {
+        let start_time = Instant::now();
+        let paths: Vec<Vec<Vec2>> = queries
+            .iter()
+            .filter_map(|&(start, goal)| planner.plan(start, goal, world))
+            .collect();
+        let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+        let total: f32 = paths.iter().map(|p| path_length(p)).sum();
+        info!(
+            "{}: {}/{} paths, total length {:.1}, {:.2} ms",
+            planner.name(),
+            paths.len(),
+            queries.len(),
+            total,
+            elapsed
+        );
+    }
+}
+
+fn step_continuous(
+    agents: &mut [Agent],
+    tick: usize,
+    collision_detector: &CollisionDetector,
+    stats: &mut Statistics,
+) {
+    for agent in agents.iter_mut() {
+        agent.last_position = agent.position;
+        let mut budget = CONTINUOUS_STEP;
+        while budget > 0.0 {
+            let Some(&target) = agent.waypoints.get(agent.waypoint_index) else {
+                break;
+            };
+            let dist = agent.position.distance(target);
+            if dist <= budget {
+                agent.position = target;
+                agent.waypoint_index += 1;
+                budget -= dist;
+            } else {
+                agent.position += (target - agent.position) / dist * budget;
+                budget = 0.0;
+            }
+        }
+        agent.record_trail(agent.current_point);
+        agent.current_point = Node::from_pixels(agent.position);
+        stats.actual_distance += agent.position.distance(agent.last_position);
+        stats.total_steps += 1;
+
+        if !agent.finished
+            && !agent.waypoints.is_empty()
+            && agent.waypoint_index == agent.waypoints.len()
+        {
+            agent.finished = true;
+            agent.arrival_tick = Some(tick);
+            stats.reached_goal_count += 1;
+            collision_detector.bus.publish(SimEvent::Arrival(agent.id));
+        }
+    }
+}
+
+const SENSE_RADIUS: i32 = 1;
+const COMM_RADII: [i32; 3] = [2, 4, 8];
+
+fn chebyshev(a: Node, b: Node) -> i32 {
+    (a.x - b.x).abs().max((a.y - b.y).abs())
+}
+
+// The grid as one agent believes it to be: only walls it has seen or been told about.
+fn knowledge_grid(grid: &Grid, known: &HashSet<Node>) -> Grid {
+    let mut view = grid.clone();
+    view.walls = known.clone();
+    view
+}
+
+// Forgets every agent's map and replans optimistically, as if the world were open.
+fn reset_knowledge(agents: &mut [Agent], grid: &Grid, movement: &dyn MovementStrategy) {
+    for agent in agents.iter_mut() {
+        agent.known_walls.clear();
+        let Some(goal) = agent.end_point else {
+            continue;
+        };
+        let view = knowledge_grid(grid, &agent.known_walls);
+        if let Some(path) = a_star(agent.current_point, goal, &view, movement) {
+            agent.path = Some(path);
+            agent.path_index = 0;
+            agent.refresh_cache();
+        }
+    }
+}
+
+// Agents sense nearby walls, swap maps with neighbours in range and replan
+// around walls (and neighbours' next cells) they have just learned about.
+fn share_knowledge(
+    agents: &mut [Agent],
+    grid: &Grid,
+    radius: i32,
+    movement: &dyn MovementStrategy,
+    stats: &mut Statistics,
+) {
+    for agent in agents.iter_mut() {
+        let c = agent.current_point;
+        let seen: Vec<Node> = grid
+            .walls
+            .iter()
+            .filter(|&&w| chebyshev(w, c) <= SENSE_RADIUS)
+            .copied()
+            .collect();
+        agent.known_walls.extend(seen);
+    }
+
+    let neighbours: Vec<Vec<usize>> = agents
+        .iter()
+        .map(|a| {
+            agents
+                .iter()
+                .filter(|b| b.id != a.id && chebyshev(a.current_point, b.current_point) <= radius)
+                .map(|b| b.id)
+                .collect()
+        })
+        .collect();
+    let received: Vec<HashSet<Node>> = neighbours
+        .iter()
+        .map(|ids| {
+            ids.iter()
+                .flat_map(|&j| agents[j].known_walls.iter().copied())
+                .collect()
+        })
+        .collect();
+
+    for i in 0..agents.len() {
+        agents[i].known_walls.extend(received[i].iter().copied());
+        let (Some(path), Some(goal)) = (&agents[i].path, agents[i].end_point) else {
+            continue;
+        };
+        let known = &agents[i].known_walls;
+        if !path
+            .iter()
+            .skip(agents[i].path_index + 1)
+            .any(|n| known.contains(n))
+        {
+            continue;
+        }
+        let intent: HashSet<Node> = neighbours[i]
+            .iter()
+            .filter_map(|&j| agents[j].next_cell())
+            .filter(|&n| n != goal)
+            .collect();
+        let view = knowledge_grid(grid, known);
+        let start = agents[i].current_point;
+        let new_path = a_star_with_avoidance(start, goal, &view, &intent, None, movement)
+            .or_else(|| a_star(start, goal, &view, movement));
+        if let Some(new_path) = new_path {
+            debug!("Agent {} replanned from shared knowledge", agents[i].id);
+            stats.knowledge_replans += 1;
+            let agent = &mut agents[i];
+            agent.path = Some(new_path);
+            agent.path_index = 0;
+            agent.refresh_cache();
+        }
+    }
+}
+
+fn replan_agent(
+    agents: &mut [Agent],
+    index: usize,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+    settings: &RerouteSettings,
+) -> bool {
+    let Some(goal) = agents[index].end_point else {
+        return false;
+    };
+    let start = agents[index].current_point;
+    let grid = &*grid.inflated(agents[index].footprint);
+    let _span = Span::enter(format!("replan agent {}", agents[index].id));
+    let new_path = if settings.time_aware {
+        let schedule = Schedule::from_agents(agents, agents[index].id);
+        a_star_timed(start, goal, grid, &schedule, movement)
+            .map(|timed| timed.into_iter().map(|(n, _)| n).collect())
+    } else if let Some(penalty) = settings.turn_penalty {
+        let heading = agents[index].heading;
+        a_star_kinematic(start, heading, goal, grid, movement, penalty)
+    } else {
+        a_star(start, goal, grid, movement)
+    };
+
+    let Some(path) = new_path else {
+        return false;
+    };
+    debug!(
+        "Agent {} replanned ({} steps)",
+        agents[index].id,
+        path.len()
+    );
+    let agent = &mut agents[index];
+    agent.path = Some(path);
+    agent.path_index = 0;
+    agent.refresh_cache();
+    true
+}
+
+// Replans the unfinished agents a wall edit at `cell` concerns: a new wall matters to paths
+// running through it, a removed one to paths passing right next to it.
+fn replan_for_wall(
+    agents: &mut [Agent],
+    cell: Node,
+    placed: bool,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+    settings: &RerouteSettings,
+) -> usize {
+    let mut replanned = 0;
+    for i in 0..agents.len() {
+        let affected = if placed {
+            agents[i].path_crosses(cell)
+        } else {
+            agents[i].path_near(cell)
+        };
+        if agents[i].finished || !affected {
+            continue;
+        }
+        if replan_agent(agents, i, grid, movement, settings) {
+            replanned += 1;
+        } else {
+            warn!(
+                "Agent {} has no path around ({}, {})",
+                agents[i].id, cell.x, cell.y
+            );
+        }
+    }
+    replanned
+}
+
+const REROUTE_COOLDOWN_TICKS: usize = 5;
+const REROUTE_HYSTERESIS_CELLS: usize = 2;
+
+fn accept_reroute(agent: &Agent, new_path: &[Node], blocked: bool) -> bool {
+    blocked
+        || agent.last_reroute_tick.is_none()
+        || new_path.len() + REROUTE_HYSTERESIS_CELLS <= agent.remaining_len()
+}
+
+fn reroute_mailbox() -> Mailbox {
+    Mailbox::new("reroutes", |event| matches!(event, SimEvent::Reroute(_)))
+}
+
+// Replans every agent the assistant asked to reroute since the last call.
+fn apply_reroutes(
+    reroutes: &Mailbox,
+    agents: &mut [Agent],
+    state: &GameState,
+    detector: &mut CollisionDetector,
+    stats: &mut Statistics,
+) {
+    let is_orca = detector.strategy.name() == "ORCA";
+    let requests: Vec<RerouteRequest> = reroutes
+        .take()
+        .into_iter()
+        .filter_map(|event| match event {
+            SimEvent::Reroute(request) => Some(request),
+            _ => None,
+        })
+        .filter(|r| state.reroute.urgent_enough(r, agents, is_orca))
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+    let rerouted = process_reroute_requests(
+        agents,
+        &requests,
+        &state.grid,
+        &state.components,
+        state.movement_strategy.as_ref(),
+        &state.reroute,
+        stats,
+    );
+    if detector.clear_on_reroute {
+        detector.forget_agents(&rerouted);
+    }
+}
+
+fn process_reroute_requests(
+    agents: &mut [Agent],
+    requests: &[RerouteRequest],
+    grid: &Grid,
+    components: &ComponentMap,
+    movement: &dyn MovementStrategy,
+    settings: &RerouteSettings,
+    stats: &mut Statistics,
+) -> Vec<usize> {
+    let _span = Span::enter(format!("reroute {} requests", requests.len()));
+    let mut rerouted = Vec::new();
+    let mut by_point: HashMap<Node, Vec<usize>> = HashMap::new();
+    for req in requests
+        .iter()
+        .filter(|r| settings.trigger.should_trigger(r, agents))
+    {
+        by_point
+            .entry(req.avoid_point)
+            .or_default()
+            .push(req.agent_id);
+    }
+
+    for (collision_point, agent_ids) in &by_point {
+        let per_agent =
+            compute_avoidance_plan(agents, agent_ids, *collision_point, settings.avoid_radius);
+
+        for (agent_id, mut avoid_set, pref_dir) in per_agent {
+            let agent = &agents[agent_id];
+            let Some(goal) = agent.end_point else {
+                continue;
+            };
+            if !components.reachable(agent.current_point, goal, movement) {
+                debug!("Agent {} cannot reroute, goal unreachable", agent_id);
+                continue;
+            }
+            let blocked = agent.path_blocked(grid);
+            if !blocked
+                && agent
+                    .last_reroute_tick
+                    .is_some_and(|t| grid.tick < t + REROUTE_COOLDOWN_TICKS)
+            {
+                debug!("Agent {} reroute suppressed by cooldown", agent_id);
+                stats.suppressed_reroutes += 1;
+                continue;
+            }
+            let full = grid.full_passage_cells(agents, agent_id);
+            let grid = &*grid.inflated(agent.footprint);
+
+            if settings.time_aware {
+                let schedule = Schedule::from_agents(agents, agent_id);
+                if let Some(timed) =
+                    a_star_timed(agent.current_point, goal, grid, &schedule, movement)
+                        .filter(|timed| timed.iter().all(|(n, _)| !full.contains(n)))
+                {
+                    let path: Vec<Node> = timed.into_iter().map(|(n, _)| n).collect();
+                    if !accept_reroute(agent, &path, blocked) {
+                        debug!("Agent {} kept its detour (hysteresis)", agent_id);
+                        stats.suppressed_reroutes += 1;
+                        continue;
+                    }
+                    debug!(
+                        "Agent {} rerouted around ({}, {}) with the timed planner",
+                        agent_id, collision_point.x, collision_point.y
+                    );
+                    stats.recalculations += 1;
+                    let agent = &mut agents[agent_id];
+                    agent.path = Some(path);
+                    agent.path_index = 0;
+                    agent.last_reroute_tick = Some(grid.tick);
+                    agent.refresh_cache();
+                    rerouted.push(agent_id);
+                    continue;
+                }
+            }
+
+            let pref = if is_zero_dir(pref_dir) {
+                None
+            } else {
+                Some(pref_dir)
+            };
+
+            avoid_set.extend(full);
+            if let Some(new_path) =
+                a_star_with_avoidance(agent.current_point, goal, grid, &avoid_set, pref, movement)
+            {
+                if !accept_reroute(agent, &new_path, blocked) {
+                    debug!("Agent {} kept its detour (hysteresis)", agent_id);
+                    stats.suppressed_reroutes += 1;
+                    continue;
+                }
+                debug!(
+                    "Agent {} rerouted around ({}, {})",
+                    agent_id, collision_point.x, collision_point.y
+                );
+                stats.recalculations += 1;
+                let agent = &mut agents[agent_id];
+                agent.path = Some(new_path);
+                agent.path_index = 0;
+                agent.last_reroute_tick = Some(grid.tick);
+                agent.refresh_cache();
+                rerouted.push(agent_id);
+            } else {
+                debug!("Agent {} found no detour, keeping its path", agent_id);
+            }
+        }
+    }
+    rerouted
+}
+
+fn compute_avoidance_plan(
+    agents: &[Agent],
+    agent_ids: &[usize],
+    collision_point: Node,
+    radius: i32,
+) -> Vec<(usize, HashSet<Node>, Node)> {
+    let occupied: HashSet<Node> = agent_ids
+        .iter()
+        .map(|&id| agents[id].current_point)
+        .collect();
+    let dirs: Vec<(usize, Node)> = agent_ids
+        .iter()
+        .filter_map(|&id| {
+            let agent = &agents[id];
+            let d = agent.direction();
+            let final_dir = if is_zero_dir(d) {
+                agent
+                    .end_point
+                    .map(|g| Node {
+                        x: (g.x - agent.current_point.x).signum(),
+                        y: (g.y - agent.current_point.y).signum(),
+                    })
+                    .unwrap_or(d)
+            } else {
+                d
+            };
+            Some((id, final_dir))
+        })
+        .collect();
+
+    // Larger agents keep a wider berth around the collision point.
+    let disk_for = |id: usize| {
+        let inflated = radius + agents[id].cell_radius - AGENT_CELL_RADIUS;
+        avoid_disk(collision_point, inflated.max(0), &occupied)
+    };
+    let mut plan = Vec::with_capacity(dirs.len());
+
+    if dirs.len() >= 2 {
+        let (a_id, a_dir) = dirs[0];
+        let (b_id, _b_dir) = dirs[1];
+
+        let axis = rotate_right(a_dir);
+
+        let (steer_a, steer_b) = if a_id <= b_id {
+            (axis, negate(axis))
+        } else {
+            (negate(axis), axis)
+        };
+
+        plan.push(make_avoid_entry(
+            a_id,
+            collision_point,
+            steer_a,
+            &disk_for(a_id),
+            &occupied,
+        ));
+        plan.push(make_avoid_entry(
+            b_id,
+            collision_point,
+            steer_b,
+            &disk_for(b_id),
+            &occupied,
+        ));
+
+        for &(id, dir) in &dirs[2..] {
+            plan.push(make_avoid_entry(
+                id,
+                collision_point,
+                rotate_right(dir),
+                &disk_for(id),
+                &occupied,
+            ));
+        }
+    } else {
+        for &(id, _) in &dirs {
+            plan.push((id, disk_for(id), Node { x: 0, y: 0 }));
+        }
+    }
+
+    plan
+}
+
+// Chebyshev disk around the collision point, minus the cells the agents stand on.
+fn avoid_disk(center: Node, radius: i32, occupied: &HashSet<Node>) -> HashSet<Node> {
+    let mut avoid = HashSet::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let n = Node {
+                x: center.x + dx,
+                y: center.y + dy,
+            };
+            if in_bounds(n) && !occupied.contains(&n) {
+                avoid.insert(n);
+            }
+        }
+    }
+    avoid
+}
+
+fn make_avoid_entry(
+    id: usize,
+    collision_point: Node,
+    avoid_dir: Node,
+    disk: &HashSet<Node>,
+    occupied: &HashSet<Node>,
+) -> (usize, HashSet<Node>, Node) {
+    let mut avoid = disk.clone();
+    let nudge = Node {
+        x: collision_point.x + avoid_dir.x,
+        y: collision_point.y + avoid_dir.y,
+    };
+    if in_bounds(nudge) && !occupied.contains(&nudge) {
+        avoid.insert(nudge);
+    }
+    (id, avoid, avoid_dir)
+}
+
+const INTENTION_HORIZON: usize = 3;
+const YIELD_LIMIT: usize = 3;
+
+// Agents that cannot move outrank moving ones, then higher priority, then the lower id.
+fn yield_rank(agent: &Agent) -> (bool, u8, usize) {
+    (
+        agent.finished || agent.next_cell().is_none(),
+        agent.priority,
+        usize::MAX - agent.id,
+    )
+}
+
+// The cells every agent broadcasts for the next K ticks, each claimed by its highest-ranked agent.
+struct IntentionTable {
+    claims: HashMap<(Node, usize), (bool, u8, usize)>,
+}
+
+impl IntentionTable {
+    fn broadcast(agents: &[Agent], horizon: usize) -> Self {
+        let mut claims: HashMap<(Node, usize), (bool, u8, usize)> = HashMap::new();
+        for agent in agents {
+            let rank = yield_rank(agent);
+            for ahead in 0..=horizon {
+                let claim = claims.entry((agent.cell_at(ahead), ahead)).or_insert(rank);
+                *claim = (*claim).max(rank);
+            }
+        }
+        IntentionTable { claims }
+    }
+
+    fn holder(&self, cell: Node, ahead: usize) -> Option<(bool, u8, usize)> {
+        self.claims.get(&(cell, ahead)).copied()
+    }
+}
+
+// Holds back agents whose next K cells run into a higher-ranked claim, either the same cell at the
+// same tick or two agents swapping cells, unless they already yielded YIELD_LIMIT ticks in a row.
+fn yield_to_intentions(
+    agents: &mut [Agent],
+    horizon: usize,
+    moves: &mut [bool],
+    stats: &mut Statistics,
+) {
+    let table = IntentionTable::broadcast(agents, horizon);
+    for (agent, can_move) in agents.iter_mut().zip(moves.iter_mut()) {
+        if agent.finished || agent.next_cell().is_none() {
+            agent.yield_streak = 0;
+            continue;
+        }
+        if !*can_move {
+            continue;
+        }
+        let rank = yield_rank(agent);
+        let conflict = (1..=horizon).any(|ahead| {
+            let here = agent.cell_at(ahead - 1);
+            let there = agent.cell_at(ahead);
+            let outranked = |cell: Node, at: usize| table.holder(cell, at).filter(|&h| h > rank);
+            outranked(there, ahead).is_some()
+                || outranked(there, ahead - 1)
+                    .is_some_and(|other| there != here && table.holder(here, ahead) == Some(other))
+        });
+        if conflict && agent.yield_streak < YIELD_LIMIT {
+            agent.yield_streak += 1;
+            *can_move = false;
+            stats.yields += 1;
+        } else {
+            agent.yield_streak = 0;
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum MoveDecision {
+    Pending,
+    Visiting,
+    Move,
+    Wait,
+}
+
+// Cancels moves that would bring an agent within D cells of a neighbour it is not already that close
+// to, judged against where earlier agents are headed and where later ones stand. A held agent goes
+// ahead after YIELD_LIMIT ticks so two agents facing each other cannot wait forever.
+fn hold_for_separation(
+    agents: &mut [Agent],
+    desired: &mut [Option<Node>],
+    distance: i32,
+    stats: &mut Statistics,
+) {
+    for i in 0..agents.len() {
+        let Some(target) = desired[i] else {
+            continue;
+        };
+        let current = agents[i].current_point;
+        let crowded = agents.iter().enumerate().any(|(j, other)| {
+            let there = if j < i {
+                desired[j].unwrap_or(other.current_point)
+            } else {
+                other.current_point
+            };
+            let gap = chebyshev(target, there);
+            j != i && gap <= distance && gap < chebyshev(current, other.current_point)
+        });
+        if crowded && agents[i].yield_streak < YIELD_LIMIT {
+            agents[i].yield_streak += 1;
+            desired[i] = None;
+            stats.separation_waits += 1;
+        }
+    }
+}
+
+fn plan_reserved_moves(
+    agents: &mut [Agent],
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+    safety_distance: i32,
+    stats: &mut Statistics,
+) -> Vec<bool> {
+    let n = agents.len();
+    let mut desired: Vec<Option<Node>> = agents.iter().map(|a| a.next_cell()).collect();
+    if safety_distance > 0 {
+        hold_for_separation(agents, &mut desired, safety_distance, stats);
+    }
+    let occupant: HashMap<Node, usize> = agents
+        .iter()
+        .enumerate()
+        .map(|(i, a)| (a.current_point, i))
+        .collect();
+    let blocked_by: Vec<Option<usize>> = desired
+        .iter()
+        .enumerate()
+        .map(|(i, d)| d.and_then(|cell| occupant.get(&cell).copied().filter(|&j| j != i)))
+        .collect();
+
+    let mut decision = vec![MoveDecision::Pending; n];
+    let mut reserved: HashSet<Node> = HashSet::new();
+
+    for i in 0..n {
+        let mut chain = Vec::new();
+        let mut cur = Some(i);
+        while let Some(c) = cur {
+            if decision[c] != MoveDecision::Pending || chain.contains(&c) {
+                break;
+            }
+            chain.push(c);
+            cur = blocked_by[c];
+        }
+        let Some(c) = cur else {
+            continue;
+        };
+        let Some(pos) = chain.iter().position(|&x| x == c) else {
+            continue;
+        };
+
+        let cycle = &chain[pos..];
+        if cycle.len() >= 3 {
+            for &a in cycle {
+                decision[a] = MoveDecision::Move;
+                reserved.insert(desired[a].unwrap());
+            }
+        } else if cycle.len() == 2 {
+            for &a in cycle {
+                decision[a] = MoveDecision::Wait;
+            }
+            let (a, b) = (cycle[0], cycle[1]);
+            let yielder = if agents[a].id > agents[b].id { a } else { b };
+            let other = if yielder == a { b } else { a };
+            if back_up_to_side_cell(agents, yielder, other, grid, movement) {
+                stats.deadlocks_resolved += 1;
+            }
+        }
+    }
+
+    for i in 0..n {
+        decide_move(i, &desired, &blocked_by, &mut decision, &mut reserved);
+    }
+
+    decision
+        .into_iter()
+        .map(|d| d == MoveDecision::Move)
+        .collect()
+}
+
+fn decide_move(
+    i: usize,
+    desired: &[Option<Node>],
+    blocked_by: &[Option<usize>],
+    decision: &mut [MoveDecision],
+    reserved: &mut HashSet<Node>,
+) -> bool {
+    match decision[i] {
+        MoveDecision::Move => return true,
+        MoveDecision::Wait | MoveDecision::Visiting => return false,
+        MoveDecision::Pending => {}
+    }
+    decision[i] = MoveDecision::Visiting;
+
+    let can_move = match desired[i] {
+        None => false,
+        Some(cell) => {
+            let blocker_moves = match blocked_by[i] {
+                Some(b) => decide_move(b, desired, blocked_by, decision, reserved),
+                None => true,
+            };
+            blocker_moves && reserved.insert(cell)
+        }
+    };
+
+    decision[i] = if can_move {
+        MoveDecision::Move
+    } else {
+        MoveDecision::Wait
+    };
+    can_move
+}
+
+fn back_up_to_side_cell(
+    agents: &mut [Agent],
+    yielder: usize,
+    other: usize,
+    grid: &Grid,
+    movement: &dyn MovementStrategy,
+) -> bool {
+    let Some(goal) = agents[yielder].end_point else {
+        return false;
+    };
+    let start = agents[yielder].current_point;
+    let occupied: HashSet<Node> = agents.iter().map(|a| a.current_point).collect();
+    let other_path: HashSet<Node> = match &agents[other].path {
+        Some(path) => path[agents[other].path_index..].iter().copied().collect(),
+        None => HashSet::new(),
+    };
+
+    let mut came_from: HashMap<Node, Node> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    let mut side = None;
+    while let Some(node) = queue.pop_front() {
+        if node != start && !other_path.contains(&node) {
+            side = Some(node);
+            break;
+        }
+        for neighbor in movement.passable_neighbors(node, grid) {
+            if neighbor == start
+                || grid.is_blocked(neighbor)
+                || occupied.contains(&neighbor)
+                || came_from.contains_key(&neighbor)
+            {
+                continue;
+            }
+            came_from.insert(neighbor, node);
+            queue.push_back(neighbor);
+        }
+    }
+
+    let Some(side) = side else {
+        return false;
+    };
+    let mut detour = vec![side];
+    let mut cur = side;
+    while let Some(&prev) = came_from.get(&cur) {
+        detour.push(prev);
+        cur = prev;
+    }
+    detour.reverse();
+
+    let Some(onward) = a_star(side, goal, grid, movement) else {
+        return false;
+    };
+    detour.push(side);
+    detour.extend(onward.into_iter().skip(1));
+
+    let agent = &mut agents[yielder];
+    agent.path = Some(detour);
+    agent.path_index = 0;
+    agent.refresh_cache();
+    true
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Scenario {
+    CrossingStreams,
+    NarrowBridge,
+    DenseCrowd,
+    RingSwap,
+    CityBlocks { street: i32, block: i32 },
+}
+
+const CITY_STREET_WIDTH: i32 = 2;
+const CITY_BLOCK_SIZE: i32 = 4;
+
+impl Scenario {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "crossing" => Some(Scenario::CrossingStreams),
+            "bridge" => Some(Scenario::NarrowBridge),
+            "crowd" => Some(Scenario::DenseCrowd),
+            "ring" => Some(Scenario::RingSwap),
+            "city" => Some(Scenario::CityBlocks {
+                street: CITY_STREET_WIDTH,
+                block: CITY_BLOCK_SIZE,
+            }),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Scenario::CrossingStreams => "crossing",
+            Scenario::NarrowBridge => "bridge",
+            Scenario::DenseCrowd => "crowd",
+            Scenario::RingSwap => "ring",
+            Scenario::CityBlocks { .. } => "city",
+        }
+    }
+
+    fn build(&self, count: usize) -> (HashSet<Node>, Vec<(Node, Node)>) {
+        let mut rng = rand::rng();
+        let mut walls = HashSet::new();
+        let mut pairs = Vec::new();
+        let (cols, rows) = (COLUMNS as i32, ROWS as i32);
+
+        match self {
+            Scenario::CrossingStreams => {
+                let horizontal = count.div_ceil(2);
+                let vertical = count - horizontal;
+                for i in 0..horizontal as i32 {
+                    let y = rows / 2 - horizontal as i32 / 2 + i;
+                    if (0..rows).contains(&y) {
+                        pairs.push((Node { x: 0, y }, Node { x: cols - 1, y }));
+                    }
+                }
+                for i in 0..vertical as i32 {
+                    let x = cols / 2 - vertical as i32 / 2 + i;
+                    if (0..cols).contains(&x) {
+                        pairs.push((Node { x, y: 0 }, Node { x, y: rows - 1 }));
+                    }
+                }
+            }
+            Scenario::NarrowBridge => {
+                let mid = cols / 2;
+                for y in 0..rows {
+                    if y != rows / 2 {
+                        walls.insert(Node { x: mid, y });
+                    }
+                }
+                let mut used = HashSet::new();
+                for i in 0..count {
+                    let left_to_right = i % 2 == 0;
+                    let (from, to) = if left_to_right {
+                        (0..mid, mid + 1..cols)
+                    } else {
+                        (mid + 1..cols, 0..mid)
+                    };
+                    let start = random_free_cell(&mut rng, from.clone(), 0..rows, &walls, &used);
+                    let Some(start) = start else { break };
+                    used.insert(start);
+                    let goal = random_free_cell(&mut rng, to.clone(), 0..rows, &walls, &used);
+                    let Some(goal) = goal else { break };
+                    used.insert(goal);
+                    pairs.push((start, goal));
+                }
+            }
+            Scenario::DenseCrowd => {
+                let mut used = HashSet::new();
+                for _ in 0..count {
+                    let start = random_free_cell(&mut rng, 0..cols, 0..rows, &walls, &used);
+                    let Some(start) = start else { break };
+                    used.insert(start);
+                    let goal = random_free_cell(&mut rng, 0..cols, 0..rows, &walls, &used);
+                    let Some(goal) = goal else { break };
+                    used.insert(goal);
+                    pairs.push((start, goal));
+                }
+            }
+            Scenario::RingSwap => {
+                let center = Vec2::new(cols as f32 / 2.0, rows as f32 / 2.0);
+                let radius = (cols.min(rows) as f32 / 2.0) - 2.0;
+                let mut used = HashSet::new();
+                for i in 0..count {
+                    let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                    let offset = Vec2::new(angle.cos(), angle.sin()) * radius;
+                    let start = Node {
+                        x: (center.x + offset.x).round() as i32,
+                        y: (center.y + offset.y).round() as i32,
+                    };
+                    let goal = Node {
+                        x: (center.x - offset.x).round() as i32,
+                        y: (center.y - offset.y).round() as i32,
+                    };
+                    if !in_bounds(start) || !in_bounds(goal) || !used.insert(start) {
+                        continue;
+                    }
+                    pairs.push((start, goal));
+                }
+            }
+            Scenario::CityBlocks { street, block } => {
+                walls = city_blocks(*street, *block);
+                let mut used = HashSet::new();
+                for _ in 0..count {
+                    let start = random_free_cell(&mut rng, 0..cols, 0..rows, &walls, &used);
+                    let Some(start) = start else { break };
+                    used.insert(start);
+                    let goal = random_free_cell(&mut rng, 0..cols, 0..rows, &walls, &used);
+                    let Some(goal) = goal else { break };
+                    used.insert(goal);
+                    pairs.push((start, goal));
+                }
+            }
+        }
+
+        (walls, pairs)
+    }
+}
+
+// Streets run along the top/left of every block; the block interior is solid.
+fn city_blocks(street: i32, block: i32) -> HashSet<Node> {
+    let street = street.max(1);
+    let period = street + block.max(0);
+    let mut walls = HashSet::new();
+    for y in 0..ROWS as i32 {
+        for x in 0..COLUMNS as i32 {
+            if x % period >= street && y % period >= street {
+                walls.insert(Node { x, y });
+            }
+        }
+    }
+    walls
+}
+
+fn random_free_cell(
+    rng: &mut impl Rng,
+    xs: std::ops::Range<i32>,
+    ys: std::ops::Range<i32>,
+    walls: &HashSet<Node>,
+    used: &HashSet<Node>,
+) -> Option<Node> {
+    let area = (xs.len() * ys.len()).max(1);
+    for _ in 0..area * 4 {
+        let cell = Node {
+            x: rng.random_range(xs.clone()),
+            y: rng.random_range(ys.clone()),
+        };
+        if !walls.contains(&cell) && !used.contains(&cell) {
+            return Some(cell);
+        }
+    }
+    None
+}
+
+// Cells a wall may not cover: where agents stand, start or are headed.
+fn cell_reserved(cell: Node, agents: &[Agent], goal_set: &HashSet<Node>) -> bool {
+    goal_set.contains(&cell)
+        || agents.iter().any(|a| {
+            [Some(a.current_point), Some(a.start_point), a.end_point]
+                .into_iter()
+                .flatten()
+                .any(|n| a.footprint.cells(n).contains(&cell))
+        })
+}
+
+const SPAWN_ATTEMPTS: usize = 50;
+
+// Start and goal on free cells nobody uses, distinct and at least `min_distance` steps apart.
+fn spawn_pair(
+    rng: &mut impl Rng,
+    walls: &HashSet<Node>,
+    used: &HashSet<Node>,
+    min_distance: i32,
+) -> Option<(Node, Node)> {
+    let (cols, rows) = (COLUMNS as i32, ROWS as i32);
+    for _ in 0..SPAWN_ATTEMPTS {
+        let start = random_free_cell(rng, 0..cols, 0..rows, walls, used)?;
+        let goal = random_free_cell(rng, 0..cols, 0..rows, walls, used)?;
+        let distance = (start.x - goal.x).abs() + (start.y - goal.y).abs();
+        if start != goal && distance >= min_distance {
+            return Some((start, goal));
+        }
+    }
+    None
+}
+
+fn load_scenario(
+    scenario: Scenario,
+    count: usize,
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    stats: &mut Statistics,
+) {
+    let (walls, pairs) = scenario.build(count);
+    let mut grid = Grid::new();
+    grid.walls = walls;
+    let map = MapFile {
+        grid,
+        goal_set: HashSet::new(),
+        agents: pairs.into_iter().map(|(s, g)| (s, Some(g))).collect(),
+        expectations: Vec::new(),
+        undo: Vec::new(),
+        redo: Vec::new(),
+    };
+    apply_map(map, Some(scenario), state, agents, history, stats);
+
+    info!(
+        "Loaded scenario '{}' with {} agents",
+        scenario.name(),
+        agents.len()
+    );
+}
+
+const MAP_VERSION: u32 = 1;
+const MAP_PATH: &str = "map.txt";
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Written periodically while a window is open and removed when it closes normally,
+// so one left behind at startup means the last session crashed.
+fn autosave_path(suffix: &str) -> String {
+    let name = format!("trabalho-11-autosave{}.map", suffix);
+    std::env::temp_dir().join(name).display().to_string()
+}
+
+fn recovery_path(suffix: &str) -> String {
+    let name = format!("trabalho-11-recovered{}.map", suffix);
+    std::env::temp_dir().join(name).display().to_string()
+}
+const HEADLESS_TICKS: usize = 1000;
+const PROGRESS_WIDTH: usize = 30;
+
+#[derive(Debug, PartialEq)]
+struct MapFile {
+    grid: Grid,
+    goal_set: HashSet<Node>,
+    agents: Vec<(Node, Option<Node>)>,
+    expectations: Vec<Expectation>,
+    undo: Vec<String>,
+    redo: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Relation {
+    Less,
+    AtMost,
+    Equal,
+    AtLeast,
+    Greater,
+}
+
+impl Relation {
+    fn parse(symbol: &str) -> Option<Self> {
+        match symbol {
+            "<" => Some(Relation::Less),
+            "<=" => Some(Relation::AtMost),
+            "==" => Some(Relation::Equal),
+            ">=" => Some(Relation::AtLeast),
+            ">" => Some(Relation::Greater),
+            _ => None,
+        }
+    }
+
+    fn symbol(&self) -> &str {
+        match self {
+            Relation::Less => "<",
+            Relation::AtMost => "<=",
+            Relation::Equal => "==",
+            Relation::AtLeast => ">=",
+            Relation::Greater => ">",
+        }
+    }
+
+    fn holds(&self, left: usize, right: usize) -> bool {
+        match self {
+            Relation::Less => left < right,
+            Relation::AtMost => left <= right,
+            Relation::Equal => left == right,
+            Relation::AtLeast => left >= right,
+            Relation::Greater => left > right,
+        }
+    }
+}
+
+// What a headless run must satisfy, declared in the map as "expect ..." lines.
+#[derive(Clone, Debug, PartialEq)]
+enum Expectation {
+    MaxCollisions(usize),
+    AllArriveBy(usize),
+    PathLength {
+        agent: usize,
+        relation: Relation,
+        value: usize,
+    },
+}
+
+impl Expectation {
+    fn parse(args: &[&str]) -> Option<Self> {
+        match args {
+            ["max_collisions", n] => Some(Expectation::MaxCollisions(n.parse().ok()?)),
+            ["all_agents_arrive_by", tick] => Some(Expectation::AllArriveBy(tick.parse().ok()?)),
+            ["path_length", agent, relation, value] => Some(Expectation::PathLength {
+                agent: agent.parse().ok()?,
+                relation: Relation::parse(relation)?,
+                value: value.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Expectation::MaxCollisions(n) => format!("max_collisions {}", n),
+            Expectation::AllArriveBy(tick) => format!("all_agents_arrive_by {}", tick),
+            Expectation::PathLength {
+                agent,
+                relation,
+                value,
+            } => format!("path_length {} {} {}", agent, relation.symbol(), value),
+        }
+    }
+
+    fn check(&self, stats: &Statistics, agents: &[Agent]) -> Result<(), String> {
+        match self {
+            Expectation::MaxCollisions(n) if stats.collisions > *n => Err(format!(
+                "{}: got {} collisions",
+                self.to_text(),
+                stats.collisions
+            )),
+            Expectation::AllArriveBy(tick) => {
+                let late: Vec<String> = agents
+                    .iter()
+                    .filter(|a| a.arrival_tick.is_none_or(|t| t > *tick))
+                    .map(|a| a.id.to_string())
+                    .collect();
+                if late.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{}: late agents {}",
+                        self.to_text(),
+                        late.join(", ")
+                    ))
+                }
+            }
+            Expectation::PathLength {
+                agent,
+                relation,
+                value,
+            } => {
+                let length = agents
+                    .get(*agent)
+                    .and_then(|a| a.path.as_ref())
+                    .map(|p| p.len())
+                    .ok_or_else(|| format!("{}: agent has no path", self.to_text()))?;
+                if relation.holds(length, *value) {
+                    Ok(())
+                } else {
+                    Err(format!("{}: got {}", self.to_text(), length))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl MapFile {
+    fn from_state(state: &GameState, agents: &[Agent], history: &CommandHistory) -> Self {
+        let g = &state.grid;
+        MapFile {
+            grid: Grid {
+                walls: g.walls.clone(),
+                zones: g.zones.clone(),
+                one_way: g.one_way.clone(),
+                gates: g.gates.clone(),
+                chargers: g.chargers.clone(),
+                capacities: g.capacities.clone(),
+                lights: g.lights.clone(),
+                notes: g.notes.clone(),
+                tick: g.tick,
+            },
+            goal_set: state.goal_set.clone(),
+            agents: agents
+                .iter()
+                .map(|a| (a.start_point, a.end_point))
+                .collect(),
+            expectations: state.expectations.clone(),
+            undo: CommandHistory::lines(&history.history),
+            redo: CommandHistory::lines(&history.undone),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        fn sorted<T: Clone, K: Ord>(
+            items: impl Iterator<Item = T>,
+            key: impl Fn(&T) -> K,
+        ) -> Vec<T> {
+            let mut v: Vec<T> = items.collect();
+            v.sort_by_key(key);
+            v
+        }
+        let g = &self.grid;
+        let mut out = format!("map {}\ntick {}\n", MAP_VERSION, g.tick);
+        for n in sorted(g.walls.iter(), |n| (n.x, n.y)) {
+            out += &format!("wall {} {}\n", n.x, n.y);
+        }
+        for (n, zone) in sorted(g.zones.iter(), |(n, _)| (n.x, n.y)) {
+            let kind = match zone {
+                Zone::Avoid => "avoid",
+                Zone::Prefer => "prefer",
+            };
+            out += &format!("zone {} {} {}\n", n.x, n.y, kind);
+        }
+        for (a, b) in sorted(g.one_way.iter(), |(a, b)| (a.x, a.y, b.x, b.y)) {
+            out += &format!("one_way {} {} {} {}\n", a.x, a.y, b.x, b.y);
+        }
+        for (n, gate) in sorted(g.gates.iter(), |(n, _)| (n.x, n.y)) {
+            out += &format!(
+                "gate {} {} {} {} {}\n",
+                n.x, n.y, gate.open_ticks, gate.closed_ticks, gate.offset
+            );
+        }
+        for n in sorted(g.chargers.iter(), |n| (n.x, n.y)) {
+            out += &format!("charger {} {}\n", n.x, n.y);
+        }
+        for (n, c) in sorted(g.capacities.iter(), |(n, _)| (n.x, n.y)) {
+            out += &format!("capacity {} {} {}\n", n.x, n.y, c);
+        }
+        for (n, light) in sorted(g.lights.iter(), |(n, _)| (n.x, n.y)) {
+            out += &format!(
+                "light {} {} {} {}\n",
+                n.x, n.y, light.green_ticks, light.offset
+            );
+        }
+        for (n, text) in sorted(g.notes.iter(), |(n, _)| (n.x, n.y)) {
+            out += &format!("note {} {} {}\n", n.x, n.y, text);
+        }
+        for n in sorted(self.goal_set.iter(), |n| (n.x, n.y)) {
+            out += &format!("goal {} {}\n", n.x, n.y);
+        }
+        for (start, goal) in &self.agents {
+            match goal {
+                Some(g) => out += &format!("agent {} {} {} {}\n", start.x, start.y, g.x, g.y),
+                None => out += &format!("agent {} {} -\n", start.x, start.y),
+            }
+        }
+        for expectation in &self.expectations {
+            out += &format!("expect {}\n", expectation.to_text());
+        }
+        for line in &self.undo {
+            out += &format!("undo {}\n", line);
+        }
+        for line in &self.redo {
+            out += &format!("redo {}\n", line);
+        }
+        out
+    }
+
+    fn parse(text: &str) -> Result<Self, SimError> {
+        let mut map = MapFile {
+            grid: Grid::new(),
+            goal_set: HashSet::new(),
+            agents: Vec::new(),
+            expectations: Vec::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+        };
+        let mut version = None;
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: &str| SimError::MapFormat {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let Some((&keyword, args)) = fields.split_first() else {
+                continue;
+            };
+            let num = |k: usize| -> Result<i32, SimError> {
+                args.get(k).and_then(|v| v.parse().ok()).ok_or_else(|| {
+                    err(&format!("bad or missing field {} for '{}'", k + 1, keyword))
+                })
+            };
+            // Durations, offsets and capacities; a negative one is an error rather than a wrap.
+            let count = |k: usize| -> Result<usize, SimError> {
+                match args.get(k) {
+                    Some(v) if v.starts_with('-') => Err(err(&format!(
+                        "field {} for '{}' must not be negative",
+                        k + 1,
+                        keyword
+                    ))),
+                    v => v.and_then(|v| v.parse().ok()).ok_or_else(|| {
+                        err(&format!("bad or missing field {} for '{}'", k + 1, keyword))
+                    }),
+                }
+            };
+            let node = |k: usize| -> Result<Node, SimError> {
+                let n = Node {
+                    x: num(k)?,
+                    y: num(k + 1)?,
+                };
+                if !in_bounds(n) {
+                    return Err(err(&format!(
+                        "cell {} {} is outside the {}x{} grid",
+                        n.x, n.y, COLUMNS, ROWS
+                    )));
+                }
+                Ok(n)
+            };
+            match keyword {
+                "map" => {
+                    let v = num(0)? as u32;
+                    if v != MAP_VERSION {
+                        return Err(err(&format!("unsupported map version {}", v)));
+                    }
+                    version = Some(v);
+                }
+                "tick" => map.grid.tick = count(0)?,
+                "wall" => {
+                    map.grid.walls.insert(node(0)?);
+                }
+                "zone" => {
+                    let zone = match args.get(2) {
+                        Some(&"avoid") => Zone::Avoid,
+                        Some(&"prefer") => Zone::Prefer,
+                        _ => return Err(err("zone must be 'avoid' or 'prefer'")),
+                    };
+                    map.grid.zones.insert(node(0)?, zone);
+                }
+                "one_way" => {
+                    map.grid.one_way.insert((node(0)?, node(2)?));
+                }
+                "gate" => {
+                    let gate = Gate {
+                        open_ticks: count(2)?,
+                        closed_ticks: count(3)?,
+                        offset: count(4)?,
+                    };
+                    if !matches!(gate.open_ticks.checked_add(gate.closed_ticks), Some(1..)) {
+                        return Err(err("gate period must be positive"));
+                    }
+                    map.grid.gates.insert(node(0)?, gate);
+                }
+                "charger" => {
+                    map.grid.chargers.insert(node(0)?);
+                }
+                "light" => {
+                    let light = TrafficLight {
+                        green_ticks: count(2)?,
+                        offset: count(3)?,
+                    };
+                    if light.green_ticks == 0 {
+                        return Err(err("light green time must be positive"));
+                    }
+                    map.grid.lights.insert(node(0)?, light);
+                }
+                "capacity" => {
+                    map.grid.capacities.insert(node(0)?, count(2)?);
+                }
+                "note" => {
+                    let text = args.get(2..).unwrap_or_default().join(" ");
+                    if text.is_empty() {
+                        return Err(err("note needs text"));
+                    }
+                    map.grid.notes.insert(node(0)?, text);
+                }
+                "goal" => {
+                    map.goal_set.insert(node(0)?);
+                }
+                "agent" => {
+                    let goal = if args.get(2) == Some(&"-") {
+                        None
+                    } else {
+                        Some(node(2)?)
+                    };
+                    map.agents.push((node(0)?, goal));
+                }
+                "expect" => {
+                    let expectation = Expectation::parse(args)
+                        .ok_or_else(|| err("unknown or malformed expectation"))?;
+                    map.expectations.push(expectation);
+                }
+                "undo" | "redo" => {
+                    let command = args.join(" ");
+                    if let Err(message) = parse_command(&command) {
+                        return Err(err(&message));
+                    }
+                    if keyword == "undo" {
+                        map.undo.push(command);
+                    } else {
+                        map.redo.push(command);
+                    }
+                }
+                other => return Err(err(&format!("unknown entry '{}'", other))),
+            }
+        }
+        if version.is_none() {
+            return Err(SimError::MapFormat {
+                line: 1,
+                message: "missing map header".to_string(),
+            });
+        }
+        Ok(map)
+    }
+}
+
+fn save_map(
+    state: &GameState,
+    agents: &[Agent],
+    history: &CommandHistory,
+    path: &str,
+) -> Result<(), SimError> {
+    std::fs::write(path, MapFile::from_state(state, agents, history).to_text())?;
+    Ok(())
+}
+
+const OBSTACLE_IMAGE_PATH: &str = "obstacles.png";
+const OBSTACLE_THRESHOLD: u32 = 128;
+
+// Averages the pixels covering each cell; dark cells become walls.
+fn obstacles_from_image(img: &image::GrayImage) -> HashSet<Node> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut walls = HashSet::new();
+    if width == 0 || height == 0 {
+        return walls;
+    }
+    for cy in 0..ROWS {
+        let (y0, y1) = (
+            cy * height / ROWS,
+            ((cy + 1) * height / ROWS).max(cy * height / ROWS + 1),
+        );
+        for cx in 0..COLUMNS {
+            let (x0, x1) = (
+                cx * width / COLUMNS,
+                ((cx + 1) * width / COLUMNS).max(cx * width / COLUMNS + 1),
+            );
+            let mut sum = 0;
+            let mut count = 0;
+            for y in y0..y1.min(height) {
+                for x in x0..x1.min(width) {
+                    sum += img.get_pixel(x as u32, y as u32).0[0] as u32;
+                    count += 1;
+                }
+            }
+            if count > 0 && sum / count < OBSTACLE_THRESHOLD {
+                walls.insert(Node {
+                    x: cx as i32,
+                    y: cy as i32,
+                });
+            }
+        }
+    }
+    walls
+}
+
+fn load_obstacle_image(path: &str) -> Result<HashSet<Node>, SimError> {
+    Ok(obstacles_from_image(&image::open(path)?.to_luma8()))
+}
+
+const SPRITE_MARGIN: usize = 4;
+
+impl Sprite {
+    // Fitted into a cell minus a small margin so neighbouring icons don't touch.
+    fn load(path: &Path) -> Result<Self, SimError> {
+        let size = (CELL_WIDTH.min(CELL_HEIGHT) - 2 * SPRITE_MARGIN) as u32;
+        let img = image::imageops::resize(
+            &image::open(path)?.to_rgba8(),
+            size,
+            size,
+            image::imageops::FilterType::Nearest,
+        );
+        Ok(Sprite {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            pixels: img
+                .pixels()
+                .map(|p| {
+                    let [r, g, b, a] = p.0;
+                    u32::from_be_bytes([a, r, g, b])
+                })
+                .collect(),
+        })
+    }
+}
+
+// Icons that replace the primitive shapes; a missing file just keeps the shape.
+#[derive(Default)]
+struct SpriteSet {
+    agent: Option<Rc<Sprite>>,
+    goal: Option<Rc<Sprite>>,
+    wall: Option<Rc<Sprite>>,
+}
+
+impl SpriteSet {
+    fn load(dir: &str) -> Result<Self, SimError> {
+        let load = |name: &str| -> Result<Option<Rc<Sprite>>, SimError> {
+            let path = Path::new(dir).join(name);
+            if !path.exists() {
+                return Ok(None);
+            }
+            Ok(Some(Rc::new(Sprite::load(&path)?)))
+        };
+        Ok(SpriteSet {
+            agent: load("agent.png")?,
+            goal: load("goal.png")?,
+            wall: load("wall.png")?,
+        })
+    }
+}
+
+fn load_map(
+    path: &str,
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    stats: &mut Statistics,
+) -> Result<(), SimError> {
+    let map = MapFile::parse(&std::fs::read_to_string(path)?)?;
+    apply_map(map, None, state, agents, history, stats);
+    Ok(())
+}
+
+fn apply_map(
+    map: MapFile,
+    scenario: Option<Scenario>,
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    stats: &mut Statistics,
+) {
+    finish_run(state, stats, agents);
+    agents.clear();
+    for (id, (start, goal)) in map.agents.into_iter().enumerate() {
+        agents.push(Agent::new(id, start, goal));
+    }
+    state.grid = map.grid;
+    state.layout_revision += 1;
+    state.goal_set = map.goal_set;
+    state.expectations = map.expectations;
+    state.scenario = scenario;
+    state.selection.clear();
+    state.step_history.clear();
+    state.tick_history.clear();
+    state.tick_history.tick = state.grid.tick;
+    *history = CommandHistory::restore(&map.undo, &map.redo).unwrap_or_else(|e| {
+        state
+            .bus
+            .warn(format!("Discarded the saved edit history: {}", e));
+        CommandHistory::new()
+    });
+    state
+        .components
+        .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+    stats.agents = agents.len();
+    begin_run(state, stats);
+}
+
+#[derive(Eq, PartialEq)]
+enum Step {
+    Obstacles,
+    Start,
+    End,
+    Goals,
+    AvoidZone,
+    PreferZone,
+    OneWay,
+    Gates,
+    Chargers,
+    Passages,
+    Select,
+    Lights,
+    Reachability,
+    Notes,
+    Measure,
+}
+
+struct GameState {
+    was_pressed: bool,
+    was_right_pressed: bool,
+    current_step: Step,
+    grid: Grid,
+    goal_set: HashSet<Node>,
+    one_way_anchor: Option<Node>,
+    components: ComponentMap,
+    reroute: RerouteSettings,
+    reservation_stepping: bool,
+    movement_strategy: Box<dyn MovementStrategy>,
+    step_history: Vec<Vec<Node>>,
+    scenario_agents: usize,
+    pending_scenario: Option<Scenario>,
+    tick_history: TickHistory,
+    snapshots: [Option<SimSnapshot>; SNAPSHOT_KEYS.len()],
+    running: bool,
+    ticks_per_second: u32,
+    // Ticks asked for with W, run by the session alongside the clock's ticks.
+    step_requests: u32,
+    trail_length: usize,
+    show_profile: bool,
+    battery_enabled: bool,
+    battery_failures: BatteryFailures,
+    warnings: HashMap<usize, WarningLevel>,
+    export_requested: bool,
+    screenshot_requested: bool,
+    annotate_screenshots: bool,
+    seed: Option<u64>,
+    scenario: Option<Scenario>,
+    run: ExperimentRun,
+    finished_runs: Vec<FinishedRun>,
+    label: String,
+    sink: SinkKind,
+    obstacle_image: String,
+    city_layout: Scenario,
+    comm_radius: Option<i32>,
+    continuous: bool,
+    world: ContinuousWorld,
+    continuous_planner: Box<dyn ContinuousPlanner>,
+    prm_samples: usize,
+    prm_radius: f32,
+    spawn_rng: StdRng,
+    min_spawn_distance: i32,
+    light_throughput: HashMap<Node, usize>,
+    selection: HashSet<usize>,
+    drag: Option<(Node, Node)>,
+    refused_cell: Option<(Node, Instant)>,
+    wall_edits: Vec<(Node, bool)>,
+    // Bumped whenever walls, zones, one-way links, gates or chargers change, or the grid is replaced.
+    layout_revision: usize,
+    reset_armed: Option<Instant>,
+    toasts: Rc<ToastBoard>,
+    recorder: Rc<RunRecorder>,
+    bus: Rc<EventBus>,
+    preview: Option<PathPreview>,
+    reach_origin: Option<Node>,
+    reachable: HashSet<Node>,
+    show_distance_field: bool,
+    distance_field: Option<DistanceField>,
+    compare_planners: [ComparePlanner; 2],
+    comparison: Option<Comparison>,
+    intention_horizon: Option<usize>,
+    safety_distance: i32,
+    stats_suffix: String,
+    goal_deliveries: Vec<GoalArrival>,
+    score_weights: ScoreWeights,
+    expectations: Vec<Expectation>,
+    recovery: Option<String>,
+    measure_anchor: Option<Node>,
+    measurement: Option<Measurement>,
+    presentation: bool,
+    theme: Theme,
+    show_rulers: bool,
+    hovered: Option<Node>,
+    sprites: SpriteSet,
+    static_layer: RefCell<StaticLayer>,
+    // Part of the frame that is on screen; walls, paths and agents outside it are never rasterized.
+    viewport: Bounds,
+}
+
+impl GameState {
+    // Presentation mode draws paths thicker and markers a quarter larger so they survive a projector.
+    fn stroke(&self) -> usize {
+        if self.presentation {
+            scaled(PRESENTATION_STROKE)
+        } else {
+            1
+        }
+    }
+
+    fn radius(&self, px: usize) -> usize {
+        if self.presentation {
+            scaled(px) * 5 / 4
+        } else {
+            scaled(px)
+        }
+    }
+}
+
+// Distances between the two cells last clicked in measure mode; path is None when no route exists.
+struct Measurement {
+    from: Node,
+    to: Node,
+    path: Option<f32>,
+}
+
+impl Measurement {
+    fn new(from: Node, to: Node, grid: &Grid, movement: &dyn MovementStrategy) -> Self {
+        Measurement {
+            from,
+            to,
+            path: a_star(from, to, grid, movement).map(|p| grid.geometric_length(&p)),
+        }
+    }
+
+    fn text(&self) -> String {
+        let (dx, dy) = (self.to.x - self.from.x, self.to.y - self.from.y);
+        format!(
+            "MANHATTAN {}  CHEBYSHEV {}  EUCLID {:.2}  PATH {}",
+            dx.abs() + dy.abs(),
+            chebyshev(self.from, self.to),
+            ((dx * dx + dy * dy) as f32).sqrt(),
+            self.path.map_or("-".to_string(), |p| format!("{:.2}", p))
+        )
+    }
+}
+
+struct DistanceField {
+    agent: usize,
+    goal: Node,
+    costs: HashMap<Node, i32>,
+}
+
+struct PathPreview {
+    target: Node,
+    computed: Instant,
+    path: Option<Vec<Node>>,
+}
+
+struct InitContext {
+    window: Option<Window>,
+    buffer: Option<Vec<u32>>,
+    game_state: Option<GameState>,
+}
+
+trait InitHandler {
+    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), SimError>;
+}
+
+struct WindowInitHandler {
+    suffix: String,
+    scale: usize,
+}
+struct BufferInitHandler;
+struct GameStateInitHandler {
+    options: CliOptions,
+    suffix: String,
+}
+
+#[derive(Clone)]
+struct CliOptions {
+    scenario: Option<Scenario>,
+    agents: usize,
+    label: String,
+    sink: SinkKind,
+    log_file: Option<String>,
+    obstacles: Option<String>,
+    street_width: i32,
+    block_size: i32,
+    prm_samples: usize,
+    prm_radius: f32,
+    seed: Option<u64>,
+    min_spawn_distance: i32,
+    compare: [ComparePlanner; 2],
+    intentions: Option<usize>,
+    safety_distance: i32,
+    score_weights: ScoreWeights,
+    headless: Vec<String>,
+    ticks: usize,
+    jobs: usize,
+    timeout: Option<Duration>,
+    record: Option<String>,
+    replay: Option<String>,
+    replay_speed: f32,
+    report: Option<String>,
+    scale: usize,
+    presentation: bool,
+    theme: Theme,
+    sprites: Option<String>,
+    annotate_screenshots: bool,
+    warning_level: WarningLevel,
+}
+
+fn parse_cli_options(args: &[String]) -> Result<CliOptions, String> {
+    let mut options = CliOptions {
+        scenario: None,
+        agents: 12,
+        label: String::new(),
+        sink: SinkKind::Csv,
+        log_file: None,
+        obstacles: None,
+        street_width: CITY_STREET_WIDTH,
+        block_size: CITY_BLOCK_SIZE,
+        prm_samples: PRM_SAMPLES,
+        prm_radius: PRM_RADIUS,
+        seed: None,
+        min_spawn_distance: 0,
+        compare: [ComparePlanner::AStar, ComparePlanner::Kinematic],
+        intentions: None,
+        safety_distance: 0,
+        score_weights: ScoreWeights::new(),
+        headless: Vec::new(),
+        ticks: HEADLESS_TICKS,
+        jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+        timeout: None,
+        record: None,
+        replay: None,
+        replay_speed: 1.0,
+        report: None,
+        scale: 1,
+        presentation: false,
+        theme: Theme::dark(),
+        sprites: None,
+        annotate_screenshots: false,
+        warning_level: WarningLevel::Info,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--scenario" => {
+                let name = iter.next().ok_or("--scenario needs a value")?;
+                options.scenario =
+                    Some(Scenario::from_name(name).ok_or(format!("Unknown scenario: {}", name))?);
+            }
+            "--agents" => {
+                let value = iter.next().ok_or("--agents needs a value")?;
+                options.agents = value
+                    .parse()
+                    .map_err(|_| format!("Invalid agent count: {}", value))?;
+            }
+            "--label" => {
+                options.label = iter.next().ok_or("--label needs a value")?.clone();
+            }
+            "--sink" => {
+                options.sink = match iter.next().map(String::as_str) {
+                    Some("csv") => SinkKind::Csv,
+                    Some("sqlite") => SinkKind::Sqlite,
+                    Some(other) => return Err(format!("Unknown stats sink: {}", other)),
+                    None => return Err("--sink needs a value".to_string()),
+                };
+            }
+            "--log-file" => {
+                options.log_file = Some(iter.next().ok_or("--log-file needs a value")?.clone());
+            }
+            "--street-width" | "--block-size" => {
+                let value = iter.next().ok_or(format!("{} needs a value", arg))?;
+                let size = value
+                    .parse()
+                    .map_err(|_| format!("Invalid size: {}", value))?;
+                if arg == "--street-width" {
+                    options.street_width = size;
+                } else {
+                    options.block_size = size;
+                }
+            }
+            "--prm-samples" => {
+                let value = iter.next().ok_or("--prm-samples needs a value")?;
+                options.prm_samples = value
+                    .parse()
+                    .map_err(|_| format!("Invalid sample count: {}", value))?;
+            }
+            "--prm-radius" => {
+                let value = iter.next().ok_or("--prm-radius needs a value")?;
+                options.prm_radius = value
+                    .parse()
+                    .map_err(|_| format!("Invalid connection radius: {}", value))?;
+            }
+            "--seed" => {
+                let value = iter.next().ok_or("--seed needs a value")?;
+                options.seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid seed: {}", value))?,
+                );
+            }
+            "--min-spawn-distance" => {
+                let value = iter.next().ok_or("--min-spawn-distance needs a value")?;
+                options.min_spawn_distance = value
+                    .parse()
+                    .map_err(|_| format!("Invalid spawn distance: {}", value))?;
+            }
+            "--compare" => {
+                let value = iter.next().ok_or("--compare needs a value")?;
+                let planners: Vec<ComparePlanner> = value
+                    .split(',')
+                    .map(|name| {
+                        ComparePlanner::from_name(name).ok_or(format!("Unknown planner: {}", name))
+                    })
+                    .collect::<Result<_, _>>()?;
+                options.compare = planners
+                    .try_into()
+                    .map_err(|_| format!("--compare needs two planners: {}", value))?;
+            }
+            "--intentions" => {
+                let value = iter.next().ok_or("--intentions needs a value")?;
+                options.intentions = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&k: &usize| k > 0)
+                        .ok_or(format!("Invalid intention horizon: {}", value))?,
+                );
+            }
+            "--safety-distance" => {
+                let value = iter.next().ok_or("--safety-distance needs a value")?;
+                options.safety_distance = value
+                    .parse()
+                    .ok()
+                    .filter(|&d: &i32| d >= 0)
+                    .ok_or(format!("Invalid safety distance: {}", value))?;
+            }
+            "--score-weights" => {
+                let value = iter.next().ok_or("--score-weights needs a value")?;
+                options.score_weights = ScoreWeights::parse(value)
+                    .ok_or(format!("Invalid score weights: {}", value))?;
+            }
+            "--headless" => {
+                let maps = iter.next().ok_or("--headless needs a map file")?;
+                options.headless.extend(maps.split(',').map(str::to_string));
+            }
+            "--ticks" => {
+                let value = iter.next().ok_or("--ticks needs a value")?;
+                options.ticks = value
+                    .parse()
+                    .map_err(|_| format!("Invalid tick count: {}", value))?;
+            }
+            "--jobs" => {
+                let value = iter.next().ok_or("--jobs needs a value")?;
+                options.jobs = value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or(format!("Invalid job count: {}", value))?;
+            }
+            "--timeout" => {
+                let value = iter.next().ok_or("--timeout needs a value")?;
+                options.timeout = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&secs: &f64| secs > 0.0)
+                        .map(Duration::from_secs_f64)
+                        .ok_or(format!("Invalid timeout: {}", value))?,
+                );
+            }
+            "--record" => {
+                options.record = Some(iter.next().ok_or("--record needs a file")?.clone());
+            }
+            "--replay" => {
+                options.replay = Some(iter.next().ok_or("--replay needs a file")?.clone());
+            }
+            "--replay-speed" => {
+                let value = iter.next().ok_or("--replay-speed needs a value")?;
+                options.replay_speed = value
+                    .parse()
+                    .ok()
+                    .filter(|&speed: &f32| speed > 0.0)
+                    .ok_or(format!("Invalid replay speed: {}", value))?;
+            }
+            "--report" => {
+                options.report = Some(iter.next().ok_or("--report needs a file")?.clone());
+            }
+            "--scale" => {
+                let value = iter.next().ok_or("--scale needs a value")?;
+                options.scale = value
+                    .parse()
+                    .ok()
+                    .filter(|n: &usize| (1..=MAX_SCALE).contains(n))
+                    .ok_or(format!("Invalid scale (1-{}): {}", MAX_SCALE, value))?;
+            }
+            "--presentation" => options.presentation = true,
+            "--annotate-screenshots" => options.annotate_screenshots = true,
+            "--warning-level" => {
+                let value = iter.next().ok_or("--warning-level needs a value")?;
+                options.warning_level = WarningLevel::parse(value).ok_or(format!(
+                    "Invalid warning level (info|warn|critical): {}",
+                    value
+                ))?;
+            }
+            "--sprites" => {
+                options.sprites = Some(iter.next().ok_or("--sprites needs a directory")?.clone());
+            }
+            "--theme" => {
+                let value = iter.next().ok_or("--theme needs a name or file")?;
+                options.theme = Theme::load(value).map_err(|e| format!("{}: {}", value, e))?;
+            }
+            "--obstacles" => {
+                options.obstacles = Some(iter.next().ok_or("--obstacles needs a value")?.clone());
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    if let Some(Scenario::CityBlocks { street, block }) = &mut options.scenario {
+        *street = options.street_width;
+        *block = options.block_size;
+    }
+    Ok(options)
+}
+
+impl InitHandler for WindowInitHandler {
+    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), SimError> {
+        let mut window = Window::new(
+            &format!("Navigation grid - Hybrid{}", self.suffix),
+            WIDTH * self.scale,
+            HEIGHT * self.scale,
+            WindowOptions {
+                resize: true,
+                scale_mode: ScaleMode::UpperLeft,
+                ..WindowOptions::default()
+            },
+        )?;
+        window.set_target_fps(TARGET_FPS);
+        ctx.window = Some(window);
+        Ok(())
+    }
+}
+
+impl InitHandler for BufferInitHandler {
+    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), SimError> {
+        ctx.buffer = Some(vec![0; WIDTH * HEIGHT]);
+        Ok(())
+    }
+}
+
+impl InitHandler for GameStateInitHandler {
+    fn initialize(&mut self, ctx: &mut InitContext) -> Result<(), SimError> {
+        let mut state = GameState {
+            was_pressed: false,
+            was_right_pressed: false,
+            current_step: Step::Obstacles,
+            grid: Grid::new(),
+            goal_set: HashSet::new(),
+            one_way_anchor: None,
+            components: ComponentMap::new(),
+            reroute: RerouteSettings {
+                min_level: self.options.warning_level,
+                ..RerouteSettings::new()
+            },
+            reservation_stepping: false,
+            movement_strategy: Box::new(OrthogonalMovement),
+            step_history: Vec::new(),
+            scenario_agents: self.options.agents,
+            pending_scenario: self.options.scenario,
+            scenario: None,
+            run: ExperimentRun::new(&self.options.label, "custom"),
+            finished_runs: Vec::new(),
+            label: self.options.label.clone(),
+            sink: self.options.sink,
+            obstacle_image: self
+                .options
+                .obstacles
+                .clone()
+                .unwrap_or_else(|| OBSTACLE_IMAGE_PATH.to_string()),
+            city_layout: Scenario::CityBlocks {
+                street: self.options.street_width,
+                block: self.options.block_size,
+            },
+            comm_radius: None,
+            continuous: false,
+            world: ContinuousWorld::new(),
+            continuous_planner: Box::new(VisibilityAStar),
+            prm_samples: self.options.prm_samples,
+            prm_radius: self.options.prm_radius,
+            spawn_rng: match self.options.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_os_rng(),
+            },
+            min_spawn_distance: self.options.min_spawn_distance,
+            light_throughput: HashMap::new(),
+            selection: HashSet::new(),
+            drag: None,
+            refused_cell: None,
+            wall_edits: Vec::new(),
+            layout_revision: 0,
+            reset_armed: None,
+            toasts: Rc::new(ToastBoard::new()),
+            recorder: Rc::new(RunRecorder::new()),
+            bus: Rc::new(EventBus::new()),
+            preview: None,
+            reach_origin: None,
+            reachable: HashSet::new(),
+            show_distance_field: false,
+            distance_field: None,
+            compare_planners: self.options.compare,
+            comparison: None,
+            intention_horizon: self.options.intentions,
+            safety_distance: self.options.safety_distance,
+            stats_suffix: self.suffix.clone(),
+            goal_deliveries: Vec::new(),
+            score_weights: self.options.score_weights,
+            expectations: Vec::new(),
+            recovery: None,
+            measure_anchor: None,
+            measurement: None,
+            presentation: self.options.presentation,
+            theme: self.options.theme,
+            show_rulers: false,
+            hovered: None,
+            sprites: SpriteSet::default(),
+            static_layer: RefCell::default(),
+            viewport: Bounds::screen(),
+            tick_history: TickHistory::new(),
+            snapshots: Default::default(),
+            running: false,
+            ticks_per_second: DEFAULT_TPS,
+            step_requests: 0,
+            trail_length: TRAIL_LENGTHS[2],
+            show_profile: false,
+            battery_enabled: false,
+            battery_failures: BatteryFailures::default(),
+            warnings: HashMap::new(),
+            export_requested: false,
+            screenshot_requested: false,
+            annotate_screenshots: self.options.annotate_screenshots,
+            seed: self.options.seed,
+        };
+        if let Some(dir) = &self.options.sprites {
+            state.sprites = SpriteSet::load(dir)?;
+            info!("Loaded sprites from {}", dir);
+        }
+        if self.options.obstacles.is_some() {
+            let path = state.obstacle_image.clone();
+            state.grid.walls = load_obstacle_image(&path)?;
+            state
+                .components
+                .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+            info!("Loaded {} walls from {}", state.grid.walls.len(), path);
+        }
+        ctx.game_state = Some(state);
+        Ok(())
+    }
+}
+
+thread_local! {
+    // Half-width of every row of a filled disc, keyed by radius; the same few radii repeat every frame.
+    static CIRCLE_MASKS: RefCell<HashMap<usize, Rc<[usize]>>> = RefCell::new(HashMap::new());
+}
+
+fn circle_mask(radius: usize) -> Rc<[usize]> {
+    CIRCLE_MASKS.with(|masks| {
+        masks
+            .borrow_mut()
+            .entry(radius)
+            .or_insert_with(|| {
+                let r2 = radius * radius;
+                (0..=2 * radius)
+                    .map(|row| {
+                        let dy = row.abs_diff(radius);
+                        (r2 - dy * dy).isqrt()
+                    })
+                    .collect()
+            })
+            .clone()
+    })
+}
+
+// Hands each on-screen row of the disc to `span` as one slice instead of testing every pixel.
+fn for_each_circle_span(
+    buffer: &mut [u32],
+    center: PixelCoord,
+    radius: usize,
+    mut span: impl FnMut(&mut [u32]),
+) {
+    for (row, &half) in circle_mask(radius).iter().enumerate() {
+        let Some(y) = (center.y + row).checked_sub(radius) else {
+            continue;
+        };
+        if y >= HEIGHT {
+            break;
+        }
+        let x0 = center.x.saturating_sub(half);
+        let x1 = (center.x + half).min(WIDTH - 1);
+        if x0 <= x1 {
+            span(&mut buffer[y * WIDTH + x0..=y * WIDTH + x1]);
+        }
+    }
+}
+
+fn draw_circle_at_pixels(buffer: &mut [u32], center: PixelCoord, radius: usize, color: u32) {
+    for_each_circle_span(buffer, center, radius, |span| span.fill(color));
+}
+
+fn blend_circle_at_pixels(
+    buffer: &mut [u32],
+    center: PixelCoord,
+    radius: usize,
+    color: u32,
+    alpha: f32,
+) {
+    for_each_circle_span(buffer, center, radius, |span| {
+        for pixel in span {
+            *pixel = blend(color, *pixel, alpha);
+        }
+    });
+}
+
+fn simulation_tick(
+    agents: &mut [Agent],
+    state: &mut GameState,
+    collision_detector: &mut CollisionDetector,
+    stats: &mut Statistics,
+) {
+    state.tick_history.record(agents, stats);
+    let previous_tick = state.grid.tick;
+    state.grid.tick = state.tick_history.tick;
+
+    if collision_detector.strategy.name() == "ORCA" {
+        let delta_time = ORCA_TICK_SECONDS;
+
+        let dodgy_agents: Vec<DodgyAgent> = agents
+            .iter()
+            .map(|a| DodgyAgent {
+                position: a.position,
+                velocity: a.velocity,
+                radius: a.radius,
+                avoidance_responsibility: 1.0,
+            })
+            .collect();
+
+        let mut new_velocities = Vec::with_capacity(agents.len());
+
+        for i in 0..agents.len() {
+            let mut neighbors: Vec<Cow<DodgyAgent>> = dodgy_agents
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| {
+                    *j != i && agents[i].position.distance(other.position) < NEIGHBOR_RADIUS
+                })
+                .map(|(_, agent)| Cow::Borrowed(agent))
+                .collect();
+
+            neighbors.sort_by(|a, b| {
+                agents[i]
+                    .position
+                    .distance(a.position)
+                    .partial_cmp(&agents[i].position.distance(b.position))
+                    .unwrap()
+            });
+
+            let preferred_velocity = if let Some(goal) = agents[i].end_point {
+                let goal_pos = goal.to_pixels();
+                let to_goal = goal_pos - agents[i].position;
+                let dist = to_goal.length();
+
+                if dist <= agents[i].radius {
+                    agents[i].position = goal_pos;
+                    Vec2::ZERO
+                } else {
+                    to_goal.normalize_or_zero() * agents[i].max_speed
+                }
+            } else {
+                Vec2::ZERO
+            };
+
+            let orca_velocity = if neighbors.is_empty() {
+                preferred_velocity
+            } else {
+                dodgy_agents[i].compute_avoiding_velocity(
+                    &neighbors,
+                    &[],
+                    preferred_velocity,
+                    delta_time,
+                    agents[i].max_speed,
+                    &AvoidanceOptions {
+                        obstacle_margin: 0.4,
+                        time_horizon: 3.0,
+                        obstacle_time_horizon: 1.2,
+                    },
+                )
+            };
+
+            let avoiding_velocity = if orca_velocity.length() >= 1.0 {
+                orca_velocity
+            } else if let Some(other) = neighbors.first() {
+                let rel_pos = other.position - agents[i].position;
+                let rel_vel = preferred_velocity - other.velocity;
+
+                let forward = preferred_velocity.normalize_or_zero();
+                let perp = Vec2::new(-forward.y, forward.x);
+
+                let cross = rel_pos.x * rel_vel.y - rel_pos.y * rel_vel.x;
+                let side = cross.signum().max(1.0);
+
+                let dodge = (forward * 0.6) + (perp * side * 0.8);
+                dodge.normalize_or_zero() * agents[i].max_speed * 0.9
+            } else {
+                Vec2::ZERO
+            };
+
+            new_velocities.push(avoiding_velocity);
+
+            if let Some(goal) = agents[i].end_point {
+                let goal_pos = goal.to_pixels();
+                if !agents[i].finished && agents[i].position.distance(goal_pos) < agents[i].radius {
+                    agents[i].finished = true;
+                    agents[i].arrival_tick = Some(state.grid.tick);
+                    stats.reached_goal_count += 1;
+                    collision_detector
+                        .bus
+                        .publish(SimEvent::Arrival(agents[i].id));
+                }
+            }
+        }
+
+        for (i, agent) in agents.iter_mut().enumerate() {
+            agent.last_position = agent.position;
+            agent.record_trail(Node::from_pixels(agent.position));
+
+            agent.velocity = new_velocities[i];
+            agent.position += agent.velocity * delta_time;
+
+            let step_dist = agent.position.distance(agent.last_position);
+            stats.actual_distance += step_dist;
+
+            stats.total_steps += 1;
+        }
+    } else if state.continuous {
+        step_continuous(agents, state.grid.tick, collision_detector, stats);
+    } else {
+        let closed_gates = state.grid.closed_gates_since(previous_tick);
+        if !closed_gates.is_empty() {
+            for i in 0..agents.len() {
+                let blocked = match &agents[i].path {
+                    Some(path) => path
+                        .iter()
+                        .skip(agents[i].path_index + 1)
+                        .any(|n| closed_gates.contains(n)),
+                    None => false,
+                };
+                if blocked
+                    && replan_agent(
+                        agents,
+                        i,
+                        &state.grid,
+                        state.movement_strategy.as_ref(),
+                        &state.reroute,
+                    )
+                {
+                    stats.recalculations += 1;
+                }
+            }
+        }
+        if let Some(radius) = state.comm_radius {
+            share_knowledge(
+                agents,
+                &state.grid,
+                radius,
+                state.movement_strategy.as_ref(),
+                stats,
+            );
+        }
+        if state.battery_enabled {
+            enforce_battery_plans(
+                agents,
+                &state.grid,
+                state.movement_strategy.as_ref(),
+                &mut state.battery_failures,
+                state.layout_revision,
+            );
+        }
+
+        let mut moves = if state.reservation_stepping {
+            plan_reserved_moves(
+                agents,
+                &state.grid,
+                state.movement_strategy.as_ref(),
+                state.safety_distance,
+                stats,
+            )
+        } else {
+            vec![true; agents.len()]
+        };
+        if let Some(horizon) = state.intention_horizon {
+            yield_to_intentions(agents, horizon, &mut moves, stats);
+        }
+
+        for (agent, can_move) in agents.iter_mut().zip(moves) {
+            agent.last_position = agent.position;
+            let next = agent.next_cell().filter(|&n| {
+                !agent
+                    .footprint
+                    .cells(n)
+                    .iter()
+                    .any(|&c| state.grid.is_blocked(c))
+            });
+            let red = next.is_some_and(|n| !state.grid.light_allows(agent.current_point, n));
+            if red && can_move {
+                stats.light_waits += 1;
+            }
+            let next = next.filter(|_| !red);
+            let next = if state.battery_enabled {
+                next.filter(|&n| {
+                    recharge(agent.battery, agent.current_point, n, &state.grid).is_some()
+                })
+            } else {
+                next
+            };
+            if can_move {
+                if let Some(next) = next {
+                    if state.battery_enabled {
+                        agent.battery =
+                            recharge(agent.battery, agent.current_point, next, &state.grid)
+                                .unwrap_or(0);
+                    }
+                    agent.record_trail(agent.current_point);
+                    agent.path_index += 1;
+                    agent.yield_streak = 0;
+                    if next != agent.current_point {
+                        agent.heading = move_dir(agent.current_point, next);
+                    }
+                    agent.current_point = next;
+                    agent.position = agent.current_point.to_pixels();
+                    agent.refresh_cache();
+                    if state.grid.lights.contains_key(&next) {
+                        stats.light_crossings += 1;
+                        *state.light_throughput.entry(next).or_default() += 1;
+                    }
+                }
+            }
+            let step_dist = agent.position.distance(agent.last_position);
+
+            stats.actual_distance += step_dist;
+            stats.total_steps += 1;
+            if step_dist > 0.0 {
+                stats.moves += 1;
+            } else if !agent.finished && agent.path.is_some() {
+                stats.waits += 1;
+            }
+
+            if let Some(goal) = agent.end_point {
+                let goal_pos = goal.to_pixels();
+                if !agent.finished && agent.position.distance(goal_pos) < agent.radius {
+                    agent.finished = true;
+                    agent.arrival_tick = Some(state.grid.tick);
+                    stats.reached_goal_count += 1;
+                    collision_detector.bus.publish(SimEvent::Arrival(agent.id));
+                }
+            }
+        }
+        log_light_phases(state, previous_tick);
+    }
+
+    record_goal_deliveries(
+        &mut state.goal_deliveries,
+        &state.goal_set,
+        agents,
+        state.grid.tick,
+    );
+    stats.goal_deliveries = state.goal_deliveries.len();
+    stats.goal_throughput = goal_throughput(&state.goal_deliveries, None, state.grid.tick);
+    state.warnings = warning_levels(agents, collision_detector.strategy.name() == "ORCA");
+    collision_detector.expire_pairs(agents, state.grid.tick);
+}
+
+fn log_light_phases(state: &mut GameState, previous_tick: usize) {
+    if previous_tick == state.grid.tick {
+        return;
+    }
+    for (node, light) in &state.grid.lights {
+        let was_horizontal = light.horizontal_green(previous_tick);
+        if was_horizontal == light.horizontal_green(state.grid.tick) {
+            continue;
+        }
+        let passed = state.light_throughput.remove(node).unwrap_or(0);
+        info!(
+            "Light ({}, {}) green {} {}: {} crossings",
+            node.x,
+            node.y,
+            light.green_ticks,
+            if was_horizontal {
+                "horizontal"
+            } else {
+                "vertical"
+            },
+            passed
+        );
+    }
+}
+
+// One frame of keyboard and mouse input, read from the window or scripted by tests.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct InputState {
+    pressed: Vec<Key>,
+    repeated: Vec<Key>,
+    mouse: Option<(f32, f32)>,
+    left: bool,
+    right: bool,
+    shift: bool,
+}
+
+// Where the fixed WIDTH x HEIGHT frame sits in a resized window: scaled to fit and centred.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Letterbox {
+    window: (usize, usize),
+    offset: (usize, usize),
+    size: (usize, usize),
+}
+
+impl Letterbox {
+    fn fit(width: usize, height: usize) -> Self {
+        let scale = (width as f32 / WIDTH as f32).min(height as f32 / HEIGHT as f32);
+        let size = (
+            ((WIDTH as f32 * scale) as usize).max(1),
+            ((HEIGHT as f32 * scale) as usize).max(1),
+        );
+        Letterbox {
+            window: (width, height),
+            offset: (
+                width.saturating_sub(size.0) / 2,
+                height.saturating_sub(size.1) / 2,
+            ),
+            size,
+        }
+    }
+
+    fn is_identity(&self) -> bool {
+        self.window == (WIDTH, HEIGHT)
+    }
+
+    // Window pixels to frame pixels, clamped onto the frame so the borders pick edge cells.
+    fn to_frame(self, (x, y): (f32, f32)) -> (f32, f32) {
+        let map = |v: f32, offset: usize, size: usize, full: usize| {
+            ((v - offset as f32) * full as f32 / size as f32).clamp(0.0, full as f32 - 1.0)
+        };
+        (
+            map(x, self.offset.0, self.size.0, WIDTH),
+            map(y, self.offset.1, self.size.1, HEIGHT),
+        )
+    }
+
+    // Nearest-neighbour scale of the frame into a window-sized buffer, bars left black.
+    // Columns are looked up once, and a row that repeats the previous source row is memcpy'd.
+    fn blit(&self, frame: &[u32], out: &mut [u32]) {
+        let (width, height) = self.window;
+        let visible = self.size.0.min(width);
+        let columns: Vec<usize> = (0..visible).map(|x| x * WIDTH / self.size.0).collect();
+        out.fill(BLACK);
+        let mut previous = None;
+        for y in 0..self.size.1.min(height) {
+            let source = (y * HEIGHT / self.size.1) * WIDTH;
+            let target = (y + self.offset.1) * width + self.offset.0;
+            if previous == Some(source) {
+                out.copy_within(target - width..target - width + visible, target);
+            } else {
+                let row = &frame[source..source + WIDTH];
+                for (pixel, &x) in out[target..target + visible].iter_mut().zip(&columns) {
+                    *pixel = row[x];
+                }
+            }
+            previous = Some(source);
+        }
+    }
+}
+
+impl InputState {
+    fn from_window(window: &Window, letterbox: &Letterbox) -> Self {
+        InputState {
+            pressed: window.get_keys_pressed(minifb::KeyRepeat::No),
+            repeated: window.get_keys_pressed(minifb::KeyRepeat::Yes),
+            mouse: window
+                .get_mouse_pos(minifb::MouseMode::Clamp)
+                .map(|pos| letterbox.to_frame(pos)),
+            left: window.get_mouse_down(MouseButton::Left),
+            right: window.get_mouse_down(MouseButton::Right),
+            shift: window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift),
+        }
+    }
+
+    fn is_key_pressed(&self, key: Key, repeat: minifb::KeyRepeat) -> bool {
+        match repeat {
+            minifb::KeyRepeat::No => self.pressed.contains(&key),
+            minifb::KeyRepeat::Yes => self.repeated.contains(&key),
+        }
+    }
+
+    fn get_mouse_down(&self, button: MouseButton) -> bool {
+        match button {
+            MouseButton::Left => self.left,
+            MouseButton::Right => self.right,
+            MouseButton::Middle => false,
+        }
+    }
+}
+
+const MACRO_VERSION: u32 = 2;
+
+// Every key handle_input reacts to; others are left out of recordings.
+const MACRO_KEYS: [Key; 69] = [
+    Key::Key0,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+    Key::Space,
+    Key::Tab,
+    Key::Enter,
+    Key::Backspace,
+    Key::Delete,
+    Key::Insert,
+    Key::Home,
+    Key::End,
+    Key::PageUp,
+    Key::PageDown,
+    Key::Apostrophe,
+    Key::Backquote,
+    Key::Backslash,
+    Key::Comma,
+    Key::Equal,
+    Key::LeftBracket,
+    Key::Minus,
+    Key::Period,
+    Key::RightBracket,
+    Key::Semicolon,
+    Key::Slash,
+];
+
+fn macro_key(name: &str) -> Option<Key> {
+    MACRO_KEYS
+        .iter()
+        .copied()
+        .find(|k| format!("{:?}", k) == name)
+}
+
+// One line per frame: elapsed ms, ticks run, mouse position, buttons, shift, then pressed and repeated keys.
+#[derive(Clone, Debug, PartialEq)]
+struct MacroFrame {
+    at_ms: u64,
+    ticks: u32,
+    input: InputState,
+}
+
+impl MacroFrame {
+    fn to_line(&self) -> String {
+        let keys = |keys: &[Key]| -> String {
+            let names: Vec<String> = keys
+                .iter()
+                .filter(|k| MACRO_KEYS.contains(k))
+                .map(|k| format!("{:?}", k))
+                .collect();
+            if names.is_empty() {
+                "-".to_string()
+            } else {
+                names.join(",")
+            }
+        };
+        let mouse = match self.input.mouse {
+            Some((x, y)) => format!("{} {}", x, y),
+            None => "- -".to_string(),
+        };
+        format!(
+            "{} {} {} {} {} {} {} {}",
+            self.at_ms,
+            self.ticks,
+            mouse,
+            self.input.left as u8,
+            self.input.right as u8,
+            self.input.shift as u8,
+            keys(&self.input.pressed),
+            keys(&self.input.repeated)
+        )
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [at_ms, ticks, mx, my, left, right, shift, pressed, repeated] = fields[..] else {
+            return None;
+        };
+        let keys = |list: &str| -> Option<Vec<Key>> {
+            if list == "-" {
+                return Some(Vec::new());
+            }
+            list.split(',').map(macro_key).collect()
+        };
+        let mouse = match (mx, my) {
+            ("-", "-") => None,
+            _ => Some((mx.parse().ok()?, my.parse().ok()?)),
+        };
+        Some(MacroFrame {
+            at_ms: at_ms.parse().ok()?,
+            ticks: ticks.parse().ok()?,
+            input: InputState {
+                pressed: keys(pressed)?,
+                repeated: keys(repeated)?,
+                mouse,
+                left: left == "1",
+                right: right == "1",
+                shift: shift == "1",
+            },
+        })
+    }
+}
+
+struct MacroRecorder {
+    out: std::io::BufWriter<std::fs::File>,
+    started: Instant,
+}
+
+impl MacroRecorder {
+    fn create(path: &str, seed: Option<u64>) -> Result<Self, SimError> {
+        use std::io::Write;
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(out, "macro {}", MACRO_VERSION)?;
+        match seed {
+            Some(seed) => writeln!(out, "seed {}", seed)?,
+            None => writeln!(out, "seed -")?,
+        }
+        Ok(MacroRecorder {
+            out,
+            started: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, input: &InputState, ticks: u32) -> Result<(), SimError> {
+        use std::io::Write;
+        let frame = MacroFrame {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            ticks,
+            input: input.clone(),
+        };
+        writeln!(self.out, "{}", frame.to_line())?;
+        Ok(())
+    }
+}
+
+// Hands back recorded frames once their timestamp, scaled by speed, has passed.
+struct MacroPlayer {
+    frames: VecDeque<MacroFrame>,
+    started: Instant,
+    speed: f32,
+}
+
+impl MacroPlayer {
+    fn parse(text: &str, speed: f32) -> Result<(Self, Option<u64>), SimError> {
+        let err = |line: usize, message: &str| SimError::MacroFormat {
+            line,
+            message: message.to_string(),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(&format!("macro {}", MACRO_VERSION)) {
+            return Err(err(1, "missing or unsupported macro header"));
+        }
+        let seed = match lines.next().and_then(|l| l.strip_prefix("seed ")) {
+            Some("-") => None,
+            Some(seed) => Some(seed.parse().map_err(|_| err(2, "bad seed"))?),
+            None => return Err(err(2, "missing seed line")),
+        };
+        let frames = lines
+            .enumerate()
+            .map(|(i, line)| MacroFrame::parse(line).ok_or_else(|| err(i + 3, "bad frame")))
+            .collect::<Result<_, _>>()?;
+        let player = MacroPlayer {
+            frames,
+            started: Instant::now(),
+            speed,
+        };
+        Ok((player, seed))
+    }
+
+    fn next_due(&mut self, now: Instant) -> Option<MacroFrame> {
+        let elapsed = now.duration_since(self.started).as_secs_f64() * self.speed as f64;
+        self.frames
+            .pop_front_if(|f| f.at_ms as f64 <= elapsed * 1000.0)
+    }
+
+    fn is_done(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+fn handle_input(
+    input: &InputState,
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    collision_detector: &mut CollisionDetector,
+    stats: &mut Statistics,
+) {
+    if input.is_key_pressed(Key::Space, minifb::KeyRepeat::No) {
+        state.current_step = Step::Start;
+    }
+    if input.is_key_pressed(Key::O, minifb::KeyRepeat::No) {
+        state.current_step = Step::Obstacles;
+    }
+    if !input.shift && input.is_key_pressed(Key::G, minifb::KeyRepeat::No) {
+        state.current_step = Step::Goals;
+    }
+    if input.shift && input.is_key_pressed(Key::G, minifb::KeyRepeat::No) {
+        state.show_rulers = !state.show_rulers;
+        info!(
+            "Coordinate rulers: {}",
+            if state.show_rulers { "on" } else { "off" }
+        );
+    }
+    if input.is_key_pressed(Key::Z, minifb::KeyRepeat::No) {
+        state.current_step = Step::AvoidZone;
+    }
+    if input.is_key_pressed(Key::X, minifb::KeyRepeat::No) {
+        state.current_step = Step::PreferZone;
+    }
+    for (key, id) in [
+        (Key::I, "logger"),
+        (Key::U, "assistant"),
+        (Key::F6, "sound"),
+    ] {
+        if input.is_key_pressed(key, minifb::KeyRepeat::No) {
+            match collision_detector.bus.toggle(id) {
+                Some(true) => info!("Subscriber '{}' enabled", id),
+                Some(false) => info!("Subscriber '{}' disabled", id),
+                None => warn!("No subscriber named '{}'", id),
+            }
+        }
+    }
+    if input.is_key_pressed(Key::V, minifb::KeyRepeat::No) {
+        state.show_profile = !state.show_profile;
+    }
+    if !input.shift && input.is_key_pressed(Key::E, minifb::KeyRepeat::No) {
+        state.export_requested = true;
+    }
+    if input.shift && input.is_key_pressed(Key::E, minifb::KeyRepeat::No) {
+        state.screenshot_requested = true;
+    }
+    if input.is_key_pressed(Key::Q, minifb::KeyRepeat::No) {
+        state.current_step = Step::Passages;
+    }
+    if input.is_key_pressed(Key::Backspace, minifb::KeyRepeat::No) {
+        if state
+            .reset_armed
+            .take()
+            .is_some_and(|at| at.elapsed() < RESET_CONFIRM_WINDOW)
+        {
+            reset_scene(state, agents, history, collision_detector, stats, false);
+            info!("Cleared the scene");
+        } else {
+            state.reset_armed = Some(Instant::now());
+            info!("Press Backspace again to clear walls, agents and statistics");
+        }
+    }
+    if input.is_key_pressed(Key::Insert, minifb::KeyRepeat::No) {
+        reset_scene(state, agents, history, collision_detector, stats, true);
+        info!("Removed all agents, walls kept");
+    }
+    if input.is_key_pressed(Key::Home, minifb::KeyRepeat::No) {
+        finish_run(state, stats, agents);
+        begin_run(state, stats);
+    }
+    if input.is_key_pressed(Key::Semicolon, minifb::KeyRepeat::No) {
+        state.current_step = Step::Lights;
+    }
+    if input.is_key_pressed(Key::Slash, minifb::KeyRepeat::No) {
+        state.current_step = Step::Select;
+    }
+    if input.is_key_pressed(Key::F, minifb::KeyRepeat::No) {
+        state.current_step = Step::Reachability;
+    }
+    if input.shift && input.is_key_pressed(Key::F2, minifb::KeyRepeat::No) {
+        state.current_step = Step::Notes;
+    }
+    if input.shift && input.is_key_pressed(Key::F3, minifb::KeyRepeat::No) {
+        state.current_step = Step::Measure;
+        state.measure_anchor = None;
+    }
+    if input.is_key_pressed(Key::Key9, minifb::KeyRepeat::No) {
+        if state.comparison.take().is_some() {
+            info!("Comparison view closed");
+        } else {
+            let [left, right] = state.compare_planners;
+            state.comparison = Some(start_comparison(state, agents));
+            info!("Comparing {} with {}", left.name(), right.name());
+        }
+    }
+    if input.is_key_pressed(Key::Key0, minifb::KeyRepeat::No) {
+        state.show_distance_field = !state.show_distance_field;
+        info!(
+            "Distance field: {}",
+            if state.show_distance_field {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+    if state.current_step == Step::Select && !state.selection.is_empty() {
+        handle_selection_keys(input, state, agents, history, collision_detector, stats);
+    }
+    if input.is_key_pressed(Key::H, minifb::KeyRepeat::No) {
+        state.current_step = Step::Chargers;
+    }
+    if input.is_key_pressed(Key::K, minifb::KeyRepeat::No) {
+        state.battery_enabled = !state.battery_enabled;
+        info!(
+            "Battery constraint: {}",
+            if state.battery_enabled { "on" } else { "off" }
+        );
+    }
+    if input.is_key_pressed(Key::J, minifb::KeyRepeat::No) {
+        state.current_step = Step::Gates;
+    }
+    if input.is_key_pressed(Key::D, minifb::KeyRepeat::No) {
+        state.current_step = Step::OneWay;
+        state.one_way_anchor = None;
+    }
+    if input.shift && input.is_key_pressed(Key::Tab, minifb::KeyRepeat::No) {
+        state.reroute.min_level = state.reroute.min_level.next();
+        info!(
+            "Reroute from warning level: {}",
+            state.reroute.min_level.name()
+        );
+    }
+    if !input.shift && input.is_key_pressed(Key::Tab, minifb::KeyRepeat::No) {
+        state.reroute.trigger = match state.reroute.trigger.name() {
+            "Always" => Box::new(WithinStepsTrigger {
+                steps: TRIGGER_STEPS,
+            }),
+            "Within steps" => Box::new(ProbabilityTrigger {
+                probability: TRIGGER_PROBABILITY,
+            }),
+            "Probability" => Box::new(PriorityTrigger),
+            _ => Box::new(AlwaysTrigger),
+        };
+        info!("Reroute trigger: {}", state.reroute.trigger.name());
+    }
+    if input.is_key_pressed(Key::F7, minifb::KeyRepeat::No) {
+        collision_detector.expiry = collision_detector.expiry.next();
+        collision_detector.ignored_pairs.clear();
+        info!("Ignored pair expiry: {:?}", collision_detector.expiry);
+    }
+    if input.is_key_pressed(Key::F8, minifb::KeyRepeat::No) {
+        collision_detector.clear_on_reroute = !collision_detector.clear_on_reroute;
+        info!(
+            "Clear pairs on reroute: {}",
+            collision_detector.clear_on_reroute
+        );
+    }
+    if input.is_key_pressed(Key::Backslash, minifb::KeyRepeat::No) {
+        state.continuous = !state.continuous;
+        info!(
+            "Planning space: {}",
+            if state.continuous {
+                "continuous"
+            } else {
+                "grid"
+            }
+        );
+    }
+    if input.is_key_pressed(Key::Backquote, minifb::KeyRepeat::No) {
+        state.continuous_planner = match state.continuous_planner.name() {
+            "Visibility A*" => Box::new(VisibilityDijkstra),
+            "Visibility Dijkstra" => Box::new(PrmPlanner::new(
+                state.prm_samples,
+                state.prm_radius,
+                StdRng::from_rng(&mut state.spawn_rng),
+            )),
+            "PRM" => Box::new(RrtPlanner::new(false)),
+            "RRT" => Box::new(RrtPlanner::new(true)),
+            _ => Box::new(VisibilityAStar),
+        };
+        info!("Continuous planner: {}", state.continuous_planner.name());
+    }
+    if input.is_key_pressed(Key::End, minifb::KeyRepeat::No) && state.continuous {
+        compare_continuous_planners(agents, &state.world);
+    }
+    if input.is_key_pressed(Key::Apostrophe, minifb::KeyRepeat::No) {
+        state.reroute.turn_penalty = match state.reroute.turn_penalty {
+            None => Some(TURN_PENALTIES[0]),
+            Some(p) => TURN_PENALTIES.iter().copied().find(|&next| next > p),
+        };
+        match state.reroute.turn_penalty {
+            Some(p) => info!("Kinematic planning: turn penalty {} per 45 degrees", p),
+            None => info!("Kinematic planning off"),
+        }
+    }
+    if input.is_key_pressed(Key::Period, minifb::KeyRepeat::No) {
+        state.reroute.avoid_radius = (state.reroute.avoid_radius + 1).min(MAX_AVOID_RADIUS);
+        info!("Avoid radius: {}", state.reroute.avoid_radius);
+    }
+    if input.is_key_pressed(Key::Comma, minifb::KeyRepeat::No) {
+        state.reroute.avoid_radius = (state.reroute.avoid_radius - 1).max(0);
+        info!("Avoid radius: {}", state.reroute.avoid_radius);
+    }
+    if input.is_key_pressed(Key::M, minifb::KeyRepeat::No) {
+        state.movement_strategy = if state.movement_strategy.name() == "Orthogonal" {
+            Box::new(DiagonalMovement)
+        } else {
+            Box::new(OrthogonalMovement)
+        };
+        state
+            .components
+            .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+    }
+
+    if input.is_key_pressed(Key::C, minifb::KeyRepeat::No) {
+        let current = collision_detector.strategy.name();
+        collision_detector.set_strategy(match current {
+            "Path-based" => Box::new(GridCollisionStrategy),
+            "Grid-based" => Box::new(OrcaCollisionStrategy),
+            "ORCA" => Box::new(PredictiveCollisionStrategy),
+            _ => Box::new(PathCollisionStrategy),
+        });
+
+        stats.collisions = 0;
+        stats.detections = 0;
+        stats.recalculations = 0;
+        stats.total_steps = 0;
+        stats.total_path_length = 0;
+        stats.geometric_path_length = 0.0;
+        stats.reached_goal_count = 0;
+        stats.deadlocks_resolved = 0;
+        stats.capacity_conflicts = 0;
+        stats.suppressed_reroutes = 0;
+        stats.light_waits = 0;
+        stats.light_crossings = 0;
+        stats.knowledge_replans = 0;
+        stats.environment_replans = 0;
+        stats.moves = 0;
+        stats.waits = 0;
+        stats.yields = 0;
+        stats.separation_waits = 0;
+        stats.min_separation = None;
+
+        if collision_detector.strategy.name() == "ORCA" {
+            stats.method_name = "ORCA".to_owned();
+        } else if collision_detector.strategy.name() == "Grid-based" {
+            stats.method_name = "GRID".to_owned();
+        } else if collision_detector.strategy.name() == "Predictive" {
+            stats.method_name = "PREDICTIVE".to_owned();
+        } else {
+            stats.method_name = "PATH".to_owned();
+        }
+
+        info!("Switched to: {}", collision_detector.strategy.name());
+    }
+
+    if input.shift && input.is_key_pressed(Key::T, minifb::KeyRepeat::No) {
+        state.theme = state.theme.next();
+        info!("Theme: {}", state.theme.name);
+    }
+    if !input.shift && input.is_key_pressed(Key::T, minifb::KeyRepeat::No) {
+        state.reroute.time_aware = !state.reroute.time_aware;
+        info!(
+            "Time-aware reroute: {}",
+            if state.reroute.time_aware {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+
+    // With shift held the F-keys pick editor actions instead of scenarios.
+    for (key, scenario) in [
+        (Key::F1, Scenario::CrossingStreams),
+        (Key::F2, Scenario::NarrowBridge),
+        (Key::F3, Scenario::DenseCrowd),
+        (Key::F4, Scenario::RingSwap),
+        (Key::F11, state.city_layout),
+    ] {
+        if !input.shift && input.is_key_pressed(key, minifb::KeyRepeat::No) {
+            state.pending_scenario = Some(scenario);
+        }
+    }
+    if input.is_key_pressed(Key::F5, minifb::KeyRepeat::No) {
+        match save_map(state, agents, history, MAP_PATH) {
+            Ok(()) => state.bus.info(format!("Saved map to {}", MAP_PATH)),
+            Err(e) => state.bus.error(format!("Could not save map: {}", e)),
+        }
+    }
+    if input.is_key_pressed(Key::F9, minifb::KeyRepeat::No) {
+        match load_map(MAP_PATH, state, agents, history, stats) {
+            Ok(()) => state.bus.info(format!("Loaded map from {}", MAP_PATH)),
+            Err(e) => state.bus.error(format!("Could not load map: {}", e)),
+        }
+    }
+    if input.shift
+        && input.is_key_pressed(Key::F4, minifb::KeyRepeat::No)
+        && let Some(path) = state.recovery.take()
+    {
+        match load_map(&path, state, agents, history, stats) {
+            Ok(()) => state.bus.info("Restored the autosaved map".to_string()),
+            Err(e) => state
+                .bus
+                .error(format!("Could not restore autosave: {}", e)),
+        }
+    }
+    if input.is_key_pressed(Key::F12, minifb::KeyRepeat::No) {
+        state.comm_radius = match state.comm_radius {
+            None => Some(COMM_RADII[0]),
+            Some(r) => COMM_RADII.iter().copied().find(|&next| next > r),
+        };
+        stats.comm_radius = state.comm_radius.unwrap_or(-1);
+        match state.comm_radius {
+            Some(r) => {
+                reset_knowledge(agents, &state.grid, state.movement_strategy.as_ref());
+                info!("Shared map mode: communication radius {}", r);
+            }
+            None => info!("Shared map mode off"),
+        }
+    }
+    if input.is_key_pressed(Key::F10, minifb::KeyRepeat::No) {
+        let path = state.obstacle_image.clone();
+        match load_obstacle_image(&path) {
+            Ok(walls) => {
+                let import = ImportWallsCommand::new(&walls, state, agents);
+                let skipped = walls.len() - walls.intersection(&state.grid.walls).count();
+                let skipped = skipped - import.placed.len();
+                history.execute(Box::new(import), state, agents);
+                state.bus.info(format!(
+                    "Loaded {} walls from {} ({} reserved cells left free)",
+                    state.grid.walls.len(),
+                    path,
+                    skipped
+                ));
+            }
+            Err(e) => state
+                .bus
+                .error(format!("Could not load obstacle image: {}", e)),
+        }
+    }
+    if input.is_key_pressed(Key::Equal, minifb::KeyRepeat::Yes) {
+        state.scenario_agents += 1;
+        info!("Scenario agents: {}", state.scenario_agents);
+    }
+    if input.is_key_pressed(Key::Minus, minifb::KeyRepeat::Yes) {
+        state.scenario_agents = state.scenario_agents.saturating_sub(1).max(1);
+        info!("Scenario agents: {}", state.scenario_agents);
+    }
+
+    if input.is_key_pressed(Key::Y, minifb::KeyRepeat::No) {
+        state.reservation_stepping = !state.reservation_stepping;
+        info!(
+            "Reservation-aware stepping: {}",
+            if state.reservation_stepping {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+
+    let undone = input.is_key_pressed(Key::N, minifb::KeyRepeat::No) && history.undo(state, agents);
+    let redone =
+        input.is_key_pressed(Key::Key6, minifb::KeyRepeat::No) && history.redo(state, agents);
+    if undone || redone {
+        stats.agents = agents.len();
+        state.selection.clear();
+        collision_detector.ignored_pairs.clear();
+        if state.current_step == Step::End && agents.last().is_none_or(|a| a.end_point.is_some()) {
+            state.current_step = Step::Start;
+        }
+    }
+    if input.is_key_pressed(Key::B, minifb::KeyRepeat::No) {
+        history.execute(Box::new(DeleteCommand::new(1)), state, agents);
+    }
+
+    for (slot, &key) in SNAPSHOT_KEYS.iter().enumerate() {
+        if !input.is_key_pressed(key, minifb::KeyRepeat::No) {
+            continue;
+        }
+        if input.shift {
+            let snapshot = SimSnapshot::capture(state, agents, collision_detector, stats);
+            state.snapshots[slot] = Some(snapshot);
+            state.bus.info(format!(
+                "Saved slot {} at tick {}",
+                slot + 1,
+                state.tick_history.tick
+            ));
+        } else if let Some(snapshot) = state.snapshots[slot].take() {
+            snapshot.restore(state, agents, collision_detector, stats);
+            state.snapshots[slot] = Some(snapshot);
+            state.bus.info(format!(
+                "Restored slot {} at tick {}",
+                slot + 1,
+                state.tick_history.tick
+            ));
+        } else {
+            state.bus.warn(format!("Slot {} is empty", slot + 1));
+        }
+    }
+
+    if input.is_key_pressed(Key::S, minifb::KeyRepeat::Yes)
+        && state.tick_history.rewind(agents, stats)
+    {
+        state.grid.tick = state.tick_history.tick;
+        collision_detector.ignored_pairs.clear();
+        info!("Rewound to tick {}", state.tick_history.tick);
+    }
+
+    if input.is_key_pressed(Key::L, minifb::KeyRepeat::No) {
+        let next = TRAIL_LENGTHS
+            .iter()
+            .position(|&l| l == state.trail_length)
+            .map_or(0, |i| (i + 1) % TRAIL_LENGTHS.len());
+        state.trail_length = TRAIL_LENGTHS[next];
+        info!("Trail length: {}", state.trail_length);
+    }
+
+    if input.is_key_pressed(Key::P, minifb::KeyRepeat::No) {
+        state.running = !state.running;
+    }
+    if input.is_key_pressed(Key::RightBracket, minifb::KeyRepeat::Yes) {
+        state.ticks_per_second = (state.ticks_per_second + 1).min(TARGET_FPS as u32);
+    }
+    if input.is_key_pressed(Key::LeftBracket, minifb::KeyRepeat::Yes) {
+        state.ticks_per_second = state.ticks_per_second.saturating_sub(1).max(1);
+    }
+
+    if input.is_key_pressed(Key::W, minifb::KeyRepeat::Yes) {
+        state.step_requests += 1;
+    }
+
+    if input.is_key_pressed(Key::R, minifb::KeyRepeat::No) {
+        let count = state.spawn_rng.random_range(3..=12);
+        let mut used: HashSet<Node> = agents
+            .iter()
+            .flat_map(|a| {
+                [a.start_point, a.current_point]
+                    .into_iter()
+                    .chain(a.end_point)
+            })
+            .collect();
+
+        for spawned in 0..count {
+            let Some((start, end)) = spawn_pair(
+                &mut state.spawn_rng,
+                &state.grid.walls,
+                &used,
+                state.min_spawn_distance,
+            ) else {
+                state.bus.warn(format!(
+                    "Only found room for {} of {} agents",
+                    spawned, count
+                ));
+                break;
+            };
+            used.extend([start, end]);
+            let add = AddAgentCommand {
+                start,
+                goal: Some(end),
+            };
+            history.execute(Box::new(add), state, agents);
+            stats.agents += 1;
+        }
+    }
+
+    if input.is_key_pressed(Key::A, minifb::KeyRepeat::No) {
+        plan_all_agents(state, agents, history, stats);
+    }
+
+    let is_pressed = input.get_mouse_down(MouseButton::Left);
+    if is_pressed && !state.was_pressed {
+        if let Some(cell) = mouse_cell(input) {
+            match state.current_step {
+                Step::Obstacles if state.continuous => {
+                    state.world.add_obstacle(random_obstacle(cell.to_pixels()));
+                }
+                Step::Obstacles if cell_reserved(cell, agents, &state.goal_set) => {
+                    debug!("Refused wall at ({}, {})", cell.x, cell.y);
+                    state.refused_cell = Some((cell, Instant::now()));
+                }
+                Step::Obstacles => {
+                    if !state.grid.walls.contains(&cell) {
+                        history.execute(Box::new(WallCommand { cell, place: true }), state, agents);
+                    }
+                }
+                Step::Start => {
+                    if !state.grid.walls.contains(&cell) {
+                        let add = AddAgentCommand {
+                            start: cell,
+                            goal: None,
+                        };
+                        history.execute(Box::new(add), state, agents);
+                        state.current_step = Step::End;
+                    }
+                }
+                Step::End => {
+                    if !state.grid.walls.contains(&cell) {
+                        let last = agents.last().unwrap();
+                        let set = SetGoalCommand {
+                            agent: last.id,
+                            goal: cell,
+                            previous: last.end_point,
+                        };
+                        history.execute(Box::new(set), state, agents);
+                        state.current_step = Step::Start;
+                    }
+                }
+                Step::Goals => {
+                    if !state.grid.walls.contains(&cell) && !state.goal_set.remove(&cell) {
+                        state.goal_set.insert(cell);
+                    }
+                }
+                Step::AvoidZone => {
+                    state.grid.toggle_zone(cell, Zone::Avoid);
+                    state.layout_revision += 1;
+                }
+                Step::PreferZone => {
+                    state.grid.toggle_zone(cell, Zone::Prefer);
+                    state.layout_revision += 1;
+                }
+                Step::Chargers => {
+                    if !state.grid.walls.contains(&cell) {
+                        if !state.grid.chargers.remove(&cell) {
+                            state.grid.chargers.insert(cell);
+                        }
+                        state.layout_revision += 1;
+                    }
+                }
+                Step::Passages => {
+                    if !state.grid.walls.contains(&cell) {
+                        state.grid.cycle_capacity(cell);
+                    }
+                }
+                Step::Gates => {
+                    if !state.grid.walls.contains(&cell) {
+                        state.grid.cycle_gate(cell);
+                        state.layout_revision += 1;
+                    }
+                }
+                Step::Lights => {
+                    if !state.grid.walls.contains(&cell) {
+                        state.grid.cycle_light(cell);
+                        state.light_throughput.remove(&cell);
+                    }
+                }
+                Step::OneWay => match state.one_way_anchor.take() {
+                    Some(from)
+                        if from != cell
+                            && (from.x - cell.x).abs() <= 1
+                            && (from.y - cell.y).abs() <= 1 =>
+                    {
+                        state.grid.toggle_one_way(from, cell);
+                        state.layout_revision += 1;
+                    }
+                    _ => state.one_way_anchor = Some(cell),
+                },
+                Step::Select => state.drag = Some((cell, cell)),
+                Step::Reachability => {
+                    state.reach_origin = (!state.grid.walls.contains(&cell)).then_some(cell);
+                    refresh_reachability(state);
+                }
+                Step::Notes => state.grid.cycle_note(cell),
+                Step::Measure => match state.measure_anchor.take() {
+                    Some(from) => {
+                        let movement = state.movement_strategy.as_ref();
+                        let measurement = Measurement::new(from, cell, &state.grid, movement);
+                        info!("Measured {:?} to {:?}: {}", from, cell, measurement.text());
+                        state.measurement = Some(measurement);
+                    }
+                    None => {
+                        state.measure_anchor = Some(cell);
+                        state.measurement = None;
+                    }
+                },
+            }
+        }
+    }
+    if let (Some(drag), Some(cell)) = (state.drag.as_mut(), mouse_cell(input)) {
+        drag.1 = cell;
+    }
+    if let Some((from, to)) = state.drag.take_if(|_| !is_pressed) {
+        state.selection = select_in_rect(agents, from, to);
+        info!("Selected {} agents", state.selection.len());
+    }
+    state.was_pressed = is_pressed;
+
+    let is_right_pressed = input.get_mouse_down(MouseButton::Right);
+    let erasing =
+        is_right_pressed && !state.was_right_pressed && state.current_step == Step::Obstacles;
+    if let Some(cell) = mouse_cell(input).filter(|c| erasing && state.grid.walls.contains(c)) {
+        history.execute(Box::new(WallCommand { cell, place: false }), state, agents);
+    }
+    let clicked =
+        is_right_pressed && !state.was_right_pressed && state.current_step == Step::Select;
+    if let Some(cell) = mouse_cell(input).filter(|c| clicked && !state.grid.walls.contains(c)) {
+        for index in selected_indices(state, agents) {
+            agents[index].end_point = Some(cell);
+            agents[index].finished = false;
+            agents[index].arrival_tick = None;
+            if replan_agent(
+                agents,
+                index,
+                &state.grid,
+                state.movement_strategy.as_ref(),
+                &state.reroute,
+            ) {
+                stats.recalculations += 1;
+            }
+        }
+        info!(
+            "Sent {} agents to ({}, {})",
+            state.selection.len(),
+            cell.x,
+            cell.y
+        );
+    }
+    state.was_right_pressed = is_right_pressed;
+    state.hovered = mouse_cell(input);
+    update_path_preview(input, state, agents);
+
+    if !state.wall_edits.is_empty() {
+        refresh_reachability(state);
+    }
+    update_distance_field(state, agents, !state.wall_edits.is_empty());
+    for (cell, placed) in std::mem::take(&mut state.wall_edits) {
+        let replanned = replan_for_wall(
+            agents,
+            cell,
+            placed,
+            &state.grid,
+            state.movement_strategy.as_ref(),
+            &state.reroute,
+        );
+        if replanned > 0 {
+            info!(
+                "Wall edit at ({}, {}) replanned {} agents",
+                cell.x, cell.y, replanned
+            );
+        }
+        stats.environment_replans += replanned;
+    }
+}
+
+fn refresh_reachability(state: &mut GameState) {
+    state.reachable = match state.reach_origin {
+        Some(origin) if !state.grid.walls.contains(&origin) => {
+            reachable_cells(origin, &state.grid, state.movement_strategy.as_ref())
+        }
+        _ => HashSet::new(),
+    };
+}
+
+// Follows the first selected agent that has a goal; walls_changed forces a recompute for the same goal.
+fn update_distance_field(state: &mut GameState, agents: &[Agent], walls_changed: bool) {
+    let target = selected_indices(state, agents)
+        .into_iter()
+        .find_map(|i| agents[i].end_point.map(|goal| (agents[i].id, goal)))
+        .filter(|_| state.show_distance_field);
+    let Some((agent, goal)) = target else {
+        state.distance_field = None;
+        return;
+    };
+    if !walls_changed
+        && state
+            .distance_field
+            .as_ref()
+            .is_some_and(|f| f.agent == agent && f.goal == goal)
+    {
+        return;
+    }
+    state.distance_field = Some(DistanceField {
+        agent,
+        goal,
+        costs: distance_field(goal, &state.grid, state.movement_strategy.as_ref()),
+    });
+}
+
+// Starts a fresh run and plans every agent from its start point.
+fn plan_all_agents(
+    state: &mut GameState,
+    agents: &mut [Agent],
+    history: &mut CommandHistory,
+    stats: &mut Statistics,
+) {
+    let _span = Span::enter(format!("plan {} agents", agents.len()));
+    finish_run(state, stats, agents);
+    begin_run(state, stats);
+    state.step_history.clear();
+    state.tick_history.clear();
+    state.grid.tick = 0;
+    history.clear();
+
+    let mut total_len = 0;
+    let mut total_geometric = 0.0;
+    for agent in agents.iter_mut() {
+        agent.last_reroute_tick = None;
+        agent.arrival_tick = None;
+        agent.waypoints.clear();
+        agent.waypoint_index = 0;
+        if state.continuous {
+            let Some(goal) = agent.end_point else {
+                continue;
+            };
+            agent.path = None;
+            agent.current_point = agent.start_point;
+            agent.position = agent.start_point.to_pixels();
+            agent.trail.clear();
+            agent.refresh_cache();
+            match state
+                .continuous_planner
+                .plan(agent.position, goal.to_pixels(), &state.world)
+            {
+                Some(waypoints) => {
+                    agent.waypoints = waypoints;
+                    agent.finished = false;
+                }
+                None => state
+                    .bus
+                    .warn(format!("No continuous path found for agent {}", agent.id)),
+            }
+            continue;
+        }
+        let grid = state.grid.inflated(agent.footprint);
+        let Some(goal) = agent.end_point else {
+            let goals: Vec<Node> = state
+                .goal_set
+                .iter()
+                .copied()
+                .filter(|&g| {
+                    state.components.reachable(
+                        agent.start_point,
+                        g,
+                        state.movement_strategy.as_ref(),
+                    )
+                })
+                .collect();
+            if let Some(path) = a_star_multi(
+                agent.start_point,
+                &goals,
+                &grid,
+                state.movement_strategy.as_ref(),
+            ) {
+                total_len += path.len();
+                total_geometric += state.grid.geometric_length(&path);
+                agent.end_point = path.last().copied();
+                agent.path = Some(path);
+                agent.finished = false;
+                agent.current_point = agent.start_point;
+                agent.position = agent.start_point.to_pixels();
+                agent.path_index = 0;
+                agent.trail.clear();
+                agent.refresh_cache();
+            } else if !state.goal_set.is_empty() {
+                state.bus.warn(format!(
+                    "No path found for agent {} — no goal is reachable",
+                    agent.id
+                ));
+            }
+            continue;
+        };
+        if !state
+            .components
+            .reachable(agent.start_point, goal, state.movement_strategy.as_ref())
+        {
+            state.bus.warn(format!(
+                "No path found for agent {} — goal is unreachable",
+                agent.id
+            ));
+            continue;
+        }
+        agent.battery = BATTERY_CAPACITY;
+        let path = if state.battery_enabled {
+            a_star_battery(
+                agent.start_point,
+                agent.battery,
+                goal,
+                &grid,
+                state.movement_strategy.as_ref(),
+            )
+        } else if let Some(penalty) = state.reroute.turn_penalty {
+            agent.heading = Node { x: 0, y: 0 };
+            a_star_kinematic(
+                agent.start_point,
+                agent.heading,
+                goal,
+                &grid,
+                state.movement_strategy.as_ref(),
+                penalty,
+            )
+        } else {
+            a_star(
+                agent.start_point,
+                goal,
+                &grid,
+                state.movement_strategy.as_ref(),
+            )
+        };
+        if let Some(path) = path {
+            total_len += path.len();
+            total_geometric += state.grid.geometric_length(&path);
+            agent.path = Some(path);
+            agent.finished = false;
+            agent.current_point = agent.start_point;
+            agent.position = agent.start_point.to_pixels();
+            agent.path_index = 0;
+            agent.trail.clear();
+            agent.refresh_cache();
+        } else {
+            state
+                .bus
+                .warn(format!("No path found for agent {}", agent.id));
+        }
+    }
+    stats.total_path_length += total_len;
+    stats.geometric_path_length += total_geometric;
+}
+
+// While a start waits for its goal, plan to the hovered cell at most once per PREVIEW_INTERVAL.
+fn update_path_preview(input: &InputState, state: &mut GameState, agents: &[Agent]) {
+    let start = match agents.last() {
+        Some(agent) if state.current_step == Step::End && agent.end_point.is_none() => {
+            agent.start_point
+        }
+        _ => {
+            state.preview = None;
+            return;
+        }
+    };
+    let Some(target) = mouse_cell(input) else {
+        return;
+    };
+    if state
+        .preview
+        .as_ref()
+        .is_some_and(|p| p.target == target || p.computed.elapsed() < PREVIEW_INTERVAL)
+    {
+        return;
+    }
+    let path = if state.grid.walls.contains(&target) {
+        None
+    } else {
+        a_star(start, target, &state.grid, state.movement_strategy.as_ref())
+    };
+    state.preview = Some(PathPreview {
+        target,
+        computed: Instant::now(),
+        path,
+    });
+}
+
+fn mouse_cell(input: &InputState) -> Option<Node> {
+    let (mx, my) = input.mouse?;
+    Some(PixelCoord::from_vec(Vec2::new(mx, my)).cell().node())
+}
+
+fn select_in_rect(agents: &[Agent], a: Node, b: Node) -> HashSet<usize> {
+    let (min_x, max_x) = (a.x.min(b.x), a.x.max(b.x));
+    let (min_y, max_y) = (a.y.min(b.y), a.y.max(b.y));
+    agents
+        .iter()
+        .filter(|agent| {
+            let p = agent.current_point;
+            (min_x..=max_x).contains(&p.x) && (min_y..=max_y).contains(&p.y)
+        })
+        .map(|agent| agent.id)
+        .collect()
+}
+
+fn selected_indices(state: &GameState, agents: &[Agent]) -> Vec<usize> {
+    (0..agents.len())
+        .filter(|&i| state.selection.contains(&agents[i].id))
+        .collect()
+}
+
+// Removes the agents and renumbers the rest so ids keep matching their index.
+// A soft reset drops agents and their paths; a full one also clears the map, undo history and counters.
+fn reset_scene(
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    collision_detector: &mut CollisionDetector,
+    stats: &mut Statistics,
+    keep_walls: bool,
+) {
+    finish_run(state, stats, agents);
+    agents.clear();
+    state.selection.clear();
+    state.drag = None;
+    state.step_history.clear();
+    state.tick_history.clear();
+    state.tick_history.tick = 0;
+    state.grid.tick = 0;
+    collision_detector.ignored_pairs.clear();
+    collision_detector.tick = 0;
+    stats.agents = 0;
+
+    if !keep_walls {
+        state.grid = Grid::new();
+        state.layout_revision += 1;
+        state.goal_set.clear();
+        state.goal_deliveries.clear();
+        state.one_way_anchor = None;
+        state.light_throughput.clear();
+        state.world.clear();
+        state.scenario = None;
+        state.reach_origin = None;
+        state.reachable.clear();
+        state.measure_anchor = None;
+        state.measurement = None;
+        state.comparison = None;
+        state
+            .components
+            .rebuild(&state.grid.walls, state.movement_strategy.as_ref());
+        history.clear();
+    }
+    begin_run(state, stats);
+}
+
+fn delete_agents(agents: &mut Vec<Agent>, ids: &HashSet<usize>) -> usize {
+    let before = agents.len();
+    agents.retain(|a| !ids.contains(&a.id));
+    for (index, agent) in agents.iter_mut().enumerate() {
+        agent.id = index;
+    }
+    before - agents.len()
+}
+
+fn handle_selection_keys(
+    input: &InputState,
+    state: &mut GameState,
+    agents: &mut Vec<Agent>,
+    history: &mut CommandHistory,
+    collision_detector: &mut CollisionDetector,
+    stats: &mut Statistics,
+) {
+    if input.is_key_pressed(Key::Delete, minifb::KeyRepeat::No) {
+        let delete = DeleteAgentsCommand::new(agents, &state.selection);
+        let removed = delete.removed.len();
+        history.execute(Box::new(delete), state, agents);
+        stats.agents = stats.agents.saturating_sub(removed);
+        state.selection.clear();
+        state.tick_history.clear();
+        collision_detector.ignored_pairs.clear();
+        info!("Deleted {} agents", removed);
+    }
+    if input.is_key_pressed(Key::Enter, minifb::KeyRepeat::No) {
+        for index in selected_indices(state, agents) {
+            if replan_agent(
+                agents,
+                index,
+                &state.grid,
+                state.movement_strategy.as_ref(),
+                &state.reroute,
+            ) {
+                stats.recalculations += 1;
+            }
+        }
+        info!("Replanned {} agents", state.selection.len());
+    }
+    if input.is_key_pressed(Key::Key7, minifb::KeyRepeat::No) {
+        for index in selected_indices(state, agents) {
+            let agent = &mut agents[index];
+            agent.footprint = agent.footprint.next();
+            info!("Agent {} footprint {}", agent.id, agent.footprint.name());
+            if state
+                .grid
+                .inflated(agent.footprint)
+                .walls
+                .contains(&agent.current_point)
+            {
+                warn!("Agent {} does not fit where it stands", agent.id);
+            }
+        }
+    }
+    if input.is_key_pressed(Key::Key8, minifb::KeyRepeat::No) {
+        for index in selected_indices(state, agents) {
+            let agent = &mut agents[index];
+            agent.cell_radius = (agent.cell_radius + 1) % (MAX_CELL_RADIUS + 1);
+            agent.refresh_cache();
+            info!("Agent {} radius {} cells", agent.id, agent.cell_radius);
+        }
+    }
+    for (key, delta) in [(Key::PageUp, 1), (Key::PageDown, -1)] {
+        if input.is_key_pressed(key, minifb::KeyRepeat::No) {
+            for index in selected_indices(state, agents) {
+                let agent = &mut agents[index];
+                agent.priority =
+                    (agent.priority as i32 + delta).clamp(0, MAX_PRIORITY as i32) as u8;
+                info!("Agent {} priority {}", agent.id, agent.priority);
+            }
+        }
+    }
+}
+
+// Draw order over the static layer; the queue sorts by it, keeping push order within a layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Layer {
+    Cells,
+    World,
+    Paths,
+    Markers,
+    Agents,
+    Overlay,
+    Labels,
+}
+
+// Inclusive pixel rectangle; the queue's viewport and each command's extent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Bounds {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+}
+
+impl Bounds {
+    fn screen() -> Self {
+        Bounds {
+            x0: 0,
+            y0: 0,
+            x1: WIDTH as i32 - 1,
+            y1: HEIGHT as i32 - 1,
+        }
+    }
+
+    fn around(x: i32, y: i32, half_w: i32, half_h: i32) -> Self {
+        Bounds {
+            x0: x - half_w,
+            y0: y - half_h,
+            x1: x + half_w,
+            y1: y + half_h,
+        }
+    }
+
+    fn of_points(points: &[Vec2]) -> Self {
+        let (min, max) = points.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), &v| (min.min(v), max.max(v)),
+        );
+        Bounds {
+            x0: min.x.floor() as i32,
+            y0: min.y.floor() as i32,
+            x1: max.x.ceil() as i32,
+            y1: max.y.ceil() as i32,
+        }
+    }
+
+    fn intersects(&self, other: &Bounds) -> bool {
+        self.x0 <= other.x1 && other.x0 <= self.x1 && self.y0 <= other.y1 && other.y0 <= self.y1
+    }
+}
+
+// Where a command's pixels can land, used to cull it before rasterizing.
+fn bounds(item: &DrawType) -> Bounds {
+    match item {
+        DrawType::Line(p) | DrawType::DashedLine(DashedLineParams { line: p, .. }) => {
+            let pad = p.thickness.max(1) as i32 / 2;
+            Bounds {
+                x0: p.x0.min(p.x1) - pad,
+                y0: p.y0.min(p.y1) - pad,
+                x1: p.x0.max(p.x1) + pad,
+                y1: p.y0.max(p.y1) + pad,
+            }
+        }
+        DrawType::Square(p) => {
+            let c = p.cell.top_left();
+            Bounds {
+                x0: c.x as i32,
+                y0: c.y as i32,
+                x1: (c.x + CELL_WIDTH) as i32 - 1,
+                y1: (c.y + CELL_HEIGHT) as i32 - 1,
+            }
+        }
+        DrawType::Circle(p) => {
+            let r = p.radius as i32;
+            Bounds::around(p.center.x as i32, p.center.y as i32, r, r)
+        }
+        DrawType::BlendCircle(p) => {
+            let r = p.radius as i32;
+            Bounds::around(p.center.x as i32, p.center.y as i32, r, r)
+        }
+        DrawType::Rect(p) => Bounds {
+            x0: p.x as i32,
+            y0: p.y as i32,
+            x1: (p.x + p.width) as i32 - 1,
+            y1: (p.y + p.height) as i32 - 1,
+        },
+        DrawType::Text(p) => Bounds {
+            x0: p.x as i32,
+            y0: p.y as i32,
+            x1: (p.x + text_width(&p.text, p.scale)) as i32,
+            y1: (p.y + GLYPH_HEIGHT * p.scale) as i32,
+        },
+        DrawType::Triangle(p) => Bounds::of_points(&p.points),
+        DrawType::Polygon(p) => Bounds::of_points(&p.points),
+        DrawType::Sprite(p) => Bounds::around(
+            p.center.x as i32,
+            p.center.y as i32,
+            p.sprite.width as i32 / 2 + 1,
+            p.sprite.height as i32 / 2 + 1,
+        ),
+    }
+}
+
+// A drawing backend; the framebuffer is the only one today.
+trait Artist {
+    fn draw(&mut self, item: &DrawType);
+}
+
+impl Artist for [u32] {
+    fn draw(&mut self, item: &DrawType) {
+        draw(self, item);
+    }
+}
+
+struct RenderQueue {
+    items: Vec<(Layer, DrawType)>,
+    viewport: Bounds,
+}
+
+impl RenderQueue {
+    fn new(viewport: Bounds) -> Self {
+        RenderQueue {
+            items: Vec::new(),
+            viewport,
+        }
+    }
+
+    // Commands that cannot reach the viewport are dropped here, before they are queued.
+    fn push(&mut self, layer: Layer, item: DrawType) {
+        if bounds(&item).intersects(&self.viewport) {
+            self.items.push((layer, item));
+        }
+    }
+
+    // Cell tints go over the cached static layer, so they leave walls alone and stop short of the grid lines.
+    fn tint(&mut self, grid: &Grid, node: Node, color: u32) {
+        if grid.walls.contains(&node) {
+            return;
+        }
+        let corner = node.cell().top_left();
+        let (dx, dy) = (usize::from(node.x > 0), usize::from(node.y > 0));
+        self.push(
+            Layer::Cells,
+            DrawType::Rect(RectParams {
+                x: corner.x + dx,
+                y: corner.y + dy,
+                width: CELL_WIDTH - dx,
+                height: CELL_HEIGHT - dy,
+                color,
+            }),
+        );
+    }
+
+    fn label(&mut self, x: usize, y: usize, text: String, color: u32) {
+        self.push(
+            Layer::Labels,
+            DrawType::Text(TextParams {
+                x,
+                y,
+                text,
+                scale: 1,
+                color,
+            }),
+        );
+    }
+
+    // Column numbers along the bottom edge, row numbers along the right one (the HUD owns the
+    // top left), and the hovered cell's coordinates next to it in the same "(x, y)" form as the logs.
+    fn rulers(&mut self, hovered: Option<Node>) {
+        let bar = |x, y, width, height| {
+            DrawType::Rect(RectParams {
+                x,
+                y,
+                width,
+                height,
+                color: BLACK,
+            })
+        };
+        let ruler_width = text_width("00", 1) + 4;
+        self.push(
+            Layer::Labels,
+            bar(0, HEIGHT - RULER_HEIGHT, WIDTH, RULER_HEIGHT),
+        );
+        self.push(
+            Layer::Labels,
+            bar(WIDTH - ruler_width, 0, ruler_width, HEIGHT),
+        );
+        let highlight = |hit: bool| if hit { YELLOW } else { GREY };
+        for col in 0..COLUMNS {
+            let text = col.to_string();
+            let x = Col(col).left() + (CELL_WIDTH - text_width(&text, 1)) / 2;
+            let hit = hovered.is_some_and(|h| h.ux() == col);
+            self.label(x, HEIGHT - RULER_HEIGHT + 2, text, highlight(hit));
+        }
+        for row in 0..ROWS {
+            let y = Row(row).top() + (CELL_HEIGHT - GLYPH_HEIGHT) / 2;
+            let hit = hovered.is_some_and(|h| h.uy() == row);
+            self.label(WIDTH - ruler_width + 2, y, row.to_string(), highlight(hit));
+        }
+        if let Some(cell) = hovered {
+            let text = format!("({}, {})", cell.x, cell.y);
+            let width = text_width(&text, 1) + 4;
+            let corner = cell.cell().top_left().offset(CELL_WIDTH, CELL_HEIGHT);
+            let x = corner.x.min(WIDTH - ruler_width - width);
+            let y = corner.y.min(HEIGHT - RULER_HEIGHT - RULER_HEIGHT);
+            self.push(Layer::Labels, bar(x, y, width, RULER_HEIGHT));
+            self.label(x + 2, y + 2, text, YELLOW);
+        }
+    }
+
+    fn arrow(&mut self, from: Node, to: Node, color: u32) {
+        let a = from.to_pixels();
+        let b = to.to_pixels();
+        let dir = (b - a).normalize_or_zero();
+        let perp = Vec2::new(-dir.y, dir.x);
+        let tail = a + dir * (CELL_WIDTH as f32 * 0.2);
+        let tip = b - dir * (CELL_WIDTH as f32 * 0.2);
+        let head = CELL_WIDTH as f32 * 0.2;
+
+        for (p0, p1) in [
+            (tail, tip),
+            (tip, tip - dir * head + perp * head * 0.6),
+            (tip, tip - dir * head - perp * head * 0.6),
+        ] {
+            self.push(
+                Layer::World,
+                DrawType::Line(LineParams {
+                    x0: p0.x as i32,
+                    y0: p0.y as i32,
+                    x1: p1.x as i32,
+                    y1: p1.y as i32,
+                    color,
+                    thickness: 1,
+                }),
+            );
+        }
+    }
+
+    // Draws the queued commands in layer order and returns how many were drawn.
+    fn flush<A: Artist + ?Sized>(mut self, artist: &mut A) -> usize {
+        self.items.sort_by_key(|(layer, _)| *layer);
+        for (_, item) in &self.items {
+            artist.draw(item);
+        }
+        self.items.len()
+    }
+}
+
+// Background, grid lines and walls; rasterized again only when the walls, their look or the viewport change.
+#[derive(Default)]
+struct StaticLayer {
+    pixels: Vec<u32>,
+    walls: HashSet<Node>,
+    viewport: Option<Bounds>,
+    theme: Option<Theme>,
+    wall_sprite: Option<Rc<Sprite>>,
+    rebuilds: usize,
+}
+
+impl StaticLayer {
+    fn is_current(&self, state: &GameState) -> bool {
+        let same_sprite = match (&self.wall_sprite, &state.sprites.wall) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        self.theme == Some(state.theme)
+            && self.viewport == Some(state.viewport)
+            && same_sprite
+            && self.walls == state.grid.walls
+    }
+
+    fn draw(&mut self, buffer: &mut [u32], state: &GameState) {
+        if !self.is_current(state) {
+            let theme = state.theme;
+            self.pixels.resize(WIDTH * HEIGHT, 0);
+            self.pixels.fill(theme.background);
+            draw_matrix(&mut self.pixels, theme.gridline);
+            for node in &state.grid.walls {
+                let item = match &state.sprites.wall {
+                    Some(sprite) => DrawType::Sprite(SpriteParams {
+                        center: node.cell().center(),
+                        sprite: sprite.clone(),
+                        tint: None,
+                    }),
+                    None => DrawType::Square(SquareParams {
+                        cell: node.cell(),
+                        color: theme.wall,
+                    }),
+                };
+                if bounds(&item).intersects(&state.viewport) {
+                    draw(&mut self.pixels, &item);
+                }
+            }
+            self.walls = state.grid.walls.clone();
+            self.viewport = Some(state.viewport);
+            self.theme = Some(theme);
+            self.wall_sprite = state.sprites.wall.clone();
+            self.rebuilds += 1;
+        }
+        buffer.copy_from_slice(&self.pixels);
+    }
+}
+
+fn render(
+    buffer: &mut [u32],
+    state: &GameState,
+    agents: &[Agent],
+    draw_radius: bool,
+    is_orca: bool,
+) {
+    state.static_layer.borrow_mut().draw(buffer, state);
+    scene(state, agents, draw_radius, is_orca).flush(buffer);
+}
+
+// Everything drawn over the static layer, as commands; nothing here touches the framebuffer.
+fn scene(state: &GameState, agents: &[Agent], draw_radius: bool, is_orca: bool) -> RenderQueue {
+    let theme = &state.theme;
+    let mut queue = RenderQueue::new(state.viewport);
+    if state.current_step == Step::Reachability && state.reach_origin.is_some() {
+        for y in 0..ROWS {
+            for x in 0..COLUMNS {
+                let node = Node {
+                    x: x as i32,
+                    y: y as i32,
+                };
+                let reachable = state.reachable.contains(&node);
+                queue.tint(
+                    &state.grid,
+                    node,
+                    if reachable {
+                        REACHABLE_TINT
+                    } else {
+                        UNREACHABLE_TINT
+                    },
+                );
+            }
+        }
+    }
+    if let Some(field) = &state.distance_field {
+        let max = field.costs.values().copied().max().unwrap_or(0).max(1);
+        for (node, &cost) in &field.costs {
+            queue.tint(
+                &state.grid,
+                *node,
+                blend(FIELD_FAR, FIELD_NEAR, cost as f32 / max as f32),
+            );
+            queue.push(
+                Layer::Labels,
+                DrawType::Text(TextParams {
+                    x: node.cell().top_left().x + 3,
+                    y: node.cell().top_left().y + CELL_HEIGHT - GLYPH_HEIGHT - 3,
+                    text: format!("{}", cost as f32 / STEP_COST as f32),
+                    scale: 1,
+                    color: WHITE,
+                }),
+            );
+        }
+    }
+    for (node, zone) in &state.grid.zones {
+        queue.tint(
+            &state.grid,
+            *node,
+            match zone {
+                Zone::Avoid => AVOID_TINT,
+                Zone::Prefer => PREFER_TINT,
+            },
+        );
+    }
+
+    for node in &state.grid.chargers {
+        queue.tint(&state.grid, *node, CHARGER_COLOR);
+    }
+
+    for (node, capacity) in &state.grid.capacities {
+        queue.tint(&state.grid, *node, PASSAGE_TINT);
+        queue.push(
+            Layer::Labels,
+            DrawType::Text(TextParams {
+                x: node.cell().top_left().x + 3,
+                y: node.cell().top_left().y + 3,
+                text: capacity.to_string(),
+                scale: 1,
+                color: WHITE,
+            }),
+        );
+    }
+
+    for (node, gate) in &state.grid.gates {
+        queue.tint(
+            &state.grid,
+            *node,
+            if gate.is_open(state.grid.tick) {
+                GATE_OPEN
+            } else {
+                GATE_CLOSED
+            },
+        );
+    }
+
+    for (node, light) in &state.grid.lights {
+        queue.tint(&state.grid, *node, LIGHT_CELL);
+        let center = node.to_pixels();
+        let (cx, cy) = (center.x as i32, center.y as i32);
+        let half = (CELL_WIDTH / 2) as i32 - 3;
+        let horizontal = light.horizontal_green(state.grid.tick);
+        for (dx, dy, green) in [(half, 0, horizontal), (0, half, !horizontal)] {
+            queue.push(
+                Layer::Cells,
+                DrawType::Line(LineParams {
+                    x0: cx - dx,
+                    y0: cy - dy,
+                    x1: cx + dx,
+                    y1: cy + dy,
+                    color: if green { LIGHT_GREEN } else { LIGHT_RED },
+                    thickness: 1,
+                }),
+            );
+        }
+    }
+
+    if state.continuous {
+        for (a, b) in state.continuous_planner.overlay(&state.world) {
+            queue.push(
+                Layer::World,
+                DrawType::Line(LineParams {
+                    x0: a.x as i32,
+                    y0: a.y as i32,
+                    x1: b.x as i32,
+                    y1: b.y as i32,
+                    color: GRAPH_COLOR,
+                    thickness: 1,
+                }),
+            );
+        }
+    }
+    for inflated in &state.world.inflated {
+        queue.push(
+            Layer::World,
+            DrawType::Polygon(PolygonParams {
+                points: inflated.clone(),
+                color: INFLATED_COLOR,
+                filled: false,
+            }),
+        );
+    }
+    for obstacle in &state.world.obstacles {
+        queue.push(
+            Layer::World,
+            DrawType::Polygon(PolygonParams {
+                points: obstacle.clone(),
+                color: OBSTACLE_COLOR,
+                filled: true,
+            }),
+        );
+    }
+
+    for node in &state.goal_set {
+        queue.push(
+            Layer::Markers,
+            DrawType::Circle(CircleParams {
+                center: node.cell().center(),
+                radius: state.radius(12),
+                color: YELLOW,
+            }),
+        );
+        let delivered = state
+            .goal_deliveries
+            .iter()
+            .filter(|d| d.goal == *node)
+            .count();
+        if delivered > 0 {
+            let recent = goal_throughput(&state.goal_deliveries, Some(*node), state.grid.tick);
+            queue.push(
+                Layer::Labels,
+                DrawType::Text(TextParams {
+                    x: node.cell().top_left().x + 3,
+                    y: node.cell().top_left().y + 3,
+                    text: format!("{} +{}", delivered, recent),
+                    scale: 1,
+                    color: WHITE,
+                }),
+            );
+        }
+    }
+
+    if let Some(preview) = &state.preview {
+        match &preview.path {
+            Some(path) => {
+                for w in path.windows(2) {
+                    let (a, b) = (w[0].to_pixels(), w[1].to_pixels());
+                    queue.push(
+                        Layer::Paths,
+                        DrawType::Line(LineParams {
+                            x0: a.x as i32,
+                            y0: a.y as i32,
+                            x1: b.x as i32,
+                            y1: b.y as i32,
+                            color: LIGHT_BLUE,
+                            thickness: state.stroke(),
+                        }),
+                    );
+                }
+            }
+            None => queue.push(
+                Layer::Paths,
+                DrawType::Circle(CircleParams {
+                    center: preview.target.cell().center(),
+                    radius: state.radius(8),
+                    color: RED,
+                }),
+            ),
+        }
+    }
+
+    if let Some((cell, at)) = state.refused_cell
+        && at.elapsed() < REFUSED_FLASH
+    {
+        queue.push(
+            Layer::Overlay,
+            DrawType::Square(SquareParams {
+                cell: cell.cell(),
+                color: RED,
+            }),
+        );
+    }
+
+    for &(from, to) in &state.grid.one_way {
+        queue.arrow(from, to, CYAN);
+    }
+    if let Some(anchor) = state.one_way_anchor {
+        queue.push(
+            Layer::Markers,
+            DrawType::Circle(CircleParams {
+                center: anchor.cell().center(),
+                radius: state.radius(5),
+                color: CYAN,
+            }),
+        );
+    }
+
+    for agent in agents {
+        let color = theme.agent_color(agent.id);
+        let path_color = if state.presentation {
+            color
+        } else {
+            theme.path
+        };
+        let visible = agent.trail.len().min(state.trail_length);
+        for (i, node) in agent
+            .trail
+            .iter()
+            .skip(agent.trail.len() - visible)
+            .enumerate()
+        {
+            let alpha = (i + 1) as f32 / (visible + 1) as f32;
+            queue.push(
+                Layer::Paths,
+                DrawType::Circle(CircleParams {
+                    center: node.cell().center(),
+                    radius: state.radius(6),
+                    color: blend(color, theme.background, alpha),
+                }),
+            );
+        }
+
+        for w in agent.waypoints.windows(2) {
+            queue.push(
+                Layer::Paths,
+                DrawType::Line(LineParams {
+                    x0: w[0].x as i32,
+                    y0: w[0].y as i32,
+                    x1: w[1].x as i32,
+                    y1: w[1].y as i32,
+                    color: path_color,
+                    thickness: state.stroke(),
+                }),
+            );
+        }
+
+        // Travelled segments stay solid but faded; what is still ahead is dashed.
+        if let Some(path) = &agent.path {
+            let mut phase = 0;
+            for (i, w) in path.windows(2).enumerate() {
+                let (a, b) = (w[0].cell().center(), w[1].cell().center());
+                let travelled = i < agent.path_index;
+                let line = LineParams {
+                    x0: a.x as i32,
+                    y0: a.y as i32,
+                    x1: b.x as i32,
+                    y1: b.y as i32,
+                    color: if travelled {
+                        blend(path_color, theme.background, TRAVELLED_ALPHA)
+                    } else {
+                        path_color
+                    },
+                    thickness: state.stroke(),
+                };
+                if !travelled
+                    && (i - agent.path_index) % PATH_ARROW_INTERVAL == PATH_ARROW_INTERVAL - 1
+                {
+                    let (a, b) = (a.to_vec(), b.to_vec());
+                    let dir = (b - a).normalize_or_zero();
+                    let size = scaled(6) as f32 + state.stroke() as f32;
+                    queue.push(
+                        Layer::Paths,
+                        arrowhead((a + b) / 2.0 + dir * size / 2.0, dir, size, line.color),
+                    );
+                }
+                if travelled {
+                    queue.push(Layer::Paths, DrawType::Line(line));
+                } else {
+                    let steps = line_steps(&line);
+                    queue.push(
+                        Layer::Paths,
+                        DrawType::DashedLine(DashedLineParams {
+                            line,
+                            dash: PATH_DASH,
+                            gap: PATH_GAP,
+                            phase,
+                        }),
+                    );
+                    phase += steps;
+                }
+            }
+        }
+
+        if let Some(goal) = agent.end_point {
+            let reachable = state.components.reachable(
+                agent.current_point,
+                goal,
+                state.movement_strategy.as_ref(),
+            );
+            if !reachable {
+                queue.push(
+                    Layer::Markers,
+                    DrawType::Circle(CircleParams {
+                        center: goal.cell().center(),
+                        radius: state.radius(14),
+                        color: RED,
+                    }),
+                );
+            }
+            let item = match &state.sprites.goal {
+                Some(sprite) => DrawType::Sprite(SpriteParams {
+                    center: goal.cell().center(),
+                    sprite: sprite.clone(),
+                    tint: (!reachable).then_some(GREY),
+                }),
+                None => DrawType::Circle(CircleParams {
+                    center: goal.cell().center(),
+                    radius: state.radius(10),
+                    color: if reachable { theme.goal } else { GREY },
+                }),
+            };
+            queue.push(Layer::Markers, item);
+        }
+
+        if draw_radius {
+            queue.push(
+                Layer::World,
+                DrawType::BlendCircle(BlendCircleParams {
+                    center: agent.current_point.cell().center(),
+                    radius: agent.cell_radius as usize * CELL_WIDTH + CELL_WIDTH / 2,
+                    color,
+                    alpha: RADIUS_ALPHA,
+                }),
+            );
+            for &node in &agent.collision_radius {
+                queue.push(
+                    Layer::Markers,
+                    DrawType::Circle(CircleParams {
+                        center: node.cell().center(),
+                        radius: state.radius(10),
+                        color: theme.collision,
+                    }),
+                );
+            }
+        } else if !is_orca {
+            // Forward markers take the colour of the most urgent warning the agent is part of.
+            let marker_color = match state.warnings.get(&agent.id) {
+                Some(WarningLevel::Critical) => theme.critical,
+                Some(WarningLevel::Warn) => theme.warning,
+                _ => theme.forward,
+            };
+            for &node in &agent.forward_path {
+                queue.push(
+                    Layer::Markers,
+                    DrawType::Circle(CircleParams {
+                        center: node.cell().center(),
+                        radius: state.radius(10),
+                        color: marker_color,
+                    }),
+                );
+            }
+        }
+
+        let (grid_x, grid_y) = if is_orca {
+            (
+                (agent.position.x / CELL_WIDTH as f32) as usize,
+                (agent.position.y / CELL_HEIGHT as f32) as usize,
+            )
+        } else {
+            (agent.current_point.ux(), agent.current_point.uy())
+        };
+
+        if state.selection.contains(&agent.id) {
+            let center = if is_orca {
+                agent.position
+            } else {
+                agent.current_point.to_pixels()
+            };
+            queue.push(
+                Layer::Agents,
+                DrawType::Circle(CircleParams {
+                    center: PixelCoord::from_vec(center),
+                    radius: state.radius(14),
+                    color: theme.selection,
+                }),
+            );
+        }
+
+        if is_orca {
+            let center = PixelCoord::from_vec(
+                agent
+                    .position
+                    .min(Vec2::new((WIDTH - 1) as f32, (HEIGHT - 1) as f32)),
+            );
+            queue.push(
+                Layer::Agents,
+                DrawType::Circle(CircleParams {
+                    center,
+                    radius: agent.radius as usize,
+                    color,
+                }),
+            );
+        } else if state.continuous {
+            let center = PixelCoord::from_vec(agent.position);
+            queue.push(
+                Layer::Agents,
+                DrawType::Circle(CircleParams {
+                    center,
+                    radius: FOOTPRINT_RADIUS as usize,
+                    color,
+                }),
+            );
+        } else if agent.footprint != Footprint::Cell {
+            let cells = agent.footprint.cells(agent.current_point);
+            let (min_x, max_x) = (
+                cells.iter().map(|c| c.x).min().unwrap_or(0).max(0),
+                cells.iter().map(|c| c.x).max().unwrap_or(0),
+            );
+            let (min_y, max_y) = (
+                cells.iter().map(|c| c.y).min().unwrap_or(0).max(0),
+                cells.iter().map(|c| c.y).max().unwrap_or(0),
+            );
+            queue.push(
+                Layer::Agents,
+                DrawType::Rect(RectParams {
+                    x: Col(min_x as usize).left() + FOOTPRINT_MARGIN,
+                    y: Row(min_y as usize).top() + FOOTPRINT_MARGIN,
+                    width: Col((max_x - min_x + 1) as usize).left() - 2 * FOOTPRINT_MARGIN,
+                    height: Row((max_y - min_y + 1) as usize).top() - 2 * FOOTPRINT_MARGIN,
+                    color,
+                }),
+            );
+        } else if let Some(sprite) = &state.sprites.agent {
+            queue.push(
+                Layer::Agents,
+                DrawType::Sprite(SpriteParams {
+                    center: agent.current_point.cell().center(),
+                    sprite: sprite.clone(),
+                    tint: Some(color),
+                }),
+            );
+        } else {
+            if state.presentation {
+                queue.push(
+                    Layer::Agents,
+                    DrawType::Circle(CircleParams {
+                        center: agent.current_point.cell().center(),
+                        radius: state.radius(10) + state.stroke(),
+                        color: theme.path,
+                    }),
+                );
+            }
+            queue.push(
+                Layer::Agents,
+                DrawType::Circle(CircleParams {
+                    center: agent.current_point.cell().center(),
+                    radius: state.radius(10),
+                    color,
+                }),
+            );
+        }
+
+        // Heading arrow just outside the body: the next move, or the last one while waiting.
+        let heading = match agent.direction() {
+            d if is_zero_dir(d) => agent.heading,
+            d => d,
+        };
+        if !is_orca && !state.continuous && !is_zero_dir(heading) {
+            let center = agent.current_point.to_pixels();
+            let dir = Vec2::new(heading.x as f32, heading.y as f32).normalize();
+            let reach = state.radius(10) as f32 + scaled(7) as f32;
+            queue.push(
+                Layer::Agents,
+                arrowhead(center + dir * reach, dir, scaled(7) as f32, theme.path),
+            );
+        }
+
+        if state.battery_enabled {
+            let center = if is_orca {
+                agent.position
+            } else {
+                agent.current_point.to_pixels()
+            };
+            let x = (center.x as usize).saturating_sub(CELL_WIDTH / 2);
+            let y = (center.y as usize).saturating_sub(CELL_HEIGHT / 2 + 4);
+            let filled = CELL_WIDTH * agent.battery as usize / BATTERY_CAPACITY as usize;
+            queue.push(
+                Layer::Agents,
+                DrawType::Rect(RectParams {
+                    x,
+                    y,
+                    width: CELL_WIDTH,
+                    height: 3,
+                    color: GREY,
+                }),
+            );
+            queue.push(
+                Layer::Agents,
+                DrawType::Rect(RectParams {
+                    x,
+                    y,
+                    width: filled,
+                    height: 3,
+                    color: if agent.battery * 4 <= BATTERY_CAPACITY {
+                        BATTERY_LOW
+                    } else {
+                        BATTERY_OK
+                    },
+                }),
+            );
+        }
+    }
+
+    if let Some((from, to)) = state.drag {
+        let (x0, x1) = (from.x.min(to.x), from.x.max(to.x) + 1);
+        let (y0, y1) = (from.y.min(to.y), from.y.max(to.y) + 1);
+        let (x0, x1) = (
+            Col(x0 as usize).left() as i32,
+            Col(x1 as usize).left() as i32 - 1,
+        );
+        let (y0, y1) = (
+            Row(y0 as usize).top() as i32,
+            Row(y1 as usize).top() as i32 - 1,
+        );
+        for (ax, ay, bx, by) in [
+            (x0, y0, x1, y0),
+            (x1, y0, x1, y1),
+            (x1, y1, x0, y1),
+            (x0, y1, x0, y0),
+        ] {
+            queue.push(
+                Layer::Overlay,
+                DrawType::Line(LineParams {
+                    x0: ax,
+                    y0: ay,
+                    x1: bx,
+                    y1: by,
+                    color: theme.selection,
+                    thickness: 1,
+                }),
+            );
+        }
+    }
+
+    if let Some(m) = &state.measurement {
+        let (from, to) = (m.from.to_pixels(), m.to.to_pixels());
+        queue.push(
+            Layer::Overlay,
+            DrawType::Line(LineParams {
+                x0: from.x as i32,
+                y0: from.y as i32,
+                x1: to.x as i32,
+                y1: to.y as i32,
+                color: CYAN,
+                thickness: state.stroke(),
+            }),
+        );
+    }
+
+    if state.show_rulers {
+        queue.rulers(state.hovered);
+    }
+
+    // Notes go last so they stay readable over walls and agents.
+    for (node, text) in &state.grid.notes {
+        let PixelCoord { x, y } = node.cell().top_left().offset(2, 2);
+        queue.push(
+            Layer::Labels,
+            DrawType::Rect(RectParams {
+                x,
+                y,
+                width: text_width(text, 1) + 3,
+                height: GLYPH_HEIGHT + 4,
+                color: BLACK,
+            }),
+        );
+        queue.push(
+            Layer::Labels,
+            DrawType::Text(TextParams {
+                x: x + 2,
+                y: y + 2,
+                text: text.clone(),
+                scale: 1,
+                color: YELLOW,
+            }),
+        );
+    }
+    queue
+}
+
+#[derive(Copy, Clone)]
+enum Phase {
+    Input,
+    Simulation,
+    Render,
+    Collision,
+    Planning,
+}
+
+const PHASE_COUNT: usize = 5;
+const PHASES: [(&str, u32); PHASE_COUNT] = [
+    ("INPUT", CYAN),
+    ("SIM", ORANGE),
+    ("RENDER", LIGHT_BLUE),
+    ("COLLIDE", RED),
+    ("PLAN", YELLOW),
+];
+const PROFILE_SMOOTHING: f32 = 0.1;
+const PROFILE_BAR_WIDTH: usize = 400;
+
+// Phases lapped once per tick add up over the frame; the averages are per frame.
+struct Profiler {
+    lap_start: Instant,
+    frame_ms: [f32; PHASE_COUNT],
+    averages_ms: [f32; PHASE_COUNT],
+}
+
+impl Profiler {
+    fn new() -> Self {
+        Profiler {
+            lap_start: Instant::now(),
+            frame_ms: [0.0; PHASE_COUNT],
+            averages_ms: [0.0; PHASE_COUNT],
+        }
+    }
+
+    fn start(&mut self) {
+        self.lap_start = Instant::now();
+    }
+
+    fn lap(&mut self, phase: Phase) {
+        let now = Instant::now();
+        self.frame_ms[phase as usize] += (now - self.lap_start).as_secs_f32() * 1000.0;
+        self.lap_start = now;
+    }
+
+    fn end_frame(&mut self) {
+        for (avg, ms) in self.averages_ms.iter_mut().zip(&mut self.frame_ms) {
+            *avg += (*ms - *avg) * PROFILE_SMOOTHING;
+            *ms = 0.0;
+        }
+    }
+}
+
+fn draw_profile(buffer: &mut [u32], profiler: &Profiler) {
+    let budget_ms = 1000.0 / TARGET_FPS as f32;
+    let y = HEIGHT - 60;
+    draw(
+        buffer,
+        &DrawType::Rect(RectParams {
+            x: 4,
+            y: y - 4,
+            width: PROFILE_BAR_WIDTH + 8,
+            height: 56,
+            color: BLACK,
+        }),
+    );
+    draw(
+        buffer,
+        &DrawType::Rect(RectParams {
+            x: 8,
+            y,
+            width: PROFILE_BAR_WIDTH,
+            height: 12,
+            color: GREY,
+        }),
+    );
+    let mut x = 8;
+    for (i, &(name, color)) in PHASES.iter().enumerate() {
+        let ms = profiler.averages_ms[i];
+        let width = (ms / budget_ms * PROFILE_BAR_WIDTH as f32) as usize;
+        draw(
+            buffer,
+            &DrawType::Rect(RectParams {
+                x,
+                y,
+                width: width.min(8 + PROFILE_BAR_WIDTH - x),
+                height: 12,
+                color,
+            }),
+        );
+        x = (x + width).min(8 + PROFILE_BAR_WIDTH);
+        draw(
+            buffer,
+            &DrawType::Text(TextParams {
+                x: 8 + (i % 3) * 136,
+                y: y + 18 + (i / 3) * 12,
+                text: format!("{} {:.2}", name, ms),
+                scale: 1,
+                color,
+            }),
+        );
+    }
+}
+
+struct SimClock {
+    last_frame: Instant,
+    accumulator: Duration,
+    window_start: Instant,
+    window_start_tick: usize,
+    frames: u32,
+    fps: f32,
+    tps: f32,
+}
+
+impl SimClock {
+    fn new() -> Self {
+        let now = Instant::now();
+        SimClock {
+            last_frame: now,
+            accumulator: Duration::ZERO,
+            window_start: now,
+            window_start_tick: 0,
+            frames: 0,
+            fps: 0.0,
+            tps: 0.0,
+        }
+    }
+
+    fn begin_frame(&mut self, state: &GameState) -> u32 {
+        let now = Instant::now();
+        let frame_time = now - self.last_frame;
+        self.last_frame = now;
+        self.frames += 1;
+
+        let tick = state.tick_history.tick;
+        let elapsed = now - self.window_start;
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f32 / elapsed.as_secs_f32();
+            self.tps = tick.saturating_sub(self.window_start_tick) as f32 / elapsed.as_secs_f32();
+            self.frames = 0;
+            self.window_start = now;
+            self.window_start_tick = tick;
+        }
+        if tick < self.window_start_tick {
+            self.window_start_tick = tick;
+        }
+
+        if !state.running {
+            self.accumulator = Duration::ZERO;
+            return 0;
+        }
+
+        self.accumulator += frame_time;
+        let step = Duration::from_secs_f64(1.0 / state.ticks_per_second as f64);
+        let mut due = 0;
+        while self.accumulator >= step && due < MAX_TICKS_PER_FRAME {
+            self.accumulator -= step;
+            due += 1;
+        }
+        if due == MAX_TICKS_PER_FRAME {
+            self.accumulator = Duration::ZERO;
+        }
+        due
+    }
+}
+
+fn draw_hud(buffer: &mut [u32], clock: &SimClock, state: &GameState, stats: &Statistics) {
+    let text = format!(
+        "FPS {:.0}  TPS {:.0}/{}  TICK {}  SCORE {:.0}  {}",
+        clock.fps,
+        clock.tps,
+        state.ticks_per_second,
+        state.tick_history.tick,
+        stats.score,
+        if state.running { "RUN" } else { "PAUSE" }
+    );
+    let lines = std::iter::once(text).chain(state.measurement.as_ref().map(Measurement::text));
+    for (row, text) in lines.enumerate() {
+        let y = 4 + row * (GLYPH_HEIGHT * 2 + 12);
+        draw(
+            buffer,
+            &DrawType::Rect(RectParams {
+                x: 4,
+                y,
+                width: text_width(&text, 2) + 8,
+                height: GLYPH_HEIGHT * 2 + 8,
+                color: BLACK,
+            }),
+        );
+        draw(
+            buffer,
+            &DrawType::Text(TextParams {
+                x: 8,
+                y: y + 4,
+                text,
+                scale: 2,
+                color: YELLOW,
+            }),
+        );
+    }
+}
+
+const LEGEND_HEIGHT: usize = 64;
+const LEGEND_SWATCH: usize = 12;
+
+// Strip appended under annotated screenshots so they explain themselves: what was run and what
+// each colour means. Drawn at the top of a scratch frame; only LEGEND_HEIGHT rows are kept.
+fn draw_legend(buffer: &mut [u32], state: &GameState, agents: &[Agent], collision: &str) {
+    let theme = &state.theme;
+    let seed = state.seed.map_or("-".to_string(), |s| s.to_string());
+    let header = format!(
+        "TICK {}  SEED {}  COLLISION {}  MOVEMENT {}  AGENTS {}",
+        state.tick_history.tick,
+        seed,
+        collision,
+        state.movement_strategy.name(),
+        agents.len()
+    );
+    draw(
+        buffer,
+        &DrawType::Text(TextParams {
+            x: 8,
+            y: 8,
+            text: header,
+            scale: 2,
+            color: WHITE,
+        }),
+    );
+    let entries = [
+        ("WALL", theme.wall),
+        ("PATH", theme.path),
+        ("GOAL", theme.goal),
+        ("PREDICTED", theme.forward),
+        ("WARN", theme.warning),
+        ("CRITICAL", theme.critical),
+        ("RADIUS", theme.collision),
+        ("SELECTED", theme.selection),
+    ];
+    let y = 8 + GLYPH_HEIGHT * 2 + 16;
+    let mut x = 8;
+    for (name, color) in entries {
+        draw(
+            buffer,
+            &DrawType::Rect(RectParams {
+                x,
+                y,
+                width: LEGEND_SWATCH,
+                height: LEGEND_SWATCH,
+                color,
+            }),
+        );
+        draw(
+            buffer,
+            &DrawType::Text(TextParams {
+                x: x + LEGEND_SWATCH + 4,
+                y: y + (LEGEND_SWATCH - GLYPH_HEIGHT) / 2,
+                text: name.to_string(),
+                scale: 1,
+                color: WHITE,
+            }),
+        );
+        x += LEGEND_SWATCH + text_width(name, 1) + 16;
+    }
+    for agent in agents.iter().take(theme.agents.len()) {
+        draw(
+            buffer,
+            &DrawType::Circle(CircleParams {
+                center: PixelCoord {
+                    x: x + LEGEND_SWATCH / 2,
+                    y: y + LEGEND_SWATCH / 2,
+                },
+                radius: LEGEND_SWATCH / 2,
+                color: theme.agent_color(agent.id),
+            }),
+        );
+        let label = agent.id.to_string();
+        draw(
+            buffer,
+            &DrawType::Text(TextParams {
+                x: x + LEGEND_SWATCH + 2,
+                y: y + (LEGEND_SWATCH - GLYPH_HEIGHT) / 2,
+                text: label.clone(),
+                scale: 1,
+                color: WHITE,
+            }),
+        );
+        x += LEGEND_SWATCH + text_width(&label, 1) + 10;
+    }
+}
+
+// The frame as shown (HUD included), with the legend strip underneath when `legend` is given.
+fn screenshot_image(frame: &[u32], legend: Option<&[u32]>) -> image::RgbImage {
+    let extra = if legend.is_some() { LEGEND_HEIGHT } else { 0 };
+    let pixels = frame
+        .iter()
+        .chain(legend.into_iter().flat_map(|l| &l[..WIDTH * LEGEND_HEIGHT]))
+        .flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8])
+        .collect();
+    image::RgbImage::from_raw(WIDTH as u32, (HEIGHT + extra) as u32, pixels)
+        .expect("frame and legend sizes match the image")
+}
+
+// Stacked above the bottom edge, newest last, fading out over their final TOAST_FADE.
+fn draw_toasts(buffer: &mut [u32], board: &ToastBoard) {
+    let mut toasts = board.toasts.borrow_mut();
+    toasts.retain(|t| t.shown.elapsed() < TOAST_DURATION);
+    let line_height = GLYPH_HEIGHT * 2 + 8;
+    let top = HEIGHT.saturating_sub(toasts.len() * (line_height + 4) + 4);
+    for (i, toast) in toasts.iter().enumerate() {
+        let left = TOAST_DURATION.saturating_sub(toast.shown.elapsed());
+        let alpha = (left.as_secs_f32() / TOAST_FADE.as_secs_f32()).min(1.0);
+        let y = top + i * (line_height + 4);
+        draw(
+            buffer,
+            &DrawType::Rect(RectParams {
+                x: 4,
+                y,
+                width: text_width(&toast.text, 2) + 8,
+                height: line_height,
+                color: blend(GREY, BLACK, alpha * 0.3),
+            }),
+        );
+        draw(
+            buffer,
+            &DrawType::Text(TextParams {
+                x: 8,
+                y: y + 4,
+                text: toast.text.clone(),
+                scale: 2,
+                color: blend(toast.color, BLACK, alpha),
+            }),
+        );
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ComparePlanner {
+    AStar,
+    Kinematic,
+    Reserved,
+    Intentions,
+}
+
+impl ComparePlanner {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "astar" => Some(ComparePlanner::AStar),
+            "kinematic" => Some(ComparePlanner::Kinematic),
+            "reserved" => Some(ComparePlanner::Reserved),
+            "intentions" => Some(ComparePlanner::Intentions),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            ComparePlanner::AStar => "A*",
+            ComparePlanner::Kinematic => "Kinematic A*",
+            ComparePlanner::Reserved => "A* + reservations",
+            ComparePlanner::Intentions => "A* + intentions",
+        }
+    }
+
+    fn turn_penalty(&self) -> Option<i32> {
+        match self {
+            ComparePlanner::Kinematic => Some(TURN_PENALTIES[1]),
+            _ => None,
+        }
+    }
+}
+
+// One half of the split view: its own copy of the agents, counters and tick clock.
+struct ComparePane {
+    planner: ComparePlanner,
+    agents: Vec<Agent>,
+    stats: Statistics,
+    detector: CollisionDetector,
+    reroutes: Rc<Mailbox>,
+    tick_history: TickHistory,
+    goal_deliveries: Vec<GoalArrival>,
+}
+
+struct Comparison {
+    panes: [ComparePane; 2],
+    scratch: Vec<u32>,
+}
+
+fn start_comparison(state: &GameState, agents: &[Agent]) -> Comparison {
+    let pane = |planner: ComparePlanner| {
+        let mut stats = Statistics::new();
+        let mut agents = agents.to_vec();
+        for agent in agents.iter_mut() {
+            agent.path = None;
+            agent.finished = false;
+            agent.arrival_tick = None;
+            agent.last_reroute_tick = None;
+            agent.current_point = agent.start_point;
+            agent.position = agent.start_point.to_pixels();
+            agent.path_index = 0;
+            agent.heading = Node { x: 0, y: 0 };
+            agent.trail.clear();
+            let Some(goal) = agent.end_point else {
+                continue;
+            };
+            let movement = state.movement_strategy.as_ref();
+            agent.path = match planner.turn_penalty() {
+                Some(penalty) => a_star_kinematic(
+                    agent.start_point,
+                    agent.heading,
+                    goal,
+                    &state.grid,
+                    movement,
+                    penalty,
+                ),
+                None => a_star(agent.start_point, goal, &state.grid, movement),
+            };
+            agent.refresh_cache();
+            if let Some(path) = &agent.path {
+                stats.total_path_length += path.len();
+                stats.geometric_path_length += state.grid.geometric_length(path);
+            }
+        }
+        stats.agents = agents.len();
+        let mut detector = CollisionDetector::new(Box::new(PathCollisionStrategy));
+        detector.safety_distance = state.safety_distance;
+        let reroutes = Rc::new(reroute_mailbox());
+        detector.bus.subscribe(Rc::new(CollisionAssistant));
+        detector.bus.subscribe(reroutes.clone());
+        ComparePane {
+            planner,
+            agents,
+            stats,
+            detector,
+            reroutes,
+            tick_history: TickHistory::new(),
+            goal_deliveries: Vec::new(),
+        }
+    };
+    Comparison {
+        panes: state.compare_planners.map(pane),
+        scratch: vec![0; WIDTH * HEIGHT],
+    }
+}
+
+// Advances both panes by one tick, lending each its planner settings, clock and delivery log.
+fn step_comparison(state: &mut GameState, comparison: &mut Comparison) {
+    let saved = (
+        state.reroute.turn_penalty,
+        state.reservation_stepping,
+        state.intention_horizon,
+    );
+    for pane in comparison.panes.iter_mut() {
+        state.reroute.turn_penalty = pane.planner.turn_penalty();
+        state.reservation_stepping = pane.planner == ComparePlanner::Reserved;
+        state.intention_horizon = (pane.planner == ComparePlanner::Intentions)
+            .then(|| saved.2.unwrap_or(INTENTION_HORIZON));
+        std::mem::swap(&mut state.tick_history, &mut pane.tick_history);
+        std::mem::swap(&mut state.goal_deliveries, &mut pane.goal_deliveries);
+        state.grid.tick = state.tick_history.tick;
+        simulation_tick(&mut pane.agents, state, &mut pane.detector, &mut pane.stats);
+        pane.detector.check_agents(&pane.agents, &mut pane.stats);
+        apply_reroutes(
+            &pane.reroutes,
+            &mut pane.agents,
+            state,
+            &mut pane.detector,
+            &mut pane.stats,
+        );
+        pane.stats.score = state.score_weights.score(&pane.stats);
+        if pane.agents.iter().all(|a| a.finished) {
+            mapf_metrics(&mut pane.stats, &pane.agents, state.grid.tick);
+        }
+        std::mem::swap(&mut state.tick_history, &mut pane.tick_history);
+        std::mem::swap(&mut state.goal_deliveries, &mut pane.goal_deliveries);
+    }
+    (
+        state.reroute.turn_penalty,
+        state.reservation_stepping,
+        state.intention_horizon,
+    ) = saved;
+    state.grid.tick = state.tick_history.tick;
+}
+
+// Each pane is rendered full size off-screen, then halved into its side of the window.
+fn draw_comparison(
+    buffer: &mut [u32],
+    state: &GameState,
+    comparison: &mut Comparison,
+    draw_radius: bool,
+    is_orca: bool,
+) {
+    buffer.fill(BLACK);
+    for (i, pane) in comparison.panes.iter().enumerate() {
+        render(
+            &mut comparison.scratch,
+            state,
+            &pane.agents,
+            draw_radius,
+            is_orca,
+        );
+        let left = i * WIDTH / 2;
+        for y in 0..HEIGHT / 2 {
+            for x in 0..WIDTH / 2 {
+                buffer[(PANE_TOP + y) * WIDTH + left + x] =
+                    comparison.scratch[2 * y * WIDTH + 2 * x];
+            }
+        }
+
+        let arrived = pane.agents.iter().filter(|a| a.finished).count();
+        let lines = [
+            pane.planner.name().to_string(),
+            format!("TICK {}", pane.tick_history.tick),
+            format!("ARRIVED {}/{}", arrived, pane.agents.len()),
+            format!("LENGTH {}", pane.stats.total_path_length),
+            format!("COLLISIONS {}", pane.stats.collisions),
+            format!("NEAR MISSES {}", pane.stats.detections),
+            format!("REPLANS {}", pane.stats.recalculations),
+            format!("YIELDS {}", pane.stats.yields),
+            format!("MAKESPAN {}", pane.stats.makespan),
+            format!("SCORE {:.0}", pane.stats.score),
+        ];
+        for (row, text) in lines.into_iter().enumerate() {
+            draw(
+                buffer,
+                &DrawType::Text(TextParams {
+                    x: left + 12,
+                    y: PANE_TOP + HEIGHT / 2 + 12 + row * (GLYPH_HEIGHT * 2 + 6),
+                    text,
+                    scale: 2,
+                    color: if row == 0 { YELLOW } else { WHITE },
+                }),
+            );
+        }
+    }
+    draw(
+        buffer,
+        &DrawType::Line(LineParams {
+            x0: (WIDTH / 2) as i32,
+            y0: 0,
+            x1: (WIDTH / 2) as i32,
+            y1: HEIGHT as i32 - 1,
+            color: GREY,
+            thickness: 1,
+        }),
+    );
+}
+
+// Everything one window owns; several sessions can share the process, each with its own stats files.
+// Without a window the session only advances through update(), which is how the tests drive it.
+struct Session {
+    window: Option<Window>,
+    buffer: Vec<u32>,
+    output: Vec<u32>,
+    letterbox: Letterbox,
+    state: GameState,
+    stats: Statistics,
+    history: CommandHistory,
+    agents: Vec<Agent>,
+    last_log: Instant,
+    last_autosave: Instant,
+    sink: Box<dyn StatsSink>,
+    clock: SimClock,
+    profiler: Profiler,
+    detector: CollisionDetector,
+    reroutes: Rc<Mailbox>,
+    all_arrived: bool,
+    macro_recording: Option<MacroRecorder>,
+    playback: Option<MacroPlayer>,
+}
+
+impl Session {
+    fn new(
+        window: Option<Window>,
+        buffer: Vec<u32>,
+        state: GameState,
+        sink: Box<dyn StatsSink>,
+    ) -> Self {
+        let mut detector =
+            CollisionDetector::with_bus(Box::new(PathCollisionStrategy), state.bus.clone());
+        detector.safety_distance = state.safety_distance;
+        let reroutes = Rc::new(reroute_mailbox());
+        let bus = &state.bus;
+        bus.subscribe(Rc::new(CollisionLogger));
+        bus.subscribe(Rc::new(CollisionAssistant));
+        bus.subscribe(reroutes.clone());
+        bus.subscribe(state.recorder.clone());
+        bus.subscribe(state.toasts.clone());
+        #[cfg(feature = "sound")]
+        bus.subscribe(Rc::new(SoundObserver::new()));
+        #[cfg(feature = "notify")]
+        bus.subscribe(Rc::new(DesktopNotifier::new()));
+
+        Session {
+            window,
+            buffer,
+            output: Vec::new(),
+            letterbox: Letterbox::fit(WIDTH, HEIGHT),
+            state,
+            stats: Statistics::new(),
+            history: CommandHistory::new(),
+            agents: Vec::new(),
+            last_log: Instant::now(),
+            last_autosave: Instant::now(),
+            sink,
+            clock: SimClock::new(),
+            profiler: Profiler::new(),
+            detector,
+            reroutes,
+            all_arrived: false,
+            macro_recording: None,
+            playback: None,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.window
+            .as_ref()
+            .is_some_and(|w| w.is_open() && !w.is_key_down(Key::Escape))
+    }
+
+    // Reads the window, advances the simulation by the ticks due, then handles all file output.
+    fn frame(&mut self) -> Result<(), SimError> {
+        let Some(window) = &self.window else {
+            return Ok(());
+        };
+        let live_ticks = self.clock.begin_frame(&self.state);
+        // A replayed frame carries its own tick count, so playback is independent of frame timing.
+        let (input, ticks) = match &mut self.playback {
+            Some(player) => match player.next_due(Instant::now()) {
+                Some(frame) => (frame.input, frame.ticks),
+                None => (InputState::default(), 0),
+            },
+            None => {
+                let size = window.get_size();
+                if size != self.letterbox.window {
+                    self.letterbox = Letterbox::fit(size.0, size.1);
+                    self.output = vec![BLACK; size.0 * size.1];
+                }
+                (InputState::from_window(window, &self.letterbox), live_ticks)
+            }
+        };
+        if self.playback.as_ref().is_some_and(MacroPlayer::is_done) {
+            self.playback = None;
+            self.state.bus.info("Macro playback finished".to_string());
+        }
+        if let Some(recording) = &mut self.macro_recording
+            && let Err(e) = recording.record(&input, ticks)
+        {
+            error!("Macro recording stopped: {}", e);
+            self.macro_recording = None;
+        }
+        self.update(&input, ticks);
+
+        let state = &mut self.state;
+        for finished in state.finished_runs.drain(..) {
+            self.sink.finish_run(&finished);
+        }
+        if std::mem::take(&mut state.screenshot_requested) {
+            let legend = state.annotate_screenshots.then(|| {
+                let mut legend = vec![BLACK; WIDTH * HEIGHT];
+                draw_legend(
+                    &mut legend,
+                    state,
+                    &self.agents,
+                    self.detector.strategy.name(),
+                );
+                legend
+            });
+            let path = format!(
+                "screenshot{}_{}.png",
+                state.stats_suffix, state.tick_history.tick
+            );
+            match screenshot_image(&self.buffer, legend.as_deref()).save(&path) {
+                Ok(()) => state.bus.info(format!("Saved {}", path)),
+                Err(e) => state.bus.error(format!("Screenshot failed: {}", e)),
+            }
+        }
+        if std::mem::take(&mut state.export_requested) {
+            match export_parquet(&state.recorder, &state.stats_suffix) {
+                Ok(()) => info!(
+                    "Exported trajectories{0}.parquet and events{0}.parquet",
+                    state.stats_suffix
+                ),
+                Err(e) => error!("Parquet export failed: {e}"),
+            }
+        }
+        if self.last_log.elapsed() >= Duration::from_secs(1) {
+            self.sink.record_sample(&self.stats, &state.run);
+            self.last_log = Instant::now();
+        }
+        if self.last_autosave.elapsed() >= AUTOSAVE_INTERVAL {
+            let path = autosave_path(&state.stats_suffix);
+            if let Err(e) = save_map(state, &self.agents, &self.history, &path) {
+                warn!("Autosave to {} failed: {}", path, e);
+            }
+            self.last_autosave = Instant::now();
+        }
+
+        if let Some(window) = &mut self.window {
+            if self.letterbox.is_identity() {
+                window.update_with_buffer(&self.buffer, WIDTH, HEIGHT)?;
+            } else {
+                self.letterbox.blit(&self.buffer, &mut self.output);
+                let (width, height) = self.letterbox.window;
+                window.update_with_buffer(&self.output, width, height)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, input: &InputState, ticks: u32) {
+        let state = &mut self.state;
+        let agents = &mut self.agents;
+        let stats = &mut self.stats;
+        let detector = &mut self.detector;
+        let profiler = &mut self.profiler;
+        let buffer = &mut self.buffer;
+
+        if let Some(scenario) = state.pending_scenario.take() {
+            load_scenario(
+                scenario,
+                state.scenario_agents,
+                state,
+                agents,
+                &mut self.history,
+                stats,
+            );
+        }
+
+        let is_orca = detector.strategy.name() == "ORCA";
+        let draw_radius = detector.strategy.name() == "Grid-based";
+
+        profiler.start();
+        handle_input(input, state, agents, &mut self.history, detector, stats);
+        profiler.lap(Phase::Input);
+        // Manual steps take the same per-tick path, so they are recorded and checked too.
+        let ticks = ticks + std::mem::take(&mut state.step_requests);
+
+        profiler.start();
+        if let Some(mut comparison) = state.comparison.take() {
+            for _ in 0..ticks {
+                step_comparison(state, &mut comparison);
+            }
+            profiler.lap(Phase::Simulation);
+            draw_comparison(buffer, state, &mut comparison, draw_radius, is_orca);
+            state.comparison = Some(comparison);
+        } else {
+            // Detection and reroutes follow every tick, as in headless runs.
+            for _ in 0..ticks {
+                simulation_tick(agents, state, detector, stats);
+                state.recorder.record_tick(state.tick_history.tick, agents);
+                profiler.lap(Phase::Simulation);
+                detector.check_agents(agents, stats);
+                if !is_orca {
+                    detector.check_capacity(agents, &state.grid, stats);
+                }
+                profiler.lap(Phase::Collision);
+                if !is_orca {
+                    apply_reroutes(&self.reroutes, agents, state, detector, stats);
+                }
+                profiler.lap(Phase::Planning);
+            }
+            render(buffer, state, agents, draw_radius, is_orca);
+        }
+        draw_hud(buffer, &self.clock, state, stats);
+        draw_toasts(buffer, &state.toasts);
+        profiler.lap(Phase::Render);
+        profiler.end_frame();
+        stats.frame_ms = profiler.averages_ms;
+        stats.score = state.score_weights.score(stats);
+
+        let done = !agents.is_empty() && agents.iter().all(|a| a.finished);
+        if done && !self.all_arrived {
+            mapf_metrics(stats, agents, state.grid.tick);
+            state.bus.publish(run_completed(state, stats));
+        }
+        self.all_arrived = done;
+
+        if state.show_profile {
+            draw_profile(buffer, profiler);
+        }
+    }
+
+    // Moves a crashed session's autosave aside so it survives until the user decides on it with Shift+F4.
+    fn offer_recovery(&mut self) {
+        let suffix = &self.state.stats_suffix;
+        let recovered = recovery_path(suffix);
+        if std::fs::rename(autosave_path(suffix), &recovered).is_ok() {
+            self.state.recovery = Some(recovered);
+            self.state.bus.warn(
+                "The last session did not exit cleanly; press Shift+F4 to restore its map"
+                    .to_string(),
+            );
+        }
+    }
+
+    fn discard_autosave(&self) {
+        let path = autosave_path(&self.state.stats_suffix);
+        if let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Could not remove autosave {}: {}", path, e);
+        }
+    }
+
+    fn close(&mut self) {
+        self.sink.record_sample(&self.stats, &self.state.run);
+        finish_run(&mut self.state, &self.stats, &self.agents);
+        for finished in self.state.finished_runs.drain(..) {
+            self.sink.finish_run(&finished);
+        }
+        self.sink.close();
+    }
+}
+
+// The end-of-run summary, published by a window once every agent arrives and by each headless run.
+fn run_completed(state: &GameState, stats: &Statistics) -> SimEvent {
+    SimEvent::RunCompleted {
+        run: format!("{} {}", state.run.scenario, state.run.label),
+        arrived: stats.reached_goal_count,
+        agents: stats.agents,
+        collisions: stats.collisions,
+        reroutes: stats.recalculations,
+        makespan: stats.makespan,
+        sum_of_costs: stats.sum_of_costs,
+    }
+}
+
+// Plans and simulates the loaded map without a window, then returns every unmet expectation.
+// Raising `cancel` stops the run before its next tick.
+fn run_expectations(
+    state: &mut GameState,
+    agents: &mut [Agent],
+    history: &mut CommandHistory,
+    stats: &mut Statistics,
+    max_ticks: usize,
+    cancel: &AtomicBool,
+) -> Vec<String> {
+    plan_all_agents(state, agents, history, stats);
+    let mut detector = CollisionDetector::new(Box::new(PathCollisionStrategy));
+    detector.safety_distance = state.safety_distance;
+    let reroutes = Rc::new(reroute_mailbox());
+    detector.bus.subscribe(Rc::new(CollisionAssistant));
+    detector.bus.subscribe(reroutes.clone());
+
+    for _ in 0..max_ticks {
+        if agents.iter().all(|a| a.finished) || cancel.load(AtomicOrdering::Relaxed) {
+            break;
+        }
+        simulation_tick(agents, state, &mut detector, stats);
+        detector.check_agents(agents, stats);
+        detector.check_capacity(agents, &state.grid, stats);
+        apply_reroutes(&reroutes, agents, state, &mut detector, stats);
+    }
+    mapf_metrics(stats, agents, state.grid.tick);
+    state
+        .expectations
+        .iter()
+        .filter_map(|e| e.check(stats, agents).err())
+        .collect()
+}
+
+struct HeadlessRun {
+    failures: Vec<String>,
+    stats: Statistics,
+}
+
+fn run_headless(
+    options: CliOptions,
+    path: &str,
+    cancel: &AtomicBool,
+) -> Result<HeadlessRun, SimError> {
+    let ticks = options.ticks;
+    let mut ctx = InitContext {
+        window: None,
+        buffer: None,
+        game_state: None,
+    };
+    GameStateInitHandler {
+        options,
+        suffix: String::new(),
+    }
+    .initialize(&mut ctx)?;
+    let mut state = ctx
+        .game_state
+        .ok_or_else(|| SimError::Init("no game state".to_string()))?;
+    state.pending_scenario = None;
+    let mut agents = Vec::new();
+    let mut history = CommandHistory::new();
+    let mut stats = Statistics::new();
+    load_map(path, &mut state, &mut agents, &mut history, &mut stats)?;
+    info!(
+        "Checking {} expectations over at most {} ticks",
+        state.expectations.len(),
+        ticks
+    );
+    #[cfg(feature = "notify")]
+    state.bus.subscribe(Rc::new(DesktopNotifier::new()));
+    let failures = run_expectations(
+        &mut state,
+        &mut agents,
+        &mut history,
+        &mut stats,
+        ticks,
+        cancel,
+    );
+    state.bus.publish(run_completed(&state, &stats));
+    Ok(HeadlessRun { failures, stats })
+}
+
+enum HeadlessOutcome {
+    Finished(Box<HeadlessRun>),
+    Error(String),
+    TimedOut,
+}
+
+impl HeadlessOutcome {
+    fn passed(&self) -> bool {
+        matches!(self, HeadlessOutcome::Finished(run) if run.failures.is_empty())
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            HeadlessOutcome::Finished(run) if run.failures.is_empty() => "passed",
+            HeadlessOutcome::Finished(_) => "failed",
+            HeadlessOutcome::Error(_) => "error",
+            HeadlessOutcome::TimedOut => "timed out",
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+// Checks every map on up to `jobs` threads, each run building its own state from the options.
+// A run past the timeout is reported and cancelled, so its thread stops at the next tick and frees the slot.
+fn run_headless_batch(options: &CliOptions) -> Vec<(String, HeadlessOutcome)> {
+    let maps = &options.headless;
+    let mut outcomes: Vec<Option<HeadlessOutcome>> = maps.iter().map(|_| None).collect();
+    let (tx, rx) = mpsc::channel();
+    let mut sender = Some(tx);
+    let mut next = 0;
+    let mut running: HashMap<usize, (Instant, Arc<AtomicBool>)> = HashMap::new();
+    let mut done = 0;
+    while done < maps.len() {
+        while running.len() < options.jobs && next < maps.len() {
+            let Some(tx) = sender.clone() else {
+                break;
+            };
+            let (options, path) = (options.clone(), maps[next].clone());
+            let index = next;
+            let cancel = Arc::new(AtomicBool::new(false));
+            let flag = cancel.clone();
+            thread::spawn(move || {
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| run_headless(options, &path, &flag)));
+                let outcome = match result {
+                    Ok(Ok(run)) => HeadlessOutcome::Finished(Box::new(run)),
+                    Ok(Err(e)) => HeadlessOutcome::Error(e.to_string()),
+                    Err(payload) => HeadlessOutcome::Error(format!(
+                        "panicked: {}",
+                        panic_message(payload.as_ref())
+                    )),
+                };
+                let _ = tx.send((index, outcome));
+            });
+            running.insert(index, (Instant::now(), cancel));
+            next += 1;
+        }
+        // Once every map is handed out only the workers hold senders, so a dead one ends the wait.
+        if next == maps.len() {
+            sender = None;
+        }
+        let wait = options.timeout.map(|timeout| {
+            let oldest = running
+                .values()
+                .map(|(started, _)| *started)
+                .min()
+                .unwrap_or_else(Instant::now);
+            (oldest + timeout).saturating_duration_since(Instant::now())
+        });
+        let received = match wait {
+            Some(wait) => rx.recv_timeout(wait),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((index, outcome)) => {
+                if running.remove(&index).is_some() {
+                    outcomes[index] = Some(outcome);
+                    done += 1;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                for (index, _) in running.drain() {
+                    outcomes[index] = Some(HeadlessOutcome::Error(
+                        "worker exited without a result".to_string(),
+                    ));
+                    done += 1;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let timeout = options.timeout.unwrap_or_default();
+                running.retain(|&index, (started, cancel)| {
+                    let expired = started.elapsed() >= timeout;
+                    if expired {
+                        cancel.store(true, AtomicOrdering::Relaxed);
+                        outcomes[index] = Some(HeadlessOutcome::TimedOut);
+                        done += 1;
+                    }
+                    !expired
+                });
+            }
+        }
+        draw_progress(done, maps.len());
+    }
+    eprintln!();
+    #[cfg(feature = "notify")]
+    DesktopNotifier::send(
+        "Batch finished",
+        &format!(
+            "{}/{} maps passed",
+            outcomes.iter().flatten().filter(|o| o.passed()).count(),
+            maps.len()
+        ),
+    );
+    maps.iter()
+        .cloned()
+        .zip(outcomes.into_iter().map(Option::unwrap))
+        .collect()
+}
+
+type Metric = fn(&Statistics) -> f32;
+
+const REPORT_CHARTS: [(&str, Metric); 3] = [
+    ("Makespan", |s| s.makespan as f32),
+    ("Collisions", |s| s.collisions as f32),
+    ("Reroutes", |s| s.recalculations as f32),
+];
+const CHART_BAR_HEIGHT: usize = 18;
+const CHART_LABEL_WIDTH: usize = 220;
+const CHART_WIDTH: usize = 640;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A horizontal bar per map, scaled to the largest value.
+fn svg_bar_chart(title: &str, bars: &[(&str, f32)]) -> String {
+    let max = bars.iter().map(|b| b.1).fold(0.0, f32::max).max(1.0);
+    let height = (bars.len() + 1) * CHART_BAR_HEIGHT + 10;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\" font-size=\"12\">\n\
+         <text x=\"0\" y=\"14\" font-weight=\"bold\">{}</text>\n",
+        CHART_WIDTH,
+        height,
+        escape_html(title)
+    );
+    for (row, (label, value)) in bars.iter().enumerate() {
+        let y = (row + 1) * CHART_BAR_HEIGHT + 4;
+        let width = (CHART_WIDTH - CHART_LABEL_WIDTH - 60) as f32 * value / max;
+        svg += &format!(
+            "<text x=\"0\" y=\"{}\">{}</text>\
+             <rect x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"#4a90d9\"/>\
+             <text x=\"{:.1}\" y=\"{}\">{}</text>\n",
+            y + 12,
+            escape_html(label),
+            CHART_LABEL_WIDTH,
+            y,
+            width,
+            CHART_BAR_HEIGHT - 4,
+            CHART_LABEL_WIDTH as f32 + width + 4.0,
+            y + 12,
+            value
+        );
+    }
+    svg + "</svg>\n"
+}
+
+// Markdown when the path ends in .md, HTML otherwise; both inline the SVG charts.
+fn report_text(results: &[(String, HeadlessOutcome)], markdown: bool) -> String {
+    let header = [
+        "Map",
+        "Result",
+        "Agents",
+        "Arrived",
+        "Makespan",
+        "Sum of costs",
+        "Collisions",
+        "Reroutes",
+        "Yields",
+    ];
+    let rows: Vec<Vec<String>> = results
+        .iter()
+        .map(|(path, outcome)| {
+            let mut row = vec![path.clone(), outcome.label().to_string()];
+            match outcome {
+                HeadlessOutcome::Finished(run) => {
+                    let s = &run.stats;
+                    row.extend(
+                        [
+                            s.agents,
+                            s.reached_goal_count,
+                            s.makespan,
+                            s.sum_of_costs,
+                            s.collisions,
+                            s.recalculations,
+                            s.yields,
+                        ]
+                        .map(|v| v.to_string()),
+                    );
+                }
+                _ => row.extend(std::iter::repeat_n("-".to_string(), header.len() - 2)),
+            }
+            row
+        })
+        .collect();
+    let charts: Vec<String> = REPORT_CHARTS
+        .iter()
+        .map(|(title, metric)| {
+            let bars: Vec<(&str, f32)> = results
+                .iter()
+                .filter_map(|(path, outcome)| match outcome {
+                    HeadlessOutcome::Finished(run) => Some((path.as_str(), metric(&run.stats))),
+                    _ => None,
+                })
+                .collect();
+            svg_bar_chart(title, &bars)
+        })
+        .collect();
+    let passed = results.iter().filter(|(_, o)| o.passed()).count();
+    let summary = format!(
+        "{} of {} maps met every expectation.",
+        passed,
+        results.len()
+    );
+
+    if markdown {
+        let line = |cells: &[String]| format!("| {} |\n", cells.join(" | ").replace('\n', " "));
+        let mut out = format!("# Headless run report\n\n{}\n\n", summary);
+        out += &line(&header.map(str::to_string));
+        out += &line(&header.map(|_| "---".to_string()));
+        for row in &rows {
+            let cells: Vec<String> = row.iter().map(|c| c.replace('|', "\\|")).collect();
+            out += &line(&cells);
+        }
+        for chart in &charts {
+            out += &format!("\n{}", chart);
+        }
+        out
+    } else {
+        let cells = |tag: &str, cells: &[String]| {
+            let cells: String = cells
+                .iter()
+                .map(|c| format!("<{0}>{1}</{0}>", tag, escape_html(c)))
+                .collect();
+            format!("<tr>{}</tr>\n", cells)
+        };
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Headless run report</title></head><body>\n\
+             <h1>Headless run report</h1>\n<p>{}</p>\n<table border=\"1\" cellpadding=\"4\">\n",
+            summary
+        );
+        out += &cells("th", &header.map(str::to_string));
+        for row in &rows {
+            out += &cells("td", row);
+        }
+        out += "</table>\n";
+        for chart in &charts {
+            out += &format!("<p>{}</p>\n", chart);
+        }
+        out + "</body></html>\n"
+    }
+}
+
+fn draw_progress(done: usize, total: usize) {
+    let filled = PROGRESS_WIDTH * done / total.max(1);
+    eprint!(
+        "\r[{}{}] {}/{}",
+        "#".repeat(filled),
+        ".".repeat(PROGRESS_WIDTH - filled),
+        done,
+        total
+    );
+}
+
+// Frames every open window in turn; a window closing only ends its own session.
+fn game_loop(sessions: &mut Vec<Session>) -> Result<(), SimError> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), SimError> {
+        while !sessions.is_empty() {
+            for session in sessions.iter_mut() {
+                session.frame()?;
+            }
+            sessions.retain_mut(|session| {
+                let open = session.is_open();
+                if !open {
+                    session.close();
+                    session.discard_autosave();
+                }
+                open
+            });
+        }
+        Ok(())
+    }));
+
+    for session in sessions.iter_mut() {
+        session.close();
+    }
+
+    match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Each "--"-separated group of options opens its own window.
+    let options = match args
+        .split(|arg| arg == "--")
+        .map(parse_cli_options)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: trabalho-11 [--scenario crossing|bridge|crowd|ring|city] [--street-width N] [--block-size N] [--agents N] [--label NAME] [--sink csv|sqlite] [--log-file PATH] [--obstacles IMAGE] [--prm-samples N] [--prm-radius R] [--seed N] [--min-spawn-distance N] [--compare astar|kinematic|reserved|intentions,...] [--intentions K] [--safety-distance D] [--score-weights STEP,REROUTE,COLLISION,WAIT] [--headless MAP[,MAP...] [--ticks N] [--jobs N] [--timeout SECS] [--report FILE.md|FILE.html]] [--record FILE] [--replay FILE [--replay-speed X]] [--scale 1-4] [--presentation] [--theme dark|light|colorblind|FILE] [--sprites DIR] [--annotate-screenshots] [--warning-level info|warn|critical] [-- OPTIONS for another window]..."
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = init_logging(options[0].log_file.as_deref()) {
+        eprintln!("Could not open log file: {}", e);
+        return;
+    }
+
+    if !options[0].headless.is_empty() {
+        let results = run_headless_batch(&options[0]);
+        for (path, outcome) in &results {
+            match outcome {
+                HeadlessOutcome::Finished(run) if run.failures.is_empty() => {
+                    info!("{}: all expectations met", path)
+                }
+                HeadlessOutcome::Finished(run) => {
+                    for failure in &run.failures {
+                        error!("{}: expectation failed: {}", path, failure);
+                    }
+                }
+                HeadlessOutcome::Error(e) => error!("{}: headless run failed: {}", path, e),
+                HeadlessOutcome::TimedOut => error!(
+                    "{}: timed out after {:?}",
+                    path,
+                    options[0].timeout.unwrap_or_default()
+                ),
+            }
+        }
+        if let Some(path) = &options[0].report {
+            let text = report_text(&results, path.ends_with(".md"));
+            match std::fs::write(path, text) {
+                Ok(()) => info!("Wrote report to {}", path),
+                Err(e) => error!("Could not write report {}: {}", path, e),
+            }
+        }
+        if !results.iter().all(|(_, outcome)| outcome.passed()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let windows = options.len();
+    let mut sessions = Vec::with_capacity(windows);
+    for (index, options) in options.into_iter().enumerate() {
+        let suffix = if windows > 1 {
+            format!("-{}", index + 1)
+        } else {
+            String::new()
+        };
+        let (record, replay, seed) = (options.record.clone(), options.replay.clone(), options.seed);
+        let replay_speed = options.replay_speed;
+        let mut handlers: Vec<Box<dyn InitHandler>> = vec![
+            Box::new(WindowInitHandler {
+                suffix: suffix.clone(),
+                scale: options.scale,
+            }),
+            Box::new(BufferInitHandler),
+            Box::new(GameStateInitHandler { options, suffix }),
+        ];
+
+        let mut ctx = InitContext {
+            window: None,
+            buffer: None,
+            game_state: None,
+        };
+
+        for handler in handlers.iter_mut() {
+            if let Err(e) = handler.initialize(&mut ctx) {
+                error!("Initialization failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        let (Some(window), Some(buffer), Some(game_state)) =
+            (ctx.window, ctx.buffer, ctx.game_state)
+        else {
+            error!("{}", SimError::Init("incomplete init chain".to_string()));
+            std::process::exit(1);
+        };
+        let sink = match open_stats_sink(game_state.sink, &game_state.stats_suffix) {
+            Ok(sink) => sink,
+            Err(e) => {
+                warn!("Falling back to CSV stats: {}", e);
+                Box::new(StatsWriter::spawn(&format!(
+                    "stats{}.csv",
+                    game_state.stats_suffix
+                )))
+            }
+        };
+        let mut session = Session::new(Some(window), buffer, game_state, sink);
+        session.offer_recovery();
+        if let Some(path) = record {
+            match MacroRecorder::create(&path, seed) {
+                Ok(recording) => session.macro_recording = Some(recording),
+                Err(e) => error!("Cannot record input to {}: {}", path, e),
+            }
+        }
+        if let Some(path) = replay {
+            match std::fs::read_to_string(&path)
+                .map_err(SimError::from)
+                .and_then(|text| MacroPlayer::parse(&text, replay_speed))
+            {
+                Ok((player, recorded_seed)) => {
+                    if recorded_seed != seed {
+                        warn!(
+                            "{} was recorded with seed {:?} but this run uses {:?}",
+                            path, recorded_seed, seed
+                        );
+                    }
+                    info!("Replaying {} frames from {}", player.frames.len(), path);
+                    session.playback = Some(player);
+                }
+                Err(e) => error!("Cannot replay {}: {}", path, e),
+            }
+        }
+        sessions.push(session);
+    }
+
+    if let Err(e) = game_loop(&mut sessions) {
+        error!("Simulation stopped: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent_on_path(id: usize, cells: &[(i32, i32)]) -> Agent {
+        let path: Vec<Node> = cells.iter().map(|&(x, y)| Node { x, y }).collect();
+        let mut agent = Agent::new(id, path[0], path.last().copied());
+        agent.path = Some(path);
+        agent.refresh_cache();
+        agent
+    }
+
+    struct Recorder(RefCell<Vec<(usize, usize, CollisionType)>>);
+
+    impl Subscriber for Recorder {
+        fn on_event(&self, event: &SimEvent, _bus: &EventBus) {
+            if let SimEvent::Collision(event) = event {
+                self.0.borrow_mut().push((
+                    event.agent1_id,
+                    event.agent2_id,
+                    event.collision_type.clone(),
+                ));
+            }
+        }
+
+        fn id(&self) -> &str {
+            "test"
+        }
+    }
+
+    fn detector_with_recorder(
+        strategy: Box<dyn CollisionStrategy>,
+    ) -> (CollisionDetector, Rc<Recorder>) {
+        let detector = CollisionDetector::new(strategy);
+        let recorder = Rc::new(Recorder(RefCell::new(Vec::new())));
+        detector.bus.subscribe(recorder.clone());
+        (detector, recorder)
+    }
+
+    #[test]
+    fn same_cell_is_direct() {
+        let a = agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]);
+        let b = agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]);
+        let event = PathCollisionStrategy.detect(&a, &b).unwrap();
+        assert!(matches!(event.collision_type, CollisionType::Direct));
+        assert_eq!(event.collision_point, Node { x: 3, y: 3 });
+    }
+
+    #[test]
+    fn crossing_forward_paths_are_proximity() {
+        let a = agent_on_path(0, &[(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)]);
+        let b = agent_on_path(1, &[(2, 4), (2, 3), (2, 2), (2, 1), (2, 0)]);
+        let event = PathCollisionStrategy.detect(&a, &b).unwrap();
+        assert!(matches!(event.collision_type, CollisionType::Proximity));
+        assert_eq!(event.collision_point, Node { x: 2, y: 2 });
+    }
+
+    #[test]
+    fn predictive_finds_distant_crossings() {
+        let row: Vec<(i32, i32)> = (0..=10).map(|x| (x, 5)).collect();
+        let column: Vec<(i32, i32)> = (0..=10).map(|y| (5, y)).collect();
+        let a = agent_on_path(0, &row);
+        let b = agent_on_path(1, &column);
+        assert!(PathCollisionStrategy.detect(&a, &b).is_none());
+        assert_eq!(first_conflict(&a, &b), Some((Node { x: 5, y: 5 }, 5)));
+        let event = PredictiveCollisionStrategy.detect(&a, &b).unwrap();
+        assert!(matches!(event.collision_type, CollisionType::Proximity));
+
+        let offset: Vec<(i32, i32)> = (0..=10).map(|y| (6, y)).collect();
+        let c = agent_on_path(2, &offset);
+        assert!(first_conflict(&a, &c).is_none());
+    }
+
+    #[test]
+    fn warning_levels_follow_ticks_to_collision() {
+        let row: Vec<(i32, i32)> = (0..=10).map(|x| (x, 5)).collect();
+        let column: Vec<(i32, i32)> = (0..=10).map(|y| (5, y)).collect();
+        let offset: Vec<(i32, i32)> = (0..=10).map(|y| (7, y)).collect();
+        let mut agents = vec![
+            agent_on_path(0, &row),
+            agent_on_path(1, &column),
+            agent_on_path(2, &offset),
+        ];
+        assert_eq!(ticks_to_collision(&agents[0], &agents[1], false), Some(5));
+        assert_eq!(
+            warning_level(&agents[0], &agents[1], false),
+            Some(WarningLevel::Warn)
+        );
+        assert_eq!(warning_level(&agents[1], &agents[2], false), None);
+
+        let mut settings = RerouteSettings::new();
+        settings.min_level = WarningLevel::Critical;
+        let request = RerouteRequest {
+            agent_id: 0,
+            other_id: 1,
+            avoid_point: Node { x: 5, y: 5 },
+        };
+        assert!(!settings.urgent_enough(&request, &agents, false));
+
+        // Both step three cells closer without changing when they meet.
+        for agent in &mut agents[..2] {
+            agent.path_index = 3;
+            agent.current_point = agent.cell_at(0);
+        }
+        assert!(settings.urgent_enough(&request, &agents, false));
+        let levels = warning_levels(&agents, false);
+        assert_eq!(levels.get(&0), Some(&WarningLevel::Critical));
+        assert_eq!(levels.get(&2), None);
+
+        // Free-moving agents close 160 px at 120 px/s: 80 ticks at 60 ticks per second.
+        let mut a = Agent::new(3, Node { x: 0, y: 0 }, None);
+        let mut b = Agent::new(4, Node { x: 4, y: 0 }, None);
+        b.position = a.position + Vec2::new(200.0, 0.0);
+        a.velocity = Vec2::new(60.0, 0.0);
+        b.velocity = Vec2::new(-60.0, 0.0);
+        assert_eq!(ticks_to_collision(&a, &b, true), Some(80));
+        // Velocities left over from ORCA do not count once a grid strategy is active.
+        assert_eq!(ticks_to_collision(&a, &b, false), None);
+        b.velocity = Vec2::new(60.0, 0.0);
+        assert_eq!(ticks_to_collision(&a, &b, true), None);
+
+        let args = ["--warning-level".to_string(), "warn".to_string()];
+        assert_eq!(
+            parse_cli_options(&args).unwrap().warning_level,
+            WarningLevel::Warn
+        );
+        assert!(parse_cli_options(&["--warning-level".into(), "loud".into()]).is_err());
+    }
+
+    #[test]
+    fn head_on_swap_is_detected() {
+        let a = agent_on_path(0, &[(5, 5), (6, 5), (7, 5), (8, 5)]);
+        let b = agent_on_path(1, &[(6, 5), (5, 5), (4, 5), (3, 5)]);
+        let event = PathCollisionStrategy.detect(&a, &b).unwrap();
+        assert!(matches!(event.collision_type, CollisionType::Proximity));
+    }
+
+    #[test]
+    fn parallel_lanes_are_a_near_miss() {
+        let a = agent_on_path(0, &[(0, 0), (1, 0), (2, 0), (3, 0), (4, 0)]);
+        let b = agent_on_path(1, &[(0, 1), (1, 1), (2, 1), (3, 1), (4, 1)]);
+        assert!(PathCollisionStrategy.detect(&a, &b).is_none());
+
+        let c = agent_on_path(2, &[(0, 3), (1, 3), (2, 3), (3, 3), (4, 3)]);
+        assert!(GridCollisionStrategy.detect(&a, &c).is_none());
+        let d = agent_on_path(3, &[(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)]);
+        assert!(matches!(
+            GridCollisionStrategy.detect(&a, &d).unwrap().collision_type,
+            CollisionType::Proximity
+        ));
+    }
+
+    #[test]
+    fn orca_only_reports_overlapping_bodies() {
+        let mut a = agent_on_path(0, &[(0, 0), (1, 0)]);
+        let mut b = agent_on_path(1, &[(5, 5), (5, 6)]);
+        a.position = Vec2::new(100.0, 100.0);
+        b.position = Vec2::new(100.0 + a.radius + b.radius + 1.0, 100.0);
+        assert!(OrcaCollisionStrategy.detect(&a, &b).is_none());
+        b.position.x -= 2.0;
+        assert!(matches!(
+            OrcaCollisionStrategy.detect(&a, &b).unwrap().collision_type,
+            CollisionType::Direct
+        ));
+    }
+
+    #[test]
+    fn ignored_pairs_deduplicate_until_cleared() {
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let agents = vec![
+            agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]),
+            agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]),
+        ];
+        let mut stats = Statistics::new();
+
+        detector.check_agents(&agents, &mut stats);
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 1);
+        assert_eq!(stats.collisions, 1);
+
+        detector.ignored_pairs.clear();
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 2);
+        assert_eq!(stats.collisions, 2);
+    }
+
+    #[test]
+    fn ignored_pairs_expire_by_policy() {
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let mut agents = vec![
+            agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]),
+            agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]),
+        ];
+        let mut stats = Statistics::new();
+
+        detector.expiry = PairExpiry::Ticks(2);
+        detector.check_agents(&agents, &mut stats);
+        detector.expire_pairs(&agents, 1);
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 1);
+        detector.expire_pairs(&agents, 2);
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 2);
+
+        detector.expiry = PairExpiry::Distance(1);
+        detector.expire_pairs(&agents, 3);
+        assert_eq!(detector.ignored_pairs.len(), 1);
+        agents[1] = agent_on_path(1, &[(9, 9), (9, 8)]);
+        detector.expire_pairs(&agents, 4);
+        assert!(detector.ignored_pairs.is_empty());
+
+        detector.ignored_pairs.insert(AgentPair::new(0, 1), 4);
+        detector.forget_agents(&[1]);
+        assert!(detector.ignored_pairs.is_empty());
+    }
+
+    #[test]
+    fn rubber_band_selects_and_deletes_agents() {
+        let mut agents = vec![
+            agent_on_path(0, &[(1, 1)]),
+            agent_on_path(1, &[(4, 2)]),
+            agent_on_path(2, &[(2, 3)]),
+            agent_on_path(3, &[(9, 9)]),
+        ];
+        let selection = select_in_rect(&agents, Node { x: 4, y: 3 }, Node { x: 1, y: 1 });
+        assert_eq!(selection, [0, 1, 2].into_iter().collect());
+
+        let removed = delete_agents(&mut agents, &[0, 2].into_iter().collect());
+        assert_eq!(removed, 2);
+        let cells: Vec<(usize, Node)> = agents.iter().map(|a| (a.id, a.current_point)).collect();
+        assert_eq!(
+            cells,
+            vec![(0, Node { x: 4, y: 2 }), (1, Node { x: 9, y: 9 })]
+        );
+    }
+
+    #[test]
+    fn dark_pixels_become_walls() {
+        let mut img =
+            image::GrayImage::from_pixel(COLUMNS as u32 * 2, ROWS as u32 * 2, image::Luma([255]));
+        for y in 0..4 {
+            for x in 0..4 {
+                img.put_pixel(x, y, image::Luma([0]));
+            }
+        }
+        img.put_pixel(10, 10, image::Luma([0]));
+        let walls = obstacles_from_image(&img);
+        let expected: HashSet<Node> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .into_iter()
+            .map(|(x, y)| Node { x, y })
+            .collect();
+        assert_eq!(walls, expected);
+
+        let small = image::GrayImage::from_pixel(1, 1, image::Luma([0]));
+        assert_eq!(obstacles_from_image(&small).len(), COLUMNS * ROWS);
+    }
+
+    #[test]
+    fn city_blocks_leave_connected_streets() {
+        let walls = city_blocks(2, 3);
+        assert!(!walls.contains(&Node { x: 1, y: 7 }));
+        assert!(walls.contains(&Node { x: 2, y: 2 }));
+        assert!(walls.contains(&Node { x: 4, y: 4 }));
+        assert!(!walls.contains(&Node { x: 5, y: 3 }));
+
+        let mut components = ComponentMap::new();
+        components.rebuild(&walls, &OrthogonalMovement);
+        let corner = Node { x: 0, y: 0 };
+        for y in 0..ROWS as i32 {
+            for x in 0..COLUMNS as i32 {
+                let cell = Node { x, y };
+                if !walls.contains(&cell) {
+                    assert!(components.reachable(corner, cell, &OrthogonalMovement));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn traffic_lights_alternate_approaches() {
+        let n = |x, y| Node { x, y };
+        let light = TrafficLight::new(3, 0);
+        assert!(light.allows(n(4, 5), n(5, 5), 0));
+        assert!(!light.allows(n(5, 4), n(5, 5), 2));
+        assert!(light.allows(n(5, 4), n(5, 5), 3));
+        assert!(!light.allows(n(6, 5), n(5, 5), 5));
+        assert!(light.allows(n(6, 5), n(5, 5), 6));
+
+        let mut map = MapFile::parse("map 1\n").unwrap();
+        map.grid.lights.insert(n(5, 5), TrafficLight::new(8, 3));
+        assert_eq!(MapFile::parse(&map.to_text()).unwrap(), map);
+        assert!(MapFile::parse("map 1\nlight 1 1 0 0\n").is_err());
+        assert!(MapFile::parse("map 1\nlight 3 3 4 -1\n").is_err());
+        assert!(MapFile::parse("map 1\nlight 3 3 -4 0\n").is_err());
+
+        let late = TrafficLight {
+            green_ticks: 3,
+            offset: usize::MAX,
+        };
+        assert_eq!(late.horizontal_green(usize::MAX), light.horizontal_green(0));
+        let gate = Gate {
+            open_ticks: 2,
+            closed_ticks: 3,
+            offset: usize::MAX - 1,
+        };
+        assert!(gate.is_open(1));
+        assert!(!gate.is_open(usize::MAX));
+    }
+
+    #[test]
+    fn knowledge_spreads_within_comm_radius() {
+        let n = |x, y| Node { x, y };
+        let mut grid = Grid::new();
+        grid.walls.insert(n(6, 5));
+        let mut agents = vec![
+            agent_on_path(0, &[(5, 5), (5, 6)]),
+            agent_on_path(1, &[(8, 5), (8, 6)]),
+            agent_on_path(2, &[(15, 15), (15, 16)]),
+        ];
+        let mut stats = Statistics::new();
+
+        share_knowledge(&mut agents, &grid, 3, &OrthogonalMovement, &mut stats);
+        assert!(agents[0].known_walls.contains(&n(6, 5)));
+        assert!(agents[1].known_walls.contains(&n(6, 5)));
+        assert!(agents[2].known_walls.is_empty());
+
+        let mut blind = agent_on_path(0, &[(4, 5), (5, 5), (6, 5), (7, 5), (7, 6)]);
+        blind.known_walls.insert(n(6, 5));
+        let mut agents = vec![blind];
+        share_knowledge(&mut agents, &grid, 3, &OrthogonalMovement, &mut stats);
+        assert_eq!(stats.knowledge_replans, 1);
+        assert!(!agents[0].path.as_ref().unwrap().contains(&n(6, 5)));
+    }
+
+    #[test]
+    fn kinematic_planner_prefers_fewer_turns() {
+        let n = |x, y| Node { x, y };
+        assert_eq!(turn_steps(n(1, 0), n(0, 1)), 2);
+        assert_eq!(turn_steps(n(1, 0), n(-1, 0)), 4);
+        assert_eq!(turn_steps(n(0, 0), n(-1, 0)), 0);
+
+        let grid = Grid::new();
+        let turns = |path: &[Node]| {
+            path.windows(3)
+                .filter(|w| move_dir(w[0], w[1]) != move_dir(w[1], w[2]))
+                .count()
+        };
+        let plain = a_star(n(0, 0), n(6, 6), &grid, &OrthogonalMovement).unwrap();
+        let smooth =
+            a_star_kinematic(n(0, 0), n(0, 0), n(6, 6), &grid, &OrthogonalMovement, 4).unwrap();
+        assert_eq!(smooth.len(), plain.len());
+        assert_eq!(turns(&smooth), 1);
+
+        let facing_away =
+            a_star_kinematic(n(5, 5), n(-1, 0), n(8, 5), &grid, &OrthogonalMovement, 4).unwrap();
+        assert_eq!(facing_away.len(), 4);
+    }
+
+    #[test]
+    fn visibility_path_wraps_inflated_obstacles() {
+        let square = |x0: f32, y0: f32, x1: f32, y1: f32| {
+            vec![
+                Vec2::new(x0, y0),
+                Vec2::new(x1, y0),
+                Vec2::new(x1, y1),
+                Vec2::new(x0, y1),
+            ]
+        };
+        let mut world = ContinuousWorld::new();
+        world.footprint = square(-10.0, -10.0, 10.0, 10.0);
+        world.add_obstacle(square(100.0, 100.0, 200.0, 200.0));
+        assert_eq!(world.inflated[0].len(), 4);
+        assert!(!world.is_free(Vec2::new(95.0, 150.0)));
+        assert!(world.is_free(Vec2::new(85.0, 150.0)));
+        assert!(world.segment_free(Vec2::new(90.0, 50.0), Vec2::new(90.0, 250.0)));
+        assert!(!world.segment_free(Vec2::new(50.0, 150.0), Vec2::new(250.0, 150.0)));
+
+        let path = VisibilityAStar
+            .plan(Vec2::new(50.0, 150.0), Vec2::new(250.0, 150.0), &world)
+            .unwrap();
+        assert_eq!(path.len(), 4);
+        assert!(path.windows(2).all(|w| world.segment_free(w[0], w[1])));
+        let length: f32 = path.windows(2).map(|w| w[0].distance(w[1])).sum();
+        let expected = 2.0 * Vec2::new(40.0, 60.0).length() + 120.0;
+        assert!((length - expected).abs() < 0.01);
+
+        let dijkstra = VisibilityDijkstra
+            .plan(Vec2::new(50.0, 150.0), Vec2::new(250.0, 150.0), &world)
+            .unwrap();
+        let dijkstra_length: f32 = dijkstra.windows(2).map(|w| w[0].distance(w[1])).sum();
+        assert!((dijkstra_length - expected).abs() < 0.01);
+        assert_eq!(world.graph.points.len(), 4);
+        assert_eq!(world.graph.edge_count(), 4);
+
+        assert!(
+            VisibilityAStar
+                .plan(Vec2::new(150.0, 150.0), Vec2::new(250.0, 150.0), &world)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn prm_roadmap_edges_stay_clear() {
+        let mut world = ContinuousWorld::new();
+        world.add_obstacle(vec![
+            Vec2::new(300.0, 300.0),
+            Vec2::new(700.0, 300.0),
+            Vec2::new(700.0, 700.0),
+            Vec2::new(300.0, 700.0),
+        ]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let roadmap = Roadmap::prm(&world, 200, 150.0, &mut rng);
+        assert_eq!(roadmap.points.len(), 200);
+        assert!(roadmap.points.iter().all(|&p| world.is_free(p)));
+        assert!(roadmap.edges.iter().all(|e| e.len() <= 2 * PRM_NEIGHBORS));
+        for (a, b) in roadmap.segments() {
+            assert!(a.distance(b) <= 150.0);
+            assert!(world.segment_free(a, b));
+        }
+
+        let planner = PrmPlanner::new(400, 200.0, StdRng::seed_from_u64(7));
+        let (start, goal) = (Vec2::new(500.0, 100.0), Vec2::new(500.0, 900.0));
+        let path = planner.plan(start, goal, &world).unwrap();
+        assert_eq!((path[0], *path.last().unwrap()), (start, goal));
+        assert!(path.windows(2).all(|w| world.segment_free(w[0], w[1])));
+        assert!(
+            planner
+                .plan(Vec2::new(500.0, 500.0), goal, &world)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rrt_star_shortens_the_tree_path() {
+        let mut world = ContinuousWorld::new();
+        world.add_obstacle(vec![
+            Vec2::new(300.0, 300.0),
+            Vec2::new(700.0, 300.0),
+            Vec2::new(700.0, 700.0),
+            Vec2::new(300.0, 700.0),
+        ]);
+        let (start, goal) = (Vec2::new(500.0, 100.0), Vec2::new(500.0, 900.0));
+        let optimal = path_length(&VisibilityAStar.plan(start, goal, &world).unwrap());
+
+        let mut lengths = Vec::new();
+        for star in [false, true] {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+            let tree = RrtTree::grow(&world, start, goal, star, &mut rng);
+            let path = tree.path().unwrap();
+            assert_eq!((path[0], *path.last().unwrap()), (start, goal));
+            assert!(path.windows(2).all(|w| world.segment_free(w[0], w[1])));
+            let length = path_length(&path);
+            assert!((length - tree.costs[tree.goal.unwrap()]).abs() < 0.1);
+            assert!(length >= optimal - 0.1);
+            lengths.push(length);
+        }
+        assert!(lengths[1] < lengths[0]);
+
+        let blocked = RrtTree::grow(
+            &world,
+            Vec2::new(500.0, 500.0),
+            goal,
+            false,
+            &mut rand::rng(),
+        );
+        assert!(blocked.path().is_none());
+    }
+
+    #[test]
+    fn set_strategy_resets_ignored_pairs() {
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let agents = vec![
+            agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]),
+            agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]),
+        ];
+        let mut stats = Statistics::new();
+
+        detector.check_agents(&agents, &mut stats);
+        detector.set_strategy(Box::new(GridCollisionStrategy));
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 2);
+    }
+
+    #[test]
+    fn detection_is_symmetric() {
+        let cases = [
+            (
+                agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]),
+                agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]),
+            ),
+            (
+                agent_on_path(0, &[(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)]),
+                agent_on_path(1, &[(2, 4), (2, 3), (2, 2), (2, 1), (2, 0)]),
+            ),
+            (
+                agent_on_path(0, &[(5, 5), (6, 5), (7, 5), (8, 5)]),
+                agent_on_path(1, &[(6, 5), (5, 5), (4, 5), (3, 5)]),
+            ),
+            (
+                agent_on_path(0, &[(0, 0), (1, 0), (2, 0), (3, 0), (4, 0)]),
+                agent_on_path(1, &[(0, 1), (1, 1), (2, 1), (3, 1), (4, 1)]),
+            ),
+        ];
+        let strategies: [&dyn CollisionStrategy; 4] = [
+            &PathCollisionStrategy,
+            &GridCollisionStrategy,
+            &OrcaCollisionStrategy,
+            &PredictiveCollisionStrategy,
+        ];
+        for strategy in strategies {
+            for (a, b) in &cases {
+                let ab = strategy.detect(a, b).map(|e| e.collision_type);
+                let ba = strategy.detect(b, a).map(|e| e.collision_type);
+                assert_eq!(
+                    format!("{:?}", ab),
+                    format!("{:?}", ba),
+                    "{} is asymmetric for agents at {:?} and {:?}",
+                    strategy.name(),
+                    a.current_point,
+                    b.current_point
+                );
+            }
+        }
+    }
+
+    fn sample_map() -> MapFile {
+        let n = |x, y| Node { x, y };
+        let mut grid = Grid::new();
+        grid.tick = 7;
+        for y in 2..6 {
+            grid.walls.insert(n(4, y));
+        }
+        grid.zones.insert(n(1, 1), Zone::Avoid);
+        grid.zones.insert(n(2, 1), Zone::Prefer);
+        grid.one_way.insert((n(5, 2), n(6, 2)));
+        grid.gates.insert(n(4, 6), Gate::new(10, 10, 7));
+        grid.chargers.insert(n(0, 9));
+        grid.capacities.insert(n(4, 1), 2);
+        grid.capacities.insert(n(4, 0), 2);
+        grid.notes.insert(n(4, 3), "bottleneck A".to_string());
+        grid.notes.insert(n(0, 0), "*".to_string());
+        MapFile {
+            grid,
+            goal_set: HashSet::from([n(9, 9), n(9, 0)]),
+            agents: vec![(n(0, 0), Some(n(8, 3))), (n(0, 5), None)],
+            expectations: vec![
+                Expectation::MaxCollisions(0),
+                Expectation::AllArriveBy(300),
+                Expectation::PathLength {
+                    agent: 0,
+                    relation: Relation::AtMost,
+                    value: 40,
+                },
+            ],
+            undo: vec![
+                "wall 4 5 place".to_string(),
+                "add_agent 0 5 -".to_string(),
+                "set_goal 0 8 3 -".to_string(),
+            ],
+            redo: vec!["delete_agents 1 0 5 - 2 3 3 7 7".to_string()],
+        }
+    }
+
+    fn check_golden(name: &str, map: &MapFile, golden: &str) {
+        let text = map.to_text();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(
+                format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name),
+                &text,
+            )
+            .unwrap();
+            return;
+        }
+        assert_eq!(
+            text, golden,
+            "{} drifted; rerun with UPDATE_GOLDEN=1 if intended",
+            name
+        );
+        let parsed = MapFile::parse(golden).unwrap();
+        assert_eq!(&parsed, map);
+        assert_eq!(parsed.to_text(), golden);
+    }
+
+    #[test]
+    fn empty_map_matches_golden() {
+        let map = MapFile {
+            grid: Grid::new(),
+            goal_set: HashSet::new(),
+            agents: Vec::new(),
+            expectations: Vec::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+        };
+        check_golden("empty.map", &map, include_str!("../testdata/empty.map"));
+    }
+
+    #[test]
+    fn full_map_matches_golden() {
+        check_golden(
+            "full.map",
+            &sample_map(),
+            include_str!("../testdata/full.map"),
+        );
+    }
+
+    #[test]
+    fn map_parse_rejects_bad_input() {
+        assert!(MapFile::parse("wall 1 2\n").is_err());
+        assert!(MapFile::parse("map 2\n").is_err());
+        assert!(MapFile::parse("map 1\nwall 1\n").is_err());
+        assert!(MapFile::parse("map 1\nzone 1 1 lava\n").is_err());
+        assert!(MapFile::parse("map 1\nportal 1 1\n").is_err());
+        assert!(MapFile::parse("map 1\nnote 1 1\n").is_err());
+        assert!(MapFile::parse("map 1\nundo wall 1 1 paint\n").is_err());
+        assert!(MapFile::parse("map 1\nredo delete_agents 0 1\n").is_err());
+        match MapFile::parse("map 1\n\nwall x 2\n") {
+            Err(SimError::MapFormat { line, .. }) => assert_eq!(line, 3),
+            other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+        }
+        for negative in [
+            "tick -4",
+            "gate 3 3 -1 -1 0",
+            "gate 3 3 2 2 -1",
+            "capacity 1 1 -2",
+        ] {
+            match MapFile::parse(&format!("map 1\n{}\n", negative)) {
+                Err(SimError::MapFormat { line, message }) => {
+                    assert_eq!(line, 2);
+                    assert!(message.contains("must not be negative"), "{}", message);
+                }
+                other => panic!("{} parsed: {:?}", negative, other.map(|_| ())),
+            }
+        }
+        for outside in [
+            format!("undo wall {} 0 place", COLUMNS),
+            format!("undo add_agent 0 0 0 {}", ROWS),
+            "redo set_goal 0 -1 2 -".to_string(),
+            "undo delete_agents 0 -3 4 -".to_string(),
+        ] {
+            match MapFile::parse(&format!("map 1\n{}\n", outside)) {
+                Err(SimError::MapFormat { line, message }) => {
+                    assert_eq!(line, 2);
+                    assert!(message.contains("outside the grid"), "{}", message);
+                }
+                other => panic!("{} parsed: {:?}", outside, other.map(|_| ())),
+            }
+        }
+        assert!(parse_command("wall 0 0 place").is_ok());
+    }
+
+    #[test]
+    fn removed_observers_stop_receiving_events() {
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let agents = vec![
+            agent_on_path(0, &[(3, 3), (4, 3), (5, 3)]),
+            agent_on_path(1, &[(3, 3), (3, 4), (3, 5)]),
+        ];
+        let mut stats = Statistics::new();
+
+        assert!(detector.bus.unsubscribe("missing").is_none());
+        assert_eq!(detector.bus.toggle("test"), Some(false));
+        detector.check_agents(&agents, &mut stats);
+        assert!(recorder.0.borrow().is_empty());
+
+        assert_eq!(detector.bus.toggle("test"), Some(true));
+        detector.ignored_pairs.clear();
+        detector.check_agents(&agents, &mut stats);
+        assert_eq!(recorder.0.borrow().len(), 1);
+        assert_eq!(detector.bus.toggle("missing"), None);
+    }
+
+    #[test]
+    fn large_footprints_inflate_walls_and_collide_directly() {
+        let mut grid = Grid::new();
+        grid.walls.insert(Node { x: 5, y: 5 });
+        let square = grid.inflated(Footprint::Square);
+        for (x, y) in [(4, 4), (5, 4), (4, 5), (5, 5)] {
+            assert!(square.walls.contains(&Node { x, y }));
+        }
+        assert!(square.walls.contains(&Node {
+            x: COLUMNS as i32 - 1,
+            y: 0
+        }));
+        assert!(!square.walls.contains(&Node { x: 6, y: 5 }));
+        assert!(matches!(grid.inflated(Footprint::Cell), Cow::Borrowed(_)));
+
+        let path = a_star(
+            Node { x: 3, y: 5 },
+            Node { x: 7, y: 5 },
+            &grid.inflated(Footprint::Bar),
+            &OrthogonalMovement,
+        )
+        .unwrap();
+        assert!(path.iter().all(|&n| {
+            Footprint::Bar
+                .cells(n)
+                .iter()
+                .all(|c| in_bounds(*c) && !grid.walls.contains(c))
+        }));
+
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let mut agents = vec![
+            agent_on_path(0, &[(2, 2), (2, 1)]),
+            agent_on_path(1, &[(3, 3), (3, 4)]),
+        ];
+        let mut stats = Statistics::new();
+        detector.check_agents(&agents, &mut stats);
+        assert!(recorder.0.borrow().is_empty());
+        agents[0].footprint = Footprint::Square;
+        detector.check_agents(&agents, &mut stats);
+        assert!(matches!(
+            recorder.0.borrow().as_slice(),
+            [(0, 1, CollisionType::Direct)]
+        ));
+    }
+
+    #[test]
+    fn cell_radius_scales_footprint_and_avoid_set() {
+        let mut agents = vec![
+            agent_on_path(0, &[(5, 5), (6, 5)]),
+            agent_on_path(1, &[(9, 5), (8, 5)]),
+        ];
+        let footprint = |agent: &mut Agent, r: i32| {
+            agent.cell_radius = r;
+            agent.refresh_cache();
+            agent.collision_radius.len()
+        };
+        assert_eq!(footprint(&mut agents[0], 0), 0);
+        assert_eq!(footprint(&mut agents[0], 1), 8);
+        assert_eq!(footprint(&mut agents[0], 2), 24);
+
+        assert!(
+            GridCollisionStrategy
+                .detect(&agents[0], &agents[1])
+                .is_none()
+        );
+        footprint(&mut agents[1], 2);
+        assert!(
+            GridCollisionStrategy
+                .detect(&agents[0], &agents[1])
+                .is_some()
+        );
+
+        footprint(&mut agents[0], 0);
+        let plan = compute_avoidance_plan(&agents, &[0, 1], Node { x: 7, y: 5 }, 1);
+        let size = |id: usize| plan.iter().find(|p| p.0 == id).unwrap().1.len();
+        assert!(size(1) > size(0));
+    }
+
+    #[test]
+    fn safety_distance_widens_proximity_and_tracks_separation() {
+        let (mut detector, recorder) = detector_with_recorder(Box::new(PathCollisionStrategy));
+        let agents = vec![
+            agent_on_path(0, &[(2, 2), (2, 1)]),
+            agent_on_path(1, &[(4, 2), (4, 1)]),
+        ];
+        let mut stats = Statistics::new();
+        detector.check_agents(&agents, &mut stats);
+        assert!(recorder.0.borrow().is_empty());
+        assert_eq!(stats.min_separation, Some(2));
+
+        detector.safety_distance = 2;
+        detector.ignored_pairs.clear();
+        detector.check_agents(&agents, &mut stats);
+        assert!(matches!(
+            recorder.0.borrow().as_slice(),
+            [(0, 1, CollisionType::Proximity)]
+        ));
+        assert_eq!(stats.detections, 1);
+    }
+
+    #[test]
+    fn reserved_moves_keep_the_safety_distance() {
+        let grid = Grid::new();
+        let mut agents = vec![
+            agent_on_path(0, &[(2, 5), (3, 5), (4, 5)]),
+            agent_on_path(1, &[(6, 5), (6, 6)]),
+        ];
+        let mut stats = Statistics::new();
+        let moves = plan_reserved_moves(&mut agents, &grid, &DiagonalMovement, 0, &mut stats);
+        assert_eq!(moves, [true, true]);
+
+        let moves = plan_reserved_moves(&mut agents, &grid, &DiagonalMovement, 3, &mut stats);
+        assert_eq!(moves, [false, true]);
+        assert_eq!(stats.separation_waits, 1);
+    }
+
+    #[test]
+    fn reserved_moves_back_the_higher_id_into_a_corridor_niche() {
+        // One-wide corridor on row 1 with a single niche at (3, 0).
+        let mut grid = Grid::new();
+        for x in 0..=7 {
+            if x != 3 {
+                grid.walls.insert(Node { x, y: 0 });
+            }
+            grid.walls.insert(Node { x, y: 2 });
+        }
+        let mut agents = vec![
+            agent_on_path(0, &[(2, 1), (3, 1), (4, 1), (5, 1), (6, 1)]),
+            agent_on_path(1, &[(3, 1), (2, 1), (1, 1), (0, 1)]),
+        ];
+        let mut stats = Statistics::new();
+
+        let moves = plan_reserved_moves(&mut agents, &grid, &OrthogonalMovement, 0, &mut stats);
+        assert_eq!(moves, [false, false]);
+        assert_eq!(stats.deadlocks_resolved, 1);
+        let detour: Vec<(i32, i32)> = agents[1].path.as_ref().unwrap()[agents[1].path_index..]
+            .iter()
+            .map(|n| (n.x, n.y))
+            .collect();
+        // Into the niche, one tick there while the other passes, then back out towards the goal.
+        assert_eq!(
+            detour,
+            [(3, 1), (3, 0), (3, 0), (3, 1), (2, 1), (1, 1), (0, 1)]
+        );
+
+        // Next tick the yielder steps into the niche and the other agent follows into its cell.
+        let moves = plan_reserved_moves(&mut agents, &grid, &OrthogonalMovement, 0, &mut stats);
+        assert_eq!(moves, [true, true]);
+        assert_eq!(stats.deadlocks_resolved, 1);
+    }
+
+    #[test]
+    fn timed_a_star_waits_for_a_crossing_agent() {
+        let grid = Grid::new();
+        // Agent 1 lingers at (2, 0), then crosses row 1 at tick 2.
+        let agents = vec![
+            agent_on_path(0, &[(0, 1), (4, 1)]),
+            agent_on_path(1, &[(2, 0), (2, 0), (2, 1), (2, 2), (2, 3)]),
+        ];
+        let schedule = Schedule::from_agents(&agents, 0);
+        let start = Node { x: 0, y: 1 };
+        let goal = Node { x: 4, y: 1 };
+
+        let path = a_star_timed(start, goal, &grid, &schedule, &OrthogonalMovement).unwrap();
+        // Four moves plus one wait: cheaper than the two-move detour around the crossing.
+        assert_eq!(path.len(), 6);
+        assert_eq!(path.last(), Some(&(goal, 5)));
+        assert!(path.iter().all(|&(n, _)| n.y == 1));
+        assert!(path.windows(2).any(|w| w[0].0 == w[1].0));
+        assert!(
+            path.iter()
+                .all(|&(n, t)| n != Schedule::at(&schedule.timelines[0], t))
+        );
+
+        // Without the other agent the straight run is taken.
+        let empty = Schedule::from_agents(&agents[..1], 0);
+        let path = a_star_timed(start, goal, &grid, &empty, &OrthogonalMovement).unwrap();
+        assert_eq!(path.len(), 5);
+    }
+
+    #[test]
+    fn lower_ranked_agents_yield_to_claimed_cells() {
+        let mut agents = vec![
+            agent_on_path(0, &[(0, 2), (1, 2), (2, 2), (3, 2)]),
+            agent_on_path(1, &[(2, 0), (2, 1), (2, 2), (2, 3)]),
+            agent_on_path(2, &[(8, 8), (9, 8)]),
+        ];
+        let mut stats = Statistics::new();
+        let mut moves = vec![true; agents.len()];
+        yield_to_intentions(&mut agents, 1, &mut moves, &mut stats);
+        assert_eq!(moves, [true, true, true]);
+
+        yield_to_intentions(&mut agents, INTENTION_HORIZON, &mut moves, &mut stats);
+        assert_eq!(moves, [true, false, true]);
+        assert_eq!(stats.yields, 1);
+
+        agents[1].priority = MAX_PRIORITY;
+        let mut moves = vec![true; agents.len()];
+        yield_to_intentions(&mut agents, INTENTION_HORIZON, &mut moves, &mut stats);
+        assert_eq!(moves, [false, true, true]);
+
+        agents[1].priority = 0;
+        for _ in 0..=YIELD_LIMIT {
+            moves = vec![true; agents.len()];
+            yield_to_intentions(&mut agents, INTENTION_HORIZON, &mut moves, &mut stats);
+        }
+        assert_eq!(moves, [true, true, true]);
+        assert_eq!(agents[1].yield_streak, 0);
+    }
+
+    #[test]
+    fn events_published_by_subscribers_follow_the_current_one() {
+        let bus = EventBus::new();
+        let log = Rc::new(Mailbox::new("log", |_| true));
+        bus.subscribe(Rc::new(CollisionAssistant));
+        bus.subscribe(log.clone());
+        bus.publish(SimEvent::Collision(CollisionEvent {
+            agent1_id: 0,
+            agent2_id: 1,
+            collision_type: CollisionType::Proximity,
+            collision_point: Node { x: 2, y: 2 },
+        }));
+        bus.publish(SimEvent::Arrival(3));
+
+        let seen: Vec<String> = log
+            .take()
+            .iter()
+            .map(|event| match event {
+                SimEvent::Collision(_) => "collision".to_string(),
+                SimEvent::Reroute(r) => format!("reroute {}", r.agent_id),
+                SimEvent::Arrival(id) => format!("arrival {}", id),
+                _ => "other".to_string(),
+            })
+            .collect();
+        assert_eq!(seen, ["collision", "reroute 0", "reroute 1", "arrival 3"]);
+        assert!(log.take().is_empty());
+    }
+
+    #[test]
+    fn letterbox_centres_the_frame_and_maps_the_mouse_back() {
+        let wide = Letterbox::fit(2 * WIDTH + 200, HEIGHT * 2);
+        assert_eq!(wide.size, (2 * WIDTH, 2 * HEIGHT));
+        assert_eq!(wide.offset, (100, 0));
+        assert_eq!(wide.to_frame((100.0, 0.0)), (0.0, 0.0));
+        assert_eq!(wide.to_frame((1100.0, 1000.0)), (500.0, 500.0));
+        assert_eq!(wide.to_frame((5.0, 0.0)), (0.0, 0.0));
+
+        let mut frame = vec![BLACK; WIDTH * HEIGHT];
+        for y in HEIGHT - 4..HEIGHT {
+            frame[y * WIDTH + WIDTH - 4..(y + 1) * WIDTH].fill(WHITE);
+        }
+        let small = Letterbox::fit(WIDTH / 2, HEIGHT / 4);
+        assert_eq!(small.size, (WIDTH / 4, HEIGHT / 4));
+        let mut out = vec![RED; small.window.0 * small.window.1];
+        small.blit(&frame, &mut out);
+        assert_eq!(out[0], BLACK);
+        let corner = (small.size.1 - 1) * small.window.0 + small.offset.0 + small.size.0 - 1;
+        assert_eq!(out[corner], WHITE);
+        assert!(Letterbox::fit(WIDTH, HEIGHT).is_identity());
+    }
+
+    #[test]
+    fn triangles_fill_either_winding_and_paths_get_arrowheads() {
+        let fill = |points: [Vec2; 3]| {
+            let mut buffer = vec![BLACK; WIDTH * HEIGHT];
+            draw_triangle(&mut buffer, &TriangleParams { points, color: RED });
+            buffer
+        };
+        let (a, b, c) = (
+            Vec2::new(10.0, 10.0),
+            Vec2::new(30.0, 10.0),
+            Vec2::new(10.0, 30.0),
+        );
+        let cw = fill([a, b, c]);
+        assert_eq!(cw, fill([a, c, b]));
+        assert_eq!(cw[12 * WIDTH + 12], RED);
+        assert_eq!(cw[28 * WIDTH + 28], BLACK);
+        assert_eq!(cw.iter().filter(|&&p| p == RED).count(), 210);
+
+        let state = default_state();
+        let mut agent = Agent::new(0, Node { x: 1, y: 1 }, Some(Node { x: 8, y: 1 }));
+        agent.path = Some((1..9).map(|x| Node { x, y: 1 }).collect());
+        let triangles = |agent: &Agent| {
+            scene(&state, std::slice::from_ref(agent), false, false)
+                .items
+                .iter()
+                .filter(|(_, item)| matches!(item, DrawType::Triangle(_)))
+                .count()
+        };
+        // Two along the seven segments ahead, one for the heading.
+        assert_eq!(triangles(&agent), 3);
+        agent.path_index = 4;
+        assert_eq!(triangles(&agent), 2);
+    }
+
+    #[test]
+    fn dashed_lines_follow_the_pattern_and_carry_their_phase() {
+        let dashes = |phase: usize| {
+            let mut buffer = vec![BLACK; WIDTH * HEIGHT];
+            draw_dashed_line(
+                &mut buffer,
+                &DashedLineParams {
+                    line: LineParams {
+                        x0: 0,
+                        y0: 0,
+                        x1: 19,
+                        y1: 0,
+                        color: WHITE,
+                        thickness: 1,
+                    },
+                    dash: PATH_DASH,
+                    gap: PATH_GAP,
+                    phase,
+                },
+            );
+            buffer[..20]
+                .iter()
+                .map(|&p| if p == WHITE { '-' } else { ' ' })
+                .collect::<String>()
+        };
+        assert_eq!(dashes(0), "------    ------    ");
+        assert_eq!(dashes(3), "---    ------    ---");
+    }
+
+    #[test]
+    fn render_queue_sorts_by_layer_and_culls_off_screen_commands() {
+        struct Recorder(Vec<u32>);
+        impl Artist for Recorder {
+            fn draw(&mut self, item: &DrawType) {
+                if let DrawType::Circle(p) = item {
+                    self.0.push(p.color);
+                }
+            }
+        }
+        let circle = |x: usize, color: u32| {
+            DrawType::Circle(CircleParams {
+                center: PixelCoord { x, y: 100 },
+                radius: 10,
+                color,
+            })
+        };
+        let mut queue = RenderQueue::new(Bounds::screen());
+        queue.push(Layer::Agents, circle(100, RED));
+        queue.push(Layer::Markers, circle(100, YELLOW));
+        queue.push(Layer::Agents, circle(120, CYAN));
+        queue.push(Layer::Paths, circle(WIDTH + 50, WHITE));
+        queue.push(
+            Layer::Paths,
+            DrawType::Line(LineParams {
+                x0: -40,
+                y0: 5,
+                x1: -2,
+                y1: 50,
+                color: WHITE,
+                thickness: 3,
+            }),
+        );
+        let mut recorder = Recorder(Vec::new());
+        assert_eq!(queue.flush(&mut recorder), 3);
+        assert_eq!(recorder.0, [YELLOW, RED, CYAN]);
+    }
+
+    #[test]
+    fn walls_paths_and_agents_outside_the_viewport_are_not_drawn() {
+        let mut state = default_state();
+        state.viewport = Bounds {
+            x0: 0,
+            y0: 0,
+            x1: Col(10).left() as i32 - 1,
+            y1: HEIGHT as i32 - 1,
+        };
+        let (near, far) = (Node { x: 2, y: 2 }, Node { x: 15, y: 2 });
+        state.grid.walls.extend([near, far]);
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        render(&mut buffer, &state, &[], false, false);
+        let center = |n: Node| {
+            let c = n.cell().center();
+            buffer[c.y * WIDTH + c.x]
+        };
+        assert_eq!(center(near), state.theme.wall);
+        assert_eq!(center(far), state.theme.background);
+
+        // One agent starts inside and heads out; the other sits entirely past the viewport.
+        let path: Vec<(i32, i32)> = (2..16).map(|x| (x, 5)).collect();
+        let agents = [agent_on_path(0, &path), agent_on_path(1, &path[12..])];
+        let counts = |queue: &RenderQueue, layer: Layer| {
+            queue.items.iter().filter(|(l, _)| *l == layer).count()
+        };
+        let culled = scene(&state, &agents, false, false);
+        assert!(
+            culled
+                .items
+                .iter()
+                .all(|(_, item)| bounds(item).intersects(&state.viewport))
+        );
+        state.viewport = Bounds::screen();
+        let full = scene(&state, &agents, false, false);
+        for layer in [Layer::Paths, Layer::Agents] {
+            assert!(counts(&culled, layer) > 0);
+            assert!(counts(&culled, layer) < counts(&full, layer));
+        }
+    }
+
+    #[test]
+    fn static_layer_is_rebuilt_only_when_walls_or_theme_change() {
+        let mut state = default_state();
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        let rebuilds = |state: &GameState| state.static_layer.borrow().rebuilds;
+        render(&mut buffer, &state, &[], false, false);
+        render(&mut buffer, &state, &[], false, false);
+        assert_eq!(rebuilds(&state), 1);
+
+        let wall = Node { x: 4, y: 4 };
+        state.grid.walls.insert(wall);
+        let zone = Node { x: 6, y: 4 };
+        state.grid.zones.insert(zone, Zone::Avoid);
+        render(&mut buffer, &state, &[], false, false);
+        assert_eq!(rebuilds(&state), 2);
+        let center = |n: Node| {
+            let c = n.cell().center();
+            buffer[c.y * WIDTH + c.x]
+        };
+        assert_eq!(center(wall), state.theme.wall);
+        assert_eq!(center(zone), AVOID_TINT);
+        let edge = zone.cell().top_left();
+        assert_eq!(buffer[(edge.y + 5) * WIDTH + edge.x], state.theme.gridline);
+
+        state.theme = state.theme.next();
+        render(&mut buffer, &state, &[], false, false);
+        assert_eq!(rebuilds(&state), 3);
+    }
+
+    #[test]
+    fn circle_spans_match_the_per_pixel_disc() {
+        for (center, radius) in [
+            (PixelCoord { x: 500, y: 500 }, 0),
+            (PixelCoord { x: 500, y: 500 }, 13),
+            (PixelCoord { x: 3, y: 2 }, 10),
+            (
+                PixelCoord {
+                    x: WIDTH - 2,
+                    y: HEIGHT - 5,
+                },
+                14,
+            ),
+        ] {
+            let mut buffer = vec![BLACK; WIDTH * HEIGHT];
+            draw_circle_at_pixels(&mut buffer, center, radius, RED);
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let (dx, dy) = (x.abs_diff(center.x), y.abs_diff(center.y));
+                    let inside = dx * dx + dy * dy <= radius * radius;
+                    assert_eq!(buffer[y * WIDTH + x] == RED, inside, "({}, {})", x, y);
+                }
+            }
+        }
+
+        let frame: Vec<u32> = (0..WIDTH * HEIGHT).map(|i| i as u32).collect();
+        let doubled = Letterbox::fit(2 * WIDTH, 2 * HEIGHT);
+        let mut out = vec![0; 4 * WIDTH * HEIGHT];
+        doubled.blit(&frame, &mut out);
+        for (x, y) in [(0, 0), (1, 1), (999, 1000), (1999, 1999)] {
+            assert_eq!(out[y * 2 * WIDTH + x], frame[(y / 2) * WIDTH + x / 2]);
+        }
+    }
+
+    #[test]
+    fn sprites_blit_with_transparency_and_tint() {
+        let sprites = SpriteSet::load("testdata/sprites").unwrap();
+        let agent = sprites.agent.unwrap();
+        assert_eq!(agent.width, CELL_WIDTH.min(CELL_HEIGHT) - 2 * SPRITE_MARGIN);
+        assert!(sprites.wall.is_some() && sprites.goal.is_some());
+        assert!(SpriteSet::load("testdata").unwrap().agent.is_none());
+
+        let mut buffer = vec![BLACK; WIDTH * HEIGHT];
+        let center = Node { x: 3, y: 3 }.cell().center();
+        draw_sprite(
+            &mut buffer,
+            &SpriteParams {
+                center,
+                sprite: agent.clone(),
+                tint: Some(RED),
+            },
+        );
+        assert_eq!(buffer[(center.y + 2) * WIDTH + center.x], RED);
+        let corner = center.y - agent.height / 2;
+        assert_eq!(buffer[corner * WIDTH + center.x - agent.width / 2], BLACK);
+    }
+
+    #[test]
+    fn themes_load_from_files_and_cycle_through_the_builtins() {
+        let theme = Theme::load("testdata/projector.theme").unwrap();
+        assert_eq!(theme.name, "custom");
+        assert_eq!(theme.background, Theme::light().background);
+        assert_eq!(theme.wall, 0);
+        assert_eq!(theme.agent_color(1), 0x002962FF);
+        assert_eq!(theme.agent_color(5), 0x002962FF);
+        assert_eq!(theme.next().name, "dark");
+        assert_eq!(Theme::dark().next().next().next(), Theme::dark());
+        assert!(matches!(
+            Theme::parse("wall 0x000000\nsparkle 0xFFFFFF"),
+            Err(SimError::ThemeFormat { line: 2, .. })
+        ));
+        assert!(Theme::parse("goal purple").is_err());
+    }
+
+    #[test]
+    fn scale_flag_and_thick_lines_for_presentations() {
+        let args: Vec<String> = ["--scale", "2", "--presentation"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let options = parse_cli_options(&args).unwrap();
+        assert_eq!(options.scale, 2);
+        assert!(options.presentation);
+        assert!(parse_cli_options(&["--scale".into(), "9".into()]).is_err());
+
+        let mut buffer = vec![BLACK; WIDTH * HEIGHT];
+        draw_line(
+            &mut buffer,
+            &LineParams {
+                x0: 10,
+                y0: 10,
+                x1: 20,
+                y1: 10,
+                color: WHITE,
+                thickness: 3,
+            },
+        );
+        let column: Vec<u32> = (8..13).map(|y| buffer[y * WIDTH + 15]).collect();
+        assert_eq!(column, [BLACK, WHITE, WHITE, WHITE, BLACK]);
+    }
+
+    #[test]
+    fn cell_and_pixel_coordinates_keep_columns_and_rows_apart() {
+        let pixel = PixelCoord {
+            x: 3 * CELL_WIDTH + 1,
+            y: 7 * CELL_HEIGHT + CELL_HEIGHT - 1,
+        };
+        assert_eq!(pixel.cell().node(), Node { x: 3, y: 7 });
+        let cell = Node { x: 2, y: 5 }.cell();
+        assert_eq!(cell.center().cell(), cell);
+        assert_eq!(
+            cell.top_left(),
+            PixelCoord {
+                x: 2 * CELL_WIDTH,
+                y: 5 * CELL_HEIGHT
+            }
+        );
+
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        draw_square(&mut buffer, &SquareParams { cell, color: 1 });
+        let at = |p: PixelCoord| buffer[p.y * WIDTH + p.x];
+        assert_eq!(at(cell.top_left()), 1);
+        assert_eq!(
+            at(cell.top_left().offset(CELL_WIDTH - 1, CELL_HEIGHT - 1)),
+            1
+        );
+        assert_eq!(at(Node { x: 5, y: 2 }.cell().center()), 0);
+        assert_eq!(
+            buffer.iter().filter(|&&c| c == 1).count(),
+            CELL_WIDTH * CELL_HEIGHT
+        );
+    }
+
+    #[test]
+    fn geometric_length_weights_diagonals_and_zones() {
+        let mut grid = Grid::new();
+        let path: Vec<Node> = [(0, 0), (1, 0), (2, 1), (3, 1)]
+            .iter()
+            .map(|&(x, y)| Node { x, y })
+            .collect();
+        let plain = grid.geometric_length(&path);
+        assert!((plain - (2.0 + std::f32::consts::SQRT_2)).abs() < 1e-5);
+
+        grid.toggle_zone(Node { x: 3, y: 1 }, Zone::Avoid);
+        let weight = (STEP_COST + AVOID_ZONE_PENALTY) as f32 / STEP_COST as f32;
+        let weighted = grid.geometric_length(&path);
+        assert!((weighted - (1.0 + std::f32::consts::SQRT_2 + weight)).abs() < 1e-5);
+        assert_eq!(grid.geometric_length(&path[..1]), 0.0);
+    }
+
+    #[test]
+    fn mapf_metrics_count_unfinished_agents_at_the_current_tick() {
+        let mut agents = vec![
+            agent_on_path(0, &[(0, 0), (1, 0), (2, 0)]),
+            agent_on_path(1, &[(2, 0), (1, 0), (0, 0)]),
+            agent_on_path(2, &[(5, 5), (5, 6)]),
+        ];
+        agents[2].finished = true;
+        agents[2].arrival_tick = Some(3);
+
+        let mut stats = Statistics::new();
+        mapf_metrics(&mut stats, &agents, 7);
+        assert_eq!(stats.makespan, 7);
+        assert_eq!(stats.sum_of_costs, 7 + 7 + 3);
+        assert_eq!(stats.unresolved_conflicts, 1);
+    }
+
+    #[test]
+    fn arrivals_are_stamped_by_the_tick_and_cleared_by_replanning() {
+        let mut state = default_state();
+        let mut agents = vec![Agent::new(
+            0,
+            Node { x: 0, y: 0 },
+            Some(Node { x: 3, y: 0 }),
+        )];
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let mut detector = CollisionDetector::new(Box::new(PathCollisionStrategy));
+
+        for _ in 0..2 {
+            plan_all_agents(&mut state, &mut agents, &mut history, &mut stats);
+            assert!(!agents[0].finished);
+            assert_eq!(agents[0].arrival_tick, None);
+            // Stepping tick by tick, as W does, stamps the arrival without any extra bookkeeping.
+            while !agents[0].finished {
+                simulation_tick(&mut agents, &mut state, &mut detector, &mut stats);
+            }
+            assert_eq!(agents[0].arrival_tick, Some(3));
+        }
+        mapf_metrics(&mut stats, &agents, state.grid.tick + 5);
+        assert_eq!(stats.makespan, 3);
+        assert_eq!(stats.sum_of_costs, 3);
+    }
+
+    #[test]
+    fn each_run_summary_counts_only_its_own_run() {
+        let mut state = default_state();
+        let mut agents = vec![Agent::new(
+            0,
+            Node { x: 0, y: 0 },
+            Some(Node { x: 3, y: 0 }),
+        )];
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let mut detector = CollisionDetector::new(Box::new(PathCollisionStrategy));
+
+        for _ in 0..2 {
+            plan_all_agents(&mut state, &mut agents, &mut history, &mut stats);
+            while !agents[0].finished {
+                simulation_tick(&mut agents, &mut state, &mut detector, &mut stats);
+            }
+        }
+        plan_all_agents(&mut state, &mut agents, &mut history, &mut stats);
+
+        let runs: Vec<&Statistics> = state.finished_runs[1..].iter().map(|r| &r.stats).collect();
+        assert_eq!(runs.len(), 2);
+        for run in runs {
+            assert_eq!(run.reached_goal_count, 1);
+            assert_eq!(run.moves, 3);
+            assert_eq!(run.total_path_length, 4);
+        }
+        assert_eq!(stats.reached_goal_count, 0);
+    }
+
+    #[test]
+    fn new_wall_replans_only_agents_crossing_it() {
+        let mut grid = Grid::new();
+        let mut agents = vec![
+            agent_on_path(0, &[(0, 0), (1, 0), (2, 0), (3, 0)]),
+            agent_on_path(1, &[(0, 5), (1, 5), (2, 5)]),
+        ];
+        let wall = Node { x: 2, y: 0 };
+        grid.walls.insert(wall);
+
+        let replanned = replan_for_wall(
+            &mut agents,
+            wall,
+            true,
+            &grid,
+            &OrthogonalMovement,
+            &RerouteSettings::new(),
+        );
+        assert_eq!(replanned, 1);
+        assert!(!agents[0].path.as_ref().unwrap().contains(&wall));
+        assert_eq!(agents[1].path.as_ref().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn new_wall_under_a_wide_footprint_replans_it() {
+        let mut grid = Grid::new();
+        let mut agent = agent_on_path(0, &[(0, 7), (1, 7), (2, 7), (3, 7), (4, 7)]);
+        agent.footprint = Footprint::Square;
+        let mut agents = vec![agent];
+        // Only the lower row of the 2x2 footprint runs over this cell.
+        let wall = Node { x: 2, y: 8 };
+        assert!(agents[0].path_crosses(wall));
+        grid.walls.insert(wall);
+
+        let replanned = replan_for_wall(
+            &mut agents,
+            wall,
+            true,
+            &grid,
+            &OrthogonalMovement,
+            &RerouteSettings::new(),
+        );
+        assert_eq!(replanned, 1);
+        assert!(!agents[0].path_crosses(wall));
+    }
+
+    #[test]
+    fn spawn_pair_avoids_walls_used_cells_and_short_trips() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let walls: HashSet<Node> = (0..ROWS as i32).map(|y| Node { x: 0, y }).collect();
+        let used: HashSet<Node> = (0..ROWS as i32).map(|y| Node { x: 1, y }).collect();
+        for _ in 0..100 {
+            let (start, goal) = spawn_pair(&mut rng, &walls, &used, 10).unwrap();
+            for cell in [start, goal] {
+                assert!(!walls.contains(&cell) && !used.contains(&cell));
+            }
+            assert!((start.x - goal.x).abs() + (start.y - goal.y).abs() >= 10);
+        }
+        let far = (COLUMNS + ROWS) as i32;
+        assert_eq!(spawn_pair(&mut rng, &walls, &used, far), None);
+    }
+
+    #[test]
+    fn erased_wall_replans_detours_next_to_it() {
+        let grid = Grid::new();
+        let mut agents = vec![
+            agent_on_path(0, &[(0, 0), (0, 1), (1, 1), (2, 1), (2, 0)]),
+            agent_on_path(1, &[(0, 9), (1, 9)]),
+        ];
+        let replanned = replan_for_wall(
+            &mut agents,
+            Node { x: 1, y: 0 },
+            false,
+            &grid,
+            &OrthogonalMovement,
+            &RerouteSettings::new(),
+        );
+        assert_eq!(replanned, 1);
+        assert_eq!(agents[0].path.as_ref().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reachability_stops_at_walls_and_one_way_edges() {
+        let mut grid = Grid::new();
+        for y in 0..ROWS as i32 {
+            grid.walls.insert(Node { x: 5, y });
+        }
+        let reachable = reachable_cells(Node { x: 0, y: 0 }, &grid, &OrthogonalMovement);
+        assert_eq!(reachable.len(), 5 * ROWS);
+        assert!(!reachable.contains(&Node { x: 6, y: 0 }));
+
+        // Only way out of the corner is the one-way edge pointing back into it.
+        grid.walls.insert(Node { x: 0, y: 1 });
+        grid.toggle_one_way(Node { x: 1, y: 0 }, Node { x: 0, y: 0 });
+        let reachable = reachable_cells(Node { x: 0, y: 0 }, &grid, &OrthogonalMovement);
+        assert_eq!(reachable, HashSet::from([Node { x: 0, y: 0 }]));
+    }
+
+    #[test]
+    fn a_star_takes_a_cheaper_prefer_lane_detour() {
+        let mut grid = Grid::new();
+        for x in 0..=6 {
+            grid.toggle_zone(Node { x, y: 1 }, Zone::Prefer);
+        }
+        let (start, goal) = (Node { x: 0, y: 0 }, Node { x: 6, y: 0 });
+        let path = a_star(start, goal, &grid, &OrthogonalMovement).unwrap();
+        let cost: i32 = path[1..].iter().map(|&n| grid.step_cost(n)).sum();
+        assert_eq!(cost, 18);
+        assert!(path.contains(&Node { x: 3, y: 1 }));
+    }
+
+    #[test]
+    fn battery_plans_detour_through_a_charger_and_remember_failures() {
+        let mut grid = Grid::new();
+        let charger = Node { x: 2, y: 1 };
+        grid.chargers.insert(charger);
+        let row: Vec<(i32, i32)> = (0..=8).map(|x| (x, 0)).collect();
+        let mut agents = vec![agent_on_path(0, &row)];
+        agents[0].battery = 4;
+        let mut failures = BatteryFailures::default();
+        let mut revision = 0;
+
+        enforce_battery_plans(
+            &mut agents,
+            &grid,
+            &OrthogonalMovement,
+            &mut failures,
+            revision,
+        );
+        let path = agents[0].path.clone().unwrap();
+        assert!(path.contains(&charger));
+        assert_eq!(path.last(), Some(&Node { x: 8, y: 0 }));
+        assert!(path_is_feasible(&path, 4, &grid));
+        assert!(failures.failed.is_empty());
+
+        // Out of range with no charger: the failure is kept until the layout revision moves on.
+        grid.chargers.clear();
+        revision += 1;
+        agents[0].path = Some(row.iter().map(|&(x, y)| Node { x, y }).collect());
+        agents[0].path_index = 0;
+        enforce_battery_plans(
+            &mut agents,
+            &grid,
+            &OrthogonalMovement,
+            &mut failures,
+            revision,
+        );
+        assert!(failures.known(&agents[0], Node { x: 8, y: 0 }));
+        grid.chargers.insert(charger);
+        enforce_battery_plans(
+            &mut agents,
+            &grid,
+            &OrthogonalMovement,
+            &mut failures,
+            revision,
+        );
+        assert_eq!(failures.failed.len(), 1);
+        revision += 1;
+        enforce_battery_plans(
+            &mut agents,
+            &grid,
+            &OrthogonalMovement,
+            &mut failures,
+            revision,
+        );
+        assert!(failures.failed.is_empty());
+        assert!(agents[0].path.as_ref().unwrap().contains(&charger));
+    }
 
-        if collision_detector.strategy.name() == "ORCA" {
-            stats.method_name = "ORCA".to_owned();
-        } else if collision_detector.strategy.name() == "Grid-based" {
-            stats.method_name = "GRID".to_owned();
-        } else {
-            stats.method_name = "PATH".to_owned();
+    #[test]
+    fn distance_field_matches_a_star_costs() {
+        let mut grid = Grid::new();
+        for y in 0..ROWS as i32 - 1 {
+            grid.walls.insert(Node { x: 4, y });
+        }
+        grid.toggle_zone(Node { x: 2, y: 2 }, Zone::Avoid);
+        grid.toggle_one_way(Node { x: 1, y: 0 }, Node { x: 0, y: 0 });
+        let goal = Node { x: 0, y: 0 };
+        let field = distance_field(goal, &grid, &OrthogonalMovement);
+        assert!(!field.contains_key(&Node { x: 4, y: 0 }));
+        for start in [
+            Node { x: 9, y: 0 },
+            Node { x: 2, y: 3 },
+            Node { x: 1, y: 0 },
+        ] {
+            let path = a_star(start, goal, &grid, &OrthogonalMovement).unwrap();
+            let cost: i32 = path[1..].iter().map(|&n| grid.step_cost(n)).sum();
+            assert_eq!(field[&start], cost);
         }
-
-        println!("Switched to: {}", collision_detector.strategy.name());
     }
 
-    if window.is_key_pressed(Key::N, minifb::KeyRepeat::No) {
-        history.undo(&mut state.step_history);
-    }
-    if window.is_key_pressed(Key::B, minifb::KeyRepeat::No) {
-        history.execute(Box::new(DeleteCommand::new(1)), &mut state.step_history);
+    fn default_state() -> GameState {
+        let mut ctx = InitContext {
+            window: None,
+            buffer: None,
+            game_state: None,
+        };
+        let options = parse_cli_options(&[]).unwrap();
+        GameStateInitHandler {
+            options,
+            suffix: String::new(),
+        }
+        .initialize(&mut ctx)
+        .unwrap();
+        ctx.game_state.unwrap()
     }
 
-    if window.is_key_pressed(Key::W, minifb::KeyRepeat::Yes) {
-        if collision_detector.strategy.name() == "ORCA" {
-            let delta_time = 1.0 / 60.0;
-
-            let dodgy_agents: Vec<DodgyAgent> = agents
-                .iter()
-                .map(|a| DodgyAgent {
-                    position: a.position,
-                    velocity: a.velocity,
-                    radius: a.radius,
-                    avoidance_responsibility: 1.0,
-                })
-                .collect();
-
-            let mut new_velocities = Vec::with_capacity(agents.len());
-
-            for i in 0..agents.len() {
-                let mut neighbors: Vec<Cow<DodgyAgent>> = dodgy_agents
-                    .iter()
-                    .enumerate()
-                    .filter(|(j, other)| {
-                        *j != i && agents[i].position.distance(other.position) < NEIGHBOR_RADIUS
-                    })
-                    .map(|(_, agent)| Cow::Borrowed(agent))
-                    .collect();
+    #[test]
+    fn comparison_panes_step_in_lockstep_without_touching_the_scene() {
+        let mut state = default_state();
+        let agents = vec![
+            Agent::new(0, Node { x: 0, y: 0 }, Some(Node { x: 6, y: 6 })),
+            Agent::new(1, Node { x: 6, y: 0 }, Some(Node { x: 0, y: 6 })),
+        ];
+        let mut comparison = start_comparison(&state, &agents);
+        let [astar, kinematic] = &comparison.panes;
+        assert_eq!(astar.stats.total_path_length, 26);
+        assert!(kinematic.agents.iter().all(|a| a.path.is_some()));
 
-                neighbors.sort_by(|a, b| {
-                    agents[i]
-                        .position
-                        .distance(a.position)
-                        .partial_cmp(&agents[i].position.distance(b.position))
-                        .unwrap()
-                });
+        for _ in 0..40 {
+            step_comparison(&mut state, &mut comparison);
+        }
+        for pane in &comparison.panes {
+            assert_eq!(pane.tick_history.tick, 40);
+            assert!(pane.agents.iter().all(|a| a.finished));
+            assert!(pane.stats.makespan > 0);
+        }
+        assert_eq!(state.tick_history.tick, 0);
+        assert_eq!(state.reroute.turn_penalty, None);
+        assert!(agents.iter().all(|a| a.path.is_none()));
+        assert!(
+            parse_cli_options(&["--compare".into(), "astar,reserved".into()])
+                .is_ok_and(|o| o.compare == [ComparePlanner::AStar, ComparePlanner::Reserved])
+        );
+        assert!(parse_cli_options(&["--compare".into(), "astar".into()]).is_err());
+    }
 
-                let preferred_velocity = if let Some(goal) = agents[i].end_point {
-                    let goal_pos = goal.to_pixels();
-                    let to_goal = goal_pos - agents[i].position;
-                    let dist = to_goal.length();
+    #[test]
+    fn deliveries_are_counted_by_every_tick_path() {
+        let goal = Node { x: 3, y: 0 };
+        let mut state = default_state();
+        state.goal_set.insert(goal);
+        let mut agents = vec![
+            Agent::new(0, Node { x: 0, y: 0 }, Some(goal)),
+            Agent::new(1, Node { x: 6, y: 0 }, Some(goal)),
+        ];
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let failures = run_expectations(
+            &mut state,
+            &mut agents,
+            &mut history,
+            &mut stats,
+            20,
+            &AtomicBool::new(false),
+        );
+        assert!(failures.is_empty());
+        assert_eq!(stats.goal_deliveries, 2);
+        let orders: Vec<usize> = state.goal_deliveries.iter().map(|d| d.order).collect();
+        assert_eq!(orders, [1, 2]);
+    }
 
-                    if dist <= agents[i].radius {
-                        agents[i].position = goal_pos;
-                        Vec2::ZERO
-                    } else {
-                        to_goal.normalize_or_zero() * agents[i].max_speed
-                    }
-                } else {
-                    Vec2::ZERO
-                };
+    #[test]
+    fn a_cancelled_headless_run_stops_before_its_next_tick() {
+        let mut state = default_state();
+        let mut agents = vec![Agent::new(
+            0,
+            Node { x: 0, y: 0 },
+            Some(Node { x: 9, y: 0 }),
+        )];
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let cancel = AtomicBool::new(true);
+        run_expectations(
+            &mut state,
+            &mut agents,
+            &mut history,
+            &mut stats,
+            50,
+            &cancel,
+        );
+        assert_eq!(state.grid.tick, 0);
+        assert!(!agents[0].finished);
+    }
 
-                let orca_velocity = if neighbors.is_empty() {
-                    preferred_velocity
-                } else {
-                    dodgy_agents[i].compute_avoiding_velocity(
-                        &neighbors,
-                        &[],
-                        preferred_velocity,
-                        delta_time,
-                        agents[i].max_speed,
-                        &AvoidanceOptions {
-                            obstacle_margin: 0.4,
-                            time_horizon: 3.0,
-                            obstacle_time_horizon: 1.2,
-                        },
-                    )
-                };
+    #[test]
+    fn goal_deliveries_are_ordered_per_goal_and_windowed() {
+        let goals = HashSet::from([Node { x: 9, y: 9 }, Node { x: 0, y: 9 }]);
+        let arrive = |id: usize, goal: (i32, i32), tick: usize| {
+            let mut agent = Agent::new(
+                id,
+                Node { x: 5, y: 5 },
+                Some(Node {
+                    x: goal.0,
+                    y: goal.1,
+                }),
+            );
+            agent.arrival_tick = Some(tick);
+            agent
+        };
+        let mut log = Vec::new();
+        let agents = vec![
+            arrive(0, (9, 9), 10),
+            arrive(1, (0, 9), 10),
+            arrive(2, (4, 4), 10),
+        ];
+        record_goal_deliveries(&mut log, &goals, &agents, 10);
+        record_goal_deliveries(&mut log, &goals, &[arrive(3, (9, 9), 150)], 150);
+        assert_eq!(log.len(), 3);
+        assert_eq!((log[2].agent_id, log[2].order), (3, 2));
+        assert_eq!(log[1].order, 1);
+
+        assert_eq!(goal_throughput(&log[..2], None, 109), 2);
+        assert_eq!(goal_throughput(&log[..2], None, 110), 0);
+        assert_eq!(goal_throughput(&log, None, 150), 1);
+        assert_eq!(goal_throughput(&log, Some(Node { x: 0, y: 9 }), 150), 0);
+    }
 
-                let avoiding_velocity = if orca_velocity.length() >= 1.0 {
-                    orca_velocity
-                } else if let Some(other) = neighbors.first() {
-                    let rel_pos = other.position - agents[i].position;
-                    let rel_vel = preferred_velocity - other.velocity;
+    #[test]
+    fn score_weights_parse_and_sum_event_costs() {
+        assert_eq!(ScoreWeights::parse("1,5,100,2"), Some(ScoreWeights::new()));
+        assert_eq!(ScoreWeights::parse("1,5,100"), None);
+        assert_eq!(ScoreWeights::parse("1,x,100,2"), None);
+        let stats = Statistics {
+            moves: 40,
+            recalculations: 2,
+            collisions: 1,
+            waits: 5,
+            ..Statistics::new()
+        };
+        assert_eq!(
+            ScoreWeights::new().score(&stats),
+            40.0 + 10.0 + 100.0 + 10.0
+        );
+    }
 
-                    let forward = preferred_velocity.normalize_or_zero();
-                    let perp = Vec2::new(-forward.y, forward.x);
+    #[test]
+    fn saved_history_restores_undo_and_redo() {
+        let mut state = default_state();
+        let mut agents = Vec::new();
+        let mut history = CommandHistory::new();
+        let n = |x, y| Node { x, y };
+        let commands: Vec<Box<dyn Command>> = vec![
+            Box::new(WallCommand {
+                cell: n(4, 4),
+                place: true,
+            }),
+            Box::new(AddAgentCommand {
+                start: n(0, 0),
+                goal: Some(n(5, 0)),
+            }),
+            Box::new(AddAgentCommand {
+                start: n(0, 2),
+                goal: None,
+            }),
+            Box::new(SetGoalCommand {
+                agent: 1,
+                goal: n(5, 2),
+                previous: None,
+            }),
+        ];
+        for command in commands {
+            history.execute(command, &mut state, &mut agents);
+        }
+        agents[0].priority = MAX_PRIORITY;
+        agents[0].footprint = Footprint::Square;
+        agents[0].path = Some(vec![n(0, 0), n(1, 0)]);
+        let delete = DeleteAgentsCommand::new(&agents, &HashSet::from([0]));
+        history.execute(Box::new(delete), &mut state, &mut agents);
+        assert_eq!(agents.len(), 1);
+        assert!(history.undo(&mut state, &mut agents));
+        assert_eq!(agents.len(), 2);
+        assert_eq!(agents[0].priority, MAX_PRIORITY);
+        assert_eq!(agents[0].footprint, Footprint::Square);
+        assert_eq!(agents[0].path.as_deref(), Some(&[n(0, 0), n(1, 0)][..]));
+        assert_eq!(agents[1].id, 1);
+
+        let text = MapFile::from_state(&state, &agents, &history).to_text();
+        assert!(text.contains("undo set_goal 1 5 2 -\n"));
+        assert!(text.contains("redo delete_agents 0 0 0 5 0\n"));
+
+        let mut restored = default_state();
+        let mut restored_agents = Vec::new();
+        let mut restored_history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let map = MapFile::parse(&text).unwrap();
+        apply_map(
+            map,
+            None,
+            &mut restored,
+            &mut restored_agents,
+            &mut restored_history,
+            &mut stats,
+        );
+        assert!(restored_history.redo(&mut restored, &mut restored_agents));
+        assert_eq!(restored_agents.len(), 1);
+        assert_eq!(restored_agents[0].start_point, n(0, 2));
+        for _ in 0..5 {
+            assert!(restored_history.undo(&mut restored, &mut restored_agents));
+        }
+        assert!(restored_agents.is_empty());
+        assert!(!restored.grid.walls.contains(&n(4, 4)));
+        assert!(!restored_history.undo(&mut restored, &mut restored_agents));
 
-                    let cross = rel_pos.x * rel_vel.y - rel_pos.y * rel_vel.x;
-                    let side = cross.signum().max(1.0);
+        let lines = [
+            "wall 4 4 place".to_string(),
+            "set_goal 1 99 2 -".to_string(),
+        ];
+        assert!(CommandHistory::restore(&lines, &[]).is_err());
+        assert!(CommandHistory::restore(&lines[..1], &[]).is_ok());
+    }
 
-                    let dodge = (forward * 0.6) + (perp * side * 0.8);
-                    dodge.normalize_or_zero() * agents[i].max_speed * 0.9
-                } else {
-                    Vec2::ZERO
-                };
+    #[test]
+    fn imported_walls_spare_reserved_cells_and_undo_as_one_edit() {
+        let mut state = default_state();
+        let n = |x, y| Node { x, y };
+        let mut agents = vec![Agent::new(0, n(0, 0), Some(n(5, 0)))];
+        let mut history = CommandHistory::new();
+        state.grid.walls.insert(n(7, 7));
+        let walls = HashSet::from([n(0, 0), n(5, 0), n(3, 3)]);
+        let import = ImportWallsCommand::new(&walls, &state, &agents);
+        let line = import.to_line().unwrap();
+        assert_eq!(line, "import_walls place 3 3 erase 7 7");
+        history.execute(Box::new(import), &mut state, &mut agents);
+        assert_eq!(state.grid.walls, HashSet::from([n(3, 3)]));
+        assert_eq!(state.wall_edits, [(n(3, 3), true), (n(7, 7), false)]);
+
+        assert!(history.undo(&mut state, &mut agents));
+        assert_eq!(state.grid.walls, HashSet::from([n(7, 7)]));
+        let mut parsed = parse_command(&line).unwrap();
+        parsed.execute(&mut state, &mut agents);
+        assert_eq!(state.grid.walls, HashSet::from([n(3, 3)]));
+        assert!(parse_command("import_walls 3 3").is_err());
+    }
 
-                new_velocities.push(avoiding_velocity);
+    #[test]
+    fn headless_run_reports_unmet_expectations() {
+        let mut state = default_state();
+        let mut agents = Vec::new();
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        let map = MapFile::parse(
+            "map 1\nagent 0 0 5 0\nexpect max_collisions 0\nexpect all_agents_arrive_by 10\n\
+             expect path_length 0 <= 6\nexpect path_length 0 > 6\n",
+        )
+        .unwrap();
+        apply_map(map, None, &mut state, &mut agents, &mut history, &mut stats);
+        let failures = run_expectations(
+            &mut state,
+            &mut agents,
+            &mut history,
+            &mut stats,
+            50,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(failures, vec!["path_length 0 > 6: got 6".to_string()]);
+        assert!(MapFile::parse("map 1\nexpect path_length 0 ~ 6\n").is_err());
+    }
 
-                if let Some(goal) = agents[i].end_point {
-                    let goal_pos = goal.to_pixels();
-                    if !agents[i].finished
-                        && agents[i].position.distance(goal_pos) < agents[i].radius
-                    {
-                        agents[i].finished = true;
-                        stats.reached_goal_count += 1;
-                    }
-                }
-            }
+    #[test]
+    fn tall_demo_map_needs_the_tall_grid() {
+        let parsed = MapFile::parse(include_str!("../testdata/tall.map"));
+        if !cfg!(feature = "tall-grid") {
+            let Err(SimError::MapFormat { message, .. }) = parsed else {
+                panic!("tall.map loaded on a square grid");
+            };
+            assert_eq!(message, "cell 19 29 is outside the 20x20 grid");
+            return;
+        }
+        let mut state = default_state();
+        let mut agents = Vec::new();
+        let mut history = CommandHistory::new();
+        let mut stats = Statistics::new();
+        apply_map(
+            parsed.unwrap(),
+            None,
+            &mut state,
+            &mut agents,
+            &mut history,
+            &mut stats,
+        );
+        let failures = run_expectations(
+            &mut state,
+            &mut agents,
+            &mut history,
+            &mut stats,
+            200,
+            &AtomicBool::new(false),
+        );
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!(agents.len(), 6);
+    }
 
-            for (i, agent) in agents.iter_mut().enumerate() {
-                agent.last_position = agent.position;
+    #[test]
+    fn headless_batch_reports_each_map_in_order() {
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let pass = dir.join(format!("batch-pass-{}.map", pid));
+        let fail = dir.join(format!("batch-fail-{}.map", pid));
+        std::fs::write(
+            &pass,
+            "map 1\nagent 0 0 5 0\nexpect all_agents_arrive_by 50\n",
+        )
+        .unwrap();
+        std::fs::write(&fail, "map 1\nagent 0 0 5 0\nexpect path_length 0 > 6\n").unwrap();
+        let args: Vec<String> = [
+            "--headless",
+            &format!("{},missing-{}.map", fail.display(), pid),
+            "--headless",
+            &pass.display().to_string(),
+            "--jobs",
+            "2",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let options = parse_cli_options(&args).unwrap();
 
-                agent.velocity = new_velocities[i];
-                agent.position += agent.velocity * delta_time;
+        let outcomes = run_headless_batch(&options);
+        let paths: Vec<&String> = outcomes.iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, options.headless.iter().collect::<Vec<_>>());
+        let HeadlessOutcome::Finished(run) = &outcomes[0].1 else {
+            panic!("first map did not finish");
+        };
+        assert_eq!(run.failures, vec!["path_length 0 > 6: got 6".to_string()]);
+        assert!(matches!(outcomes[1].1, HeadlessOutcome::Error(_)));
+        assert!(outcomes[2].1.passed());
+
+        let markdown = report_text(&outcomes, true);
+        assert!(markdown.contains("1 of 3 maps met every expectation."));
+        assert!(markdown.contains(&format!("| missing-{}.map | error | - |", pid)));
+        assert!(markdown.contains("| passed | 1 | 1 | 5 | 5 | 0 | 0 | 0 |"));
+        assert_eq!(markdown.matches("<svg").count(), REPORT_CHARTS.len());
+        let html = report_text(&outcomes, false);
+        assert!(html.contains("<td>failed</td>"));
+        assert_eq!(html.matches("<rect").count(), 2 * REPORT_CHARTS.len());
+        std::fs::remove_file(pass).unwrap();
+        std::fs::remove_file(fail).unwrap();
+    }
 
-                let step_dist = agent.position.distance(agent.last_position);
-                stats.actual_distance += step_dist;
+    struct NullSink;
 
-                stats.total_steps += 1;
-            }
-        } else {
-            for agent in agents.iter_mut() {
-                agent.last_position = agent.position;
-                if let Some(path) = &agent.path {
-                    if agent.path_index + 1 < path.len() {
-                        agent.path_index += 1;
-                        agent.current_point = path[agent.path_index];
-                        agent.position = agent.current_point.to_pixels();
-                        agent.refresh_cache();
-                    }
-                }
-                let step_dist = agent.position.distance(agent.last_position);
+    impl StatsSink for NullSink {
+        fn record_sample(&mut self, _stats: &Statistics, _run: &ExperimentRun) {}
+        fn finish_run(&mut self, _finished: &FinishedRun) {}
+    }
 
-                stats.actual_distance += step_dist;
-                stats.total_steps += 1;
+    // Drives a window-less session with scripted input, one update per frame.
+    struct Harness {
+        session: Session,
+    }
 
-                if let Some(goal) = agent.end_point {
-                    let goal_pos = goal.to_pixels();
-                    if !agent.finished && agent.position.distance(goal_pos) < agent.radius {
-                        agent.finished = true;
-                        stats.reached_goal_count += 1;
-                    }
-                }
+    impl Harness {
+        fn new() -> Self {
+            Harness {
+                session: Session::new(
+                    None,
+                    vec![0; WIDTH * HEIGHT],
+                    default_state(),
+                    Box::new(NullSink),
+                ),
             }
         }
 
-        collision_detector.ignored_pairs.clear();
-    }
-
-    if window.is_key_pressed(Key::R, minifb::KeyRepeat::No) {
-        let mut rng = rand::rng();
-        let count = rng.random_range(3..=12);
-
-        stats.agents += count;
-        for _ in 0..count {
-            let id = agents.len();
-            let start = Node {
-                x: rng.random_range(0..COLUMNS) as i32,
-                y: rng.random_range(0..ROWS) as i32,
-            };
-            let end = Node {
-                x: rng.random_range(0..COLUMNS) as i32,
-                y: rng.random_range(0..ROWS) as i32,
-            };
-            agents.push(Agent::new(id, start, Some(end)));
+        fn frame(&mut self, input: InputState, ticks: u32) {
+            self.session.update(&input, ticks);
         }
-    }
 
-    if window.is_key_pressed(Key::A, minifb::KeyRepeat::No) {
-        state.step_history.clear();
-        history.history.clear();
+        fn press(&mut self, key: Key) {
+            self.frame(
+                InputState {
+                    pressed: vec![key],
+                    repeated: vec![key],
+                    ..InputState::default()
+                },
+                0,
+            );
+        }
 
-        let mut total_len = 0;
-        for agent in agents.iter_mut() {
-            let Some(goal) = agent.end_point else {
-                continue;
-            };
-            if let Some(path) = a_star(
-                agent.start_point,
-                goal,
-                &state.walls,
-                state.movement_strategy.as_ref(),
-            ) {
-                total_len += path.len();
-                agent.path = Some(path);
-                agent.current_point = agent.start_point;
-                agent.position = agent.start_point.to_pixels();
-                agent.path_index = 0;
-                agent.refresh_cache();
-            } else {
-                println!("No path found for agent {}", agent.id);
-            }
+        fn press_shifted(&mut self, key: Key) {
+            self.frame(
+                InputState {
+                    pressed: vec![key],
+                    repeated: vec![key],
+                    shift: true,
+                    ..InputState::default()
+                },
+                0,
+            );
         }
-        stats.total_path_length += total_len;
-    }
 
-    let is_pressed = window.get_mouse_down(MouseButton::Left);
-    if is_pressed && !state.was_pressed {
-        if let Some((mx, my)) = window.get_mouse_pos(minifb::MouseMode::Clamp) {
-            let cell = Node {
-                x: (mx as usize / (WIDTH / COLUMNS)) as i32,
-                y: (my as usize / (HEIGHT / ROWS)) as i32,
+        fn click(&mut self, x: i32, y: i32, button: MouseButton) {
+            let mouse = Some((
+                Node { x, y }.cell().center().x as f32,
+                Node { x, y }.cell().center().y as f32,
+            ));
+            let pressed = InputState {
+                mouse,
+                left: button == MouseButton::Left,
+                right: button == MouseButton::Right,
+                ..InputState::default()
             };
+            self.frame(pressed, 0);
+            self.frame(
+                InputState {
+                    mouse,
+                    ..InputState::default()
+                },
+                0,
+            );
+        }
 
-            match state.current_step {
-                Step::Obstacles => {
-                    state.walls.insert(cell);
-                }
-                Step::Start => {
-                    if !state.walls.contains(&cell) {
-                        let id = agents.len();
-                        agents.push(Agent::new(id, cell, None));
-                        state.current_step = Step::End;
-                    }
-                }
-                Step::End => {
-                    if !state.walls.contains(&cell) {
-                        let last = agents.last_mut().unwrap();
-                        last.end_point = Some(cell);
-                        last.refresh_cache();
-                        state.current_step = Step::Start;
-                    }
-                }
-            }
+        fn run(&mut self, ticks: u32) {
+            self.frame(InputState::default(), ticks);
         }
     }
-    state.was_pressed = is_pressed;
-}
-
-fn render(
-    buffer: &mut Vec<u32>,
-    state: &GameState,
-    agents: &[Agent],
-    draw_radius: bool,
-    is_orca: bool,
-) {
-    buffer.fill(BLACK);
-    draw_matrix(buffer);
 
-    for node in &state.walls {
-        draw(
-            buffer,
-            &DrawType::Square(SquareParams {
-                x: node.ux(),
-                y: node.uy(),
-                color: WHITE,
-            }),
+    #[test]
+    fn annotated_screenshots_carry_a_legend_strip() {
+        let mut state = default_state();
+        state.seed = Some(42);
+        let agents = vec![Agent::new(3, Node { x: 1, y: 1 }, None)];
+        let mut frame = vec![BLACK; WIDTH * HEIGHT];
+        frame[WIDTH + 2] = 0x00123456;
+        let plain = screenshot_image(&frame, None);
+        assert_eq!(plain.dimensions(), (WIDTH as u32, HEIGHT as u32));
+        assert_eq!(plain.get_pixel(2, 1).0, [0x12, 0x34, 0x56]);
+
+        let mut legend = vec![BLACK; WIDTH * HEIGHT];
+        draw_legend(&mut legend, &state, &agents, "PATH");
+        let annotated = screenshot_image(&frame, Some(&legend));
+        assert_eq!(
+            annotated.dimensions(),
+            (WIDTH as u32, (HEIGHT + LEGEND_HEIGHT) as u32)
+        );
+        let swatch_y = (HEIGHT + 8 + GLYPH_HEIGHT * 2 + 16 + 1) as u32;
+        let wall = state.theme.wall;
+        assert_eq!(
+            annotated.get_pixel(9, swatch_y).0,
+            [(wall >> 16) as u8, (wall >> 8) as u8, wall as u8]
         );
     }
 
-    for agent in agents {
-        if let Some(path) = &agent.path {
-            for w in path.windows(2) {
-                let (a, b) = (w[0], w[1]);
-                draw(
-                    buffer,
-                    &DrawType::Line(LineParams {
-                        x0: a.x * CELL_HEIGHT as i32 + (CELL_WIDTH / 2) as i32,
-                        y0: a.y * CELL_WIDTH as i32 + (CELL_HEIGHT / 2) as i32,
-                        x1: b.x * CELL_HEIGHT as i32 + (CELL_WIDTH / 2) as i32,
-                        y1: b.y * CELL_WIDTH as i32 + (CELL_HEIGHT / 2) as i32,
-                        color: WHITE,
-                    }),
-                );
-            }
+    #[test]
+    fn collisions_are_detected_on_every_tick_of_a_frame() {
+        let crossing = || {
+            vec![
+                agent_on_path(0, &[(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)]),
+                agent_on_path(1, &[(2, 0), (2, 1), (2, 2), (2, 3), (2, 4)]),
+            ]
+        };
+        let mut batched = Harness::new();
+        batched.session.agents = crossing();
+        batched.run(6);
+        let mut stepped = Harness::new();
+        stepped.session.agents = crossing();
+        for _ in 0..6 {
+            stepped.run(1);
         }
+        let counts = |h: &Harness| {
+            let stats = &h.session.stats;
+            (stats.collisions, stats.detections, stats.recalculations)
+        };
+        assert!(counts(&stepped).1 > 0);
+        assert_eq!(counts(&batched), counts(&stepped));
+    }
 
-        if let Some(goal) = agent.end_point {
-            draw(
-                buffer,
-                &DrawType::Circle(CircleParams {
-                    x: goal.ux(),
-                    y: goal.uy(),
-                    radius: 10,
-                    color: ORANGE,
-                }),
-            );
+    #[test]
+    fn finished_runs_carry_only_their_own_events() {
+        let mut h = Harness::new();
+        h.session.agents = vec![
+            agent_on_path(0, &[(0, 2), (1, 2), (2, 2), (3, 2), (4, 2)]),
+            agent_on_path(1, &[(2, 0), (2, 1), (2, 2), (2, 3), (2, 4)]),
+        ];
+        for _ in 0..6 {
+            h.press(Key::W);
         }
+        h.press(Key::Home);
+        h.press(Key::Home);
+        let runs = &h.session.state.finished_runs;
+        assert_eq!(runs.len(), 2);
+        assert!(!runs[0].events.is_empty());
+        assert!(runs[1].events.is_empty());
+        assert!(h.session.state.recorder.events.borrow().is_empty());
+    }
 
-        if draw_radius {
-            for &node in &agent.collision_radius {
-                draw(
-                    buffer,
-                    &DrawType::Circle(CircleParams {
-                        x: node.ux(),
-                        y: node.uy(),
-                        radius: 10,
-                        color: PALE_RED,
-                    }),
-                );
-            }
-        } else if !is_orca {
-            for &node in &agent.forward_path {
-                draw(
-                    buffer,
-                    &DrawType::Circle(CircleParams {
-                        x: node.ux(),
-                        y: node.uy(),
-                        radius: 10,
-                        color: LIGHT_BLUE,
-                    }),
-                );
-            }
-        }
+    #[test]
+    fn manual_steps_are_recorded_like_clock_ticks() {
+        let mut h = Harness::new();
+        h.session.agents = vec![agent_on_path(0, &[(0, 0), (1, 0), (2, 0)])];
+        h.press(Key::W);
+        h.press(Key::W);
+        let rows = h.session.state.recorder.trajectories.borrow();
+        let cells: Vec<(usize, Node)> = rows.iter().map(|r| (r.tick, r.cell)).collect();
+        assert_eq!(cells, [(1, Node { x: 1, y: 0 }), (2, Node { x: 2, y: 0 })]);
+    }
 
-        let (grid_x, grid_y) = if is_orca {
-            (
-                (agent.position.x / CELL_WIDTH as f32) as usize,
-                (agent.position.y / CELL_HEIGHT as f32) as usize,
-            )
-        } else {
-            (agent.current_point.ux(), agent.current_point.uy())
+    #[test]
+    fn rulers_label_the_edges_and_the_hovered_cell() {
+        let mut h = Harness::new();
+        let labels = |h: &Harness| -> Vec<String> {
+            scene(&h.session.state, &[], false, false)
+                .items
+                .into_iter()
+                .filter_map(|(_, item)| match item {
+                    DrawType::Text(t) => Some(t.text),
+                    _ => None,
+                })
+                .collect()
         };
+        let hover = InputState {
+            mouse: Some((12.5 * CELL_WIDTH as f32, 7.5 * CELL_HEIGHT as f32)),
+            ..InputState::default()
+        };
+        h.frame(hover.clone(), 0);
+        assert!(labels(&h).is_empty());
+        h.press_shifted(Key::G);
+        assert!(h.session.state.current_step == Step::Obstacles);
+        h.frame(hover, 0);
+        let shown = labels(&h);
+        assert_eq!(shown.len(), COLUMNS + ROWS + 1);
+        assert_eq!(shown.last().unwrap(), "(12, 7)");
+        assert_eq!(shown[COLUMNS - 1], (COLUMNS - 1).to_string());
+    }
 
-        if is_orca {
-            let px = agent.position.x.max(0.0).min((WIDTH - 1) as f32) as usize;
-            let py = agent.position.y.max(0.0).min((HEIGHT - 1) as f32) as usize;
-            draw_circle_at_pixels(buffer, px, py, agent.radius as usize, RED);
-        } else {
-            draw(
-                buffer,
-                &DrawType::Circle(CircleParams {
-                    x: agent.current_point.ux(),
-                    y: agent.current_point.uy(),
-                    radius: 10,
-                    color: RED,
-                }),
-            );
+    #[test]
+    fn scripted_session_places_plans_and_delivers_an_agent() {
+        let mut h = Harness::new();
+        for y in 0..4 {
+            h.click(3, y, MouseButton::Left);
         }
+        h.press(Key::Space);
+        h.click(0, 0, MouseButton::Left);
+        h.click(6, 0, MouseButton::Left);
+        h.press(Key::A);
+        h.run(30);
+
+        let agent = &h.session.agents[0];
+        assert_eq!(agent.end_point, Some(Node { x: 6, y: 0 }));
+        assert!(agent.finished);
+        let path = agent.path.as_ref().unwrap();
+        assert!(path.iter().all(|n| !h.session.state.grid.walls.contains(n)));
+        assert_eq!(h.session.stats.reached_goal_count, 1);
+        assert_eq!(h.session.state.tick_history.tick, 30);
     }
-}
 
-fn game_loop(window: &mut Window, buffer: &mut Vec<u32>, state: &mut GameState) {
-    let mut stats = Statistics::new();
-    let mut history = CommandHistory::new();
-    let mut agents: Vec<Agent> = Vec::new();
-    let mut last_log = Instant::now();
+    #[test]
+    fn leftover_autosave_is_offered_and_restored_on_shift_f4() {
+        let mut h = Harness::new();
+        h.session.state.stats_suffix = format!("-test-{}", std::process::id());
+        let suffix = h.session.state.stats_suffix.clone();
+        std::fs::write(autosave_path(&suffix), "map 1\nwall 3 4\nagent 0 0 5 0\n").unwrap();
+
+        h.session.offer_recovery();
+        assert!(!std::path::Path::new(&autosave_path(&suffix)).exists());
+        h.press_shifted(Key::F4);
+        assert!(h.session.state.pending_scenario.is_none());
+        assert!(h.session.state.grid.walls.contains(&Node { x: 3, y: 4 }));
+        assert_eq!(h.session.agents.len(), 1);
+        assert_eq!(h.session.state.recovery, None);
+
+        h.session.offer_recovery();
+        assert_eq!(h.session.state.recovery, None);
+        std::fs::remove_file(recovery_path(&suffix)).unwrap();
+    }
 
-    let mut detector = CollisionDetector::new(Box::new(PathCollisionStrategy));
-    let logger = Rc::new(CollisionLogger);
-    let assistant = Rc::new(CollisionAssistant::new());
-    detector.register_observer(logger);
-    detector.register_observer(assistant.clone());
+    #[test]
+    fn measure_mode_reports_grid_and_path_distances() {
+        let mut h = Harness::new();
+        for y in 0..4 {
+            h.click(2, y, MouseButton::Left);
+        }
+        h.press_shifted(Key::F3);
+        h.click(0, 0, MouseButton::Left);
+        assert!(h.session.state.measurement.is_none());
+        h.click(4, 0, MouseButton::Left);
+        let m = h.session.state.measurement.as_ref().unwrap();
+        assert_eq!((m.from, m.to), (Node { x: 0, y: 0 }, Node { x: 4, y: 0 }));
+        assert_eq!(
+            m.text(),
+            "MANHATTAN 4  CHEBYSHEV 4  EUCLID 4.00  PATH 12.00"
+        );
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let is_orca = detector.strategy.name() == "ORCA";
-        let draw_radius = detector.strategy.name() == "Grid-based";
+        h.click(0, 3, MouseButton::Left);
+        h.click(2, 1, MouseButton::Left);
+        let m = h.session.state.measurement.as_ref().unwrap();
+        assert_eq!(m.text(), "MANHATTAN 4  CHEBYSHEV 2  EUCLID 2.83  PATH -");
+    }
 
-        handle_input(
-            window,
-            state,
-            &mut agents,
-            &mut history,
-            &mut detector,
-            &mut stats,
+    #[test]
+    fn snapshot_slots_restore_agents_mid_run() {
+        let mut h = Harness::new();
+        h.press(Key::Space);
+        h.click(0, 0, MouseButton::Left);
+        h.click(19, 0, MouseButton::Left);
+        h.press(Key::A);
+        h.run(5);
+        let saved = h.session.agents[0].current_point;
+        assert_ne!(saved, Node { x: 0, y: 0 });
+        h.frame(
+            InputState {
+                pressed: vec![Key::Key2],
+                shift: true,
+                ..InputState::default()
+            },
+            0,
         );
-        render(buffer, state, &agents, draw_radius, is_orca);
-
-        if !is_orca {
-            detector.check_agents(&agents, &mut stats);
-            if assistant.has_requests() {
-                let requests = assistant.take_requests();
-                process_reroute_requests(
-                    &mut agents,
-                    &requests,
-                    &state.walls,
-                    state.movement_strategy.as_ref(),
-                    &mut stats,
-                );
-            }
-        } else {
-            detector.check_agents(&agents, &mut stats);
+        h.run(40);
+        assert!(h.session.agents[0].finished);
+
+        for _ in 0..2 {
+            h.press(Key::Key2);
+            let agent = &h.session.agents[0];
+            assert_eq!(agent.current_point, saved);
+            assert!(!agent.finished);
+            assert_eq!(h.session.state.tick_history.tick, 5);
+            assert_eq!(h.session.stats.reached_goal_count, 0);
+            h.run(40);
         }
+        h.press(Key::Key3);
+        assert!(h.session.agents[0].finished);
+    }
 
-        if last_log.elapsed() >= Duration::from_secs(1) {
-            let _ = save_statistics(&stats);
-            last_log = Instant::now();
-        }
+    #[test]
+    fn scripted_session_erases_walls_and_resets_on_double_backspace() {
+        let mut h = Harness::new();
+        h.click(2, 2, MouseButton::Left);
+        h.click(4, 4, MouseButton::Left);
+        h.click(2, 2, MouseButton::Right);
+        assert_eq!(
+            h.session.state.grid.walls,
+            HashSet::from([Node { x: 4, y: 4 }])
+        );
 
-        window.update_with_buffer(buffer, WIDTH, HEIGHT).unwrap();
+        h.press(Key::Backspace);
+        assert_eq!(h.session.state.grid.walls.len(), 1);
+        h.press(Key::Backspace);
+        assert!(h.session.state.grid.walls.is_empty());
     }
-}
 
-fn main() {
-    let mut handlers: Vec<Box<dyn InitHandler>> = vec![
-        Box::new(WindowInitHandler),
-        Box::new(BufferInitHandler),
-        Box::new(GameStateInitHandler),
-    ];
+    #[test]
+    fn macro_frames_round_trip_and_replay_at_speed() {
+        let frame = MacroFrame {
+            at_ms: 250,
+            ticks: 3,
+            input: InputState {
+                pressed: vec![Key::A, Key::Key9, Key::Slash],
+                repeated: vec![Key::Equal],
+                mouse: Some((125.5, 40.0)),
+                left: true,
+                right: false,
+                shift: true,
+            },
+        };
+        assert_eq!(MacroFrame::parse(&frame.to_line()), Some(frame.clone()));
+        let idle = MacroFrame {
+            at_ms: 1000,
+            ticks: 0,
+            input: InputState::default(),
+        };
+        assert_eq!(MacroFrame::parse(&idle.to_line()), Some(idle.clone()));
 
-    let mut ctx = InitContext {
-        window: None,
-        buffer: None,
-        game_state: None,
-    };
+        let text = format!(
+            "macro 2\nseed 42\n{}\n{}\n",
+            frame.to_line(),
+            idle.to_line()
+        );
+        let (mut player, seed) = MacroPlayer::parse(&text, 4.0).unwrap();
+        assert_eq!(seed, Some(42));
+        let start = player.started;
+        assert_eq!(player.next_due(start + Duration::from_millis(50)), None);
+        assert_eq!(
+            player.next_due(start + Duration::from_millis(70)),
+            Some(frame)
+        );
+        assert_eq!(player.next_due(start + Duration::from_millis(200)), None);
+        assert_eq!(
+            player.next_due(start + Duration::from_millis(250)),
+            Some(idle)
+        );
+        assert!(player.is_done());
+        assert!(MacroPlayer::parse("macro 2\nseed -\n1 0 - - 0 0 0 Nope -\n", 1.0).is_err());
+    }
 
-    for handler in handlers.iter_mut() {
-        if let Err(e) = handler.initialize(&mut ctx) {
-            eprintln!("Initialization failed: {}", e);
-            return;
+    #[test]
+    fn toast_board_keeps_the_newest_messages() {
+        let board = ToastBoard::new();
+        for i in 0..MAX_TOASTS + 2 {
+            board.push(format!("agent {} — blocked", i), ORANGE);
         }
+        let toasts = board.toasts.borrow();
+        assert_eq!(toasts.len(), MAX_TOASTS);
+        assert_eq!(toasts[0].text, "agent 2 - blocked");
     }
 
-    let mut window = ctx.window.unwrap();
-    let mut buffer = ctx.buffer.unwrap();
-    let mut game_state = ctx.game_state.unwrap();
+    #[test]
+    fn walls_cannot_cover_agents_or_goals() {
+        let mut agent = agent_on_path(0, &[(0, 0), (1, 0), (2, 0)]);
+        agent.current_point = Node { x: 1, y: 0 };
+        agent.end_point = Some(Node { x: 2, y: 0 });
+        let agents = vec![agent];
+        let goals: HashSet<Node> = [Node { x: 9, y: 9 }].into_iter().collect();
+
+        for (x, y) in [(0, 0), (1, 0), (2, 0), (9, 9)] {
+            assert!(cell_reserved(Node { x, y }, &agents, &goals));
+        }
+        assert!(!cell_reserved(Node { x: 3, y: 0 }, &agents, &goals));
+
+        // A 2x2 agent covers the cells right and below of each of those anchors as well.
+        let mut agents = agents;
+        agents[0].footprint = Footprint::Square;
+        assert!(cell_reserved(Node { x: 3, y: 1 }, &agents, &goals));
+        assert!(!cell_reserved(Node { x: 4, y: 0 }, &agents, &goals));
+    }
 
-    game_loop(&mut window, &mut buffer, &mut game_state);
+    #[test]
+    fn avoid_disk_is_clipped_and_skips_occupied_cells() {
+        let occupied: HashSet<Node> = [Node { x: 1, y: 0 }].into_iter().collect();
+        let disk = avoid_disk(Node { x: 0, y: 0 }, 2, &occupied);
+        assert_eq!(disk.len(), 8);
+        assert!(!disk.contains(&Node { x: 1, y: 0 }));
+        assert!(disk.contains(&Node { x: 2, y: 2 }));
+        assert!(disk.iter().all(|&n| in_bounds(n)));
+
+        let agents = vec![
+            agent_on_path(0, &[(5, 5), (6, 5)]),
+            agent_on_path(1, &[(7, 5), (6, 5)]),
+        ];
+        for (_, avoid, _) in compute_avoidance_plan(&agents, &[0, 1], Node { x: 6, y: 5 }, 1) {
+            assert_eq!(avoid.len(), 7);
+            assert!(!avoid.contains(&Node { x: 5, y: 5 }));
+            assert!(!avoid.contains(&Node { x: 7, y: 5 }));
+        }
+    }
 }
@@ -1,11 +1,14 @@
-use chrono::prelude::*;
-use csv::Writer;
+use geometry::{
+    Segment, boundary_distance, convex_hull, minkowski_sum, point_in_polygon, polygon_distance,
+};
 use minifb::{Key, MouseButton, Window, WindowOptions};
 use rand::Rng;
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::time::{Duration, Instant};
+use telemetry::{Kind, Metric, Registry, TimeUnit};
+
+// Shapes keep their real, signed coordinates; only rasterization clips to the canvas.
+type Point = geometry::Point<i32>;
+type Polygon = geometry::Polygon<i32>;
 
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 1000;
@@ -13,56 +16,67 @@ const WHITE: u32 = 0x00FFFFFF;
 const RED: u32 = 0x00FF0000;
 const BLACK: u32 = 0x00080808;
 const ORANGE: u32 = 0x00FF963C;
-
-type Point = (usize, usize);
-type Polygon = Vec<Point>;
-
-struct Statistics {
-    obstacles_amount: usize,
-    points_amount: usize,
-    time_to_finish_in_micros: usize,
+const BLUE: u32 = 0x003C78FF;
+const GREEN: u32 = 0x0028B450;
+const VERTEX_PICK_RADIUS: f32 = 8.0;
+const ROBOT_CENTER: Point = (220, 220);
+const CIRCLE_SIDES: usize = 16;
+const CIRCLE_RADIUS: f32 = 20.0;
+const SLICE_STEPS_DEG: [usize; 5] = [5, 15, 30, 45, 90];
+const PROXIMITY_WARNING: f32 = 10.0;
+
+enum Drag {
+    Polygon { index: usize, last: Point },
+    Vertex { index: usize, vertex: usize },
 }
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            obstacles_amount: 0,
-            points_amount: 0,
-            time_to_finish_in_micros: 0,
+impl Drag {
+    fn index(&self) -> usize {
+        match *self {
+            Drag::Polygon { index, .. } | Drag::Vertex { index, .. } => index,
         }
     }
 }
 
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
-
-    let mut wtr = Writer::from_writer(file);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stat {
+    ObstaclesAmount,
+    PointsAmount,
+    TimeToFinish,
+}
 
-    if !file_exists {
-        wtr.write_record(&[
-            "timestamp",
-            "obstacles_amount",
-            "points_amount",
-            "time_to_finish_in_micros",
-        ])?;
+impl Metric for Stat {
+    const ALL: &'static [Self] = &[
+        Stat::ObstaclesAmount,
+        Stat::PointsAmount,
+        Stat::TimeToFinish,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stat::ObstaclesAmount => "obstacles_amount",
+            Stat::PointsAmount => "points_amount",
+            Stat::TimeToFinish => "time_to_finish_in_micros",
+        }
     }
 
-    wtr.write_record(&[
-        Local::now().to_string(),
-        stats.obstacles_amount.to_string(),
-        stats.points_amount.to_string(),
-        stats.time_to_finish_in_micros.to_string(),
-    ])?;
+    fn kind(self) -> Kind {
+        match self {
+            Stat::ObstaclesAmount | Stat::PointsAmount => Kind::Gauge,
+            Stat::TimeToFinish => Kind::Timer(TimeUnit::Micros),
+        }
+    }
+}
 
-    wtr.flush()?;
-    Ok(())
+fn new_statistics() -> Registry<Stat> {
+    Registry::builder()
+        .csv("stats.csv")
+        .interval(Duration::from_secs(1))
+        .build()
 }
 
-fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize, color: u32) {
-    let (mut x0, mut y0, x1, y1) = (x0 as i32, y0 as i32, x1 as i32, y1 as i32);
+fn draw_line(buffer: &mut [u32], x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+    let (mut x0, mut y0) = (x0, y0);
     let dx = (x1 - x0).abs();
     let sx = if x0 < x1 { 1 } else { -1 };
     let dy = -(y1 - y0).abs();
@@ -88,36 +102,43 @@ fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize, col
 }
 
 fn fill_polygon(buffer: &mut [u32], polygon: &Polygon, color: u32) {
-    let min_y = polygon.iter().map(|&(_, y)| y).min().unwrap_or(0);
-    let max_y = polygon.iter().map(|&(_, y)| y).max().unwrap_or(HEIGHT - 1);
+    if polygon.len() < 3 {
+        return;
+    }
+    let min_y = polygon.iter().map(|&(_, y)| y).min().unwrap().max(0);
+    let max_y = polygon
+        .iter()
+        .map(|&(_, y)| y)
+        .max()
+        .unwrap()
+        .min(HEIGHT as i32 - 1);
+    let mut intersections: Vec<f32> = Vec::new();
 
     for y in min_y..=max_y {
-        let mut intersections = Vec::new();
+        // Sampling at pixel centres keeps the scanline off every vertex, so each
+        // edge crossing is counted exactly once and the even-odd pairing holds even
+        // for self-intersecting outlines.
+        let yc = y as f32 + 0.5;
+        intersections.clear();
 
         for i in 0..polygon.len() {
-            let (x0, y0) = polygon[i];
+            let (x0, y0) = (polygon[i].0 as f32, polygon[i].1 as f32);
             let (x1, y1) = polygon[(i + 1) % polygon.len()];
+            let (x1, y1) = (x1 as f32, y1 as f32);
 
-            if (y0 <= y && y1 > y) || (y1 <= y && y0 > y) {
-                let dy = y1 as f32 - y0 as f32;
-                let dx = x1 as f32 - x0 as f32;
-                let t = (y as f32 - y0 as f32) / dy;
-                let x_int = x0 as f32 + t * dx;
-                intersections.push(x_int as usize);
+            if (y0 <= yc) != (y1 <= yc) {
+                intersections.push(x0 + (yc - y0) / (y1 - y0) * (x1 - x0));
             }
         }
 
-        intersections.sort_unstable();
-        for pair in intersections.chunks(2) {
-            if pair.len() == 2 {
-                let (x_start, x_end) = (pair[0], pair[1]);
-                if x_end > x_start && y < HEIGHT {
-                    let start = y * WIDTH + x_start.min(WIDTH - 1);
-                    let end = y * WIDTH + x_end.min(WIDTH - 1);
-                    for px in start..end {
-                        buffer[px] = color;
-                    }
-                }
+        intersections.sort_unstable_by(f32::total_cmp);
+        let row = y as usize * WIDTH;
+        for pair in intersections.chunks_exact(2) {
+            // Pixels whose centres lie inside the span, clipped to the viewport.
+            let start = (pair[0] - 0.5).ceil().clamp(0.0, WIDTH as f32) as usize;
+            let end = (pair[1] - 0.5).ceil().clamp(0.0, WIDTH as f32) as usize;
+            if start < end {
+                buffer[row + start..row + end].fill(color);
             }
         }
     }
@@ -133,68 +154,7 @@ fn draw_polygon(buffer: &mut [u32], polygon: &Polygon, color: u32) {
     fill_polygon(buffer, polygon, color);
 }
 
-fn convex_hull(points: &Vec<Point>) -> Polygon {
-    let mut pts = points.clone();
-    pts.sort_by_key(|&(x, y)| (x, y));
-
-    fn cross(o: Point, a: Point, b: Point) -> isize {
-        (a.0 as isize - o.0 as isize) * (b.1 as isize - o.1 as isize)
-            - (a.1 as isize - o.1 as isize) * (b.0 as isize - o.0 as isize)
-    }
-
-    let mut lower = Vec::new();
-    for &p in &pts {
-        while lower.len() >= 2 && cross(lower[lower.len() - 2], lower[lower.len() - 1], p) <= 0 {
-            lower.pop();
-        }
-        lower.push(p);
-    }
-
-    let mut upper = Vec::new();
-    for &p in pts.iter().rev() {
-        while upper.len() >= 2 && cross(upper[upper.len() - 2], upper[upper.len() - 1], p) <= 0 {
-            upper.pop();
-        }
-        upper.push(p);
-    }
-
-    lower.pop();
-    upper.pop();
-    lower.extend(upper);
-    lower
-}
-
-fn minkowski_sum(a: &Polygon, b: &Polygon, polygons_expanded: &mut Vec<Polygon>) {
-    let robot_center_x = b.iter().map(|&(x, _)| x as isize).sum::<isize>() / b.len() as isize;
-    let robot_center_y = b.iter().map(|&(_, y)| y as isize).sum::<isize>() / b.len() as isize;
-
-    let robot_reflected: Vec<(isize, isize)> = b
-        .iter()
-        .map(|&(x, y)| {
-            let rel_x = x as isize - robot_center_x;
-            let rel_y = y as isize - robot_center_y;
-            (-rel_x, -rel_y)
-        })
-        .collect();
-
-    let mut sum: Vec<Point> = Vec::new();
-    for &(ox, oy) in a {
-        for &(rx, ry) in &robot_reflected {
-            let x_result = ox as isize + rx;
-            let y_result = oy as isize + ry;
-
-            let x_clamped = x_result.clamp(0, WIDTH as isize - 1) as usize;
-            let y_clamped = y_result.clamp(0, HEIGHT as isize - 1) as usize;
-
-            sum.push((x_clamped, y_clamped));
-        }
-    }
-
-    let hull = convex_hull(&sum);
-    polygons_expanded.push(hull);
-}
-
-fn generate_random_obstacle(center_x: usize, center_y: usize, polygons: &mut Vec<Polygon>) {
+fn generate_random_obstacle(center_x: i32, center_y: i32, polygons: &mut Vec<Polygon>) {
     let mut rng = rand::rng();
     let num_vertices = rng.random_range(3..=8);
     let max_radius = 50;
@@ -207,130 +167,513 @@ fn generate_random_obstacle(center_x: usize, center_y: usize, polygons: &mut Vec
         let x = center_x as f32 + radius * angle.cos();
         let y = center_y as f32 + radius * angle.sin();
 
-        let x = (x as usize).clamp(0, WIDTH - 1);
-        let y = (y as usize).clamp(0, HEIGHT - 1);
-
-        points.push((x, y));
+        points.push((x.round() as i32, y.round() as i32));
     }
 
     polygons.push(points)
 }
 
-fn point_to_segment_distance(px: f32, py: f32, x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
-    let dx = x2 - x1;
-    let dy = y2 - y1;
-    let len_sq = dx * dx + dy * dy;
+fn circle_footprint(center: Point, radius: f32, sides: usize) -> Polygon {
+    (0..sides)
+        .map(|i| {
+            let angle = (i as f32 / sides as f32) * 2.0 * std::f32::consts::PI;
+            (
+                (center.0 as f32 + radius * angle.cos()).round() as i32,
+                (center.1 as f32 + radius * angle.sin()).round() as i32,
+            )
+        })
+        .collect()
+}
 
-    if len_sq == 0.0 {
-        return ((px - x1) * (px - x1) + (py - y1) * (py - y1)).sqrt();
+fn l_shape_footprint(center: Point) -> Polygon {
+    let (cx, cy) = center;
+    vec![
+        (cx - 20, cy - 20),
+        (cx - 5, cy - 20),
+        (cx - 5, cy + 5),
+        (cx + 20, cy + 5),
+        (cx + 20, cy + 20),
+        (cx - 20, cy + 20),
+    ]
+}
+
+fn rotate_polygon(polygon: &Polygon, degrees: f32) -> Polygon {
+    let n = polygon.len() as f32;
+    let cx = polygon.iter().map(|&(x, _)| x as f32).sum::<f32>() / n;
+    let cy = polygon.iter().map(|&(_, y)| y as f32).sum::<f32>() / n;
+    let (sin, cos) = degrees.to_radians().sin_cos();
+
+    polygon
+        .iter()
+        .map(|&(x, y)| {
+            let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+            let rx = cx + dx * cos - dy * sin;
+            let ry = cy + dx * sin + dy * cos;
+            (rx.round() as i32, ry.round() as i32)
+        })
+        .collect()
+}
+
+// One inflated obstacle set per heading, every step_deg degrees.
+fn orientation_slices(polygons: &[Polygon], robot: &Polygon, step_deg: usize) -> Vec<Vec<Polygon>> {
+    (0..360)
+        .step_by(step_deg)
+        .map(|angle| {
+            let rotated = rotate_polygon(robot, angle as f32);
+            let mut expanded = Vec::new();
+            for polygon in polygons {
+                expanded.push(expand_obstacle(polygon, &rotated));
+            }
+            expanded
+        })
+        .collect()
+}
+
+// Non-convex footprints like the L-shape are inflated by their hull, which over-approximates.
+fn inflate_obstacles(
+    polygons: &[Polygon],
+    robot: &Polygon,
+    polygons_expanded: &mut Vec<Polygon>,
+    distance_table: &mut Vec<(usize, usize)>,
+    stats: &mut Registry<Stat>,
+) {
+    polygons_expanded.clear();
+    distance_table.clear();
+
+    let start_time = Instant::now();
+    for polygon in polygons {
+        polygons_expanded.push(expand_obstacle(polygon, robot));
     }
+    let duration = start_time.elapsed();
 
-    let t = ((px - x1) * dx + (py - y1) * dy) / len_sq;
-    let t = t.clamp(0.0, 1.0);
+    stats.record(Stat::TimeToFinish, duration);
 
-    let closest_x = x1 + t * dx;
-    let closest_y = y1 + t * dy;
+    for i in 0..polygons.len() {
+        let smallest_distance = boundary_distance(&polygons[i], &polygons_expanded[i]);
+        distance_table.push((i, smallest_distance as usize));
+    }
 
-    ((px - closest_x) * (px - closest_x) + (py - closest_y) * (py - closest_y)).sqrt()
+    for x in distance_table.iter() {
+        println!("obstacle:{}, min_distance:{}", x.0, x.1);
+    }
 }
 
-fn min_distance_to_polygon_edges(point: Point, polygon: &Polygon) -> f32 {
-    let mut min_dist = f32::MAX;
-    let (px, py) = point;
+fn translate_polygon(polygon: &Polygon, center: Point) -> Polygon {
+    let n = polygon.len() as i32;
+    let cx = polygon.iter().map(|&(x, _)| x).sum::<i32>() / n;
+    let cy = polygon.iter().map(|&(_, y)| y).sum::<i32>() / n;
+    let (dx, dy) = (center.0 - cx, center.1 - cy);
+    polygon.iter().map(|&(x, y)| (x + dx, y + dy)).collect()
+}
 
-    for i in 0..polygon.len() {
-        let (x1, y1) = polygon[i];
-        let (x2, y2) = polygon[(i + 1) % polygon.len()];
+// The robot's reference point is its centroid, so it overlaps an obstacle exactly
+// when that point lies inside the obstacle's Minkowski expansion.
+fn probe_collision(point: Point, expanded: &[Polygon]) -> Option<usize> {
+    expanded
+        .iter()
+        .position(|polygon| point_in_polygon(point, polygon))
+}
 
-        let dist = point_to_segment_distance(
-            px as f32, py as f32, x1 as f32, y1 as f32, x2 as f32, y2 as f32,
-        );
+// Sweeping the robot along start->goal is the same as moving its reference point
+// through the expanded obstacles; returns the first obstacle hit and where.
+fn first_sweep_collision(
+    start: Point,
+    goal: Point,
+    expanded: &[Polygon],
+) -> Option<(usize, Point)> {
+    let (index, t) = expanded
+        .iter()
+        .enumerate()
+        .filter_map(|(i, polygon)| Some((i, Segment::new(start, goal).entry(polygon)?)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))?;
+    let x = start.0 as f32 + t * (goal.0 as f32 - start.0 as f32);
+    let y = start.1 as f32 + t * (goal.1 as f32 - start.1 as f32);
+    Some((index, (x.round() as i32, y.round() as i32)))
+}
 
-        min_dist = min_dist.min(dist);
+// Vertices win over interiors, and later polygons sit on top of earlier ones.
+fn pick_obstacle(point: Point, polygons: &[Polygon]) -> Option<Drag> {
+    let (px, py) = (point.0 as f32, point.1 as f32);
+    for (index, polygon) in polygons.iter().enumerate().rev() {
+        let vertex = polygon
+            .iter()
+            .position(|&(x, y)| (x as f32 - px).hypot(y as f32 - py) <= VERTEX_PICK_RADIUS);
+        if let Some(vertex) = vertex {
+            return Some(Drag::Vertex { index, vertex });
+        }
     }
+    polygons
+        .iter()
+        .rposition(|polygon| point_in_polygon(point, polygon))
+        .map(|index| Drag::Polygon { index, last: point })
+}
 
-    min_dist
+fn apply_drag(drag: &mut Drag, mouse: Point, polygons: &mut [Polygon]) -> bool {
+    match drag {
+        Drag::Vertex { index, vertex } => {
+            let moved = polygons[*index][*vertex] != mouse;
+            polygons[*index][*vertex] = mouse;
+            moved
+        }
+        Drag::Polygon { index, last } => {
+            let (dx, dy) = (mouse.0 - last.0, mouse.1 - last.1);
+            if dx == 0 && dy == 0 {
+                return false;
+            }
+            for point in polygons[*index].iter_mut() {
+                point.0 += dx;
+                point.1 += dy;
+            }
+            *last = mouse;
+            true
+        }
+    }
 }
 
-fn min_distance_polygon_to_expanded(polygon: &Polygon, expanded: &Polygon) -> f32 {
-    let mut min_dist = f32::MAX;
+fn expand_obstacle(polygon: &Polygon, robot: &Polygon) -> Polygon {
+    minkowski_sum(polygon, robot)
+}
 
-    for &vertex in polygon {
-        let dist = min_distance_to_polygon_edges(vertex, expanded);
-        min_dist = min_dist.min(dist);
+// Only the edited obstacle is re-inflated, in the plain set and in every orientation slice.
+fn refresh_obstacle(
+    index: usize,
+    polygons: &[Polygon],
+    robot: &Polygon,
+    polygons_expanded: &mut Vec<Polygon>,
+    distance_table: &mut Vec<(usize, usize)>,
+    slices: &mut [Vec<Polygon>],
+    slice_step_deg: usize,
+) {
+    let polygon = &polygons[index];
+    if !polygons_expanded.is_empty() {
+        let expanded = expand_obstacle(polygon, robot);
+        let distance = boundary_distance(polygon, &expanded) as usize;
+        if index < polygons_expanded.len() {
+            polygons_expanded[index] = expanded;
+        } else {
+            polygons_expanded.push(expanded);
+        }
+        match distance_table.iter_mut().find(|(id, _)| *id == index) {
+            Some(row) => row.1 = distance,
+            None => distance_table.push((index, distance)),
+        }
+    }
+    for (k, slice) in slices.iter_mut().enumerate() {
+        let rotated = rotate_polygon(robot, (k * slice_step_deg) as f32);
+        let expanded = expand_obstacle(polygon, &rotated);
+        if index < slice.len() {
+            slice[index] = expanded;
+        } else {
+            slice.push(expanded);
+        }
     }
+}
 
-    min_dist
+fn remove_obstacle(
+    index: usize,
+    polygons: &mut Vec<Polygon>,
+    polygons_expanded: &mut Vec<Polygon>,
+    distance_table: &mut Vec<(usize, usize)>,
+    slices: &mut [Vec<Polygon>],
+) {
+    polygons.remove(index);
+    if index < polygons_expanded.len() {
+        polygons_expanded.remove(index);
+    }
+    distance_table.retain(|(id, _)| *id != index);
+    for row in distance_table.iter_mut().filter(|(id, _)| *id > index) {
+        row.0 -= 1;
+    }
+    for slice in slices.iter_mut().filter(|slice| index < slice.len()) {
+        slice.remove(index);
+    }
 }
 
 fn main() {
-    let mut stats = Statistics::new();
+    let mut stats = new_statistics();
     let mut polygons: Vec<Polygon> = Vec::new();
     let mut polygons_expanded: Vec<Polygon> = Vec::new();
-    let mut last_log_time = Instant::now();
-    let robot: Polygon = vec![(200, 200), (240, 200), (240, 240), (200, 240)];
+    let mut robot: Polygon = vec![(200, 200), (240, 200), (240, 240), (200, 240)];
+    let mut footprint_draft: Option<Polygon> = None;
     let mut window = Window::new("Moving Box", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
     let mut was_pressed = false;
     let mut distance_table: Vec<(usize, usize)> = Vec::new();
+    let mut slices: Vec<Vec<Polygon>> = Vec::new();
+    let mut slice_index = 0;
+    let mut slice_step_deg = SLICE_STEPS_DEG[1];
+    let mut selected: Option<usize> = None;
+    let mut drag: Option<Drag> = None;
+    let mut probing = false;
+    let mut probe_title = String::new();
+    let mut sweep_start: Option<Point> = None;
+    let mut sweep_goal: Option<Point> = None;
+    let mut sweep_report: Option<Option<(usize, Point)>> = None;
 
     polygons.push(vec![(20, 20), (60, 20), (60, 60), (20, 60)]);
     polygons.push(vec![(200, 20), (260, 20), (260, 60), (200, 60)]);
     polygons.push(vec![(71, 272), (91, 321), (147, 314)]);
 
-    stats.obstacles_amount = 3;
-    stats.points_amount = 22;
+    stats.set(Stat::ObstaclesAmount, 3usize);
+    stats.set(Stat::PointsAmount, 22usize);
 
     while window.is_open() && !window.is_key_down(minifb::Key::Escape) {
         buffer.fill(WHITE);
         let is_pressed = window.get_mouse_down(MouseButton::Left);
 
-        for expanded in &polygons_expanded {
-            draw_polygon(&mut buffer, expanded, RED);
+        if let Some(slice) = slices.get(slice_index) {
+            for expanded in slice {
+                draw_polygon(&mut buffer, expanded, RED);
+            }
+            let heading = (slice_index * slice_step_deg) as f32;
+            draw_polygon(&mut buffer, &rotate_polygon(&robot, heading), ORANGE);
+        } else {
+            for expanded in &polygons_expanded {
+                draw_polygon(&mut buffer, expanded, RED);
+            }
+
+            draw_polygon(&mut buffer, &robot, ORANGE);
         }
 
-        draw_polygon(&mut buffer, &robot, ORANGE);
+        if let Some(draft) = &footprint_draft {
+            for pair in draft.windows(2) {
+                draw_line(&mut buffer, pair[0].0, pair[0].1, pair[1].0, pair[1].1, RED);
+            }
+        }
 
-        for polygon in &polygons {
-            draw_polygon(&mut buffer, polygon, BLACK);
+        for (i, polygon) in polygons.iter().enumerate() {
+            let color = if selected == Some(i) { BLUE } else { BLACK };
+            draw_polygon(&mut buffer, polygon, color);
         }
 
-        if window.is_key_pressed(Key::M, minifb::KeyRepeat::No) {
-            let start_time = Instant::now();
-            for polygon in &polygons {
-                minkowski_sum(polygon, &robot, &mut polygons_expanded);
+        let mouse_pos = window
+            .get_mouse_pos(minifb::MouseMode::Clamp)
+            .map(|(x, y)| (x as i32, y as i32));
+        let mut needs_expanded = false;
+        if window.is_key_pressed(Key::S, minifb::KeyRepeat::No) {
+            sweep_start = mouse_pos;
+            needs_expanded = true;
+        }
+        if window.is_key_pressed(Key::G, minifb::KeyRepeat::No) {
+            sweep_goal = mouse_pos;
+            needs_expanded = true;
+        }
+        if window.is_key_pressed(Key::P, minifb::KeyRepeat::No) {
+            probing = !probing;
+            needs_expanded |= probing;
+        }
+        if needs_expanded && polygons_expanded.is_empty() {
+            inflate_obstacles(
+                &polygons,
+                &robot,
+                &mut polygons_expanded,
+                &mut distance_table,
+                &mut stats,
+            );
+        }
+        let (expanded, footprint) = match slices.get(slice_index) {
+            Some(slice) => (
+                slice,
+                rotate_polygon(&robot, (slice_index * slice_step_deg) as f32),
+            ),
+            None => (&polygons_expanded, robot.clone()),
+        };
+
+        if let (Some(start), Some(goal)) = (sweep_start, sweep_goal) {
+            let start_pose = translate_polygon(&footprint, start);
+            let goal_pose = translate_polygon(&footprint, goal);
+            let swept = convex_hull(&[start_pose.clone(), goal_pose.clone()].concat());
+            for i in 0..swept.len() {
+                let (x0, y0) = swept[i];
+                let (x1, y1) = swept[(i + 1) % swept.len()];
+                draw_line(&mut buffer, x0, y0, x1, y1, ORANGE);
+            }
+            draw_polygon(&mut buffer, &start_pose, ORANGE);
+            draw_polygon(&mut buffer, &goal_pose, ORANGE);
+            draw_line(&mut buffer, start.0, start.1, goal.0, goal.1, BLACK);
+
+            let hit = first_sweep_collision(start, goal, expanded);
+            if let Some((_, contact)) = hit {
+                draw_polygon(&mut buffer, &translate_polygon(&footprint, contact), RED);
             }
-            let duration = start_time.elapsed();
+            if sweep_report != Some(hit) {
+                match hit {
+                    Some((i, (x, y))) => {
+                        println!("sweep: obstacle:{}, penetration:({}, {})", i, x, y)
+                    }
+                    None => println!("sweep: free"),
+                }
+                sweep_report = Some(hit);
+            }
+        }
 
-            stats.time_to_finish_in_micros = duration.as_micros() as usize;
+        let mut title = String::from("Moving Box");
+        if probing && let Some(mouse) = mouse_pos {
+            let hit = probe_collision(mouse, expanded);
+            let placed = translate_polygon(&footprint, mouse);
+            let nearest = polygons
+                .iter()
+                .enumerate()
+                .map(|(i, polygon)| (i, polygon_distance(&placed, polygon)))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            let color = if hit.is_some() {
+                RED
+            } else if nearest.is_some_and(|(_, d)| d < PROXIMITY_WARNING) {
+                ORANGE
+            } else {
+                GREEN
+            };
+            draw_polygon(&mut buffer, &placed, color);
+            title = match (hit, nearest) {
+                (Some(i), _) => format!(
+                    "Probe ({}, {}): collides with obstacle {}",
+                    mouse.0, mouse.1, i
+                ),
+                (None, Some((i, d))) if d < PROXIMITY_WARNING => format!(
+                    "Probe ({}, {}): warning, {:.1}px from obstacle {}",
+                    mouse.0, mouse.1, d, i
+                ),
+                _ => format!("Probe ({}, {}): free", mouse.0, mouse.1),
+            };
+        }
+        if title != probe_title {
+            window.set_title(&title);
+            probe_title = title;
+        }
 
-            for i in 0..polygons.len() {
-                let smallest_distance =
-                    min_distance_polygon_to_expanded(&polygons[i], &polygons_expanded[i]);
+        if window.is_key_pressed(Key::M, minifb::KeyRepeat::No) {
+            inflate_obstacles(
+                &polygons,
+                &robot,
+                &mut polygons_expanded,
+                &mut distance_table,
+                &mut stats,
+            );
+        }
 
-                if !distance_table.iter().any(|(id, _)| *id == i) {
-                    distance_table.push((i, smallest_distance as usize));
-                }
+        let mut rebuild_slices = window.is_key_pressed(Key::O, minifb::KeyRepeat::No);
+        if window.is_key_pressed(Key::Up, minifb::KeyRepeat::No) {
+            let i = SLICE_STEPS_DEG
+                .iter()
+                .position(|&s| s == slice_step_deg)
+                .unwrap_or(0);
+            slice_step_deg = SLICE_STEPS_DEG[(i + 1) % SLICE_STEPS_DEG.len()];
+            rebuild_slices |= !slices.is_empty();
+            println!("slice_step:{}", slice_step_deg);
+        }
+        if window.is_key_pressed(Key::Down, minifb::KeyRepeat::No) {
+            let i = SLICE_STEPS_DEG
+                .iter()
+                .position(|&s| s == slice_step_deg)
+                .unwrap_or(0);
+            slice_step_deg =
+                SLICE_STEPS_DEG[(i + SLICE_STEPS_DEG.len() - 1) % SLICE_STEPS_DEG.len()];
+            rebuild_slices |= !slices.is_empty();
+            println!("slice_step:{}", slice_step_deg);
+        }
+        if !slices.is_empty() {
+            let mut scrubbed = false;
+            if window.is_key_pressed(Key::Right, minifb::KeyRepeat::Yes) {
+                slice_index = (slice_index + 1) % slices.len();
+                scrubbed = true;
             }
+            if window.is_key_pressed(Key::Left, minifb::KeyRepeat::Yes) {
+                slice_index = (slice_index + slices.len() - 1) % slices.len();
+                scrubbed = true;
+            }
+            if scrubbed {
+                println!(
+                    "slice:{}, heading:{}",
+                    slice_index,
+                    slice_index * slice_step_deg
+                );
+            }
+        }
 
-            for x in &distance_table {
-                println!("obstacle:{}, min_distance:{}", x.0, x.1);
+        let mut new_footprint = None;
+        if window.is_key_pressed(Key::F, minifb::KeyRepeat::No) {
+            footprint_draft = match footprint_draft {
+                Some(_) => None,
+                None => Some(Vec::new()),
+            };
+        }
+        if window.is_key_pressed(Key::Enter, minifb::KeyRepeat::No)
+            && let Some(draft) = footprint_draft.take_if(|draft| draft.len() >= 3)
+        {
+            new_footprint = Some(draft);
+        }
+        if window.is_key_pressed(Key::C, minifb::KeyRepeat::No) {
+            new_footprint = Some(circle_footprint(ROBOT_CENTER, CIRCLE_RADIUS, CIRCLE_SIDES));
+        }
+        if window.is_key_pressed(Key::L, minifb::KeyRepeat::No) {
+            new_footprint = Some(l_shape_footprint(ROBOT_CENTER));
+        }
+        if let Some(footprint) = new_footprint {
+            robot = footprint;
+            footprint_draft = None;
+            if !polygons_expanded.is_empty() {
+                inflate_obstacles(
+                    &polygons,
+                    &robot,
+                    &mut polygons_expanded,
+                    &mut distance_table,
+                    &mut stats,
+                );
             }
+            rebuild_slices |= !slices.is_empty();
+        }
+
+        if rebuild_slices {
+            let start_time = Instant::now();
+            slices = orientation_slices(&polygons, &robot, slice_step_deg);
+            stats.record(Stat::TimeToFinish, start_time.elapsed());
+            slice_index = slice_index.min(slices.len() - 1);
+            println!("slices:{}, step:{}", slices.len(), slice_step_deg);
+        }
+
+        if window.is_key_pressed(Key::Delete, minifb::KeyRepeat::No)
+            && let Some(index) = selected.take()
+        {
+            remove_obstacle(
+                index,
+                &mut polygons,
+                &mut polygons_expanded,
+                &mut distance_table,
+                &mut slices,
+            );
+            drag = None;
+            stats.set(Stat::ObstaclesAmount, polygons.len());
+            stats.set(
+                Stat::PointsAmount,
+                polygons.iter().map(|p| p.len() * 2).sum::<usize>(),
+            );
         }
 
         if window.is_key_pressed(Key::Space, minifb::KeyRepeat::No) {
             polygons_expanded.clear();
+            slices.clear();
+            slice_index = 0;
         }
 
         if let Some((x, y)) = window.get_mouse_pos(minifb::MouseMode::Clamp) {
-            let mouse_x = x as usize;
-            let mouse_y = y as usize;
+            let mouse_x = x as i32;
+            let mouse_y = y as i32;
+
+            if is_pressed
+                && !was_pressed
+                && let Some(draft) = &mut footprint_draft
+            {
+                draft.push((mouse_x, mouse_y));
+            } else if is_pressed && !was_pressed {
+                drag = pick_obstacle((mouse_x, mouse_y), &polygons);
+                selected = drag.as_ref().map(Drag::index);
+            }
 
-            if is_pressed && !was_pressed {
-                let mut points_amount = 0;
+            if is_pressed && !was_pressed && footprint_draft.is_none() && drag.is_none() {
+                let mut points_amount: usize = 0;
 
                 generate_random_obstacle(mouse_x, mouse_y, &mut polygons);
-                stats.obstacles_amount += 1;
+                stats.set(Stat::ObstaclesAmount, polygons.len());
 
                 for polygon in &polygons {
                     for _ in polygon {
@@ -338,14 +681,42 @@ fn main() {
                     }
                 }
 
-                stats.points_amount = points_amount;
+                stats.set(Stat::PointsAmount, points_amount);
+                refresh_obstacle(
+                    polygons.len() - 1,
+                    &polygons,
+                    &robot,
+                    &mut polygons_expanded,
+                    &mut distance_table,
+                    &mut slices,
+                    slice_step_deg,
+                );
+            } else if is_pressed
+                && let Some(active) = &mut drag
+                && apply_drag(active, (mouse_x, mouse_y), &mut polygons)
+            {
+                refresh_obstacle(
+                    active.index(),
+                    &polygons,
+                    &robot,
+                    &mut polygons_expanded,
+                    &mut distance_table,
+                    &mut slices,
+                    slice_step_deg,
+                );
             }
         }
 
-        if last_log_time.elapsed() >= Duration::from_secs(1) {
-            save_statistics(&stats).unwrap();
-            last_log_time = Instant::now();
+        if !is_pressed
+            && let Some(released) = drag.take()
+            && let Some(row) = distance_table
+                .iter()
+                .find(|(id, _)| *id == released.index())
+        {
+            println!("obstacle:{}, min_distance:{}", row.0, row.1);
         }
+
+        stats.tick().unwrap();
         was_pressed = is_pressed;
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
@@ -1,14 +1,10 @@
-use chrono::prelude::*;
-use csv::Writer;
 use minifb::{Key, MouseButton, Window, WindowOptions};
 use rand::Rng;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::collections::{BinaryHeap, HashMap};
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::time::{Duration, Instant};
+use telemetry::{Kind, Metric, Registry, TimeUnit};
 
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 1000;
@@ -21,13 +17,6 @@ const ORANGE: u32 = 0x00FF963C;
 const CELL_WIDTH: usize = WIDTH / COLUMNS;
 const CELL_HEIGHT: usize = HEIGHT / ROWS;
 
-struct Statistics {
-    obstacles_amount: usize,
-    start_points: usize,
-    end_points: usize,
-    time_to_finish_in_micros: usize,
-}
-
 #[derive(Eq, PartialEq)]
 enum Steps {
     Obstacles,
@@ -35,45 +24,44 @@ enum Steps {
     End,
 }
 
-impl Statistics {
-    fn new() -> Self {
-        Statistics {
-            obstacles_amount: 0,
-            start_points: 0,
-            end_points: 0,
-            time_to_finish_in_micros: 0,
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stat {
+    ObstaclesAmount,
+    StartPoints,
+    EndPoints,
+    TimeToFinish,
 }
 
-fn save_statistics(stats: &Statistics) -> Result<(), Box<dyn Error>> {
-    let path = "stats.csv";
-    let file_exists = Path::new(path).exists();
-
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
-
-    let mut wtr = Writer::from_writer(file);
-
-    if !file_exists {
-        wtr.write_record(&[
-            "timestamp",
-            "obstacles_amount",
-            "start_points",
-            "end_points",
-            "time_to_finish_in_micros",
-        ])?;
+impl Metric for Stat {
+    const ALL: &'static [Self] = &[
+        Stat::ObstaclesAmount,
+        Stat::StartPoints,
+        Stat::EndPoints,
+        Stat::TimeToFinish,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stat::ObstaclesAmount => "obstacles_amount",
+            Stat::StartPoints => "start_points",
+            Stat::EndPoints => "end_points",
+            Stat::TimeToFinish => "time_to_finish_in_micros",
+        }
     }
 
-    wtr.write_record(&[
-        Local::now().to_string(),
-        stats.obstacles_amount.to_string(),
-        stats.start_points.to_string(),
-        stats.end_points.to_string(),
-        stats.time_to_finish_in_micros.to_string(),
-    ])?;
+    fn kind(self) -> Kind {
+        match self {
+            Stat::ObstaclesAmount | Stat::StartPoints | Stat::EndPoints => Kind::Counter,
+            Stat::TimeToFinish => Kind::Timer(TimeUnit::Micros),
+        }
+    }
+}
 
-    wtr.flush()?;
-    Ok(())
+fn new_statistics() -> Registry<Stat> {
+    Registry::builder()
+        .csv("stats.csv")
+        .interval(Duration::from_secs(1))
+        .build()
 }
 
 fn draw_line(buffer: &mut [u32], x0: usize, y0: usize, x1: usize, y1: usize, color: u32) {
@@ -243,8 +231,7 @@ fn a_star(start: Node, goal: Node, walls: &HashSet<Node>) -> Option<Vec<Node>> {
 }
 
 fn main() {
-    let mut stats = Statistics::new();
-    let mut last_log_time = Instant::now();
+    let mut stats = new_statistics();
     let mut window =
         Window::new("Navigation grid", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
@@ -317,7 +304,7 @@ fn main() {
                 }
                 let duration = start_time.elapsed();
 
-                stats.time_to_finish_in_micros = duration.as_micros() as usize;
+                stats.record(Stat::TimeToFinish, duration);
             }
         }
 
@@ -370,7 +357,7 @@ fn main() {
                         if !start_points.contains(&(mod_x, mod_y))
                             & !end_points.contains(&(mod_x, mod_y))
                         {
-                            stats.obstacles_amount += 1;
+                            stats.increment(Stat::ObstaclesAmount);
                             walls.insert(Node {
                                 x: mod_x as i32,
                                 y: mod_y as i32,
@@ -382,7 +369,7 @@ fn main() {
                             x: mod_x as i32,
                             y: mod_y as i32,
                         }) {
-                            stats.start_points += 1;
+                            stats.increment(Stat::StartPoints);
                             start_points.push((mod_x, mod_y));
                             currect_step = Steps::End;
                         }
@@ -392,7 +379,7 @@ fn main() {
                             x: mod_x as i32,
                             y: mod_y as i32,
                         }) {
-                            stats.end_points += 1;
+                            stats.increment(Stat::EndPoints);
                             end_points.push((mod_x, mod_y));
                             currect_step = Steps::Start;
                         }
@@ -401,10 +388,7 @@ fn main() {
             }
         }
 
-        if last_log_time.elapsed() >= Duration::from_secs(1) {
-            save_statistics(&stats).unwrap();
-            last_log_time = Instant::now();
-        }
+        stats.tick().unwrap();
         was_pressed = is_pressed;
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }